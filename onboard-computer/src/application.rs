@@ -1,5 +1,10 @@
+#[cfg(feature = "gnss-uart")]
+use core::cell::RefCell;
+
+#[cfg(feature = "gnss-uart")]
+use critical_section::Mutex;
 use embassy_executor::Executor;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use esp_println::println;
 use hal::{
     clock::ClockControl,
@@ -13,20 +18,69 @@ use hal::{
         config::{Config, DataBits, Parity, StopBits},
         TxRxPins,
     },
-    Rng, Rtc, Uart, IO,
+    Rtc, Uart, IO,
+};
+
+#[cfg(feature = "gnss-uart")]
+use hal::{
+    interrupt,
+    peripherals::{Interrupt, UART1},
 };
 
-use nmea::ParseResult;
+#[cfg(feature = "gnss-uart")]
+use crate::nmea::uart::{RxBuffer, UartGnss};
+use crate::nmea::{
+    receive,
+    solution::{SolutionAggregator, NAVIGATION_SOLUTION},
+    Constellation, GnssSource,
+};
+#[cfg(not(feature = "gnss-uart"))]
+use crate::nmea::{replay::EpochReplay, MOCK_SENTENCES};
 
 /// The Rust ESP32-C3 board has onboard LED on GPIO 7
 // pub type OnboardLed = Gpio7<Output<PushPull>>;
 
-static MOCK_SENTENCES: &'static str = include_str!("../../tests/nmea.log");
+/// The GNSS module on UART1, the `gnss` task can't be generic
+#[cfg(feature = "gnss-uart")]
+pub type GnssReceiver = UartGnss<&'static RxBuffer<GNSS_RX_BUFFER>>;
+
+/// The bytes received from the GNSS module, `~180 ms` at 115200 baud
+/// for the `gnss` task to catch up after e.g. a blocking `println!`
+#[cfg(feature = "gnss-uart")]
+pub const GNSS_RX_BUFFER: usize = 2048;
+
+/// The UART1 interrupt moves the RX FIFO here once it has this many bytes (of 128)
+#[cfg(feature = "gnss-uart")]
+pub const GNSS_RX_FIFO_THRESHOLD: u16 = 64;
+
+/// The UART1 interrupt also moves the RX FIFO once the line has been idle
+/// for this many bit times (10 bytes at 8N1), i.e. at the end of each burst of sentences
+#[cfg(feature = "gnss-uart")]
+pub const GNSS_RX_TIMEOUT_BITS: u16 = 100;
+
+#[cfg(feature = "gnss-uart")]
+static GNSS_RX: RxBuffer<GNSS_RX_BUFFER> = RxBuffer::new();
+
+/// UART1 for its interrupt handler
+#[cfg(feature = "gnss-uart")]
+static GNSS_UART: Mutex<RefCell<Option<Uart<'static, UART1>>>> = Mutex::new(RefCell::new(None));
+
+/// The simulated GNSS module, the mock log at the cadence it was recorded
+#[cfg(not(feature = "gnss-uart"))]
+pub type GnssReceiver = EpochReplay<core::iter::Cycle<core::str::Lines<'static>>>;
+
+/// The default baud rate of the Quectel GNSS modules
+#[cfg(feature = "gnss-uart")]
+pub const GNSS_BAUDRATE: u32 = 115_200;
+
+/// How many times faster the mock log is replayed
+#[cfg(not(feature = "gnss-uart"))]
+pub const MOCK_SPEED_UP: u32 = 1;
 
 // #[derive(Default)]
 pub struct Application {
     uart0: Uart<'static, UART0>,
-    _rng: Rng<'static>,
+    gnss: GnssReceiver,
 }
 
 impl Application {
@@ -56,35 +110,185 @@ impl Application {
         embassy::init(&clocks, timer_group0.timer0);
 
         // Setup peripherals for application
+        let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
 
         // Optional code for onboard LED
         // Rust ESP32-C3 schematics: https://raw.githubusercontent.com/esp-rs/esp-rust-board/master/assets/rust_board_v1_pin-layout.png
         // Set GPIO7 as an output, and set its state high initially.
         // let onboard_led = io.pins.gpio7.into_push_pull_output();
 
-        // Setup Random Generator for GNSS Reading
+        // Setup the simulated GNSS
+        #[cfg(not(feature = "gnss-uart"))]
+        let gnss = GnssReceiver::new(MOCK_SENTENCES.lines().cycle(), MOCK_SPEED_UP);
 
         // Configure UART
+        let config = Config {
+            baudrate: 115_200,
+            data_bits: DataBits::DataBits8,
+            parity: Parity::ParityNone,
+            stop_bits: StopBits::STOP1,
+        };
 
         // The Rust ESP32-C3 board has debug UART on pins
-        // 20 (TX) and 21 (RX)
+        // 21 (TX) and 20 (RX)
+        let pins = TxRxPins::new_tx_rx(
+            io.pins.gpio21.into_push_pull_output(),
+            io.pins.gpio20.into_floating_input(),
+        );
+        let uart0 = Uart::new_with_config(
+            peripherals.UART0,
+            Some(config),
+            Some(pins),
+            &clocks,
+            &mut peripheral_clock_control,
+        );
+
+        // The GNSS module at 0 (TX) & 1 (RX) GPIO pins
+        #[cfg(feature = "gnss-uart")]
+        let gnss = {
+            let config = Config {
+                baudrate: GNSS_BAUDRATE,
+                ..config
+            };
+            let pins = TxRxPins::new_tx_rx(
+                io.pins.gpio0.into_push_pull_output(),
+                io.pins.gpio1.into_floating_input(),
+            );
+
+            let mut uart1 = Uart::new_with_config(
+                peripherals.UART1,
+                Some(config),
+                Some(pins),
+                &clocks,
+                &mut peripheral_clock_control,
+            );
+            // polling the 128 bytes FIFO would overflow, the interrupt moves it to the buffer
+            // when it's half full and when the line goes idle after the epoch's sentences
+            // (the AT-CMD detector needs an idle line before and after the pattern,
+            // so it doesn't fire in the continuous NMEA stream)
+            uart1
+                .set_rx_fifo_full_threshold(GNSS_RX_FIFO_THRESHOLD)
+                .expect("Should set the UART1 RX FIFO threshold");
+            uart1.listen_rx_fifo_full();
+            listen_gnss_rx_timeout(GNSS_RX_TIMEOUT_BITS);
+            critical_section::with(|cs| GNSS_UART.borrow_ref_mut(cs).replace(uart1));
+            interrupt::enable(Interrupt::UART1, interrupt::Priority::Priority1)
+                .expect("Should enable the UART1 interrupt");
 
-        Self { uart0, _rng }
+            GnssReceiver::new(&GNSS_RX)
+        };
+
+        Self { uart0, gnss }
     }
 
     pub fn run(self, executor: &'static mut Executor) -> ! {
         executor.run(|spawner| {
             spawner.must_spawn(uart_comm(self.uart0));
-            spawner.must_spawn(gnss(self.rng));
+            spawner.must_spawn(gnss(self.gnss));
         })
     }
 }
 
 #[embassy_executor::task]
-async fn gnss(mut rng: Rng<'static>) {
-    // This task parses NMEA sentences simulated from a GNSS data log file
-    // The task picks random sentences from a log file and looks out for GNS and GSV messages
-    // The task prints the number of sats in GNS data and number of sats in view from GSV data
+async fn gnss(source: GnssReceiver) {
+    receive_gnss(source).await
+}
+
+/// Moves the bytes of the GNSS module from the UART1 RX FIFO to the [`GNSS_RX`] buffer.
+#[cfg(feature = "gnss-uart")]
+#[interrupt]
+fn UART1() {
+    critical_section::with(|cs| {
+        let mut uart = GNSS_UART.borrow_ref_mut(cs);
+        let uart = match uart.as_mut() {
+            Some(uart) => uart,
+            None => return,
+        };
+
+        // reading the FIFO doesn't fail, and there's no time for printing here
+        GNSS_RX.drain(uart).ok();
+        uart.reset_rx_fifo_full_interrupt();
+        reset_gnss_rx_timeout_interrupt();
+    });
+}
+
+/// Enables the UART1 RX FIFO timeout interrupt, the HAL has no API for it.
+///
+/// `bits` is the idle time of the RX line in bit times (`0..1024`).
+#[cfg(feature = "gnss-uart")]
+fn listen_gnss_rx_timeout(bits: u16) {
+    // Safety: the HAL's `Uart` doesn't use the RX timeout bits
+    let uart1 = unsafe { &*UART1::PTR };
+    uart1
+        .mem_conf
+        .modify(|_, w| unsafe { w.rx_tout_thrhd().bits(bits) });
+    uart1.conf1.modify(|_, w| w.rx_tout_en().set_bit());
+    // the configuration is synchronized to the UART core clock domain
+    uart1.id.modify(|_, w| w.reg_update().set_bit());
+    while uart1.id.read().reg_update().bit_is_set() {}
+
+    uart1
+        .int_ena
+        .modify(|_, w| w.rxfifo_tout_int_ena().set_bit());
+}
+
+#[cfg(feature = "gnss-uart")]
+fn reset_gnss_rx_timeout_interrupt() {
+    // Safety: writing 1 clears only the RX timeout interrupt
+    let uart1 = unsafe { &*UART1::PTR };
+    uart1.int_clr.write(|w| w.rxfifo_tout_int_clr().set_bit());
+}
+
+async fn receive_gnss<S: GnssSource>(mut source: S) {
+    // This task combines the NMEA sentences of each epoch from the GNSS source,
+    // e.g. simulated from a GNSS data log file, into the navigation solution.
+    // The task prints the number of sats in the fix from GGA data and number of sats in view from GSV data
+    let publisher = NAVIGATION_SOLUTION
+        .publisher()
+        .expect("Should have a free publisher for the navigation solution");
+    let mut aggregator = SolutionAggregator::new();
+    #[cfg(feature = "gnss-uart")]
+    let mut dropped = 0;
+
+    loop {
+        let sentence = match receive(&mut source).await {
+            Ok(sentence) => sentence,
+            Err(err) => {
+                println!("Failed to receive a GNSS sentence: {:?}", err);
+                Timer::after(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        if let Some(solution) = aggregator.push(&sentence, Instant::now()) {
+            println!(
+                "Satellites in the fix: {:?}, in view: {:?}",
+                solution.fix_satellites.map(|satellites| satellites.value),
+                solution.satellites_in_view()
+            );
+            if let Some(sky_view) = &solution.sky_view {
+                for constellation in Constellation::ALL {
+                    let counts = sky_view.value.counts(constellation);
+                    println!(
+                        "{}: {} in view, {} used",
+                        constellation.as_str(),
+                        counts.in_view,
+                        counts.used
+                    );
+                }
+            }
+            publisher.publish_immediate(solution.clone());
+        }
+
+        #[cfg(feature = "gnss-uart")]
+        if GNSS_RX.dropped() != dropped {
+            dropped = GNSS_RX.dropped();
+            println!(
+                "GNSS RX buffer overflowed, {} bytes dropped since boot",
+                dropped
+            );
+        }
+    }
 }
 
 #[embassy_executor::task]
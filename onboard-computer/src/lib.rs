@@ -1,9 +1,10 @@
 #![cfg_attr(not(any(feature = "std", test)), no_std)]
-
-#![no_main]
+#![cfg_attr(not(any(feature = "std", test)), no_main)]
 #![feature(type_alias_impl_trait)]
 
+#[cfg(feature = "riscv")]
 pub use application::Application;
 
+#[cfg(feature = "riscv")]
 mod application;
-pub mod nmea;
\ No newline at end of file
+pub mod nmea;
@@ -1,46 +1,391 @@
-use core::{iter::Cycle, str::Lines};
+//! NMEA 0183 sentences from the GNSS.
+//!
+//! The `gnss` task only needs "something that yields NMEA sentences", a [`GnssSource`]:
+//!
+//! - [`NmeaReceiver`] - cycles through the `tests/nmea.log` with random delays
+//! - [`UartGnss`](uart::UartGnss) - a real GNSS module on a UART
+//! - [`HostReplay`](host::HostReplay) - replays a log file or the standard input on the host
+//! - [`EpochReplay`](replay::EpochReplay) - replays a log at the cadence it was recorded
+//!
+//! The bytes of a serial port are split into sentences by the [`NmeaFramer`](framer::NmeaFramer).
+//! The sentences of each epoch are combined into a
+//! [`NavigationSolution`](solution::NavigationSolution), with the
+//! [`SkyView`](sky_view::SkyView) of the satellites in view of each constellation.
+use core::{
+    fmt,
+    iter::Cycle,
+    str::{Lines, Split},
+};
 
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Timer};
+use defmt::Format;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::blocking::rng::Read;
+use heapless::{String, Vec};
 
-use embedded_hal::prelude::_embedded_hal_blocking_rng_Read;
+pub mod framer;
+#[cfg(any(feature = "std", test))]
+pub mod host;
+pub mod replay;
+pub mod sky_view;
+pub mod solution;
+pub mod uart;
 
-use hal::Rng;
+pub static MOCK_SENTENCES: &str = include_str!("../../tests/nmea.log");
 
-pub static MOCK_SENTENCES: &'static str = include_str!("../../tests/nmea.log");
+/// The maximum length of an NMEA sentence, including the `$` and `\r\n`
+pub const MAX_SENTENCE_LENGTH: usize = 82;
 
-pub struct NmeaReceiver {
-    mock_sentences: Mutex<CriticalSectionRawMutex, Cycle<Lines<'static>>>,
-    rng: Mutex<CriticalSectionRawMutex, Rng<'static>>,
+/// A sentence without the `\r\n`, e.g. `$GNGGA,...*71`
+pub type Sentence = String<MAX_SENTENCE_LENGTH>;
+
+/// A source of NMEA sentences.
+pub trait GnssSource {
+    type Error: fmt::Debug;
+
+    /// Returns the next sentence (without the `\r\n`) once it's received.
+    ///
+    /// Returns [`nb::Error::WouldBlock`] until then.
+    fn poll_sentence(&mut self, now: Instant) -> nb::Result<Sentence, Self::Error>;
+}
+
+impl<T: GnssSource> GnssSource for &mut T {
+    type Error = T::Error;
+
+    fn poll_sentence(&mut self, now: Instant) -> nb::Result<Sentence, Self::Error> {
+        T::poll_sentence(self, now)
+    }
+}
+
+/// How often a [`GnssSource`] is polled by [`receive`] while it's blocking
+pub const POLLING_PERIOD: Duration = Duration::from_millis(10);
+
+/// Waits for the next sentence of the source.
+pub async fn receive<S: GnssSource>(source: &mut S) -> Result<Sentence, S::Error> {
+    loop {
+        match source.poll_sentence(Instant::now()) {
+            Ok(sentence) => return Ok(sentence),
+            Err(nb::Error::Other(err)) => return Err(err),
+            Err(nb::Error::WouldBlock) => Timer::after(POLLING_PERIOD).await,
+        }
+    }
+}
+
+/// The UTC time field (`hhmmss.ss`) in milliseconds since midnight
+pub fn utc_millis(field: &str) -> Option<u32> {
+    let (hhmmss, fraction) = field.split_once('.').unwrap_or((field, ""));
+    if hhmmss.len() != 6
+        || !hhmmss
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|byte| byte.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let hours: u32 = hhmmss[0..2].parse().ok()?;
+    let minutes: u32 = hhmmss[2..4].parse().ok()?;
+    // up to a leap second
+    let seconds: u32 = hhmmss[4..6].parse().ok()?;
+    if hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    let millis = fraction
+        .bytes()
+        .chain(core::iter::repeat(b'0'))
+        .take(3)
+        .fold(0, |millis, digit| millis * 10 + u32::from(digit - b'0'));
+
+    Some(((hours * 60 + minutes) * 60 + seconds) * 1000 + millis)
+}
+
+/// Splits a sentence into its talker (e.g. `GN`), type (e.g. `GGA`) and fields,
+/// without the `$` and the `*hh` checksum.
+pub fn fields(sentence: &str) -> Option<(&str, &str, Split<'_, char>)> {
+    let body = sentence.strip_prefix('$')?;
+    let body = body.rsplit_once('*').map_or(body, |(body, _checksum)| body);
+    let mut fields = body.split(',');
+    let address = fields.next()?;
+    if address.len() != 5 || !address.is_ascii() {
+        return None;
+    }
+
+    Some((&address[..2], &address[2..], fields))
+}
+
+/// The GNSS constellations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub enum Constellation {
+    Gps,
+    Glonass,
+    Galileo,
+    BeiDou,
+    Qzss,
+}
+
+impl Constellation {
+    pub const ALL: [Constellation; 5] = [
+        Constellation::Gps,
+        Constellation::Glonass,
+        Constellation::Galileo,
+        Constellation::BeiDou,
+        Constellation::Qzss,
+    ];
+
+    /// From the talker of a sentence, `GN` (a combined solution) is none of them.
+    ///
+    /// The Quectel modules send the BeiDou sentences with their proprietary `PQ` talker.
+    pub fn from_talker(talker: &str) -> Option<Self> {
+        match talker {
+            "GP" => Some(Constellation::Gps),
+            "GL" => Some(Constellation::Glonass),
+            "GA" => Some(Constellation::Galileo),
+            "GB" | "BD" | "PQ" => Some(Constellation::BeiDou),
+            "GQ" => Some(Constellation::Qzss),
+            _ => None,
+        }
+    }
+
+    /// From the system ID field of NMEA 4.11
+    pub fn from_system_id(system_id: u8) -> Option<Self> {
+        match system_id {
+            1 => Some(Constellation::Gps),
+            2 => Some(Constellation::Glonass),
+            3 => Some(Constellation::Galileo),
+            4 => Some(Constellation::BeiDou),
+            5 => Some(Constellation::Qzss),
+            _ => None,
+        }
+    }
+
+    /// Of the satellites in a GSA sentence, by its system ID or else its talker.
+    ///
+    /// Quectel's BeiDou `PQGSA` has the system ID 5, which is QZSS in NMEA 4.11.
+    pub fn from_gsa(talker: &str, system_id: Option<u8>) -> Option<Self> {
+        match (talker, system_id) {
+            ("PQ", _) => Some(Constellation::BeiDou),
+            (_, Some(system_id)) => Constellation::from_system_id(system_id),
+            (talker, None) => Constellation::from_talker(talker),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Constellation::Gps => "GPS",
+            Constellation::Glonass => "GLONASS",
+            Constellation::Galileo => "GALILEO",
+            Constellation::BeiDou => "BEIDOU",
+            Constellation::Qzss => "QZSS",
+        }
+    }
+}
+
+/// The maximum number of different sentences in an epoch which are told apart
+pub const MAX_EPOCH_SENTENCES: usize = 16;
+
+/// Splits the sentences into epochs, a GNSS module sends a burst of sentences every epoch.
+///
+/// The next epoch starts with a sentence which was already sent in the current one,
+/// unless it's the next part of a sequence, e.g. GSV 2 of 3.
+#[derive(Debug, Clone, Default)]
+pub struct EpochSplitter {
+    /// The talker and type of the epoch's sentences, e.g. `GNGSA`
+    seen: Vec<[u8; 5], MAX_EPOCH_SENTENCES>,
+    previous: Option<[u8; 5]>,
+}
+
+impl EpochSplitter {
+    pub const fn new() -> Self {
+        Self {
+            seen: Vec::new(),
+            previous: None,
+        }
+    }
+
+    /// Adds the next sentence and returns whether it starts a new epoch.
+    pub fn push(&mut self, sentence: &str) -> bool {
+        let mut kind = [0; 5];
+        for (kind, byte) in kind.iter_mut().zip(sentence.bytes().skip(1)) {
+            *kind = byte;
+        }
+
+        let new_epoch = self.previous != Some(kind) && self.seen.contains(&kind);
+        if new_epoch {
+            self.seen.clear();
+        }
+        if !self.seen.contains(&kind) {
+            self.seen.push(kind).ok();
+        }
+        self.previous = Some(kind);
+
+        new_epoch
+    }
 }
 
-impl NmeaReceiver {
-    pub fn new(rng: Mutex<CriticalSectionRawMutex, Rng<'static>>) -> Self {
-        let sentences_iterator = MOCK_SENTENCES.lines().cycle();
+/// Simulates a GNSS module by cycling through the [`MOCK_SENTENCES`]
+/// with a random delay of up to `255 ms` before each one.
+pub struct NmeaReceiver<R> {
+    mock_sentences: Cycle<Lines<'static>>,
+    rng: R,
+    /// When the next sentence is received, `None` until it's polled
+    next_at: Option<Instant>,
+}
 
+impl<R: Read> NmeaReceiver<R> {
+    pub fn new(rng: R) -> Self {
         Self {
-            mock_sentences: Mutex::new(sentences_iterator),
+            mock_sentences: MOCK_SENTENCES.lines().cycle(),
             rng,
+            next_at: None,
         }
     }
 
-    /// We can parse the `&str` and look for the following sentences which have
+    fn random_delay(&mut self) -> Duration {
+        let mut random_bytes = [0_u8];
+        // without randomness the sentences are received at the maximum delay
+        if self.rng.read(&mut random_bytes).is_err() {
+            random_bytes[0] = 0;
+        }
+
+        Duration::from_millis((255 - random_bytes[0]).into())
+    }
+}
+
+impl<R: Read> GnssSource for NmeaReceiver<R> {
+    type Error = core::convert::Infallible;
+
+    /// We can parse the sentences and look for the following ones which have
     /// longitude and latitude information:
     /// - GGA
     /// - RMC
-    pub async fn receive(&self) -> &str {
-        let milliseconds = {
-            let mut rng = self.rng.lock().await;
-            let mut random_bytes = [0_u8];
-            rng.read(&mut random_bytes).expect("Should get random byte");
-
-            255 - random_bytes[0]
+    fn poll_sentence(&mut self, now: Instant) -> nb::Result<Sentence, Self::Error> {
+        let next_at = match self.next_at {
+            Some(next_at) => next_at,
+            None => {
+                let next_at = now + self.random_delay();
+                self.next_at = Some(next_at);
+                next_at
+            }
         };
-        
-        Timer::after(Duration::from_millis(milliseconds.into())).await;
-        let mut sentence_guard = self.mock_sentences.lock().await;
+        if now < next_at {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.next_at = None;
+
+        loop {
+            let line = self
+                .mock_sentences
+                .next()
+                .expect("Should always have a next sentence since we have a Cycle iterator");
+
+            // the log has only valid sentences, but an empty line is not one
+            match line.trim_end().parse::<Sentence>() {
+                Ok(sentence) if !sentence.is_empty() => return Ok(sentence),
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Always returns the same byte
+    struct FixedRng(u8);
+
+    impl Read for FixedRng {
+        type Error = ();
 
+        fn read(&mut self, buffer: &mut [u8]) -> Result<(), Self::Error> {
+            buffer.fill(self.0);
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_utc_millis() {
+        assert_eq!(Some(19_425_770), utc_millis("052345.77"));
+        assert_eq!(Some(19_425_000), utc_millis("052345"));
+        assert_eq!(Some(86_399_123), utc_millis("235959.1234"));
+        assert_eq!(Some(5), utc_millis("000000.005"));
+        assert_eq!(None, utc_millis(""));
+        assert_eq!(None, utc_millis("52345.77"));
+        assert_eq!(None, utc_millis("246000.00"));
+        assert_eq!(None, utc_millis("052345.7a"));
+    }
 
-        sentence_guard.next().expect("Should always have a next sentence since we have a Cycle iterator")
+    #[test]
+    fn test_fields() {
+        let (talker, kind, values) =
+            fields("$GNVTG,,T,,M,0.0,N,0.0,K,A*3D").expect("Should be a sentence");
+        assert_eq!(("GN", "VTG"), (talker, kind));
+        assert_eq!(
+            std::vec!["", "T", "", "M", "0.0", "N", "0.0", "K", "A"],
+            values.collect::<std::vec::Vec<_>>()
+        );
+
+        let (talker, kind, mut values) = fields("$PQGSV,1,1,0,0").expect("Without a checksum");
+        assert_eq!(("PQ", "GSV"), (talker, kind));
+        assert_eq!(Some("0"), values.nth(3));
+
+        assert!(fields("GNGGA,*00").is_none());
+        assert!(fields("$PQTMVER*00").is_none());
+    }
+
+    #[test]
+    fn test_epoch_splitter() {
+        let mut epochs = EpochSplitter::new();
+        let starts: std::vec::Vec<usize> = MOCK_SENTENCES
+            .lines()
+            .enumerate()
+            .filter_map(|(index, sentence)| epochs.push(sentence).then_some(index))
+            .collect();
+
+        // the epoch before the first fix and then one per GGA/RMC pair
+        assert_eq!(36, starts.len());
+        assert_eq!(17, starts[0]);
+        assert!(MOCK_SENTENCES
+            .lines()
+            .nth(starts[1])
+            .unwrap()
+            .starts_with("$GNGSA"));
+    }
+
+    #[test]
+    fn test_mock_receiver() {
+        // 255 - 155 = 100 ms between the sentences
+        let mut receiver = NmeaReceiver::new(FixedRng(155));
+        let start = Instant::from_secs(0);
+
+        assert_eq!(Err(nb::Error::WouldBlock), receiver.poll_sentence(start));
+        assert_eq!(
+            Err(nb::Error::WouldBlock),
+            receiver.poll_sentence(start + Duration::from_millis(99))
+        );
+        assert_eq!(
+            Ok("$GPGSA,A,1,,,,,,,,,,,,,,,*1E"),
+            receiver
+                .poll_sentence(start + Duration::from_millis(100))
+                .as_deref()
+        );
+
+        // cycles through the whole log
+        let lines = MOCK_SENTENCES.lines().count();
+        let mut now = start + Duration::from_millis(100);
+        for _ in 0..lines {
+            now += Duration::from_millis(100);
+            assert_eq!(Err(nb::Error::WouldBlock), receiver.poll_sentence(now));
+            now += Duration::from_millis(100);
+            assert!(receiver.poll_sentence(now).is_ok());
+        }
+        now += Duration::from_millis(100);
+        receiver.poll_sentence(now).ok();
+        assert_eq!(
+            Ok("$GNGSA,A,1,,,,,,,,,,,,,,,*00"),
+            receiver
+                .poll_sentence(now + Duration::from_millis(100))
+                .as_deref()
+        );
     }
 }
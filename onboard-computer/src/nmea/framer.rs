@@ -0,0 +1,395 @@
+//! Frames NMEA sentences from a stream of bytes.
+//!
+//! A receiver on a UART delivers the bytes in arbitrary chunks, possibly with garbage
+//! (e.g. a partial sentence at power-up or noise on the line) between the sentences.
+//! The [`NmeaFramer`] accepts the bytes one by one and yields the valid `$...*hh\r\n` frames.
+use defmt::Format;
+use heapless::Vec;
+
+use super::{Sentence, MAX_SENTENCE_LENGTH};
+
+/// Why a frame was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Reject {
+    /// Longer than [`MAX_SENTENCE_LENGTH`]
+    TooLong,
+    /// A new frame started (`$`) before the end of this one
+    Interrupted,
+    /// A byte which is not printable ASCII
+    InvalidByte,
+    /// No `*hh` checksum field before the `\r\n`
+    Malformed,
+    /// The checksum doesn't match the XOR of the bytes between the `$` and the `*`
+    Checksum,
+}
+
+/// The rejected frames since boot by [`Reject`] reason
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct RejectCounts {
+    pub too_long: u32,
+    pub interrupted: u32,
+    pub invalid_byte: u32,
+    pub malformed: u32,
+    pub checksum: u32,
+}
+
+impl RejectCounts {
+    pub fn count(&self, reason: Reject) -> u32 {
+        match reason {
+            Reject::TooLong => self.too_long,
+            Reject::Interrupted => self.interrupted,
+            Reject::InvalidByte => self.invalid_byte,
+            Reject::Malformed => self.malformed,
+            Reject::Checksum => self.checksum,
+        }
+    }
+
+    pub fn total(&self) -> u32 {
+        self.too_long + self.interrupted + self.invalid_byte + self.malformed + self.checksum
+    }
+
+    fn add(&mut self, reason: Reject) {
+        let count = match reason {
+            Reject::TooLong => &mut self.too_long,
+            Reject::Interrupted => &mut self.interrupted,
+            Reject::InvalidByte => &mut self.invalid_byte,
+            Reject::Malformed => &mut self.malformed,
+            Reject::Checksum => &mut self.checksum,
+        };
+        *count = count.saturating_add(1);
+    }
+}
+
+/// Where the framer is in the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Looking for the `$` of the next frame, the bytes in between are skipped
+    Hunting,
+    /// Within a frame
+    Framing,
+    /// The frame was rejected, waiting for its end
+    Discarding,
+}
+
+/// Finds the `$...*hh\r\n` frames in a stream of bytes and validates them.
+///
+/// After a rejected frame or garbage it resyncs on the next `$`.
+#[derive(Debug, Clone)]
+pub struct NmeaFramer {
+    state: State,
+    /// The frame so far, from the `$`
+    frame: Vec<u8, MAX_SENTENCE_LENGTH>,
+    sentences: u32,
+    skipped_bytes: u32,
+    rejected: RejectCounts,
+}
+
+impl NmeaFramer {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Hunting,
+            frame: Vec::new(),
+            sentences: 0,
+            skipped_bytes: 0,
+            rejected: RejectCounts {
+                too_long: 0,
+                interrupted: 0,
+                invalid_byte: 0,
+                malformed: 0,
+                checksum: 0,
+            },
+        }
+    }
+
+    /// The valid sentences since boot
+    pub fn sentences(&self) -> u32 {
+        self.sentences
+    }
+
+    /// The bytes outside of any frame since boot, e.g. garbage between the sentences
+    pub fn skipped_bytes(&self) -> u32 {
+        self.skipped_bytes
+    }
+
+    pub fn rejected(&self) -> &RejectCounts {
+        &self.rejected
+    }
+
+    /// Feeds the next byte of the stream.
+    ///
+    /// Returns the sentence (without the `\r\n`) on the last byte of a valid frame
+    /// and the [`Reject`] reason as soon as the frame is rejected.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Sentence, Reject>> {
+        match (self.state, byte) {
+            (State::Framing, b'$') => {
+                self.start_frame();
+                Some(Err(self.reject(Reject::Interrupted, State::Framing)))
+            }
+            (_, b'$') => {
+                self.start_frame();
+                None
+            }
+            (State::Hunting, _) => {
+                self.skipped_bytes = self.skipped_bytes.saturating_add(1);
+                None
+            }
+            (State::Discarding, b'\n') => {
+                self.state = State::Hunting;
+                None
+            }
+            (State::Discarding, _) => None,
+            (State::Framing, byte) => {
+                if !(byte.is_ascii_graphic() || matches!(byte, b' ' | b'\r' | b'\n')) {
+                    return Some(Err(self.reject(Reject::InvalidByte, State::Discarding)));
+                }
+                if self.frame.push(byte).is_err() {
+                    return Some(Err(self.reject(Reject::TooLong, State::Discarding)));
+                }
+
+                if byte == b'\n' {
+                    self.state = State::Hunting;
+                    Some(self.validate())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Feeds a chunk of the stream and calls back with every valid sentence in it.
+    pub fn feed(&mut self, bytes: &[u8], mut on_sentence: impl FnMut(Sentence)) {
+        for byte in bytes {
+            if let Some(Ok(sentence)) = self.push(*byte) {
+                on_sentence(sentence);
+            }
+        }
+    }
+
+    fn start_frame(&mut self) {
+        self.frame.clear();
+        self.frame.push(b'$').ok();
+        self.state = State::Framing;
+    }
+
+    fn reject(&mut self, reason: Reject, state: State) -> Reject {
+        self.rejected.add(reason);
+        self.state = state;
+
+        reason
+    }
+
+    /// Validates the whole frame, i.e. `$`, the body, `*hh` and `\r\n`.
+    fn validate(&mut self) -> Result<Sentence, Reject> {
+        let result = match self.frame.as_slice() {
+            [b'$', body @ .., b'*', high, low, b'\r', b'\n'] => {
+                let expected = hex_digit(*high)
+                    .zip(hex_digit(*low))
+                    .map(|(high, low)| high << 4 | low);
+
+                match expected {
+                    Some(expected) if checksum(body) == expected => {
+                        let length = self.frame.len() - 2;
+                        // only printable ASCII is framed
+                        core::str::from_utf8(&self.frame[..length])
+                            .ok()
+                            .and_then(|sentence| sentence.parse::<Sentence>().ok())
+                            .ok_or(Reject::InvalidByte)
+                    }
+                    Some(_) => Err(Reject::Checksum),
+                    None => Err(Reject::Malformed),
+                }
+            }
+            _ => Err(Reject::Malformed),
+        };
+
+        match result {
+            Ok(_) => self.sentences = self.sentences.saturating_add(1),
+            Err(reason) => self.rejected.add(reason),
+        }
+
+        result
+    }
+}
+
+impl Default for NmeaFramer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The NMEA checksum - XOR of all the bytes between the `$` and the `*`
+pub fn checksum(body: &[u8]) -> u8 {
+    body.iter().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    char::from(byte).to_digit(16).map(|digit| digit as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+
+    use crate::nmea::MOCK_SENTENCES;
+
+    use super::*;
+
+    /// A deterministic xorshift generator for the chunk sizes
+    struct XorShift(u32);
+
+    impl XorShift {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    fn frame_all(framer: &mut NmeaFramer, bytes: &[u8]) -> Vec<Result<Sentence, Reject>> {
+        bytes.iter().filter_map(|byte| framer.push(*byte)).collect()
+    }
+
+    #[test]
+    fn test_valid_sentences() {
+        let mut framer = NmeaFramer::new();
+
+        assert_eq!(
+            std::vec![
+                Ok("$GPGSA,A,1,,,,,,,,,,,,,,,*1E".parse().unwrap()),
+                Ok("$gpvtg,lowercase hex*7c".parse().unwrap()),
+            ],
+            frame_all(
+                &mut framer,
+                b"$GPGSA,A,1,,,,,,,,,,,,,,,*1E\r\n$gpvtg,lowercase hex*7c\r\n"
+            )
+        );
+        assert_eq!(2, framer.sentences());
+        assert_eq!(RejectCounts::default(), *framer.rejected());
+    }
+
+    #[test]
+    fn test_rejected_frames() {
+        let mut framer = NmeaFramer::new();
+        let too_long = std::format!("$GPTXT,{}*00\r\n", "A".repeat(MAX_SENTENCE_LENGTH));
+        let cases: [(&[u8], Reject); 7] = [
+            (b"$GPGSA,A,1,,,,,,,,,,,,,,,*1F\r\n", Reject::Checksum),
+            (b"$GPGSA,A,1,,,,,,,,,,,,,,,\r\n", Reject::Malformed),
+            (b"$GPGSA,A,1,,,,,,,,,,,,,,,*1E\n", Reject::Malformed),
+            (b"$GPGSA,A,1,,,,,,,,,,,,,,,*XY\r\n", Reject::Malformed),
+            (b"$GPGSA,A,1,,,", Reject::Interrupted),
+            (b"$GPGSA,\x00,1*00\r\n", Reject::InvalidByte),
+            (too_long.as_bytes(), Reject::TooLong),
+        ];
+
+        for (bytes, reason) in cases {
+            let before = framer.rejected().count(reason);
+            let results = frame_all(&mut framer, bytes);
+            // the interrupted frame is only rejected by the next one
+            if reason == Reject::Interrupted {
+                assert!(results.is_empty());
+                assert_eq!(std::vec![Err(reason)], frame_all(&mut framer, b"$"));
+                framer = NmeaFramer {
+                    rejected: framer.rejected,
+                    ..NmeaFramer::new()
+                };
+            } else {
+                assert_eq!(std::vec![Err(reason)], results, "{:?}", bytes);
+            }
+            assert_eq!(before + 1, framer.rejected().count(reason));
+        }
+        assert_eq!(3, framer.rejected().malformed);
+        assert_eq!(7, framer.rejected().total());
+        assert_eq!(0, framer.sentences());
+
+        // exactly 82 characters
+        let longest = {
+            let body = std::format!("GPTXT,{}", "A".repeat(MAX_SENTENCE_LENGTH - 12));
+            std::format!("${}*{:02X}\r\n", body, checksum(body.as_bytes()))
+        };
+        assert_eq!(MAX_SENTENCE_LENGTH, longest.len());
+        assert!(matches!(
+            frame_all(&mut framer, longest.as_bytes()).as_slice(),
+            [Ok(_)]
+        ));
+    }
+
+    #[test]
+    fn test_resync_after_garbage() {
+        let mut framer = NmeaFramer::new();
+        let bytes = b"\xFF\x00garbage*12\r\n$GPGSA,A,1,,,,,,,,,,,,,,,*1E\r\nnoise$GNGSA,\x80\r\n$GPVTG,,T,,M,,N,,K,N*2C\r\n";
+
+        let sentences: Vec<Sentence> = frame_all(&mut framer, bytes)
+            .into_iter()
+            .filter_map(Result::ok)
+            .collect();
+
+        assert_eq!(
+            std::vec!["$GPGSA,A,1,,,,,,,,,,,,,,,*1E", "$GPVTG,,T,,M,,N,,K,N*2C"],
+            sentences
+        );
+        assert_eq!(1, framer.rejected().invalid_byte);
+        assert_eq!(1, framer.rejected().total());
+        assert_eq!(
+            b"\xFF\x00garbage*12\r\nnoise".len() as u32,
+            framer.skipped_bytes()
+        );
+    }
+
+    #[test]
+    fn test_log_in_random_chunks() {
+        let bytes = MOCK_SENTENCES.as_bytes();
+        let expected: Vec<&str> = MOCK_SENTENCES.lines().collect();
+
+        for seed in [1, 0xDEAD_BEEF, 42, 7_919] {
+            let mut random = XorShift(seed);
+            let mut framer = NmeaFramer::new();
+            let mut sentences = Vec::new();
+
+            let mut remaining = bytes;
+            while !remaining.is_empty() {
+                let size = (random.next() as usize % 100 + 1).min(remaining.len());
+                let (chunk, rest) = remaining.split_at(size);
+                framer.feed(chunk, |sentence| sentences.push(sentence));
+                remaining = rest;
+            }
+
+            assert_eq!(expected, sentences, "seed {}", seed);
+            assert_eq!(expected.len() as u32, framer.sentences());
+            assert_eq!(0, framer.rejected().total());
+            assert_eq!(0, framer.skipped_bytes());
+        }
+    }
+
+    #[test]
+    fn test_log_with_corrupted_bytes() {
+        let mut bytes = MOCK_SENTENCES.as_bytes().to_vec();
+        let mut random = XorShift(0x1234_5678);
+        // corrupts a printable byte of 20 different sentences, i.e. flips the checksum
+        let mut corrupted = std::collections::BTreeSet::new();
+        while corrupted.len() < 20 {
+            let index = random.next() as usize % bytes.len();
+            let line = bytes[..index].iter().filter(|byte| **byte == b'\n').count();
+            if bytes[index].is_ascii_alphanumeric() && !corrupted.contains(&line) {
+                let star = bytes[index..]
+                    .iter()
+                    .position(|byte| *byte == b'*')
+                    .unwrap();
+                // not within the checksum itself
+                if bytes[index..index + star].contains(&b'\n') {
+                    continue;
+                }
+                bytes[index] ^= 0x01;
+                corrupted.insert(line);
+            }
+        }
+
+        let mut framer = NmeaFramer::new();
+        let mut sentences = 0;
+        framer.feed(&bytes, |_| sentences += 1);
+
+        assert_eq!(MOCK_SENTENCES.lines().count() - 20, sentences);
+        assert_eq!(20, framer.rejected().checksum);
+        assert_eq!(20, framer.rejected().total());
+    }
+}
@@ -0,0 +1,112 @@
+//! Replays NMEA sentences on the host, e.g. from a log file or the standard input.
+//!
+//! ```no_run
+//! use std::io;
+//!
+//! use onboard_computer::nmea::host::HostReplay;
+//!
+//! let from_file = HostReplay::open("tests/nmea.log")?;
+//! let from_stdin = HostReplay::new(io::stdin().lock());
+//! # Ok::<(), io::Error>(())
+//! ```
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use embassy_time::Instant;
+
+use super::{GnssSource, Sentence};
+
+/// The error returned by the [`HostReplay`].
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    /// All the sentences were replayed
+    EndOfInput,
+}
+
+/// Reads the sentences line by line as soon as they're polled,
+/// the lines which are not sentences are skipped.
+pub struct HostReplay<R> {
+    reader: R,
+    line: std::string::String,
+}
+
+impl HostReplay<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> HostReplay<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: std::string::String::new(),
+        }
+    }
+}
+
+impl<R: BufRead> GnssSource for HostReplay<R> {
+    type Error = ReplayError;
+
+    fn poll_sentence(&mut self, _now: Instant) -> nb::Result<Sentence, Self::Error> {
+        loop {
+            self.line.clear();
+            let read = self
+                .reader
+                .read_line(&mut self.line)
+                .map_err(ReplayError::Io)?;
+            if read == 0 {
+                return Err(nb::Error::Other(ReplayError::EndOfInput));
+            }
+
+            let line = self.line.trim_end();
+            if !line.starts_with('$') {
+                continue;
+            }
+            if let Ok(sentence) = line.parse() {
+                return Ok(sentence);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::nmea::MOCK_SENTENCES;
+
+    use super::*;
+
+    #[test]
+    fn test_host_replay() {
+        let now = Instant::from_secs(0);
+        let input =
+            "\r\n$GPGSA,A,1,,,,,,,,,,,,,,,*1E\r\nnot a sentence\n$GNVTG,,T,,M,0.0,N,0.0,K,A*3D";
+        let mut replay = HostReplay::new(input.as_bytes());
+
+        assert_eq!(
+            Ok("$GPGSA,A,1,,,,,,,,,,,,,,,*1E"),
+            replay.poll_sentence(now).as_deref().map_err(|_| ())
+        );
+        assert_eq!(
+            Ok("$GNVTG,,T,,M,0.0,N,0.0,K,A*3D"),
+            replay.poll_sentence(now).as_deref().map_err(|_| ())
+        );
+        assert!(matches!(
+            replay.poll_sentence(now),
+            Err(nb::Error::Other(ReplayError::EndOfInput))
+        ));
+
+        let mut replay =
+            HostReplay::open(concat!(env!("CARGO_MANIFEST_DIR"), "/../tests/nmea.log")).unwrap();
+        for line in MOCK_SENTENCES.lines() {
+            assert_eq!(
+                Ok(line),
+                replay.poll_sentence(now).as_deref().map_err(|_| ())
+            );
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! Replays a log of NMEA sentences at the cadence they were recorded.
+//!
+//! A GNSS module sends a burst of sentences every epoch (e.g. 1 Hz), the epochs of the log
+//! are timed with the UTC time of their GGA or RMC sentence:
+//!
+//! ```text
+//! $GNGSA,A,3,...      \
+//! $GNVTG,...           |
+//! $GNRMC,052345.77,... | the epoch at 05:23:45.77
+//! $GNGGA,052345.77,... |
+//! $GPGSV,3,1,...      /
+//! $GNGSA,A,3,...      - the next epoch starts with a repeated sentence
+//! ```
+//!
+//! A burst is emitted as a whole at the time of its epoch, divided by a speed-up factor.
+use embassy_time::{Duration, Instant};
+use heapless::Deque;
+
+use super::{utc_millis, EpochSplitter, GnssSource, Sentence};
+
+/// The maximum number of sentences in an epoch, a longer burst is split.
+pub const MAX_BURST: usize = 32;
+
+/// The epoch period when it can't be timed, e.g. before the first fix
+/// or when the log starts over.
+pub const NOMINAL_EPOCH_PERIOD: Duration = Duration::from_secs(1);
+
+/// The milliseconds in a day, for the epochs past midnight
+const DAY_MILLIS: u32 = 24 * 60 * 60 * 1000;
+
+/// The log has no more sentences
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndOfLog;
+
+/// Emits the epochs of the log at their original cadence,
+/// e.g. to test the fix timeouts and rate estimation.
+pub struct EpochReplay<L> {
+    lines: L,
+    /// `1` is real time, `10` is 10 times faster
+    speed_up: u32,
+    /// The sentences of the current epoch to be emitted
+    burst: Deque<Sentence, MAX_BURST>,
+    /// When the current epoch is emitted
+    burst_at: Option<Instant>,
+    /// The first sentence of the next epoch
+    next: Option<Sentence>,
+    /// The UTC time (in milliseconds since midnight) of the last epoch and when it was emitted
+    anchor: Option<(Option<u32>, Instant)>,
+}
+
+impl<L> EpochReplay<L>
+where
+    L: Iterator,
+    L::Item: AsRef<str>,
+{
+    /// # Panics
+    ///
+    /// When the speed-up factor is 0.
+    pub fn new(lines: L, speed_up: u32) -> Self {
+        assert!(speed_up > 0, "Speed-up factor should be at least 1");
+
+        Self {
+            lines,
+            speed_up,
+            burst: Deque::new(),
+            burst_at: None,
+            next: None,
+            anchor: None,
+        }
+    }
+
+    /// The next sentence of the log, skipping the lines which are not sentences
+    fn next_sentence(&mut self) -> Option<Sentence> {
+        if let Some(sentence) = self.next.take() {
+            return Some(sentence);
+        }
+
+        loop {
+            let line = self.lines.next()?;
+            let line = line.as_ref().trim_end();
+            if !line.starts_with('$') {
+                continue;
+            }
+            if let Ok(sentence) = line.parse() {
+                return Some(sentence);
+            }
+        }
+    }
+
+    /// Reads the next epoch into the burst and returns its UTC time (if it has one).
+    fn read_epoch(&mut self) -> Option<u32> {
+        let mut epoch = EpochSplitter::new();
+        let mut utc = None;
+
+        while let Some(sentence) = self.next_sentence() {
+            if epoch.push(&sentence) {
+                self.next = Some(sentence);
+                break;
+            }
+
+            if utc.is_none() && matches!(sentence.get(3..6), Some("GGA" | "RMC")) {
+                utc = sentence.split(',').nth(1).and_then(utc_millis);
+            }
+            // split when it's too long
+            if self.burst.push_back(sentence).is_err() || self.burst.is_full() {
+                break;
+            }
+        }
+
+        utc
+    }
+
+    /// When the epoch with the UTC time is emitted, the first one right away.
+    fn schedule(&mut self, utc: Option<u32>, now: Instant) -> Instant {
+        let at = match self.anchor {
+            None => now,
+            Some((last_utc, last_at)) => {
+                let elapsed = match (utc, last_utc) {
+                    (Some(utc), Some(last_utc)) => {
+                        // past midnight the time of day starts from 0
+                        let millis = (utc + DAY_MILLIS - last_utc) % DAY_MILLIS;
+                        // otherwise the log started over
+                        (millis < DAY_MILLIS / 2).then(|| Duration::from_millis(millis.into()))
+                    }
+                    _ => None,
+                };
+
+                last_at + elapsed.unwrap_or(NOMINAL_EPOCH_PERIOD) / self.speed_up
+            }
+        };
+
+        // an untimed epoch is a nominal period after the last one
+        let utc = utc.or_else(|| {
+            let last_utc = self.anchor?.0?;
+            Some((last_utc + NOMINAL_EPOCH_PERIOD.as_millis() as u32) % DAY_MILLIS)
+        });
+        self.anchor = Some((utc, at));
+
+        at
+    }
+}
+
+impl<L> GnssSource for EpochReplay<L>
+where
+    L: Iterator,
+    L::Item: AsRef<str>,
+{
+    type Error = EndOfLog;
+
+    fn poll_sentence(&mut self, now: Instant) -> nb::Result<Sentence, Self::Error> {
+        let burst_at = match self.burst_at {
+            Some(burst_at) if !self.burst.is_empty() => burst_at,
+            _ => {
+                let utc = self.read_epoch();
+                if self.burst.is_empty() {
+                    return Err(nb::Error::Other(EndOfLog));
+                }
+                let burst_at = self.schedule(utc, now);
+                self.burst_at = Some(burst_at);
+                burst_at
+            }
+        };
+
+        if now < burst_at {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        self.burst.pop_front().ok_or(nb::Error::Other(EndOfLog))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+
+    use crate::nmea::MOCK_SENTENCES;
+
+    use super::*;
+
+    /// Replays the whole log once and returns the time of each epoch and its sentences.
+    fn replay_epochs(speed_up: u32) -> Vec<(Instant, Vec<Sentence>)> {
+        let mut replay = EpochReplay::new(MOCK_SENTENCES.lines(), speed_up);
+        let mut now = Instant::from_secs(0);
+        let mut epochs: Vec<(Instant, Vec<Sentence>)> = Vec::new();
+
+        loop {
+            match replay.poll_sentence(now) {
+                Ok(sentence) => match epochs.last_mut() {
+                    Some((at, sentences)) if *at == now => sentences.push(sentence),
+                    _ => epochs.push((now, std::vec![sentence])),
+                },
+                Err(nb::Error::WouldBlock) => now += Duration::from_millis(1),
+                Err(nb::Error::Other(EndOfLog)) => return epochs,
+            }
+        }
+    }
+
+    #[test]
+    fn test_epochs_at_original_cadence() {
+        let epochs = replay_epochs(1);
+
+        // the epoch before the first fix and then one per GGA/RMC pair
+        assert_eq!(1 + 36, epochs.len());
+        assert_eq!(
+            MOCK_SENTENCES.lines().count(),
+            epochs.iter().map(|(_, sentences)| sentences.len()).sum()
+        );
+
+        let (first_at, first) = &epochs[0];
+        assert_eq!(Instant::from_secs(0), *first_at);
+        assert_eq!(17, first.len());
+        assert!(first[16].starts_with("$PQGSV,3,3,"));
+
+        // the first fix is a nominal period after the untimed epoch
+        let (at, sentences) = &epochs[1];
+        assert_eq!(Instant::from_secs(1), *at);
+        assert!(sentences[0].starts_with("$GNGSA"));
+        assert!(sentences[5].starts_with("$GNGGA,052345.77"));
+        // 052345.77 to 052347.00
+        assert_eq!(Instant::from_millis(2230), epochs[2].0);
+        assert!(epochs[2].1.last().unwrap().starts_with("$PQGSV,3,3,"));
+
+        // and 1 Hz afterwards, 052347.00 to 052421.00
+        assert_eq!(Instant::from_millis(2230 + 34_000), epochs[36].0);
+        for window in epochs[2..].windows(2) {
+            assert_eq!(Duration::from_secs(1), window[1].0 - window[0].0);
+        }
+    }
+
+    #[test]
+    fn test_speed_up() {
+        let epochs = replay_epochs(10);
+
+        assert_eq!(Instant::from_millis(100), epochs[1].0);
+        assert_eq!(Instant::from_millis(223), epochs[2].0);
+        assert_eq!(Instant::from_millis(223 + 3_400), epochs[36].0);
+    }
+
+    #[test]
+    fn test_midnight_and_restart() {
+        let log = [
+            "$GNRMC,235959.50,A*00",
+            "$GNGGA,235959.50,*00",
+            "$GNRMC,000000.50,A*00",
+            "$GNGGA,000000.50,*00",
+            // the log started over
+            "$GNRMC,235959.50,A*00",
+            "",
+            "$GNGGA,235959.50,*00",
+        ];
+        let mut replay = EpochReplay::new(log.iter(), 2);
+        let start = Instant::from_secs(0);
+
+        assert!(replay.poll_sentence(start).is_ok());
+        assert!(replay.poll_sentence(start).is_ok());
+        assert_eq!(Err(nb::Error::WouldBlock), replay.poll_sentence(start));
+        let midnight = start + Duration::from_millis(500);
+        assert_eq!(
+            Ok("$GNRMC,000000.50,A*00"),
+            replay.poll_sentence(midnight).as_deref()
+        );
+        replay.poll_sentence(midnight).unwrap();
+
+        assert_eq!(Err(nb::Error::WouldBlock), replay.poll_sentence(midnight));
+        let restart = midnight + NOMINAL_EPOCH_PERIOD / 2;
+        assert!(replay.poll_sentence(restart).is_ok());
+        assert!(replay.poll_sentence(restart).is_ok());
+        assert_eq!(
+            Err(nb::Error::Other(EndOfLog)),
+            replay.poll_sentence(restart)
+        );
+    }
+}
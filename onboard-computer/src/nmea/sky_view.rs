@@ -0,0 +1,540 @@
+//! The satellites in view of every constellation and signal.
+//!
+//! A GSV sequence lists the satellites in view of a constellation on a signal
+//! (the NMEA 4.11 signal ID), 4 satellites per part:
+//!
+//! ```text
+//! $GPGSV,3,1,12,05,19,222,31,07,05,090,25,13,84,239,35,14,56,052,33,1*65
+//! $GPGSV,3,2,12,15,50,296,28,17,35,125,20,23,11,319,21,24,16,284,26,1*66
+//! $GPGSV,3,3,12,28,00,000,28,30,28,084,18,19,23,147,,20,03,201,,1*62
+//!        | |  |  |  |  |   |                                    |
+//!        | |  |  |  |  |   SNR (dB-Hz), empty when not tracked  signal ID
+//!        | |  |  |  |  azimuth
+//!        | |  |  |  elevation
+//!        | |  |  PRN
+//!        | |  satellites in view
+//!        | part
+//!        parts
+//! ```
+//!
+//! The parts are assembled in the [`SkyView`] once all of them are received, in any order.
+//! A sequence with a missing part is dropped when the next one starts, i.e. a part is
+//! received again or in a later epoch, and the table keeps the last complete one.
+//! Each view has the time of the epoch which completed it and it's dropped once it's
+//! older than [`MAX_SIGNAL_AGE`], e.g. when a constellation is no longer received.
+//!
+//! The satellites used in the fix come from the GSA sentences of the epoch,
+//! parsed by the [`SolutionAggregator`](super::solution::SolutionAggregator).
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use super::{
+    fields,
+    solution::{Timed, UsedSatellite},
+    Constellation,
+};
+
+/// The maximum number of fields in a GSV sentence
+const MAX_FIELDS: usize = 24;
+
+/// The maximum number of satellites of a constellation on a signal, i.e. 6 parts
+pub const MAX_SATELLITES: usize = 24;
+
+/// The maximum number of parts of a GSV sequence
+const MAX_PARTS: usize = MAX_SATELLITES / 4;
+
+/// The maximum number of constellations and signals in view
+pub const MAX_SIGNALS: usize = 8;
+
+/// The age after which the view of a signal which isn't sent anymore is dropped,
+/// the GSV sentences may be sent only every few epochs
+pub const MAX_SIGNAL_AGE: Duration = Duration::from_secs(10);
+
+/// The maximum number of sequences which are assembled at the same time
+const MAX_PENDING: usize = 2;
+
+/// The maximum number of satellites used in a fix, 12 per GSA of each constellation
+pub const MAX_USED_SATELLITES: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct SatelliteInView {
+    pub prn: u8,
+    /// Degrees
+    pub elevation: Option<u8>,
+    /// Degrees from the true north
+    pub azimuth: Option<u16>,
+    /// dB-Hz, `None` when it's not tracked
+    pub snr: Option<u8>,
+}
+
+/// The satellites in view of a constellation on a signal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignalView {
+    pub constellation: Constellation,
+    /// The NMEA 4.11 signal ID, e.g. `1` for GPS L1 C/A, `None` before NMEA 4.10
+    pub signal_id: Option<u8>,
+    pub satellites: Vec<SatelliteInView, MAX_SATELLITES>,
+}
+
+/// The satellites in view and used in the fix of a constellation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct SatelliteCounts {
+    /// A satellite in view on multiple signals is counted once
+    pub in_view: u8,
+    pub used: u8,
+}
+
+/// A GSV sequence which is being assembled
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Sequence {
+    constellation: Constellation,
+    signal_id: Option<u8>,
+    parts: u8,
+    in_view: u8,
+    /// A bit for each received part
+    received: u8,
+    /// The epoch of the parts, the ones of a later epoch start the next sequence
+    at: Instant,
+    satellites: [Option<SatelliteInView>; MAX_SATELLITES],
+}
+
+impl Sequence {
+    fn is_complete(&self) -> bool {
+        u32::from(self.received) == (1 << self.parts) - 1
+    }
+}
+
+/// The table of the satellites in view by constellation and signal.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SkyView {
+    /// The last complete sequence of each constellation and signal
+    signals: Vec<Timed<SignalView>, MAX_SIGNALS>,
+    pending: Vec<Sequence, MAX_PENDING>,
+    /// The satellites used in the fix, from the GSA sentences of the last epoch
+    used: Vec<UsedSatellite, MAX_USED_SATELLITES>,
+    dropped_sequences: u32,
+}
+
+impl SkyView {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The complete views, in the order they were first received
+    pub fn signals(&self) -> &[Timed<SignalView>] {
+        &self.signals
+    }
+
+    pub fn signal(
+        &self,
+        constellation: Constellation,
+        signal_id: Option<u8>,
+    ) -> Option<&Timed<SignalView>> {
+        self.signals.iter().find(|signal| {
+            signal.value.constellation == constellation && signal.value.signal_id == signal_id
+        })
+    }
+
+    pub fn is_used(&self, constellation: Constellation, prn: u8) -> bool {
+        self.used.contains(&UsedSatellite {
+            constellation: Some(constellation),
+            prn,
+        })
+    }
+
+    /// Replaces the satellites used in the fix, the ones without a constellation
+    /// (e.g. of a combined GSA) are not counted.
+    pub fn set_used(&mut self, used: &[UsedSatellite]) {
+        self.used.clear();
+        // both have the same capacity
+        self.used.extend(used.iter().copied());
+    }
+
+    pub fn counts(&self, constellation: Constellation) -> SatelliteCounts {
+        let mut in_view = Vec::<u8, { MAX_SATELLITES * MAX_SIGNALS }>::new();
+        let satellites = self
+            .signals
+            .iter()
+            .filter(|signal| signal.value.constellation == constellation)
+            .flat_map(|signal| signal.value.satellites.iter());
+        for satellite in satellites {
+            if !in_view.contains(&satellite.prn) {
+                in_view.push(satellite.prn).ok();
+            }
+        }
+        let used = self
+            .used
+            .iter()
+            .filter(|used| used.constellation == Some(constellation))
+            .count();
+
+        SatelliteCounts {
+            in_view: in_view.len() as u8,
+            used: used as u8,
+        }
+    }
+
+    /// The sequences dropped because of a missing part since boot
+    pub fn dropped_sequences(&self) -> u32 {
+        self.dropped_sequences
+    }
+
+    /// Drops the views older than [`MAX_SIGNAL_AGE`] and returns whether there were any.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        let signals = self.signals.len();
+        self.signals
+            .retain(|signal| signal.age(now) <= MAX_SIGNAL_AGE);
+
+        self.signals.len() != signals
+    }
+
+    /// Adds the next sentence of the epoch which started `at`,
+    /// the ones other than GSV are ignored.
+    ///
+    /// Returns whether the table was updated, i.e. a sequence was completed.
+    pub fn push(&mut self, sentence: &str, at: Instant) -> bool {
+        let (talker, kind, values) = match fields(sentence) {
+            Some(sentence) => sentence,
+            None => return false,
+        };
+        if kind != "GSV" {
+            return false;
+        }
+        let values: Vec<&str, MAX_FIELDS> = values.take(MAX_FIELDS).collect();
+
+        self.push_gsv(talker, &values, at)
+    }
+
+    fn push_gsv(&mut self, talker: &str, values: &[&str], at: Instant) -> bool {
+        let constellation = match Constellation::from_talker(talker) {
+            Some(constellation) => constellation,
+            None => return false,
+        };
+        let header = (
+            values.first().and_then(|parts| parts.parse::<u8>().ok()),
+            values.get(1).and_then(|part| part.parse::<u8>().ok()),
+            values.get(2).and_then(|in_view| in_view.parse::<u8>().ok()),
+        );
+        let (parts, part, in_view) = match header {
+            (Some(parts), Some(part), Some(in_view))
+                if (1..=parts).contains(&part) && usize::from(parts) <= MAX_PARTS =>
+            {
+                (parts, part, in_view)
+            }
+            _ => return false,
+        };
+        // the header, 4 fields per satellite and the optional signal ID
+        let (satellites, signal_id) = match values[3..].len() % 4 {
+            0 => (&values[3..], None),
+            1 => (
+                &values[3..values.len() - 1],
+                values.last().and_then(|signal_id| signal_id.parse().ok()),
+            ),
+            _ => return false,
+        };
+
+        let index = self.pending.iter().position(|sequence| {
+            sequence.constellation == constellation && sequence.signal_id == signal_id
+        });
+        // a part received again, in a later epoch or a different header is the next sequence
+        let index = match index {
+            Some(index)
+                if self.pending[index].at == at
+                    && self.pending[index].parts == parts
+                    && self.pending[index].in_view == in_view
+                    && self.pending[index].received & 1 << (part - 1) == 0 =>
+            {
+                index
+            }
+            index => {
+                if let Some(index) = index {
+                    self.pending.swap_remove(index);
+                    self.dropped_sequences = self.dropped_sequences.saturating_add(1);
+                }
+                // the oldest one is dropped when interleaved with too many others
+                if self.pending.is_full() {
+                    self.pending.remove(0);
+                    self.dropped_sequences = self.dropped_sequences.saturating_add(1);
+                }
+                self.pending
+                    .push(Sequence {
+                        constellation,
+                        signal_id,
+                        parts,
+                        in_view,
+                        received: 0,
+                        at,
+                        satellites: [None; MAX_SATELLITES],
+                    })
+                    .ok();
+                self.pending.len() - 1
+            }
+        };
+
+        let sequence = &mut self.pending[index];
+        sequence.received |= 1 << (part - 1);
+        let first = usize::from(part - 1) * 4;
+        for (slot, satellite) in sequence.satellites[first..first + 4]
+            .iter_mut()
+            .zip(satellites.chunks_exact(4))
+        {
+            *slot = satellite[0].parse().ok().map(|prn| SatelliteInView {
+                prn,
+                elevation: satellite[1].parse().ok(),
+                azimuth: satellite[2].parse().ok(),
+                snr: satellite[3].parse().ok(),
+            });
+        }
+        if !sequence.is_complete() {
+            return false;
+        }
+
+        let sequence = self.pending.swap_remove(index);
+        let view = Timed {
+            value: SignalView {
+                constellation,
+                signal_id,
+                satellites: sequence.satellites.iter().flatten().copied().collect(),
+            },
+            at,
+        };
+        match self.signals.iter_mut().find(|signal| {
+            signal.value.constellation == constellation && signal.value.signal_id == signal_id
+        }) {
+            Some(signal) => *signal = view,
+            None => {
+                // too many signals are ignored
+                self.signals.push(view).ok();
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::nmea::MOCK_SENTENCES;
+
+    use super::*;
+
+    fn push_all(sky_view: &mut SkyView, sentences: &[&str], at: Instant) {
+        for sentence in sentences {
+            sky_view.push(sentence, at);
+        }
+    }
+
+    #[test]
+    fn test_first_epoch_of_the_log() {
+        let mut sky_view = SkyView::new();
+        push_all(
+            &mut sky_view,
+            &MOCK_SENTENCES
+                .lines()
+                .take(17)
+                .collect::<std::vec::Vec<_>>(),
+            Instant::from_secs(0),
+        );
+
+        assert_eq!(4, sky_view.signals().len());
+        let gps = sky_view
+            .signal(Constellation::Gps, Some(1))
+            .expect("Should have the GPS L1 C/A satellites");
+        assert_eq!(Instant::from_secs(0), gps.at);
+        let gps = &gps.value;
+        assert_eq!(11, gps.satellites.len());
+        assert_eq!(
+            SatelliteInView {
+                prn: 5,
+                elevation: Some(19),
+                azimuth: Some(222),
+                snr: Some(36),
+            },
+            gps.satellites[0]
+        );
+        assert_eq!(
+            SatelliteInView {
+                prn: 30,
+                elevation: Some(28),
+                azimuth: Some(84),
+                snr: None,
+            },
+            gps.satellites[10]
+        );
+
+        // Galileo E1 and the empty sequence before the BeiDou one
+        assert_eq!(
+            8,
+            sky_view
+                .signal(Constellation::Galileo, Some(7))
+                .unwrap()
+                .value
+                .satellites
+                .len()
+        );
+        assert_eq!(
+            12,
+            sky_view
+                .signal(Constellation::BeiDou, Some(0))
+                .unwrap()
+                .value
+                .satellites
+                .len()
+        );
+
+        // without a fix
+        for (constellation, in_view) in [
+            (Constellation::Gps, 11),
+            (Constellation::Glonass, 8),
+            (Constellation::Galileo, 8),
+            (Constellation::BeiDou, 12),
+            (Constellation::Qzss, 0),
+        ] {
+            assert_eq!(
+                SatelliteCounts { in_view, used: 0 },
+                sky_view.counts(constellation)
+            );
+        }
+        assert_eq!(0, sky_view.dropped_sequences());
+    }
+
+    #[test]
+    fn test_whole_log() {
+        let mut sky_view = SkyView::new();
+        push_all(
+            &mut sky_view,
+            &MOCK_SENTENCES.lines().collect::<std::vec::Vec<_>>(),
+            Instant::from_secs(0),
+        );
+
+        // the last epoch, the GSA sentences are ignored
+        let in_view =
+            Constellation::ALL.map(|constellation| sky_view.counts(constellation).in_view);
+        assert_eq!([12, 7, 8, 12, 0], in_view);
+        assert!(!sky_view.is_used(Constellation::Gps, 5));
+        assert_eq!(0, sky_view.dropped_sequences());
+    }
+
+    #[test]
+    fn test_out_of_order_and_missing_parts() {
+        let mut sky_view = SkyView::new();
+        let at = Instant::from_secs(0);
+
+        // out of order, the table is updated with the last part
+        assert!(!sky_view.push("$GLGSV,2,2,06,70,14,330,,88,15,082,,1*00", at));
+        assert!(sky_view.push(
+            "$GLGSV,2,1,06,79,61,298,38,69,63,275,32,68,39,185,38,78,43,037,,1*00",
+            at
+        ));
+        let prns: std::vec::Vec<u8> = sky_view
+            .signal(Constellation::Glonass, Some(1))
+            .unwrap()
+            .value
+            .satellites
+            .iter()
+            .map(|satellite| satellite.prn)
+            .collect();
+        assert_eq!(std::vec![79, 69, 68, 78, 70, 88], prns);
+
+        // the second part is missing, the last complete sequence is kept
+        assert!(!sky_view.push(
+            "$GLGSV,2,1,05,79,61,298,38,69,63,275,32,68,39,185,38,78,43,037,,1*00",
+            at
+        ));
+        assert!(!sky_view.push(
+            "$GLGSV,2,1,05,79,61,298,38,69,63,275,32,68,39,185,38,78,43,037,,1*00",
+            at
+        ));
+        assert_eq!(1, sky_view.dropped_sequences());
+        assert_eq!(6, sky_view.counts(Constellation::Glonass).in_view);
+        assert!(sky_view.push("$GLGSV,2,2,05,70,14,330,,1*00", at));
+        assert_eq!(5, sky_view.counts(Constellation::Glonass).in_view);
+
+        // the first part is lost, the next epoch's one doesn't complete the stale sequence
+        let next = at + Duration::from_secs(1);
+        assert!(!sky_view.push("$GLGSV,2,2,05,70,14,330,,1*00", at));
+        assert!(!sky_view.push(
+            "$GLGSV,2,1,05,79,61,298,38,69,63,275,32,68,39,185,38,78,43,037,,1*00",
+            next
+        ));
+        assert_eq!(2, sky_view.dropped_sequences());
+        assert_eq!(
+            at,
+            sky_view.signal(Constellation::Glonass, Some(1)).unwrap().at
+        );
+        assert!(sky_view.push("$GLGSV,2,2,05,70,14,330,,1*00", next));
+        assert_eq!(
+            next,
+            sky_view.signal(Constellation::Glonass, Some(1)).unwrap().at
+        );
+
+        // another signal of the same satellites and a sequence without the signal ID
+        assert!(sky_view.push("$GLGSV,1,1,02,79,61,298,30,70,14,330,22,3*00", at));
+        assert_eq!(2, sky_view.signals().len());
+        assert_eq!(5, sky_view.counts(Constellation::Glonass).in_view);
+        assert!(sky_view.push("$GPGSV,1,1,01,05,19,222,36*00", at));
+        assert_eq!(
+            1,
+            sky_view
+                .signal(Constellation::Gps, None)
+                .unwrap()
+                .value
+                .satellites
+                .len()
+        );
+
+        // a part out of range, a combined talker and a broken header
+        assert!(!sky_view.push("$GPGSV,2,3,05,05,19,222,36,1*00", at));
+        assert!(!sky_view.push("$GNGSV,1,1,01,05,19,222,36,1*00", at));
+        assert!(!sky_view.push("$GPGSV,1,1,01,05,19,222*00", at));
+        assert_eq!(3, sky_view.signals().len());
+    }
+
+    #[test]
+    fn test_expired_signals() {
+        let mut sky_view = SkyView::new();
+        let first = Instant::from_secs(0);
+        assert!(sky_view.push("$GPGSV,1,1,01,05,19,222,36,1*00", first));
+        assert!(sky_view.push("$GLGSV,1,1,01,79,61,298,38,1*00", first));
+
+        // only GLONASS is received afterwards
+        let refreshed = first + MAX_SIGNAL_AGE;
+        assert!(sky_view.push("$GLGSV,1,1,01,79,61,298,38,1*00", refreshed));
+        assert!(!sky_view.expire(refreshed));
+        assert_eq!(
+            MAX_SIGNAL_AGE,
+            sky_view
+                .signal(Constellation::Gps, Some(1))
+                .unwrap()
+                .age(refreshed)
+        );
+
+        assert!(sky_view.expire(refreshed + Duration::from_secs(1)));
+        assert!(sky_view.signal(Constellation::Gps, Some(1)).is_none());
+        assert_eq!(0, sky_view.counts(Constellation::Gps).in_view);
+        assert_eq!(1, sky_view.counts(Constellation::Glonass).in_view);
+    }
+
+    #[test]
+    fn test_used_satellites() {
+        let mut sky_view = SkyView::new();
+        let used = |constellation, prn| UsedSatellite { constellation, prn };
+        sky_view.set_used(&[
+            used(Some(Constellation::Gps), 5),
+            used(Some(Constellation::Gps), 7),
+            used(Some(Constellation::Galileo), 7),
+            // of a combined GSA without the system ID
+            used(None, 9),
+        ]);
+        assert_eq!(2, sky_view.counts(Constellation::Gps).used);
+        assert_eq!(1, sky_view.counts(Constellation::Galileo).used);
+        assert!(sky_view.is_used(Constellation::Galileo, 7));
+        assert!(!sky_view.is_used(Constellation::Glonass, 7));
+
+        // replaced by the next epoch
+        sky_view.set_used(&[used(Some(Constellation::Gps), 5)]);
+        assert_eq!(1, sky_view.counts(Constellation::Gps).used);
+        assert_eq!(0, sky_view.counts(Constellation::Galileo).used);
+    }
+}
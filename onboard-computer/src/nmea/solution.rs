@@ -0,0 +1,719 @@
+//! Combines the sentences of an epoch into a navigation solution.
+//!
+//! Every epoch the GNSS module sends a burst of sentences, each one with a part of the solution:
+//!
+//! - GGA - the position, altitude and fix quality
+//! - RMC - the date and whether the solution is valid
+//! - VTG - the ground speed and course
+//! - GSA - the dilution of precision and the satellites used in the fix, one per constellation
+//! - GSV - the satellites in view, in multiple parts per constellation and signal
+//!
+//! The [`SolutionAggregator`] collects them and once the next epoch starts, merges them into
+//! the [`NavigationSolution`] which is published on [`NAVIGATION_SOLUTION`].
+//! A field missing from the epoch, e.g. the position without a fix, keeps its last value
+//! and the [`Timed::age`] tells how old it is.
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use super::{
+    fields,
+    sky_view::{SkyView, MAX_USED_SATELLITES},
+    utc_millis, Constellation, EpochSplitter,
+};
+
+/// The maximum number of fields in a sentence, a GSV has 21
+const MAX_FIELDS: usize = 24;
+
+/// A value of the solution and when it was received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timed<T> {
+    pub value: T,
+    /// When the first sentence of the epoch with the value was received
+    pub at: Instant,
+}
+
+impl<T> Timed<T> {
+    pub fn age(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.at)
+    }
+}
+
+/// The GGA fix quality
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum FixQuality {
+    Invalid,
+    Gps,
+    Dgps,
+    Pps,
+    Rtk,
+    FloatRtk,
+    /// Dead reckoning
+    Estimated,
+    Manual,
+    Simulation,
+}
+
+impl FixQuality {
+    fn from_field(field: &str) -> Option<Self> {
+        let quality = match field {
+            "0" => FixQuality::Invalid,
+            "1" => FixQuality::Gps,
+            "2" => FixQuality::Dgps,
+            "3" => FixQuality::Pps,
+            "4" => FixQuality::Rtk,
+            "5" => FixQuality::FloatRtk,
+            "6" => FixQuality::Estimated,
+            "7" => FixQuality::Manual,
+            "8" => FixQuality::Simulation,
+            _ => return None,
+        };
+
+        Some(quality)
+    }
+}
+
+/// The GSA fix mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub enum FixMode {
+    NoFix,
+    Fix2d,
+    Fix3d,
+}
+
+impl FixMode {
+    fn from_field(field: &str) -> Option<Self> {
+        match field {
+            "1" => Some(FixMode::NoFix),
+            "2" => Some(FixMode::Fix2d),
+            "3" => Some(FixMode::Fix3d),
+            _ => None,
+        }
+    }
+}
+
+/// The position in degrees, the south and west are negative
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Velocity {
+    /// km/h
+    pub ground_speed: f32,
+    /// The true course in degrees, `None` when standing still
+    pub course: Option<f32>,
+}
+
+/// The dilution of precision
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dop {
+    pub position: f32,
+    pub horizontal: f32,
+    pub vertical: f32,
+}
+
+/// A satellite used in the fix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct UsedSatellite {
+    /// `None` for a combined (`GN`) GSA without a system ID
+    pub constellation: Option<Constellation>,
+    pub prn: u8,
+}
+
+/// The navigation solution of the latest epochs, each field with its own age.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NavigationSolution {
+    /// The UTC time of the epoch in milliseconds since midnight
+    pub utc: Option<Timed<u32>>,
+    pub date: Option<Timed<Date>>,
+    /// Whether the RMC status is valid (`A`)
+    pub valid: Option<Timed<bool>>,
+    pub fix_quality: Option<Timed<FixQuality>>,
+    pub position: Option<Timed<Position>>,
+    /// Metres above the mean sea level
+    pub altitude: Option<Timed<f32>>,
+    /// The satellites in the fix according to the GGA
+    pub fix_satellites: Option<Timed<u8>>,
+    pub velocity: Option<Timed<Velocity>>,
+    /// The best mode of the epoch's GSA sentences
+    pub fix_mode: Option<Timed<FixMode>>,
+    pub dop: Option<Timed<Dop>>,
+    pub used_satellites: Option<Timed<Vec<UsedSatellite, MAX_USED_SATELLITES>>>,
+    /// Updated by the epochs with GSV or GSA sentences,
+    /// each signal in view has the time of its own epoch
+    pub sky_view: Option<Timed<SkyView>>,
+}
+
+impl NavigationSolution {
+    /// The satellites in view of all the constellations,
+    /// a satellite is counted once even if it's in view on multiple signals.
+    pub fn satellites_in_view(&self) -> Option<u8> {
+        let sky_view = &self.sky_view.as_ref()?.value;
+
+        let satellites = Constellation::ALL
+            .iter()
+            .fold(0_u8, |total, constellation| {
+                total.saturating_add(sky_view.counts(*constellation).in_view)
+            });
+
+        Some(satellites)
+    }
+}
+
+/// The capacity, subscribers and publishers of the [`NAVIGATION_SOLUTION`] channel.
+pub type SolutionChannel = PubSubChannel<CriticalSectionRawMutex, NavigationSolution, 1, 2, 1>;
+
+/// The latest solution, published once per epoch.
+///
+/// It's used as a watch channel since embassy-sync 0.2 doesn't have one:
+/// with a capacity of 1 and `publish_immediate` a subscriber which falls behind
+/// skips straight to the latest solution.
+pub static NAVIGATION_SOLUTION: SolutionChannel = PubSubChannel::new();
+
+/// Merges the sentences of each epoch into the [`NavigationSolution`].
+#[derive(Debug, Clone, Default)]
+pub struct SolutionAggregator {
+    epochs: EpochSplitter,
+    /// When the first sentence of the current epoch was received
+    epoch_at: Option<Instant>,
+    /// The solution with the sentences of the current epoch so far
+    next: NavigationSolution,
+    /// The GSA sentences of the current epoch are merged with each other
+    has_gsa: bool,
+    sky_view: SkyView,
+    /// The sky view was updated by the current epoch
+    sky_view_updated: bool,
+    solution: NavigationSolution,
+}
+
+impl SolutionAggregator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The solution of the last completed epoch
+    pub fn solution(&self) -> &NavigationSolution {
+        &self.solution
+    }
+
+    /// Adds a sentence received at `now`.
+    ///
+    /// Returns the new solution when the sentence starts the next epoch.
+    pub fn push(&mut self, sentence: &str, now: Instant) -> Option<&NavigationSolution> {
+        let completed = self.epochs.push(sentence) && self.complete_epoch();
+
+        let at = match self.epoch_at {
+            Some(at) => at,
+            None => {
+                self.epoch_at = Some(now);
+                now
+            }
+        };
+        self.merge(sentence, at);
+        if self.sky_view.push(sentence, at) {
+            self.sky_view_updated = true;
+        }
+
+        completed.then_some(&self.solution)
+    }
+
+    /// Completes the current epoch without waiting for the next one,
+    /// e.g. when the module stopped sending sentences.
+    pub fn finish_epoch(&mut self) -> Option<&NavigationSolution> {
+        self.epochs = EpochSplitter::new();
+
+        self.complete_epoch().then_some(&self.solution)
+    }
+
+    fn complete_epoch(&mut self) -> bool {
+        let at = match self.epoch_at.take() {
+            Some(at) => at,
+            None => return false,
+        };
+        // the GSA sentences are parsed once, by `merge`
+        if self.has_gsa {
+            let used = self.next.used_satellites.as_ref();
+            self.sky_view
+                .set_used(used.map_or(&[], |used| used.value.as_slice()));
+            self.sky_view_updated = true;
+        }
+        if self.sky_view.expire(at) {
+            self.sky_view_updated = true;
+        }
+        if self.sky_view_updated {
+            self.next.sky_view = Some(Timed {
+                value: self.sky_view.clone(),
+                at,
+            });
+        }
+        self.solution = self.next.clone();
+        self.has_gsa = false;
+        self.sky_view_updated = false;
+
+        true
+    }
+
+    /// Merges the fields of the sentence into the next solution, invalid fields are ignored.
+    fn merge(&mut self, sentence: &str, at: Instant) {
+        let (talker, kind, values) = match fields(sentence) {
+            Some(sentence) => sentence,
+            None => return,
+        };
+        let values: Vec<&str, MAX_FIELDS> = values.take(MAX_FIELDS).collect();
+        let field = |index: usize| values.get(index).copied().unwrap_or_default();
+        let next = &mut self.next;
+
+        match kind {
+            "GGA" => {
+                update(&mut next.utc, utc_millis(field(0)), at);
+                update(
+                    &mut next.position,
+                    position(field(1), field(2), field(3), field(4)),
+                    at,
+                );
+                update(&mut next.fix_quality, FixQuality::from_field(field(5)), at);
+                update(&mut next.fix_satellites, field(6).parse().ok(), at);
+                update(&mut next.altitude, field(8).parse().ok(), at);
+            }
+            "RMC" => {
+                update(&mut next.utc, utc_millis(field(0)), at);
+                let valid = match field(1) {
+                    "A" => Some(true),
+                    "V" => Some(false),
+                    _ => None,
+                };
+                update(&mut next.valid, valid, at);
+                update(&mut next.date, date(field(8)), at);
+            }
+            "VTG" => {
+                let velocity = field(6).parse().ok().map(|ground_speed| Velocity {
+                    ground_speed,
+                    course: field(0).parse().ok(),
+                });
+                update(&mut next.velocity, velocity, at);
+            }
+            "GSA" => {
+                let constellation = Constellation::from_gsa(talker, field(17).parse().ok());
+
+                // the first GSA of the epoch replaces the satellites of the last one
+                let first = !self.has_gsa;
+                self.has_gsa = true;
+                if first {
+                    next.used_satellites = Some(Timed {
+                        value: Vec::new(),
+                        at,
+                    });
+                }
+                if let Some(used) = next.used_satellites.as_mut() {
+                    let prns = (2..14).filter_map(|index| field(index).parse().ok());
+                    for prn in prns {
+                        used.value.push(UsedSatellite { constellation, prn }).ok();
+                    }
+                }
+
+                let fix_mode = FixMode::from_field(field(1)).map(|mode| match &next.fix_mode {
+                    Some(best) if !first && best.value > mode => best.value,
+                    _ => mode,
+                });
+                update(&mut next.fix_mode, fix_mode, at);
+
+                let dop = match (field(14).parse(), field(15).parse(), field(16).parse()) {
+                    (Ok(position), Ok(horizontal), Ok(vertical)) => Some(Dop {
+                        position,
+                        horizontal,
+                        vertical,
+                    }),
+                    _ => None,
+                };
+                update(&mut next.dop, dop, at);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Updates the field when the epoch has a value for it
+fn update<T>(field: &mut Option<Timed<T>>, value: Option<T>, at: Instant) {
+    if let Some(value) = value {
+        *field = Some(Timed { value, at });
+    }
+}
+
+/// The latitude (`ddmm.mmmm`) and longitude (`dddmm.mmmm`) with their hemispheres
+fn position(
+    latitude: &str,
+    north_south: &str,
+    longitude: &str,
+    east_west: &str,
+) -> Option<Position> {
+    let latitude = match north_south {
+        "N" => degrees(latitude, 2)?,
+        "S" => -degrees(latitude, 2)?,
+        _ => return None,
+    };
+    let longitude = match east_west {
+        "E" => degrees(longitude, 3)?,
+        "W" => -degrees(longitude, 3)?,
+        _ => return None,
+    };
+
+    Some(Position {
+        latitude,
+        longitude,
+    })
+}
+
+fn degrees(field: &str, degree_digits: usize) -> Option<f64> {
+    let (degrees, minutes) = (field.get(..degree_digits)?, field.get(degree_digits..)?);
+    if !degrees.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let degrees: f64 = degrees.parse().ok()?;
+    let minutes: f64 = minutes.parse().ok()?;
+    if !(0.0..60.0).contains(&minutes) {
+        return None;
+    }
+
+    Some(degrees + minutes / 60.0)
+}
+
+/// The RMC date, `ddmmyy`
+fn date(field: &str) -> Option<Date> {
+    if field.len() != 6 || !field.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let day: u8 = field[0..2].parse().ok()?;
+    let month: u8 = field[2..4].parse().ok()?;
+    let year: u16 = field[4..6].parse().ok()?;
+    if !(1..=31).contains(&day) || !(1..=12).contains(&month) {
+        return None;
+    }
+
+    Some(Date {
+        year: 2000 + year,
+        month,
+        day,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::nmea::{sky_view::SatelliteCounts, MOCK_SENTENCES};
+
+    use super::*;
+
+    /// Pushes the sentences at the given time and returns the completed solution (if any).
+    fn push_all(
+        aggregator: &mut SolutionAggregator,
+        sentences: &[&str],
+        at: Instant,
+    ) -> Option<NavigationSolution> {
+        let mut completed = None;
+        for sentence in sentences {
+            if let Some(solution) = aggregator.push(sentence, at) {
+                completed = Some(solution.clone());
+            }
+        }
+
+        completed
+    }
+
+    #[test]
+    fn test_epoch_of_the_log() {
+        let mut aggregator = SolutionAggregator::new();
+        let lines: std::vec::Vec<&str> = MOCK_SENTENCES.lines().collect();
+        let first = Instant::from_secs(0);
+        let second = Instant::from_secs(1);
+
+        // the epoch before the first fix is completed by the second one
+        assert!(push_all(&mut aggregator, &lines[..17], first).is_none());
+        let before_fix = push_all(&mut aggregator, &lines[17..23], second)
+            .expect("Should complete the first epoch");
+        assert_eq!(
+            Some(FixQuality::Invalid),
+            before_fix.fix_quality.map(|q| q.value)
+        );
+        assert_eq!(Some(false), before_fix.valid.map(|valid| valid.value));
+        assert_eq!(
+            Some(FixMode::NoFix),
+            before_fix.fix_mode.map(|mode| mode.value)
+        );
+        assert!(before_fix.position.is_none());
+        assert!(before_fix.utc.is_none());
+        assert!(before_fix.dop.is_none());
+        assert_eq!(
+            Some(0),
+            before_fix
+                .used_satellites
+                .as_ref()
+                .map(|used| used.value.len())
+        );
+        // 11 GPS, 8 GLONASS, 8 Galileo and 12 BeiDou
+        assert_eq!(Some(39), before_fix.satellites_in_view());
+
+        // the second epoch has no GSV
+        let solution = aggregator
+            .finish_epoch()
+            .expect("Should complete the second epoch");
+        assert_eq!(Some(19_425_770), solution.utc.map(|utc| utc.value));
+        assert_eq!(
+            Some(Date {
+                year: 2022,
+                month: 4,
+                day: 13
+            }),
+            solution.date.map(|date| date.value)
+        );
+        assert_eq!(Some(true), solution.valid.map(|valid| valid.value));
+        assert_eq!(Some(FixQuality::Gps), solution.fix_quality.map(|q| q.value));
+        assert_eq!(Some(12), solution.fix_satellites.map(|sats| sats.value));
+        assert_eq!(
+            Some(171.9),
+            solution.altitude.map(|altitude| altitude.value)
+        );
+        let position = solution.position.expect("Should have a position").value;
+        assert!((position.latitude - (42.0 + 10.472433 / 60.0)).abs() < 1e-9);
+        assert!((position.longitude - (24.0 + 45.362882 / 60.0)).abs() < 1e-9);
+        assert_eq!(
+            Some(Velocity {
+                ground_speed: 0.0,
+                course: None
+            }),
+            solution.velocity.map(|velocity| velocity.value)
+        );
+        assert_eq!(
+            Some(FixMode::Fix3d),
+            solution.fix_mode.map(|mode| mode.value)
+        );
+        assert_eq!(
+            Some(Dop {
+                position: 1.0,
+                horizontal: 0.7,
+                vertical: 0.7
+            }),
+            solution.dop.map(|dop| dop.value)
+        );
+
+        let used = &solution.used_satellites.as_ref().unwrap().value;
+        assert_eq!(9 + 4 + 4, used.len());
+        assert_eq!(
+            UsedSatellite {
+                constellation: Some(Constellation::Glonass),
+                prn: 68
+            },
+            used[9]
+        );
+
+        // the satellites in view of the first epoch and used in the second one
+        let sky_view = solution.sky_view.as_ref().unwrap();
+        assert_eq!(second, sky_view.at);
+        assert!(sky_view
+            .value
+            .signals()
+            .iter()
+            .all(|signal| signal.age(second) == Duration::from_secs(1)));
+        assert_eq!(
+            SatelliteCounts {
+                in_view: 11,
+                used: 9
+            },
+            sky_view.value.counts(Constellation::Gps)
+        );
+        assert_eq!(second, solution.position.unwrap().at);
+        assert_eq!(
+            Duration::from_secs(1),
+            before_fix.fix_quality.unwrap().age(second)
+        );
+        assert!(aggregator.finish_epoch().is_none());
+    }
+
+    #[test]
+    fn test_whole_log() {
+        let mut aggregator = SolutionAggregator::new();
+        let mut now = Instant::from_secs(0);
+        let mut solutions = std::vec::Vec::new();
+
+        for sentence in MOCK_SENTENCES.lines() {
+            if let Some(solution) = aggregator.push(sentence, now) {
+                solutions.push(solution.clone());
+            }
+            now += Duration::from_millis(10);
+        }
+        solutions.extend(aggregator.finish_epoch().cloned());
+
+        assert_eq!(1 + 36, solutions.len());
+        let last = solutions.last().unwrap();
+        assert_eq!(Some(19_461_000), last.utc.map(|utc| utc.value));
+        assert!(last.satellites_in_view().is_some());
+        assert!(solutions[1..]
+            .iter()
+            .all(|solution| solution.valid.map(|valid| valid.value) == Some(true)));
+
+        // the satellites in view and used in the fix of the last epoch
+        let sky_view = &last.sky_view.as_ref().unwrap().value;
+        assert_eq!(
+            [
+                SatelliteCounts {
+                    in_view: 12,
+                    used: 9
+                },
+                SatelliteCounts {
+                    in_view: 7,
+                    used: 4
+                },
+                // no Galileo GSA without a Galileo satellite in the fix
+                SatelliteCounts {
+                    in_view: 8,
+                    used: 0
+                },
+                // from the PQGSA with the system ID 5
+                SatelliteCounts {
+                    in_view: 12,
+                    used: 2
+                },
+                SatelliteCounts::default(),
+            ],
+            Constellation::ALL.map(|constellation| sky_view.counts(constellation))
+        );
+        assert!(sky_view.is_used(Constellation::BeiDou, 11));
+        assert!(!sky_view.is_used(Constellation::Qzss, 11));
+        assert!(sky_view.is_used(Constellation::Glonass, 70));
+        assert!(!sky_view.is_used(Constellation::Glonass, 78));
+
+        // the BeiDou GSA of Quectel
+        let beidou = solutions.iter().find_map(|solution| {
+            solution
+                .used_satellites
+                .as_ref()?
+                .value
+                .iter()
+                .find(|used| used.constellation == Some(Constellation::BeiDou))
+                .copied()
+        });
+        assert_eq!(
+            Some(UsedSatellite {
+                constellation: Some(Constellation::BeiDou),
+                prn: 12
+            }),
+            beidou
+        );
+    }
+
+    #[test]
+    fn test_used_satellites_of_each_epoch() {
+        let mut aggregator = SolutionAggregator::new();
+        let start = Instant::from_secs(0);
+
+        push_all(
+            &mut aggregator,
+            &[
+                // a GNGSA after it would start the next epoch
+                "$GPGSA,A,3,08,,,,,,,,,,,,1.0,0.7,0.7*00",
+                "$GNGSA,A,3,05,07,13,,,,,,,,,,1.0,0.7,0.7,1*00",
+                "$GNGSA,A,3,02,07,,,,,,,,,,,1.0,0.7,0.7,3*00",
+                // a combined GSA without the system ID is not counted
+                "$GNGSA,A,3,09,,,,,,,,,,,,1.0,0.7,0.7*00",
+            ],
+            start,
+        );
+        let solution = aggregator.finish_epoch().unwrap();
+        let sky_view = &solution.sky_view.as_ref().unwrap().value;
+        assert_eq!(4, sky_view.counts(Constellation::Gps).used);
+        assert_eq!(2, sky_view.counts(Constellation::Galileo).used);
+        assert!(sky_view.is_used(Constellation::Galileo, 7));
+
+        // the next epoch has no Galileo GSA
+        push_all(
+            &mut aggregator,
+            &[
+                "$GNGGA,052421.00,,,,,0,,,,,,,,*00",
+                "$GNGSA,A,3,05,,,,,,,,,,,,1.0,0.7,0.7,1*00",
+            ],
+            start + Duration::from_secs(1),
+        );
+        let solution = aggregator.finish_epoch().unwrap();
+        let sky_view = &solution.sky_view.as_ref().unwrap().value;
+        assert_eq!(1, sky_view.counts(Constellation::Gps).used);
+        assert_eq!(0, sky_view.counts(Constellation::Galileo).used);
+    }
+
+    #[test]
+    fn test_fields_and_ages() {
+        let mut aggregator = SolutionAggregator::new();
+        let start = Instant::from_secs(0);
+
+        push_all(
+            &mut aggregator,
+            &[
+                "$GPGGA,235959.00,3352.1234,S,15112.5,W,2,08,0.9,-12.5,M,,M,,*00",
+                "$GPVTG,271.3,T,,M,5.4,N,10.0,K,A*00",
+                "$GPRMC,235959.00,A,3352.1234,S,15112.5,W,5.4,271.3,311299,,,A*00",
+            ],
+            start,
+        );
+        let solution = aggregator.finish_epoch().unwrap().clone();
+        let position = solution.position.unwrap().value;
+        assert!((position.latitude + (33.0 + 52.1234 / 60.0)).abs() < 1e-9);
+        assert!((position.longitude + (151.0 + 12.5 / 60.0)).abs() < 1e-9);
+        assert_eq!(
+            Some(-12.5),
+            solution.altitude.map(|altitude| altitude.value)
+        );
+        assert_eq!(
+            Some(FixQuality::Dgps),
+            solution.fix_quality.map(|q| q.value)
+        );
+        assert_eq!(
+            Some(Date {
+                year: 2099,
+                month: 12,
+                day: 31
+            }),
+            solution.date.map(|date| date.value)
+        );
+        assert_eq!(
+            Some(Velocity {
+                ground_speed: 10.0,
+                course: Some(271.3)
+            }),
+            solution.velocity.map(|velocity| velocity.value)
+        );
+
+        // lost the fix, the last position is kept
+        let later = start + Duration::from_secs(5);
+        push_all(
+            &mut aggregator,
+            &[
+                "$GPGGA,000004.00,,,,,0,00,,,M,,M,,*00",
+                "$GPRMC,000004.00,V,,,,,,,010100,,,N*00",
+                "$GPGGA,000005.00,bad,N,15112.5,W,1,08,0.9,1.0,M,,M,,*00",
+            ],
+            later,
+        );
+        let solution = aggregator.solution();
+        assert_eq!(Some(4_000), solution.utc.map(|utc| utc.value));
+        assert_eq!(
+            Some(FixQuality::Invalid),
+            solution.fix_quality.map(|q| q.value)
+        );
+        assert_eq!(Some(false), solution.valid.map(|valid| valid.value));
+        let position = solution.position.unwrap();
+        assert_eq!(start, position.at);
+        assert_eq!(Duration::from_secs(5), position.age(later));
+
+        assert_eq!(None, degrees("42.5", 3));
+        assert_eq!(None, degrees("4260.0", 2));
+        assert_eq!(None, date("320122"));
+    }
+}
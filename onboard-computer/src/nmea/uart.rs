@@ -0,0 +1,205 @@
+//! A GNSS module on a UART.
+use core::{cell::Cell, convert::Infallible};
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    pipe::Pipe,
+};
+use embassy_time::Instant;
+use embedded_hal::serial::Read;
+
+use super::{
+    framer::{NmeaFramer, RejectCounts},
+    GnssSource, Sentence,
+};
+
+/// Receives the sentences of a GNSS module from a serial port, e.g. UART1 of the ESP32-C3.
+///
+/// The bytes are read without blocking, something has to keep the bytes received
+/// between the polls. The 128 bytes of the ESP32-C3 UART FIFO last only `~11 ms`
+/// at 115200 baud, so on the board it's an [`RxBuffer`] filled by the UART interrupt.
+/// The [`NmeaFramer`] discards anything which is not a valid sentence,
+/// e.g. the bytes before the first `$` or a frame with a wrong checksum.
+pub struct UartGnss<U> {
+    serial: U,
+    framer: NmeaFramer,
+}
+
+impl<U: Read<u8>> UartGnss<U> {
+    pub fn new(serial: U) -> Self {
+        Self {
+            serial,
+            framer: NmeaFramer::new(),
+        }
+    }
+
+    pub fn framer(&self) -> &NmeaFramer {
+        &self.framer
+    }
+
+    /// The frames rejected since boot
+    pub fn rejected(&self) -> &RejectCounts {
+        self.framer.rejected()
+    }
+
+    /// Releases the serial port
+    pub fn release(self) -> U {
+        self.serial
+    }
+}
+
+impl<U> GnssSource for UartGnss<U>
+where
+    U: Read<u8>,
+    U::Error: core::fmt::Debug,
+{
+    type Error = U::Error;
+
+    fn poll_sentence(&mut self, _now: Instant) -> nb::Result<Sentence, Self::Error> {
+        loop {
+            let byte = self.serial.read()?;
+
+            if let Some(Ok(sentence)) = self.framer.push(byte) {
+                return Ok(sentence);
+            }
+        }
+    }
+}
+
+/// A ring buffer of the bytes received by a UART interrupt handler,
+/// read without blocking by the [`UartGnss`].
+pub struct RxBuffer<const N: usize> {
+    pipe: Pipe<CriticalSectionRawMutex, N>,
+    /// The bytes which didn't fit in the buffer
+    dropped: Mutex<CriticalSectionRawMutex, Cell<u32>>,
+}
+
+impl<const N: usize> RxBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            pipe: Pipe::new(),
+            dropped: Mutex::new(Cell::new(0)),
+        }
+    }
+
+    /// Moves all the received bytes of the serial port (e.g. the UART RX FIFO)
+    /// to the buffer, the ones which don't fit are dropped and counted.
+    pub fn drain<U: Read<u8>>(&self, serial: &mut U) -> Result<(), U::Error> {
+        loop {
+            let byte = match serial.read() {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => return Ok(()),
+                Err(nb::Error::Other(err)) => return Err(err),
+            };
+
+            if self.pipe.try_write(&[byte]).is_err() {
+                self.dropped
+                    .lock(|dropped| dropped.set(dropped.get().saturating_add(1)));
+            }
+        }
+    }
+
+    /// The bytes dropped since boot because the buffer was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped.lock(Cell::get)
+    }
+}
+
+impl<const N: usize> Default for RxBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Read<u8> for &RxBuffer<N> {
+    type Error = Infallible;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        let mut byte = [0_u8; 1];
+        match self.pipe.try_read(&mut byte) {
+            Ok(1) => Ok(byte[0]),
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// A serial port which has received the given bytes
+    struct FakeSerial(VecDeque<u8>);
+
+    impl Read<u8> for FakeSerial {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<u8, Self::Error> {
+            self.0.pop_front().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    #[test]
+    fn test_uart_gnss() {
+        let now = Instant::from_secs(0);
+        let mut gnss = UartGnss::new(FakeSerial(VecDeque::new()));
+        assert_eq!(Err(nb::Error::WouldBlock), gnss.poll_sentence(now));
+
+        gnss.serial
+            .0
+            .extend(b"garbage\r\n$GPGSA,A,1,,,,,,,,,,,,,,,*1E\r\n$GNVTG,,T,,M,0.0,N");
+        assert_eq!(
+            Ok("$GPGSA,A,1,,,,,,,,,,,,,,,*1E"),
+            gnss.poll_sentence(now).as_deref()
+        );
+        // the rest of the sentence is not received yet
+        assert_eq!(Err(nb::Error::WouldBlock), gnss.poll_sentence(now));
+        gnss.serial.0.extend(b",0.0,K,A*3D\r\n");
+        assert_eq!(
+            Ok("$GNVTG,,T,,M,0.0,N,0.0,K,A*3D"),
+            gnss.poll_sentence(now).as_deref()
+        );
+
+        // too long for a sentence, a non-ASCII byte and a wrong checksum
+        gnss.serial.0.push_back(b'$');
+        gnss.serial.0.extend([b'A'; 100]);
+        gnss.serial
+            .0
+            .extend(b"\r\n$GPTXT,\xFF*00\r\n$GPTXT*00\r\n$GPTXT*4F\r\n");
+        assert_eq!(Ok("$GPTXT*4F"), gnss.poll_sentence(now).as_deref());
+        assert_eq!(Err(nb::Error::WouldBlock), gnss.poll_sentence(now));
+        assert_eq!(3, gnss.rejected().total());
+
+        assert!(gnss.release().0.is_empty());
+    }
+
+    #[test]
+    fn test_rx_buffer() {
+        let buffer = RxBuffer::<32>::new();
+        let mut fifo = FakeSerial(VecDeque::new());
+
+        // two interrupts, the first one in the middle of the sentence
+        fifo.0.extend(b"$GPGSA,A,1,,,,,,,");
+        buffer.drain(&mut fifo).unwrap();
+        assert!(fifo.0.is_empty());
+        let mut gnss = UartGnss::new(&buffer);
+        assert_eq!(
+            Err(nb::Error::WouldBlock),
+            gnss.poll_sentence(Instant::from_secs(0))
+        );
+        fifo.0.extend(b",,,,,,,,*1E\r\n");
+        buffer.drain(&mut fifo).unwrap();
+        assert_eq!(
+            Ok("$GPGSA,A,1,,,,,,,,,,,,,,,*1E"),
+            gnss.poll_sentence(Instant::from_secs(0)).as_deref()
+        );
+        assert_eq!(0, buffer.dropped());
+
+        // the bytes which don't fit are dropped, not the buffered ones
+        fifo.0.extend([b'A'; 40]);
+        buffer.drain(&mut fifo).unwrap();
+        assert!(fifo.0.is_empty());
+        assert_eq!(8, buffer.dropped());
+    }
+}
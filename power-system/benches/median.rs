@@ -0,0 +1,66 @@
+//! Compares the sorting [`find_mediana`] of the whole history with the [`SlidingMedian`].
+//!
+//! Run on the host with `cargo bench --no-default-features -F std --bench median`
+#![feature(test)]
+
+extern crate test;
+
+use heapless::{HistoryBuffer, Vec};
+use power_system::helper::{find_mediana, sliding_median::SlidingMedian};
+use test::{black_box, Bencher};
+
+const HISTORY: usize = 101;
+
+/// Noisy ADC values around 2300 with an occasional glitch
+fn adc_values() -> impl Iterator<Item = u16> {
+    (0_u32..).map(|i| {
+        let noise = (i.wrapping_mul(2_654_435_761) >> 26) as u16;
+
+        if i % 50 == 0 {
+            4000
+        } else {
+            2300 + noise
+        }
+    })
+}
+
+#[bench]
+fn bench_history_buffer_find_mediana(bencher: &mut Bencher) {
+    let mut history = HistoryBuffer::<u16, HISTORY>::new();
+    let mut values = adc_values();
+    history.extend(values.by_ref().take(HISTORY));
+
+    bencher.iter(|| {
+        history.write(values.next().unwrap());
+
+        let mut sorted = Vec::<_, HISTORY>::new();
+        sorted.extend_from_slice(history.as_slice()).unwrap();
+        black_box(find_mediana(&mut sorted))
+    });
+}
+
+#[bench]
+fn bench_sliding_median(bencher: &mut Bencher) {
+    let mut history = SlidingMedian::<HISTORY>::new();
+    let mut values = adc_values();
+    values.by_ref().take(HISTORY).for_each(|value| history.write(value));
+
+    bencher.iter(|| {
+        history.write(values.next().unwrap());
+
+        black_box(history.median())
+    });
+}
+
+#[bench]
+fn bench_sliding_median_percentiles(bencher: &mut Bencher) {
+    let mut history = SlidingMedian::<HISTORY>::new();
+    let mut values = adc_values();
+    values.by_ref().take(HISTORY).for_each(|value| history.write(value));
+
+    bencher.iter(|| {
+        history.write(values.next().unwrap());
+
+        black_box((history.percentile(5), history.percentile(95)))
+    });
+}
@@ -0,0 +1,255 @@
+//! Hardware-independent sources of raw ADC samples.
+//!
+//! The battery measurement code only needs "something that yields 12-bit samples",
+//! this allows us to run and test it on the host using the [`ScriptedAdc`]
+//! and use the ESP32-C3 ADC ([`AdcChannel`]) on the board.
+use core::fmt;
+
+use defmt::Format;
+
+/// The maximum raw value of a 12-bit ADC sample.
+pub const ADC_MAX: u16 = 4095;
+
+/// A source of raw 12-bit ADC samples.
+pub trait AdcSource {
+    type Error: fmt::Debug;
+
+    /// Starts (or continues) a conversion and returns the raw sample once it's ready.
+    ///
+    /// Returns [`nb::Error::WouldBlock`] while the conversion is still in progress.
+    fn read_raw(&mut self) -> nb::Result<u16, Self::Error>;
+}
+
+impl<T: AdcSource> AdcSource for &mut T {
+    type Error = T::Error;
+
+    fn read_raw(&mut self) -> nb::Result<u16, Self::Error> {
+        T::read_raw(self)
+    }
+}
+
+/// The ADC1 channels of the ESP32-C3, each one is connected to a GPIO pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Adc1Channel {
+    Gpio0 = 0,
+    Gpio1,
+    Gpio2,
+    Gpio3,
+    Gpio4,
+}
+
+/// A source of raw 12-bit ADC samples from multiple channels sharing a single converter.
+pub trait MultiChannelAdc {
+    type Error: fmt::Debug;
+
+    /// Starts (or continues) a conversion on the given channel
+    /// and returns the raw sample once it's ready.
+    ///
+    /// Returns [`nb::Error::WouldBlock`] while the conversion is still in progress.
+    fn read_raw(&mut self, channel: Adc1Channel) -> nb::Result<u16, Self::Error>;
+}
+
+/// A single channel of a [`MultiChannelAdc`] used as an [`AdcSource`].
+pub struct SelectedChannel<'a, A> {
+    pub adc: &'a mut A,
+    pub channel: Adc1Channel,
+}
+
+impl<'a, A> SelectedChannel<'a, A> {
+    pub fn new(adc: &'a mut A, channel: Adc1Channel) -> Self {
+        Self { adc, channel }
+    }
+}
+
+impl<'a, A: MultiChannelAdc> AdcSource for SelectedChannel<'a, A> {
+    type Error = A::Error;
+
+    fn read_raw(&mut self) -> nb::Result<u16, Self::Error> {
+        self.adc.read_raw(self.channel)
+    }
+}
+
+/// A single ADC channel of the ESP32-C3 - the converter and the pin we're reading.
+///
+/// The pin should be configured with `into_analog()` which disables the
+/// internal pull-up and pull-down resistors.
+#[cfg(feature = "riscv")]
+pub struct AdcChannel<'a, 'd, PIN> {
+    pub adc: &'a mut hal::adc::ADC<'d, hal::adc::ADC1>,
+    pub pin: &'a mut PIN,
+}
+
+#[cfg(feature = "riscv")]
+impl<'a, 'd, PIN> AdcChannel<'a, 'd, PIN> {
+    pub fn new(adc: &'a mut hal::adc::ADC<'d, hal::adc::ADC1>, pin: &'a mut PIN) -> Self {
+        Self { adc, pin }
+    }
+}
+
+#[cfg(feature = "riscv")]
+impl<'a, 'd, PIN> AdcSource for AdcChannel<'a, 'd, PIN>
+where
+    hal::adc::ADC<'d, hal::adc::ADC1>: embedded_hal::adc::OneShot<hal::adc::ADC1, u16, PIN>,
+{
+    type Error = <hal::adc::ADC<'d, hal::adc::ADC1> as embedded_hal::adc::OneShot<
+        hal::adc::ADC1,
+        u16,
+        PIN,
+    >>::Error;
+
+    fn read_raw(&mut self) -> nb::Result<u16, Self::Error> {
+        embedded_hal::adc::OneShot::read(self.adc, self.pin)
+    }
+}
+
+/// The error returned by [`Adc1Channels`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Adc1Error {
+    /// The channel's pin was not enabled in the ADC configuration
+    Disabled(Adc1Channel),
+    /// The conversion failed
+    Conversion(Adc1Channel),
+}
+
+/// The ESP32-C3 ADC1 with all the pins enabled in its configuration.
+///
+/// The attenuation of each pin is set when enabling it in the `AdcConfig`
+/// and cannot be changed afterwards.
+#[cfg(feature = "riscv")]
+pub struct Adc1Channels<'d> {
+    pub adc: hal::adc::ADC<'d, hal::adc::ADC1>,
+    pub gpio0: Option<hal::adc::AdcPin<hal::gpio::Gpio0<hal::gpio::Analog>, hal::adc::ADC1>>,
+    pub gpio1: Option<hal::adc::AdcPin<hal::gpio::Gpio1<hal::gpio::Analog>, hal::adc::ADC1>>,
+    pub gpio2: Option<hal::adc::AdcPin<hal::gpio::Gpio2<hal::gpio::Analog>, hal::adc::ADC1>>,
+    pub gpio3: Option<hal::adc::AdcPin<hal::gpio::Gpio3<hal::gpio::Analog>, hal::adc::ADC1>>,
+    pub gpio4: Option<hal::adc::AdcPin<hal::gpio::Gpio4<hal::gpio::Analog>, hal::adc::ADC1>>,
+}
+
+#[cfg(feature = "riscv")]
+impl<'d> Adc1Channels<'d> {
+    /// Creates the channels without any enabled pins.
+    pub fn new(adc: hal::adc::ADC<'d, hal::adc::ADC1>) -> Self {
+        Self {
+            adc,
+            gpio0: None,
+            gpio1: None,
+            gpio2: None,
+            gpio3: None,
+            gpio4: None,
+        }
+    }
+}
+
+#[cfg(feature = "riscv")]
+impl<'d> MultiChannelAdc for Adc1Channels<'d> {
+    type Error = Adc1Error;
+
+    fn read_raw(&mut self, channel: Adc1Channel) -> nb::Result<u16, Self::Error> {
+        match channel {
+            Adc1Channel::Gpio0 => read_pin(&mut self.adc, self.gpio0.as_mut(), channel),
+            Adc1Channel::Gpio1 => read_pin(&mut self.adc, self.gpio1.as_mut(), channel),
+            Adc1Channel::Gpio2 => read_pin(&mut self.adc, self.gpio2.as_mut(), channel),
+            Adc1Channel::Gpio3 => read_pin(&mut self.adc, self.gpio3.as_mut(), channel),
+            Adc1Channel::Gpio4 => read_pin(&mut self.adc, self.gpio4.as_mut(), channel),
+        }
+    }
+}
+
+#[cfg(feature = "riscv")]
+fn read_pin<'d, PIN>(
+    adc: &mut hal::adc::ADC<'d, hal::adc::ADC1>,
+    pin: Option<&mut PIN>,
+    channel: Adc1Channel,
+) -> nb::Result<u16, Adc1Error>
+where
+    hal::adc::ADC<'d, hal::adc::ADC1>: embedded_hal::adc::OneShot<hal::adc::ADC1, u16, PIN>,
+{
+    let pin = pin.ok_or(nb::Error::Other(Adc1Error::Disabled(channel)))?;
+
+    embedded_hal::adc::OneShot::read(adc, pin)
+        .map_err(|err| err.map(|_| Adc1Error::Conversion(channel)))
+}
+
+/// A single scripted step of the [`ScriptedAdc`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Sample {
+    /// The conversion finishes with the given raw value
+    Value(u16),
+    /// The conversion is still in progress
+    WouldBlock,
+    /// The conversion fails
+    Error,
+}
+
+/// The error returned by [`ScriptedAdc`] for [`Sample::Error`] steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct ScriptedError;
+
+/// A simulated ADC which replays a script of samples.
+///
+/// Once the end of the script is reached it starts from the beginning.
+#[derive(Debug, Clone)]
+pub struct ScriptedAdc<'a> {
+    script: &'a [Sample],
+    position: usize,
+}
+
+impl<'a> ScriptedAdc<'a> {
+    /// # Panics
+    ///
+    /// When the script is empty.
+    pub fn new(script: &'a [Sample]) -> Self {
+        assert!(!script.is_empty(), "Script should have at least 1 sample");
+
+        Self {
+            script,
+            position: 0,
+        }
+    }
+
+    /// The total number of steps taken so far, including [`Sample::WouldBlock`]
+    /// and [`Sample::Error`] ones.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl<'a> AdcSource for ScriptedAdc<'a> {
+    type Error = ScriptedError;
+
+    fn read_raw(&mut self) -> nb::Result<u16, Self::Error> {
+        let sample = self.script[self.position % self.script.len()];
+        self.position += 1;
+
+        match sample {
+            Sample::Value(value) => Ok(value.min(ADC_MAX)),
+            Sample::WouldBlock => Err(nb::Error::WouldBlock),
+            Sample::Error => Err(nb::Error::Other(ScriptedError)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scripted_adc_replays_and_cycles() {
+        let script = [
+            Sample::Value(1000),
+            Sample::WouldBlock,
+            Sample::Error,
+            Sample::Value(5000),
+        ];
+        let mut adc = ScriptedAdc::new(&script);
+
+        assert_eq!(Ok(1000), adc.read_raw());
+        assert_eq!(Err(nb::Error::WouldBlock), adc.read_raw());
+        assert_eq!(Err(nb::Error::Other(ScriptedError)), adc.read_raw());
+        // values are clamped to 12-bits
+        assert_eq!(Ok(ADC_MAX), adc.read_raw());
+        // starts from the beginning
+        assert_eq!(Ok(1000), adc.read_raw());
+        assert_eq!(5, adc.position());
+    }
+}
@@ -0,0 +1,446 @@
+//! A scheduler which owns ADC1 and shares it between multiple measurements.
+//!
+//! Each [`AdcRequest`] samples a single [`Adc1Channel`] on its own period
+//! and the [`AdcReading`]s are sent over an embassy-sync [`Channel`] to the
+//! measurement task which consumes them, e.g. the battery or the power sense.
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+use crate::{
+    adc::{Adc1Channel, MultiChannelAdc, SelectedChannel},
+    battery::{read_with_retries, Error, MeasurementFailures, MeasurementStep, RetryPolicy},
+    calibration::Attenuation,
+    helper::find_mediana,
+    println,
+};
+
+/// The maximum number of samples in a single [`AdcReading`]
+pub const MAX_SAMPLES: usize = 32;
+
+/// The delay between the samples of a single [`AdcReading`]
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How many [`AdcReading`]s can wait for the consumer before new ones are dropped
+pub const READINGS_DEPTH: usize = 2;
+
+pub type AdcReadings = Channel<CriticalSectionRawMutex, AdcReading, READINGS_DEPTH>;
+
+/// Sample a channel every `period`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdcRequest {
+    pub channel: Adc1Channel,
+    /// The attenuation the channel's pin is enabled with,
+    /// needed for choosing the right [`Calibration`](crate::calibration::Calibration)
+    pub attenuation: Attenuation,
+    /// The number of samples in each [`AdcReading`], up to [`MAX_SAMPLES`]
+    pub samples: usize,
+    pub period: Duration,
+}
+
+impl AdcRequest {
+    /// # Panics
+    ///
+    /// When `samples` is `0` or more than [`MAX_SAMPLES`].
+    pub const fn new(
+        channel: Adc1Channel,
+        attenuation: Attenuation,
+        samples: usize,
+        period: Duration,
+    ) -> Self {
+        assert!(
+            samples > 0 && samples <= MAX_SAMPLES,
+            "Samples should be between 1 and MAX_SAMPLES"
+        );
+
+        Self {
+            channel,
+            attenuation,
+            samples,
+            period,
+        }
+    }
+}
+
+/// The samples of a single [`AdcRequest`] taken [`SAMPLE_INTERVAL`] apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdcReading {
+    pub channel: Adc1Channel,
+    pub attenuation: Attenuation,
+    pub samples: Vec<u16, MAX_SAMPLES>,
+    /// When the first sample was taken
+    pub taken_at: Instant,
+}
+
+impl AdcReading {
+    pub fn mediana(&self) -> u16 {
+        let mut samples = self.samples.clone();
+
+        find_mediana(&mut samples)
+    }
+}
+
+struct Scheduled<'a> {
+    request: AdcRequest,
+    readings: &'a AdcReadings,
+    due: Instant,
+    /// The number of readings dropped because the consumer was not keeping up
+    dropped: u32,
+}
+
+/// Serves up to `N` [`AdcRequest`]s, each one on its own period.
+///
+/// When multiple requests are due at the same time,
+/// they are served in the order they were added.
+pub struct AdcScheduler<'a, const N: usize> {
+    scheduled: Vec<Scheduled<'a>, N>,
+    /// How the ADC reads of each sample are retried
+    pub retry_policy: RetryPolicy,
+    failures: MeasurementFailures,
+}
+
+impl<'a, const N: usize> AdcScheduler<'a, N> {
+    pub fn new() -> Self {
+        Self {
+            scheduled: Vec::new(),
+            retry_policy: RetryPolicy::DEFAULT,
+            failures: MeasurementFailures::new(),
+        }
+    }
+
+    /// Adds a request which is first due at `now`.
+    ///
+    /// Returns the request back if there are already `N` requests.
+    pub fn add(
+        &mut self,
+        request: AdcRequest,
+        readings: &'a AdcReadings,
+        now: Instant,
+    ) -> Result<(), AdcRequest> {
+        self.scheduled
+            .push(Scheduled {
+                request,
+                readings,
+                due: now,
+                dropped: 0,
+            })
+            .map_err(|scheduled| scheduled.request)
+    }
+
+    /// Changes the period of all the requests for the given channel,
+    /// e.g. when the [`PowerMode`](crate::power_mode::PowerMode) changes.
+    ///
+    /// The next reading is due one new `period` after the previous one.
+    pub fn set_period(&mut self, channel: Adc1Channel, period: Duration) {
+        for scheduled in self
+            .scheduled
+            .iter_mut()
+            .filter(|scheduled| scheduled.request.channel == channel)
+        {
+            let previous = scheduled
+                .due
+                .checked_sub(scheduled.request.period)
+                .unwrap_or(Instant::from_ticks(0));
+
+            scheduled.request.period = period;
+            scheduled.due = previous + period;
+        }
+    }
+
+    pub fn requests(&self) -> impl Iterator<Item = &AdcRequest> {
+        self.scheduled.iter().map(|scheduled| &scheduled.request)
+    }
+
+    /// The number of readings dropped for the given channel
+    /// because the consumer was not keeping up.
+    pub fn dropped(&self, channel: Adc1Channel) -> u32 {
+        self.scheduled
+            .iter()
+            .filter(|scheduled| scheduled.request.channel == channel)
+            .map(|scheduled| scheduled.dropped)
+            .sum()
+    }
+
+    /// The failed readings of all the requests.
+    pub fn failures(&self) -> MeasurementFailures {
+        self.failures
+    }
+
+    /// The index of the request which is due first and when it's due.
+    fn next_due(&self) -> Option<(usize, Instant)> {
+        self.scheduled
+            .iter()
+            .enumerate()
+            // returns the first one of equally due requests
+            .min_by_key(|(_index, scheduled)| scheduled.due)
+            .map(|(index, scheduled)| (index, scheduled.due))
+    }
+
+    /// Schedules the next reading of a request one period after the previous one.
+    ///
+    /// If we've fallen behind (e.g. a long reading of another request),
+    /// the reading is due right away instead of trying to catch up with a burst of readings.
+    fn reschedule(&mut self, index: usize, now: Instant) {
+        let scheduled = &mut self.scheduled[index];
+
+        scheduled.due = (scheduled.due + scheduled.request.period).max(now);
+    }
+
+    /// Waits for the next due request, samples its channel and sends the [`AdcReading`].
+    ///
+    /// Returns the served request or `None` if there are no requests.
+    pub async fn serve_next<A: MultiChannelAdc>(&mut self, adc: &mut A) -> Option<AdcRequest> {
+        let (index, due) = self.next_due()?;
+        Timer::at(due).await;
+
+        let Scheduled {
+            request, readings, ..
+        } = self.scheduled[index];
+
+        match sample(adc, &request, &self.retry_policy).await {
+            Ok(reading) => {
+                if readings.try_send(reading).is_err() {
+                    self.scheduled[index].dropped += 1;
+                    println!(
+                        "ADC reading of {:?} dropped, the consumer is not keeping up",
+                        request.channel
+                    );
+                }
+            }
+            Err(error) => {
+                self.failures.record(&error);
+                println!("ADC reading of {:?} failed: {:?}", request.channel, error);
+            }
+        }
+
+        self.reschedule(index, Instant::now());
+
+        Some(request)
+    }
+
+    /// Serves the requests forever.
+    ///
+    /// # Panics
+    ///
+    /// When there are no requests.
+    pub async fn run<A: MultiChannelAdc>(&mut self, adc: &mut A) -> ! {
+        assert!(
+            !self.scheduled.is_empty(),
+            "Should have at least 1 ADC request"
+        );
+
+        loop {
+            self.serve_next(adc).await;
+        }
+    }
+}
+
+impl<'a, const N: usize> Default for AdcScheduler<'a, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Takes the requested number of samples [`SAMPLE_INTERVAL`] apart.
+async fn sample<A: MultiChannelAdc>(
+    adc: &mut A,
+    request: &AdcRequest,
+    policy: &RetryPolicy,
+) -> Result<AdcReading, Error<A::Error>> {
+    let mut source = SelectedChannel::new(adc, request.channel);
+    let taken_at = Instant::now();
+    let mut samples = Vec::new();
+
+    for i in 1..=request.samples {
+        let sample = read_with_retries(&mut source, policy)
+            .await
+            .map_err(|error| error.at(MeasurementStep::Batch(i - 1)))?;
+        samples.push(sample).expect("Should fit MAX_SAMPLES");
+
+        // skip last timer
+        if i != request.samples {
+            Timer::after(SAMPLE_INTERVAL).await;
+        }
+    }
+
+    Ok(AdcReading {
+        channel: request.channel,
+        attenuation: request.attenuation,
+        samples,
+        taken_at,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use embassy_futures::block_on;
+
+    use crate::adc::{AdcSource, Sample, ScriptedAdc, ScriptedError};
+
+    /// A scripted ADC per channel
+    struct ScriptedChannels<'a> {
+        channels: [Option<ScriptedAdc<'a>>; 5],
+    }
+
+    impl<'a> MultiChannelAdc for ScriptedChannels<'a> {
+        type Error = ScriptedError;
+
+        fn read_raw(&mut self, channel: Adc1Channel) -> nb::Result<u16, Self::Error> {
+            match self.channels[channel as usize].as_mut() {
+                Some(adc) => adc.read_raw(),
+                None => Err(nb::Error::Other(ScriptedError)),
+            }
+        }
+    }
+
+    const BATTERY: AdcRequest = AdcRequest::new(
+        Adc1Channel::Gpio3,
+        Attenuation::Attenuation11dB,
+        3,
+        Duration::from_secs(1),
+    );
+    const POWER_SENSE: AdcRequest = AdcRequest::new(
+        Adc1Channel::Gpio4,
+        Attenuation::Attenuation11dB,
+        1,
+        Duration::from_secs(3),
+    );
+
+    #[test]
+    fn test_schedule_order() {
+        let battery_readings = AdcReadings::new();
+        let power_sense_readings = AdcReadings::new();
+        let start = Instant::from_secs(100);
+
+        let mut scheduler = AdcScheduler::<2>::new();
+        scheduler.add(BATTERY, &battery_readings, start).unwrap();
+        scheduler
+            .add(POWER_SENSE, &power_sense_readings, start)
+            .unwrap();
+        assert_eq!(
+            Err(BATTERY),
+            scheduler.add(BATTERY, &battery_readings, start)
+        );
+
+        let mut served = std::vec::Vec::new();
+        for _ in 0..7 {
+            let (index, due) = scheduler.next_due().unwrap();
+            served.push((scheduler.scheduled[index].request.channel, due.as_secs()));
+            // readings take no time
+            scheduler.reschedule(index, due);
+        }
+
+        use Adc1Channel::{Gpio3, Gpio4};
+        assert_eq!(
+            std::vec![
+                (Gpio3, 100),
+                (Gpio4, 100),
+                (Gpio3, 101),
+                (Gpio3, 102),
+                (Gpio3, 103),
+                (Gpio4, 103),
+                (Gpio3, 104),
+            ],
+            served
+        );
+
+        // fallen behind, the battery is due right away at 107 instead of at 106 and 107
+        assert_eq!(Some((0, Instant::from_secs(105))), scheduler.next_due());
+        scheduler.reschedule(0, Instant::from_secs(107));
+        assert_eq!(Some((1, Instant::from_secs(106))), scheduler.next_due());
+        scheduler.reschedule(1, Instant::from_secs(106));
+        assert_eq!(Some((0, Instant::from_secs(107))), scheduler.next_due());
+    }
+
+    #[test]
+    fn test_set_period() {
+        let readings = AdcReadings::new();
+        let start = Instant::from_secs(100);
+
+        let mut scheduler = AdcScheduler::<1>::new();
+        scheduler.add(BATTERY, &readings, start).unwrap();
+        scheduler.reschedule(0, start);
+        assert_eq!(Some((0, Instant::from_secs(101))), scheduler.next_due());
+
+        scheduler.set_period(Adc1Channel::Gpio3, Duration::from_secs(10));
+        assert_eq!(Some((0, Instant::from_secs(110))), scheduler.next_due());
+        assert_eq!(
+            Duration::from_secs(10),
+            scheduler.requests().next().unwrap().period
+        );
+    }
+
+    #[test]
+    fn test_serve_and_deliver_readings() {
+        let battery_readings = AdcReadings::new();
+        let power_sense_readings = AdcReadings::new();
+        let mut adc = ScriptedChannels {
+            channels: [
+                None,
+                None,
+                None,
+                Some(ScriptedAdc::new(&[
+                    Sample::Value(2400),
+                    Sample::WouldBlock,
+                    Sample::Value(2300),
+                    Sample::Value(2500),
+                ])),
+                Some(ScriptedAdc::new(&[Sample::Value(3103)])),
+            ],
+        };
+
+        let mut scheduler = AdcScheduler::<2>::new();
+        let now = Instant::now();
+        scheduler.add(BATTERY, &battery_readings, now).unwrap();
+        scheduler
+            .add(POWER_SENSE, &power_sense_readings, now)
+            .unwrap();
+
+        assert_eq!(Some(BATTERY), block_on(scheduler.serve_next(&mut adc)));
+        assert_eq!(Some(POWER_SENSE), block_on(scheduler.serve_next(&mut adc)));
+
+        let battery = battery_readings.try_recv().unwrap();
+        assert_eq!(Adc1Channel::Gpio3, battery.channel);
+        assert_eq!(&[2400, 2300, 2500], battery.samples.as_slice());
+        assert_eq!(2400, battery.mediana());
+
+        let power_sense = power_sense_readings.try_recv().unwrap();
+        assert_eq!(&[3103], power_sense.samples.as_slice());
+        assert!(power_sense_readings.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_dropped_and_failed_readings() {
+        let readings = AdcReadings::new();
+        let mut adc = ScriptedChannels {
+            channels: [None, None, None, None, None],
+        };
+
+        let mut scheduler = AdcScheduler::<1>::new();
+        scheduler
+            .add(POWER_SENSE, &readings, Instant::now())
+            .unwrap();
+
+        // the pin is not enabled, nothing is sent
+        block_on(scheduler.serve_next(&mut adc));
+        assert!(readings.try_recv().is_err());
+        assert_eq!(0, scheduler.dropped(Adc1Channel::Gpio4));
+        assert_eq!(
+            MeasurementFailures {
+                adc_errors: 1,
+                ..MeasurementFailures::new()
+            },
+            scheduler.failures()
+        );
+
+        adc.channels[4] = Some(ScriptedAdc::new(&[Sample::Value(1000)]));
+        for _ in 0..READINGS_DEPTH + 1 {
+            // make the request due right away
+            scheduler.scheduled[0].due = Instant::from_ticks(0);
+            block_on(scheduler.serve_next(&mut adc));
+        }
+        assert_eq!(1, scheduler.dropped(Adc1Channel::Gpio4));
+    }
+}
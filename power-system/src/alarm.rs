@@ -0,0 +1,638 @@
+//! Battery alarms with debouncing.
+//!
+//! The [`BatteryMonitor`] is fed with every battery reading and raises (or clears)
+//! a [`BatteryAlarm`] only after its condition held for a number of consecutive
+//! readings ([`Debounce`]). Each alarm is cleared on its own condition, e.g. at
+//! a higher voltage than it was raised, so a noisy voltage doesn't flap the alarm.
+//! The resulting [`AlarmEvent`]s are published on [`BATTERY_ALARMS`].
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
+use embassy_time::Duration;
+use heapless::Vec;
+
+use crate::{adc::ADC_MAX, battery::Battery};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BatteryAlarm {
+    /// The battery is almost empty
+    UnderVoltage,
+    /// The battery is at or below its cut-out voltage
+    CriticalVoltage,
+    /// The battery is above the charge termination voltage
+    OverVoltage,
+    /// The reading is outside of the absolute limits of the cells
+    /// or the ADC is at the end of its range
+    Implausible,
+    /// The voltage dropped too much between two readings
+    RapidDrop,
+    /// All the samples of a reading are the same, real ADC readings are noisy
+    StuckAdc,
+}
+
+impl BatteryAlarm {
+    pub const ALL: [BatteryAlarm; 6] = [
+        BatteryAlarm::UnderVoltage,
+        BatteryAlarm::CriticalVoltage,
+        BatteryAlarm::OverVoltage,
+        BatteryAlarm::Implausible,
+        BatteryAlarm::RapidDrop,
+        BatteryAlarm::StuckAdc,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BatteryAlarm::UnderVoltage => "UNDER_VOLTAGE",
+            BatteryAlarm::CriticalVoltage => "CRITICAL_VOLTAGE",
+            BatteryAlarm::OverVoltage => "OVER_VOLTAGE",
+            BatteryAlarm::Implausible => "IMPLAUSIBLE",
+            BatteryAlarm::RapidDrop => "RAPID_DROP",
+            BatteryAlarm::StuckAdc => "STUCK_ADC",
+        }
+    }
+
+    /// The LED blink pattern while any alarm is active - the time the LED is on and then off.
+    pub fn blink_pattern() -> (Duration, Duration) {
+        (Duration::from_millis(50), Duration::from_millis(150))
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum AlarmState {
+    Raised,
+    Cleared,
+}
+
+impl AlarmState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlarmState::Raised => "RAISED",
+            AlarmState::Cleared => "CLEARED",
+        }
+    }
+}
+
+/// An alarm was raised or cleared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct AlarmEvent {
+    pub alarm: BatteryAlarm,
+    pub state: AlarmState,
+    /// The battery voltage (in millivolts) of the reading which raised or cleared the alarm
+    pub millivolts: u32,
+}
+
+/// The alarms which are currently raised
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct ActiveAlarms(u8);
+
+impl ActiveAlarms {
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    pub fn contains(&self, alarm: BatteryAlarm) -> bool {
+        self.0 & (1 << alarm.index()) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Marks the alarm of the event as raised or cleared.
+    pub fn apply(&mut self, event: &AlarmEvent) {
+        self.set(event.alarm, event.state);
+    }
+
+    fn set(&mut self, alarm: BatteryAlarm, state: AlarmState) {
+        match state {
+            AlarmState::Raised => self.0 |= 1 << alarm.index(),
+            AlarmState::Cleared => self.0 &= !(1 << alarm.index()),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = BatteryAlarm> + '_ {
+        BatteryAlarm::ALL
+            .into_iter()
+            .filter(|alarm| self.contains(*alarm))
+    }
+}
+
+/// The number of consecutive readings for which the condition of an alarm
+/// (or its clear condition) should hold before it's raised (or cleared).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Debounce {
+    pub raise: u8,
+    pub clear: u8,
+}
+
+impl Debounce {
+    pub const fn new(raise: u8, clear: u8) -> Self {
+        assert!(
+            raise > 0 && clear > 0,
+            "Should take at least a single reading"
+        );
+
+        Self { raise, clear }
+    }
+}
+
+/// The level at which an alarm is raised and the one at which it's cleared (hysteresis).
+///
+/// Whether the alarm is raised above or below the level depends on the alarm,
+/// see [`AlarmConfig`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Level {
+    pub raise: u32,
+    pub clear: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct AlarmConfig {
+    /// Battery voltage (in mV), raised below `raise` and cleared at or above `clear`
+    pub under_voltage: Level,
+    /// Battery voltage (in mV), raised below `raise` and cleared at or above `clear`
+    pub critical_voltage: Level,
+    /// Battery voltage (in mV), raised above `raise` and cleared at or below `clear`
+    pub over_voltage: Level,
+    /// The minimum and maximum battery voltage (in mV) of a plausible reading
+    pub plausible_millivolts: (u32, u32),
+    /// The voltage drop (in mV) between two readings,
+    /// raised above `raise` and cleared at or below `clear`
+    pub rapid_drop: Level,
+    /// Indexed by [`BatteryAlarm`]
+    pub debounce: [Debounce; BatteryAlarm::ALL.len()],
+}
+
+impl AlarmConfig {
+    /// The State of Charge (in per-mille) at which [`BatteryAlarm::UnderVoltage`]
+    /// is raised and cleared
+    pub const UNDER_VOLTAGE_PERMILLE: (u16, u16) = (50, 100);
+    /// How much (in mV per cell) above the cut-out voltage
+    /// [`BatteryAlarm::CriticalVoltage`] is cleared
+    pub const CRITICAL_CLEAR_MILLIVOLTS: u32 = 100;
+    /// The cell voltage drop (in mV) between two readings at which
+    /// [`BatteryAlarm::RapidDrop`] is raised and cleared
+    pub const RAPID_DROP_MILLIVOLTS: (u32, u32) = (100, 20);
+
+    pub const DEFAULT_DEBOUNCE: [Debounce; BatteryAlarm::ALL.len()] = [
+        // under voltage
+        Debounce::new(3, 3),
+        // critical voltage
+        Debounce::new(3, 3),
+        // over voltage
+        Debounce::new(3, 3),
+        // implausible
+        Debounce::new(2, 3),
+        // rapid drop
+        Debounce::new(2, 5),
+        // stuck ADC
+        Debounce::new(5, 1),
+    ];
+
+    /// Alarms for the voltages of the [`Battery`] profile:
+    ///
+    /// - under voltage at the `UNDER_VOLTAGE_PERMILLE` levels on its OCV curve
+    /// - critical voltage below the cut-out voltage
+    /// - over voltage halfway between the charge termination and the absolute maximum
+    /// - implausible outside of the absolute limits
+    pub fn for_battery(battery: &Battery) -> Self {
+        let (under_raise, under_clear) = Self::UNDER_VOLTAGE_PERMILLE;
+        let (min, max) = battery.absolute_millivolts;
+        let (drop_raise, drop_clear) = Self::RAPID_DROP_MILLIVOLTS;
+
+        Self {
+            under_voltage: Level {
+                raise: battery.millivolts(under_raise),
+                clear: battery.millivolts(under_clear),
+            },
+            critical_voltage: Level {
+                raise: battery.pack_millivolts(battery.cut_out_millivolts),
+                clear: battery
+                    .pack_millivolts(battery.cut_out_millivolts + Self::CRITICAL_CLEAR_MILLIVOLTS),
+            },
+            over_voltage: Level {
+                raise: battery.pack_millivolts((battery.charge_termination_millivolts + max) / 2),
+                clear: battery.pack_millivolts(battery.charge_termination_millivolts),
+            },
+            plausible_millivolts: (battery.pack_millivolts(min), battery.pack_millivolts(max)),
+            rapid_drop: Level {
+                raise: battery.pack_millivolts(drop_raise),
+                clear: battery.pack_millivolts(drop_clear),
+            },
+            debounce: Self::DEFAULT_DEBOUNCE,
+        }
+    }
+
+    pub fn debounce(&self, alarm: BatteryAlarm) -> Debounce {
+        self.debounce[alarm.index()]
+    }
+}
+
+impl Default for AlarmConfig {
+    fn default() -> Self {
+        Self::for_battery(&Battery::LIPO_1S)
+    }
+}
+
+/// What a single reading says about an alarm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Condition {
+    Raise,
+    Clear,
+    /// Neither, e.g. between the raise and clear levels
+    Hold,
+}
+
+impl Condition {
+    /// For alarms raised below `level.raise`
+    fn below(value: u32, level: Level) -> Self {
+        if value < level.raise {
+            Condition::Raise
+        } else if value >= level.clear {
+            Condition::Clear
+        } else {
+            Condition::Hold
+        }
+    }
+
+    /// For alarms raised above `level.raise`
+    fn above(value: u32, level: Level) -> Self {
+        if value > level.raise {
+            Condition::Raise
+        } else if value <= level.clear {
+            Condition::Clear
+        } else {
+            Condition::Hold
+        }
+    }
+}
+
+/// The capacity, subscribers and publishers of the [`BATTERY_ALARMS`] channel.
+pub type BatteryAlarmChannel = PubSubChannel<CriticalSectionRawMutex, AlarmEvent, 8, 2, 1>;
+
+/// Every raised and cleared battery alarm is published here
+/// for the telemetry and LED tasks.
+pub static BATTERY_ALARMS: BatteryAlarmChannel = PubSubChannel::new();
+
+/// Raises and clears the battery alarms from the battery readings.
+#[derive(Debug, Clone)]
+pub struct BatteryMonitor {
+    config: AlarmConfig,
+    active: ActiveAlarms,
+    /// The consecutive readings towards raising an inactive alarm or clearing an active one
+    counts: [u8; BatteryAlarm::ALL.len()],
+    /// The last plausible battery voltage
+    previous_millivolts: Option<u32>,
+}
+
+impl BatteryMonitor {
+    pub fn new(config: AlarmConfig) -> Self {
+        Self {
+            config,
+            active: ActiveAlarms::new(),
+            counts: [0; BatteryAlarm::ALL.len()],
+            previous_millivolts: None,
+        }
+    }
+
+    pub fn config(&self) -> &AlarmConfig {
+        &self.config
+    }
+
+    pub fn active(&self) -> ActiveAlarms {
+        self.active
+    }
+
+    /// Feeds a battery reading - its voltage (in mV) and the raw ADC samples it was taken from.
+    ///
+    /// The voltage alarms and [`BatteryAlarm::RapidDrop`] are held (neither raised nor cleared)
+    /// while the reading is implausible.
+    pub fn update(
+        &mut self,
+        millivolts: u32,
+        samples: &[u16],
+    ) -> Vec<AlarmEvent, { BatteryAlarm::ALL.len() }> {
+        let (min_sample, max_sample) = samples
+            .iter()
+            .fold((u16::MAX, u16::MIN), |(min, max), sample| {
+                (min.min(*sample), max.max(*sample))
+            });
+
+        let stuck = if samples.len() > 1 && min_sample == max_sample {
+            Condition::Raise
+        } else {
+            Condition::Clear
+        };
+
+        let (plausible_min, plausible_max) = self.config.plausible_millivolts;
+        let railed = !samples.is_empty() && (min_sample >= ADC_MAX || max_sample == 0);
+        let plausible = !railed && (plausible_min..=plausible_max).contains(&millivolts);
+
+        let mut events = Vec::new();
+        let mut check = |monitor: &mut Self, alarm: BatteryAlarm, condition: Condition| {
+            if let Some(state) = monitor.debounce(alarm, condition) {
+                events
+                    .push(AlarmEvent {
+                        alarm,
+                        state,
+                        millivolts,
+                    })
+                    .expect("Should fit a single event per alarm");
+            }
+        };
+
+        if plausible {
+            let drop = self
+                .previous_millivolts
+                .map_or(0, |previous| previous.saturating_sub(millivolts));
+            self.previous_millivolts = Some(millivolts);
+
+            check(
+                self,
+                BatteryAlarm::UnderVoltage,
+                Condition::below(millivolts, self.config.under_voltage),
+            );
+            check(
+                self,
+                BatteryAlarm::CriticalVoltage,
+                Condition::below(millivolts, self.config.critical_voltage),
+            );
+            check(
+                self,
+                BatteryAlarm::OverVoltage,
+                Condition::above(millivolts, self.config.over_voltage),
+            );
+            check(self, BatteryAlarm::Implausible, Condition::Clear);
+            check(
+                self,
+                BatteryAlarm::RapidDrop,
+                Condition::above(drop, self.config.rapid_drop),
+            );
+        } else {
+            for alarm in [
+                BatteryAlarm::UnderVoltage,
+                BatteryAlarm::CriticalVoltage,
+                BatteryAlarm::OverVoltage,
+                BatteryAlarm::RapidDrop,
+            ] {
+                check(self, alarm, Condition::Hold);
+            }
+            check(self, BatteryAlarm::Implausible, Condition::Raise);
+        }
+        check(self, BatteryAlarm::StuckAdc, stuck);
+
+        events
+    }
+
+    /// Counts the consecutive readings and changes the state of the alarm
+    /// once there's enough of them.
+    fn debounce(&mut self, alarm: BatteryAlarm, condition: Condition) -> Option<AlarmState> {
+        let debounce = self.config.debounce(alarm);
+        let active = self.active.contains(alarm);
+        let count = &mut self.counts[alarm.index()];
+
+        let (towards, needed, state) = match active {
+            false => (Condition::Raise, debounce.raise, AlarmState::Raised),
+            true => (Condition::Clear, debounce.clear, AlarmState::Cleared),
+        };
+
+        if condition != towards {
+            *count = 0;
+            return None;
+        }
+
+        *count = count.saturating_add(1);
+        if *count < needed {
+            return None;
+        }
+
+        *count = 0;
+        self.active.set(alarm, state);
+
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Noisy samples, i.e. not stuck
+    const SAMPLES: [u16; 3] = [2300, 2310, 2305];
+
+    fn monitor() -> BatteryMonitor {
+        BatteryMonitor::new(AlarmConfig::for_battery(&Battery::OLIMEX_LIPO_250MAH))
+    }
+
+    /// Feeds a voltage trace with noisy samples and returns the events
+    /// with the index of the reading which caused them.
+    fn run_trace(
+        monitor: &mut BatteryMonitor,
+        trace: &[u32],
+    ) -> std::vec::Vec<(usize, AlarmEvent)> {
+        trace
+            .iter()
+            .enumerate()
+            .flat_map(|(index, millivolts)| {
+                monitor
+                    .update(*millivolts, &SAMPLES)
+                    .into_iter()
+                    .map(move |event| (index, event))
+            })
+            .collect()
+    }
+
+    fn event(alarm: BatteryAlarm, state: AlarmState, millivolts: u32) -> AlarmEvent {
+        AlarmEvent {
+            alarm,
+            state,
+            millivolts,
+        }
+    }
+
+    #[test]
+    fn test_config_for_battery() {
+        let config = AlarmConfig::for_battery(&Battery::OLIMEX_LIPO_250MAH);
+
+        assert_eq!(3000, config.critical_voltage.raise);
+        assert_eq!(3100, config.critical_voltage.clear);
+        assert!(config.under_voltage.raise > config.critical_voltage.clear);
+        assert!(config.under_voltage.clear > config.under_voltage.raise);
+        assert_eq!(
+            Level {
+                raise: 4250,
+                clear: 4200
+            },
+            config.over_voltage
+        );
+        assert_eq!((2500, 4300), config.plausible_millivolts);
+
+        // 2S doubles all the voltages
+        let config_2s = AlarmConfig::for_battery(&Battery::LIPO_2S);
+        assert_eq!(6000, config_2s.critical_voltage.raise);
+        assert_eq!(
+            Level {
+                raise: 8500,
+                clear: 8400
+            },
+            config_2s.over_voltage
+        );
+        assert_eq!(
+            Level {
+                raise: 200,
+                clear: 40
+            },
+            config_2s.rapid_drop
+        );
+    }
+
+    #[test]
+    fn test_under_voltage_is_debounced() {
+        let mut monitor = monitor();
+        let config = *monitor.config();
+        let below = config.under_voltage.raise - 10;
+        let between = config.under_voltage.raise + 1;
+        let above = config.under_voltage.clear;
+
+        // a single low reading is ignored and the ones between the levels don't clear it
+        let trace = [
+            3900, below, 3900, below, below, below, between, between, above, above,
+        ];
+        let events = run_trace(&mut monitor, &trace);
+        assert_eq!(
+            [(
+                5,
+                event(BatteryAlarm::UnderVoltage, AlarmState::Raised, below)
+            )]
+            .as_slice(),
+            events.as_slice()
+        );
+        assert!(monitor.active().contains(BatteryAlarm::UnderVoltage));
+
+        // the third consecutive reading at the clear level
+        let events = run_trace(&mut monitor, &[above]);
+        assert_eq!(
+            [(
+                0,
+                event(BatteryAlarm::UnderVoltage, AlarmState::Cleared, above)
+            )]
+            .as_slice(),
+            events.as_slice()
+        );
+        assert!(monitor.active().is_empty());
+    }
+
+    #[test]
+    fn test_critical_and_over_voltage() {
+        let mut monitor = monitor();
+
+        let events = run_trace(&mut monitor, &[2990, 2980, 2970]);
+        assert_eq!(
+            [
+                (
+                    2,
+                    event(BatteryAlarm::UnderVoltage, AlarmState::Raised, 2970)
+                ),
+                (
+                    2,
+                    event(BatteryAlarm::CriticalVoltage, AlarmState::Raised, 2970)
+                ),
+            ]
+            .as_slice(),
+            events.as_slice()
+        );
+
+        let mut monitor = self::monitor();
+        let events = run_trace(&mut monitor, &[4260, 4270, 4260, 4230, 4200, 4190, 4200]);
+        assert_eq!(
+            [
+                (
+                    2,
+                    event(BatteryAlarm::OverVoltage, AlarmState::Raised, 4260)
+                ),
+                (
+                    6,
+                    event(BatteryAlarm::OverVoltage, AlarmState::Cleared, 4200)
+                ),
+            ]
+            .as_slice(),
+            events.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_implausible_holds_the_other_alarms() {
+        let mut monitor = monitor();
+
+        // far below 0%
+        let events = run_trace(&mut monitor, &[3800, 1200, 1100, 1000]);
+        assert_eq!(
+            [(
+                2,
+                event(BatteryAlarm::Implausible, AlarmState::Raised, 1100)
+            )]
+            .as_slice(),
+            events.as_slice()
+        );
+
+        // no rapid drop nor under voltage for the implausible readings
+        let events = run_trace(&mut monitor, &[3800, 3800, 3800]);
+        assert_eq!(
+            [(
+                2,
+                event(BatteryAlarm::Implausible, AlarmState::Cleared, 3800)
+            )]
+            .as_slice(),
+            events.as_slice()
+        );
+
+        // railed ADC is implausible whatever the voltage
+        let mut monitor = self::monitor();
+        for _ in 0..2 {
+            monitor.update(3800, &[ADC_MAX, ADC_MAX, ADC_MAX]);
+        }
+        assert!(monitor.active().contains(BatteryAlarm::Implausible));
+    }
+
+    #[test]
+    fn test_rapid_drop() {
+        let mut monitor = monitor();
+
+        let trace = [4000, 3880, 3760, 3750, 3740, 3740, 3740, 3740];
+        let events = run_trace(&mut monitor, &trace);
+        assert_eq!(
+            [
+                (2, event(BatteryAlarm::RapidDrop, AlarmState::Raised, 3760)),
+                (7, event(BatteryAlarm::RapidDrop, AlarmState::Cleared, 3740)),
+            ]
+            .as_slice(),
+            events.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_stuck_adc() {
+        let mut monitor = monitor();
+
+        for _ in 0..4 {
+            assert!(monitor.update(3800, &[2300; 5]).is_empty());
+        }
+        assert_eq!(
+            [event(BatteryAlarm::StuckAdc, AlarmState::Raised, 3800)].as_slice(),
+            monitor.update(3800, &[2300; 5]).as_slice()
+        );
+        assert_eq!(
+            [BatteryAlarm::StuckAdc].as_slice(),
+            monitor.active().iter().collect::<std::vec::Vec<_>>()
+        );
+
+        // a single noisy reading clears it
+        assert_eq!(
+            [event(BatteryAlarm::StuckAdc, AlarmState::Cleared, 3800)].as_slice(),
+            monitor.update(3800, &SAMPLES).as_slice()
+        );
+    }
+}
@@ -1,17 +1,23 @@
-use embassy_executor::Executor;
+use core::{cell::RefCell, fmt::Write};
 
-use embassy_time::{Duration, Timer};
+use embassy_executor::Executor;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::digital::v2::OutputPin;
+use heapless::Vec;
+use static_cell::StaticCell;
 
 use esp_println::println;
-
-use hal::gpio::Analog;
+use esp_storage::FlashStorage;
 
 use hal::{
-    adc::{AdcConfig, AdcPin, Attenuation, ADC, ADC1},
+    adc::{AdcConfig, ADC, ADC1},
     clock::ClockControl,
     embassy,
-    gpio::{Gpio3, Gpio8, Output, PushPull},
-    peripherals::{Peripherals, UART0},
+    gpio::{Gpio10, Gpio5, Gpio8, Input, Output, PullUp, PushPull},
+    i2c::I2C,
+    macros::ram,
+    peripherals::{Peripherals, I2C0, UART0},
     prelude::*,
     system::SystemParts,
     timer::TimerGroup,
@@ -22,14 +28,152 @@ use hal::{
     Rtc, Uart, IO,
 };
 
-use core::sync::atomic::{AtomicU8, Ordering};
+use crate::{
+    adc::{Adc1Channel, Adc1Channels},
+    adc_scheduler::{AdcReadings, AdcRequest, AdcScheduler},
+    alarm::{ActiveAlarms, AlarmConfig, BatteryAlarm, BatteryMonitor, BATTERY_ALARMS},
+    battery::{Async, Battery, BatteryMeasurement, VoltageDivider, Voltmeter, MEASUREMENTS},
+    calibration::{Attenuation, Calibration, EfuseCalibration, FixedCalibration},
+    charger::{ChargerMonitor, ChargerStatus, CHARGER_STATE},
+    current_sense::{Ina219, SharedI2c},
+    energy::{EnergyAccountant, EnergyConfig, PowerFlow},
+    health::{BatteryHealth, HealthConfig, HealthRecord, HealthStorage, LoadChange, LOAD_CHANGES},
+    helper::{
+        filter::{Chain, ExponentialMovingAverage, Filter, MadOutlierRejection},
+        Ratio,
+    },
+    power_mode::{PowerMode, PowerModeConfig, PowerModeManager, POWER_MODE},
+    power_sense::{PowerSense, PowerSenseConfig},
+    prediction::{Predictor, PREDICTION_SAMPLES},
+    rails::{
+        CommandError, RailChange, RailCommand, RailConfig, RailId, RailManager, RailState,
+        RailSwitches, RAIL_COMMANDS, RAIL_RESPONSES,
+    },
+    solar::{EclipseConfig, EclipseDetector, EclipseEvent, SolarInput, ECLIPSE_EVENTS},
+    telemetry::{
+        BatteryTelemetry, ChargerTelemetry, EnergyTelemetry, HealthTelemetry, PowerSenseTelemetry,
+        RailsTelemetry, SolarTelemetry, TemperatureTelemetry, MAX_SENTENCE_LENGTH, TELEMETRY,
+    },
+    temperature::{InternalTemperatureSensor, TemperatureSource, Thermistor},
+};
+
+/// The Olimex ESP32-C3 board has onboard LED on GPIO 8
+pub type OnboardLed = Gpio8<Output<PushPull>>;
+
+/// The CN3791 CHRG status output on GPIO10
+pub type ChargerChrgPin = Gpio10<Input<PullUp>>;
+
+/// The CN3791 DONE status output on GPIO5
+pub type ChargerDonePin = Gpio5<Input<PullUp>>;
+
+/// How often the charger status outputs are read, fast enough to see CHRG blinking
+pub const CHARGER_STATUS_PERIOD: Duration = Duration::from_millis(250);
+
+/// Rejects glitches before smoothing the battery measurements
+pub type BatteryFilter = Chain<MadOutlierRejection<9>, ExponentialMovingAverage>;
+
+/// The voltage divider on the Olimex board is 470 Ohms each
+pub type OlimexBatteryMeasurement = BatteryMeasurement<470, 470, BatteryFilter>;
+
+/// The power sense voltage divider on the Olimex board is 470 Ohms each
+pub type OlimexPowerSense = PowerSense<470, 470>;
+
+/// The solar panels' voltage divider of 10 kOhm and 4.7 kOhm,
+/// up to `~7.8 V` of the panels is within the ADC range
+pub type SolarPanels = SolarInput<10_000, 4_700>;
+
+/// The battery voltage divider on GPIO3, sampled on the [`PowerMode::Nominal`] period
+/// until the power mode changes
+pub const BATTERY_REQUEST: AdcRequest = AdcRequest::new(
+    Adc1Channel::Gpio3,
+    Attenuation::Attenuation11dB,
+    MEASUREMENTS,
+    Duration::from_secs(1),
+);
+
+/// The power sense voltage divider on GPIO4, sampled together with the battery
+pub const POWER_SENSE_REQUEST: AdcRequest = AdcRequest::new(
+    Adc1Channel::Gpio4,
+    Attenuation::Attenuation11dB,
+    5,
+    Duration::from_secs(1),
+);
+
+/// The battery NTC thermistor divider on GPIO1, the temperature changes slowly
+pub const NTC_REQUEST: AdcRequest = AdcRequest::new(
+    Adc1Channel::Gpio1,
+    Attenuation::Attenuation11dB,
+    5,
+    Duration::from_secs(10),
+);
+
+/// The solar panels' voltage divider on GPIO0, always sampled every second
+/// for timing the eclipses regardless of the power mode
+pub const SOLAR_REQUEST: AdcRequest = AdcRequest::new(
+    Adc1Channel::Gpio0,
+    Attenuation::Attenuation11dB,
+    5,
+    Duration::from_secs(1),
+);
+
+pub static BATTERY_READINGS: AdcReadings = Channel::new();
+pub static POWER_SENSE_READINGS: AdcReadings = Channel::new();
+pub static NTC_READINGS: AdcReadings = Channel::new();
+pub static SOLAR_READINGS: AdcReadings = Channel::new();
+
+/// The internal resistance of a new 250 mAh LiPo cell in milliohms
+pub const OLIMEX_CELL_MILLIOHMS: u16 = 200;
+
+/// The latest battery health in the RTC fast memory, it's kept across resets and deep sleep
+/// but not across a power loss, then [`HealthRecord::from_bytes`] rejects it
+/// and the one saved in the flash is used.
+#[ram(rtc_fast, uninitialized)]
+static mut HEALTH_RECORD: [u8; HealthRecord::LEN] = [0; HealthRecord::LEN];
+
+/// The `health` partition of `partitions.csv` for the [`HealthStorage`]
+pub const HEALTH_PARTITION_OFFSET: u32 = 0x3F_0000;
+pub const HEALTH_PARTITION_LEN: u32 = 0x2000;
+/// How often a changed battery health is saved in the flash,
+/// it wears the flash unlike the RTC memory
+pub const HEALTH_SAVE_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+/// The optional INA219 between the solar panels and the charger (`A0` to VCC)
+pub const SOLAR_INA219_ADDRESS: u8 = 0x41;
+/// The optional INA219 between the charger and the battery (`A0` and `A1` to GND)
+pub const BATTERY_INA219_ADDRESS: u8 = 0x40;
+/// The shunt resistor of the INA219 breakout boards
+pub const INA219_SHUNT_MILLIOHMS: u32 = 100;
+/// How often the currents are measured for the energy accounting
+pub const ENERGY_SAMPLING_PERIOD: Duration = Duration::from_secs(1);
+
+/// How often the UART is checked for the onboard computer's commands
+/// and the events are reported, the telemetry is sent with the power mode's period
+pub const UART_POLLING_PERIOD: Duration = Duration::from_millis(100);
+
+/// How often the rails follow the power mode and the onboard computer's commands
+pub const RAILS_PERIOD: Duration = Duration::from_millis(100);
+
+/// The TCA9534 with the rail enables and over-current flags (`A0`, `A1` and `A2` to GND)
+pub const RAIL_SWITCHES_ADDRESS: u8 = 0x20;
+
+/// The I2C bus is shared by the tasks of the current sensors and the rail switches,
+/// they run on the same executor and don't hold it across an `.await`
+pub type SharedI2c0 = RefCell<I2C<'static, I2C0>>;
+
+static I2C0_BUS: StaticCell<SharedI2c0> = StaticCell::new();
+
+/// The rail switches on the shared I2C bus
+pub type BusRailSwitches = RailSwitches<SharedI2c<'static, I2C<'static, I2C0>>>;
 
 // #[derive(Default)]
 pub struct Application {
-    _adc: ADC<'static, ADC1>,
-    _uart0: Uart<'static, UART0>,
-    // _onboard_led: OnboardLed,
-    _battery_measurement_pin: AdcPin<Gpio3<Analog>, ADC1>,
+    adc: Adc1Channels<'static>,
+    uart0: Uart<'static, UART0>,
+    i2c0: &'static SharedI2c0,
+    onboard_led: OnboardLed,
+    charger_status: ChargerStatus<ChargerChrgPin, ChargerDonePin>,
+    rail_switches: BusRailSwitches,
+    calibration: Calibration,
 }
 
 impl Application {
@@ -59,71 +203,739 @@ impl Application {
         embassy::init(&clocks, timer_group0.timer0);
 
         // Setup peripherals for application
+        let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
 
         // Olimex ESP32-C3 schematics: https://raw.githubusercontent.com/OLIMEX/ESP32-C3-DevKit-Lipo/main/HARDWARE/ESP32-C3-DevKit-Lipo_Rev_B/ESP32-C3-DevKit-Lipo_Rev_B.pdf
         // Configure GPIO and set GPIO8 (LED pin) as an output
+        let onboard_led = io.pins.gpio8.into_push_pull_output();
+
+        // the CN3791 status outputs are open-drain
+        let charger_status = ChargerStatus::new(
+            io.pins.gpio10.into_pull_up_input(),
+            io.pins.gpio5.into_pull_up_input(),
+        );
+
+        // Configure ADC
+        let analog = peripherals.APB_SARADC.split();
+        let mut adc1_config = AdcConfig::new();
+        let battery_measurement_pin = adc1_config.enable_pin(
+            io.pins.gpio3.into_analog(),
+            BATTERY_REQUEST.attenuation.into(),
+        );
+        let power_sense_pin = adc1_config.enable_pin(
+            io.pins.gpio4.into_analog(),
+            POWER_SENSE_REQUEST.attenuation.into(),
+        );
+        let ntc_pin =
+            adc1_config.enable_pin(io.pins.gpio1.into_analog(), NTC_REQUEST.attenuation.into());
+        let solar_pin = adc1_config.enable_pin(
+            io.pins.gpio0.into_analog(),
+            SOLAR_REQUEST.attenuation.into(),
+        );
+        let mut adc = Adc1Channels::new(
+            ADC::<ADC1>::adc(&mut peripheral_clock_control, analog.adc1, adc1_config)
+                .expect("Should configure ADC1"),
+        );
+        adc.gpio3 = Some(battery_measurement_pin);
+        adc.gpio4 = Some(power_sense_pin);
+        adc.gpio1 = Some(ntc_pin);
+        adc.gpio0 = Some(solar_pin);
+
+        // the factory calibration of the 11dB attenuation used by all the ADC pins,
+        // its gain is valid only with the init code removing the offset
+        let efuse = EfuseCalibration::read();
+        let calibration = match efuse
+            .apply_init_code(Attenuation::Attenuation11dB)
+            .and_then(|()| Calibration::from_efuse(&efuse, Attenuation::Attenuation11dB))
+        {
+            Ok(calibration) => calibration,
+            Err(error) => {
+                println!(
+                    "No eFuse ADC calibration ({:?}), using the uncalibrated one",
+                    error
+                );
+                Calibration::UNCALIBRATED
+            }
+        };
 
-        // Configure ADC Here
+        // Configure UART
+        let config = Config {
+            baudrate: 115_200,
+            data_bits: DataBits::DataBits8,
+            parity: Parity::ParityNone,
+            stop_bits: StopBits::STOP1,
+        };
+        // UART0 at 21 (TX) & 20 (RX) GPIO pins
+        let pins = TxRxPins::new_tx_rx(
+            io.pins.gpio21.into_push_pull_output(),
+            io.pins.gpio20.into_floating_input(),
+        );
+        let uart0 = Uart::new_with_config(
+            peripherals.UART0,
+            Some(config),
+            Some(pins),
+            &clocks,
+            &mut peripheral_clock_control,
+        );
 
-        // Configure UART Here
+        // I2C0 for the current sensors and the rail switches at 6 (SDA) & 7 (SCL) GPIO pins
+        let i2c0 = &*I2C0_BUS.init(RefCell::new(I2C::new(
+            peripherals.I2C0,
+            io.pins.gpio6,
+            io.pins.gpio7,
+            100u32.kHz(),
+            &mut peripheral_clock_control,
+            &clocks,
+        )));
+
+        // the essential rails are never switched off by a reboot,
+        // the others are kept as they are until the rails task switches them
+        let mut rail_switches = RailSwitches::new(SharedI2c(i2c0), RAIL_SWITCHES_ADDRESS);
+        if let Err(err) = rail_switches.init(&RailConfig::BUS) {
+            println!("Rail switches not found: {:?}", err);
+        }
 
         Self {
-            _adc,
-            // _onboard_led,
-            _battery_measurement_pin,
-            _uart0,
+            adc,
+            onboard_led,
+            uart0,
+            i2c0,
+            charger_status,
+            rail_switches,
+            calibration,
         }
     }
 
     pub fn run(self, executor: &'static mut Executor) -> ! {
-        // executor.run(|spawner| {
-        //     spawner.must_spawn(battery_measurement_adc(
-        //         self.adc,
-        //         self.battery_measurement_pin,
-        //     ));
-            // spawner.must_spawn(blink(self.onboard_led));
-            // spawner.must_spawn(uart_comm(self.uart0));
+        executor.run(|spawner| {
+            spawner.must_spawn(adc_scheduler(self.adc));
+            spawner.must_spawn(battery_measurement(self.calibration));
+            spawner.must_spawn(blink(self.onboard_led));
+            spawner.must_spawn(uart_comm(self.uart0));
+            spawner.must_spawn(energy_accounting(self.i2c0));
+            spawner.must_spawn(solar_measurement(self.calibration));
+            spawner.must_spawn(charger_status(self.charger_status));
+            spawner.must_spawn(rails(self.rail_switches));
         })
     }
 }
 
-// UART Transmit Communication Task
+// UART Communication Task
 #[embassy_executor::task]
 async fn uart_comm(mut uart: Uart<'static, UART0>) {
-    // This communication task will be executed every 1 second
-    // First the battery percentage will be sent to the power system
-    // Second, the task will enter blocking state until new GNSS message is recieved from power system
+    let mut power_mode_changes = POWER_MODE
+        .subscriber()
+        .expect("Should have a free subscriber for the UART task");
+    let mut alarms = BATTERY_ALARMS
+        .subscriber()
+        .expect("Should have a free subscriber for the UART task");
+    let mut eclipse_events = ECLIPSE_EVENTS
+        .subscriber()
+        .expect("Should have a free subscriber for the UART task");
+    let mut power_mode = PowerMode::Nominal;
+    let mut telemetry_at = Instant::now();
+    // the bytes of the command being received
+    let mut command_line = Vec::<u8, MAX_SENTENCE_LENGTH>::new();
+
     loop {
         // Transmit Operations
-        // First we send the battery percentage value to the Power system
+        // Report every power mode change to the onboard computer
+        while let Some(change) = power_mode_changes.try_next_message_pure() {
+            power_mode = change.to;
+
+            match change.to_sentence() {
+                Ok(sentence) => {
+                    if uart.write_str(&sentence).is_err() {
+                        println!("Failed to send power mode change over UART");
+                    }
+                }
+                Err(_) => println!("Power mode change sentence does not fit"),
+            }
+        }
+
+        // Report every raised and cleared battery alarm
+        while let Some(alarm) = alarms.try_next_message_pure() {
+            match alarm.to_sentence() {
+                Ok(sentence) => {
+                    if uart.write_str(&sentence).is_err() {
+                        println!("Failed to send battery alarm over UART");
+                    }
+                }
+                Err(_) => println!("Battery alarm sentence does not fit"),
+            }
+        }
+
+        // Report every eclipse entry and exit
+        while let Some(event) = eclipse_events.try_next_message_pure() {
+            match event.to_sentence() {
+                Ok(sentence) => {
+                    if uart.write_str(&sentence).is_err() {
+                        println!("Failed to send eclipse event over UART");
+                    }
+                }
+                Err(_) => println!("Eclipse event sentence does not fit"),
+            }
+        }
+
+        // Report the result of every rail command
+        while let Ok(response) = RAIL_RESPONSES.try_recv() {
+            match response.to_sentence() {
+                Ok(sentence) => {
+                    if uart.write_str(&sentence).is_err() {
+                        println!("Failed to send rail command response over UART");
+                    }
+                }
+                Err(_) => println!("Rail command response sentence does not fit"),
+            }
+        }
+
+        // Send the latest telemetry to the onboard computer
+        if Instant::now() >= telemetry_at {
+            let telemetry = *TELEMETRY.lock().await;
+            if telemetry.write_sentences(&mut uart).is_err() {
+                println!("Failed to send telemetry over UART");
+            }
+            telemetry_at = Instant::now() + power_mode.telemetry_period();
+        }
 
         // Recieve Operations
-        // Second we poll UART reciever to check if any messages are recieved
-        // This code will recieve NEMA messages from the power board
-        // Code will NOT? block until message is recieved
-        // On board computer needs to send the GNSS messages more frequently so that this task does not block for long
-        // (Can look into option of using async hal/interrupts but not sure if supported)
+        // The commands of the onboard computer (`$PPWC,...*hh\r\n`) are read without blocking,
+        // the UART FIFO keeps the bytes received in between
+        while let Ok(byte) = uart.read() {
+            match byte {
+                b'$' => {
+                    command_line.clear();
+                    command_line.push(byte).ok();
+                }
+                b'\n' => {
+                    if let Some(result) = parse_command(&command_line) {
+                        match result {
+                            Ok(command) => {
+                                if RAIL_COMMANDS.try_send(command).is_err() {
+                                    println!("Rail commands channel is full");
+                                }
+                            }
+                            Err(err) => match err.to_sentence() {
+                                Ok(sentence) => {
+                                    if uart.write_str(&sentence).is_err() {
+                                        println!("Failed to send command error over UART");
+                                    }
+                                }
+                                Err(_) => println!("Command error sentence does not fit"),
+                            },
+                        }
+                    }
+                    command_line.clear();
+                }
+                // a line too long for a sentence is discarded
+                _ => {
+                    if command_line.push(byte).is_err() {
+                        command_line.clear();
+                    }
+                }
+            }
+        }
+
+        Timer::after(UART_POLLING_PERIOD).await;
+    }
+}
+
+/// Parses a received line, `None` when it's empty or not a sentence at all.
+fn parse_command(line: &[u8]) -> Option<Result<RailCommand, CommandError>> {
+    if line.first() != Some(&b'$') {
+        return None;
+    }
+
+    Some(
+        core::str::from_utf8(line)
+            .map_err(|_| CommandError::Malformed)
+            .and_then(RailCommand::parse),
+    )
+}
+
+// ADC Scheduler Task, the only owner of ADC1
+#[embassy_executor::task]
+async fn adc_scheduler(mut adc: Adc1Channels<'static>) {
+    let mut scheduler = AdcScheduler::<4>::new();
+    let now = Instant::now();
+    // the power sense goes first so it's already available with each battery reading
+    scheduler
+        .add(POWER_SENSE_REQUEST, &POWER_SENSE_READINGS, now)
+        .expect("Should fit the power sense request");
+    scheduler
+        .add(BATTERY_REQUEST, &BATTERY_READINGS, now)
+        .expect("Should fit the battery request");
+    scheduler
+        .add(NTC_REQUEST, &NTC_READINGS, now)
+        .expect("Should fit the NTC request");
+    scheduler
+        .add(SOLAR_REQUEST, &SOLAR_READINGS, now)
+        .expect("Should fit the solar request");
+
+    let mut power_mode_changes = POWER_MODE
+        .subscriber()
+        .expect("Should have a free subscriber for the ADC scheduler task");
+    let mut reported_failures = scheduler.failures();
+
+    loop {
+        while let Some(change) = power_mode_changes.try_next_message_pure() {
+            let period = change.to.sampling_period();
+            scheduler.set_period(BATTERY_REQUEST.channel, period);
+            scheduler.set_period(POWER_SENSE_REQUEST.channel, period);
+        }
+
+        scheduler.serve_next(&mut adc).await;
+
+        let failures = scheduler.failures();
+        if failures != reported_failures {
+            TELEMETRY.lock().await.failures = failures;
+            reported_failures = failures;
+        }
+    }
+}
+
+// Battery Measurement Task
+#[embassy_executor::task]
+async fn battery_measurement(calibration: Calibration) {
+    let battery = Battery::OLIMEX_LIPO_250MAH;
+    let battery_filter =
+        MadOutlierRejection::new(Ratio::new(3, 1), 4).then(ExponentialMovingAverage::new(2));
+    let mut battery_measurement =
+        OlimexBatteryMeasurement::with_filter(VoltageDivider, calibration, battery_filter);
+    let power_sense_voltmeter = Voltmeter::new(VoltageDivider, calibration, Async);
+    let mut power_sense = OlimexPowerSense::new(
+        power_sense_voltmeter,
+        PowerSenseConfig::for_battery(&battery),
+    );
+    let mut supply_millivolts = None;
+    let mut charger_monitor = ChargerMonitor::new(PowerSenseConfig::for_battery(&battery));
+    let mut charger_state = None;
+    let thermistor = Thermistor::NTC_10K_3950;
+    let ntc_calibration = FixedCalibration::from(&calibration);
+    let mut internal_temperature = InternalTemperatureSensor::new();
+    // `None` while the NTC is disconnected (or shorted), the chip temperature is used instead
+    let mut ntc_decicelsius = None;
+    // 1 sample per minute, i.e. the trend of the last 101 minutes
+    let mut predictor = Predictor::<PREDICTION_SAMPLES>::new(Duration::from_secs(60), 5);
+    let mut monitor = BatteryMonitor::new(AlarmConfig::for_battery(&battery));
+    let mut health_storage = HealthStorage::new(
+        FlashStorage::new(),
+        HEALTH_PARTITION_OFFSET,
+        HEALTH_PARTITION_LEN,
+    );
+    let mut saved_record = health_storage.load().unwrap_or_else(|err| {
+        println!("Failed to load the battery health: {:?}", err);
+        None
+    });
+    let mut saved_at = Instant::now();
+    // the RTC memory has the latest record unless the power was lost
+    // Safety: only the battery measurement task accesses the record
+    let mut health_record = HealthRecord::from_bytes(unsafe { &HEALTH_RECORD })
+        .or(saved_record)
+        .unwrap_or(HealthRecord::NEW);
+    let mut health = BatteryHealth::restore(
+        HealthConfig::for_battery(&battery, OLIMEX_CELL_MILLIOHMS),
+        health_record,
+    );
+    let alarm_publisher = BATTERY_ALARMS
+        .publisher()
+        .expect("Should have a free publisher for the battery alarms");
+    let mut power_mode =
+        PowerModeManager::new(PowerModeConfig::for_battery(&battery), Instant::now());
+    let power_mode_publisher = POWER_MODE
+        .publisher()
+        .expect("Should have a free publisher for the power mode");
+
+    loop {
+        let mut reading = BATTERY_READINGS.recv().await;
+        // the latest supply-side voltage decides how to interpret the battery voltage
+        while let Ok(power_sense_reading) = POWER_SENSE_READINGS.try_recv() {
+            supply_millivolts = Some(power_sense.millivolts(power_sense_reading.mediana()));
+        }
+        while let Ok(ntc_reading) = NTC_READINGS.try_recv() {
+            ntc_decicelsius = thermistor.raw_decicelsius(ntc_reading.mediana(), &ntc_calibration);
+        }
+        let (decicelsius, temperature_source) = match ntc_decicelsius {
+            Some(decicelsius) => (decicelsius, TemperatureSource::Ntc),
+            None => (
+                internal_temperature.read_decicelsius(),
+                TemperatureSource::Internal,
+            ),
+        };
+        let previous_inhibits = battery_measurement.inhibits();
+        let inhibits = battery_measurement.update_temperature(decicelsius, &battery);
+        if inhibits != previous_inhibits {
+            println!(
+                "Battery temperature {} (0.1°C) from {:?}, inhibits: {}",
+                decicelsius,
+                temperature_source,
+                inhibits.as_str()
+            );
+        }
+        // the filter smooths out the voltage step of a load change (for the health)
+        // and delays a rapid drop (for the alarms), both need the unfiltered voltage
+        let unfiltered_millivolts = battery_measurement.adc_to_millivolts(reading.mediana());
+
+        // an implausible reading raises the `Implausible` alarm
+        for event in monitor.update(unfiltered_millivolts, &reading.samples) {
+            println!(
+                "Battery alarm {:?} {:?} at {}mV",
+                event.alarm, event.state, event.millivolts
+            );
+            alarm_publisher.publish_immediate(event);
+        }
+
+        // and it's not used for the State of Charge, the prediction or the telemetry
+        let ocv_permille = match battery_measurement.record_permille(&mut reading.samples, &battery)
+        {
+            Ok(permille) => permille,
+            Err(implausible) => {
+                println!(
+                    "Battery voltage {}mV is {:?} of the cells, the reading is dropped",
+                    implausible.millivolts, implausible.limit
+                );
+                continue;
+            }
+        };
+        let millivolts = battery_measurement.adc_to_millivolts(
+            *battery_measurement
+                .last_measurements
+                .recent()
+                .expect("Measurement was just written to history"),
+        );
+
+        if let Some(state) = CHARGER_STATE.try_take() {
+            charger_state = Some(state);
+        }
+        let charger_telemetry = charger_state.map(|state| {
+            let flagged = charger_monitor.flagged();
+            let disagreement =
+                charger_monitor.update(state, millivolts, power_sense.battery_trend());
+            if disagreement != flagged {
+                println!(
+                    "Charger state {:?} disagreement with the battery voltage: {:?}",
+                    state, disagreement
+                );
+            }
+
+            ChargerTelemetry {
+                state,
+                disagreement,
+            }
+        });
+
+        let (permille, power_sense_telemetry) = match supply_millivolts {
+            Some(supply_millivolts) => {
+                let charging_state =
+                    power_sense.update(supply_millivolts, millivolts, reading.taken_at);
+
+                (
+                    battery_measurement.state_of_charge(millivolts, &battery, charging_state),
+                    Some(PowerSenseTelemetry {
+                        millivolts: supply_millivolts,
+                        charging_state,
+                    }),
+                )
+            }
+            None => (ocv_permille, None),
+        };
+
+        predictor.update(permille, reading.taken_at);
+
+        if let Some(load_change) = LOAD_CHANGES.take() {
+            health.load_changed(load_change);
+        }
+        if let Some(milliohms) = health.update(unfiltered_millivolts, permille, reading.taken_at) {
+            println!("Battery internal resistance: {}mOhm", milliohms);
+        }
+        if health.record() != health_record {
+            health_record = health.record();
+            // Safety: only the battery measurement task accesses the record
+            unsafe { HEALTH_RECORD = health_record.to_bytes() };
+        }
+        if saved_record != Some(health_record) && saved_at.elapsed() >= HEALTH_SAVE_PERIOD {
+            // a failed save is retried in the next period
+            saved_at = Instant::now();
+            match health_storage.save(&health_record) {
+                Ok(()) => saved_record = Some(health_record),
+                Err(err) => println!("Failed to save the battery health: {:?}", err),
+            }
+        }
+
+        // the battery must not be discharged, only the essentials keep running until it's
+        // back within the limits, the charge inhibit is reported to the onboard computer
+        let power_mode_change = if inhibits.discharge {
+            power_mode.degrade_to(PowerMode::Survival, millivolts, reading.taken_at)
+        } else {
+            power_mode.update(millivolts, reading.taken_at)
+        };
+        if let Some(change) = power_mode_change {
+            println!(
+                "Power mode changed from {:?} to {:?} at {}mV",
+                change.from, change.to, change.millivolts
+            );
+            power_mode_publisher.publish_immediate(change);
+        }
+
+        let mut telemetry = TELEMETRY.lock().await;
+        telemetry.battery = Some(BatteryTelemetry {
+            millivolts,
+            permille,
+            prediction: predictor.predict(),
+        });
+        telemetry.power_mode = power_mode.mode();
+        telemetry.health = Some(HealthTelemetry {
+            state_of_health: health.state_of_health(),
+            resistance_milliohms: health.resistance_milliohms(),
+            cycles_permille: health.cycles_permille(),
+        });
+        telemetry.temperature = Some(TemperatureTelemetry {
+            decicelsius,
+            source: temperature_source,
+            inhibits,
+        });
+        if charger_telemetry.is_some() {
+            telemetry.charger = charger_telemetry;
+        }
+        if power_sense_telemetry.is_some() {
+            telemetry.power_sense = power_sense_telemetry;
+        }
     }
 }
 
-// ADC Measurement Task
+// Charger Status Task
 #[embassy_executor::task]
-async fn battery_measurement_adc(
-    mut adc_1: ADC<'static, ADC1>,
-    mut battery_measurement_pin: AdcPin<Gpio3<Analog>, ADC1>,
-) {
+async fn charger_status(mut status: ChargerStatus<ChargerChrgPin, ChargerDonePin>) {
     loop {
-        // Take an ADC Reading
+        let previous = status.state();
+        match status.read(Instant::now()) {
+            Ok(state) if Some(state) != previous => {
+                println!("Charger state changed from {:?} to {:?}", previous, state);
+                // the battery task only needs the latest state
+                CHARGER_STATE.signal(state);
+            }
+            Ok(_) => {}
+            Err(err) => println!("Failed to read the charger status: {:?}", err),
+        }
 
-        // ADC is 12 bit resolution
-        // Resolution = Vref/Full Scale = 3.3V/2^12
-        // Measured Voltage =  Resolution * ADC Reading = reading * 3.3V/2^12
-        // Battery Percentage = (Measured Voltage/Full Battery Voltage) * 100% = (3.7 - (reading * 3.3V / 2^12))/(3.7-3.3))/100
+        Timer::after(CHARGER_STATUS_PERIOD).await;
+    }
+}
+
+// Power Rails Task
+#[embassy_executor::task]
+async fn rails(mut switches: BusRailSwitches) {
+    let mut manager = RailManager::new(RailConfig::BUS);
+    let mut power_mode_changes = POWER_MODE
+        .subscriber()
+        .expect("Should have a free subscriber for the rails task");
+
+    // the default rails, the ones left on before a reboot are switched off
+    for change in manager.set_power_mode(PowerMode::Nominal) {
+        switch_rail(&mut switches, change);
+    }
+    for rail in RailId::ALL {
+        if switches.is_on(rail) && manager.state(rail) != Some(RailState::On) {
+            println!("Rail {} switched off after the reboot", rail.as_str());
+            if let Err(err) = switches.switch(rail, false) {
+                println!("Failed to switch the rail {}: {:?}", rail.as_str(), err);
+            }
+        }
+    }
+
+    let mut reported = None;
+    let mut faults_unreadable = false;
+    loop {
+        while let Some(change) = power_mode_changes.try_next_message_pure() {
+            for change in manager.set_power_mode(change.to) {
+                println!("Rail {} switched by the power mode", change.rail.as_str());
+                switch_rail(&mut switches, change);
+            }
+        }
+
+        while let Ok(command) = RAIL_COMMANDS.try_recv() {
+            let (response, change) = manager.command(command);
+            if let Some(change) = change {
+                switch_rail(&mut switches, change);
+            }
+            if RAIL_RESPONSES.try_send(response).is_err() {
+                println!("Rail responses channel is full");
+            }
+        }
+
+        match switches.over_current() {
+            Ok(rails) => {
+                faults_unreadable = false;
+                for rail in rails {
+                    if let Some(change) = manager.over_current(rail) {
+                        println!("Rail {} tripped by an over-current", rail.as_str());
+                        switch_rail(&mut switches, change);
+                    }
+                }
+            }
+            Err(err) if !faults_unreadable => {
+                println!("Failed to read the rail faults: {:?}", err);
+                faults_unreadable = true;
+            }
+            Err(_) => {}
+        }
+        // the enables of a failed switch are written again
+        if !switches.is_synced() {
+            switches.sync().ok();
+        }
+
+        let states = RailsTelemetry {
+            states: RailId::ALL.map(|rail| manager.state(rail)),
+        };
+        if reported != Some(states) {
+            TELEMETRY.lock().await.rails = Some(states);
+            reported = Some(states);
+        }
+
+        Timer::after(RAILS_PERIOD).await;
+    }
+}
+
+/// Switches the rail's enable and lets the battery task know about the load change.
+fn switch_rail(switches: &mut BusRailSwitches, change: RailChange) {
+    if let Err(err) = switches.switch(change.rail, change.on) {
+        println!(
+            "Failed to switch the rail {}: {:?}",
+            change.rail.as_str(),
+            err
+        );
+    }
+
+    LOAD_CHANGES.add(LoadChange {
+        milliamps: change.milliamps,
+        at: Instant::now(),
+    });
+}
+
+// Solar Measurement Task
+#[embassy_executor::task]
+async fn solar_measurement(calibration: Calibration) {
+    let solar = SolarPanels::new(Voltmeter::new(VoltageDivider, calibration, Async));
+    let mut detector = EclipseDetector::new(EclipseConfig::SOLAR_6V);
+    let eclipse_publisher = ECLIPSE_EVENTS
+        .publisher()
+        .expect("Should have a free publisher for the eclipse events");
+
+    loop {
+        let reading = SOLAR_READINGS.recv().await;
+        let millivolts = solar.millivolts(reading.mediana());
+
+        if let Some(event) = detector.update(millivolts, reading.taken_at) {
+            match event {
+                EclipseEvent::Entered { at } => {
+                    println!("Eclipse entered at {}s", at.as_secs())
+                }
+                EclipseEvent::Exited { at, duration } => println!(
+                    "Eclipse exited at {}s after {:?}s",
+                    at.as_secs(),
+                    duration.map(|duration| duration.as_secs())
+                ),
+            }
+            eclipse_publisher.publish_immediate(event);
+        }
+
+        TELEMETRY.lock().await.solar = Some(SolarTelemetry {
+            millivolts,
+            illumination: detector.illumination(),
+        });
+    }
+}
+
+// Energy Accounting Task
+#[embassy_executor::task]
+async fn energy_accounting(i2c: &'static SharedI2c0) {
+    // the current sensors are optional, a missing one doesn't acknowledge the configuration
+    let mut solar_sense = Some(Ina219::new(
+        SharedI2c(i2c),
+        SOLAR_INA219_ADDRESS,
+        INA219_SHUNT_MILLIOHMS,
+    ));
+    let mut battery_sense = Some(Ina219::new(
+        SharedI2c(i2c),
+        BATTERY_INA219_ADDRESS,
+        INA219_SHUNT_MILLIOHMS,
+    ));
+    for (name, sense) in [("Solar", &mut solar_sense), ("Battery", &mut battery_sense)] {
+        if let Some(Err(err)) = sense.as_mut().map(|sense| sense.init()) {
+            println!("{} current sensor not found: {:?}", name, err);
+            *sense = None;
+        }
+    }
+    if solar_sense.is_none() && battery_sense.is_none() {
+        return;
+    }
+
+    let mut accountant = EnergyAccountant::new(EnergyConfig::LEO, Instant::now());
+
+    loop {
+        Timer::after(ENERGY_SAMPLING_PERIOD).await;
+
+        let flow = PowerFlow {
+            solar: measure_milliwatts(solar_sense.as_mut())
+                .map(|milliwatts| milliwatts.max(0) as u32),
+            battery: measure_milliwatts(battery_sense.as_mut()),
+        };
+        accountant.update(flow, Instant::now());
+
+        TELEMETRY.lock().await.energy = Some(EnergyTelemetry {
+            last_minute: accountant.last_minute(),
+            last_orbit: accountant.last_orbit(),
+            since_boot: accountant.since_boot(),
+        });
+    }
+}
+
+/// The power (in milliwatts) from the bus voltage and the current of a single conversion,
+/// `None` without a sensor or when the measurement fails.
+fn measure_milliwatts(
+    sense: Option<&mut Ina219<SharedI2c<'_, I2C<'static, I2C0>>>>,
+) -> Option<i32> {
+    match sense?.measure() {
+        Ok(measurement) => Some(PowerFlow::milliwatts(
+            measurement.millivolts,
+            measurement.milliamps,
+        )),
+        Err(err) => {
+            println!("Current sensor measurement failed: {:?}", err);
+            None
+        }
     }
 }
 
 // LED Blinking Task
 #[embassy_executor::task]
 async fn blink(mut led: OnboardLed) {
-    // LED Blinking Code goes here
+    let mut power_mode_changes = POWER_MODE
+        .subscriber()
+        .expect("Should have a free subscriber for the LED task");
+    let mut alarms = BATTERY_ALARMS
+        .subscriber()
+        .expect("Should have a free subscriber for the LED task");
+    let mut power_mode = PowerMode::Nominal;
+    let mut active_alarms = ActiveAlarms::new();
+
+    loop {
+        while let Some(change) = power_mode_changes.try_next_message_pure() {
+            power_mode = change.to;
+        }
+        while let Some(alarm) = alarms.try_next_message_pure() {
+            active_alarms.apply(&alarm);
+        }
+
+        // alarms are signaled unless the LED would drain the battery in Survival mode
+        let (on, off) = if active_alarms.is_empty() || power_mode == PowerMode::Survival {
+            power_mode.blink_pattern()
+        } else {
+            BatteryAlarm::blink_pattern()
+        };
+        led.set_high().ok();
+        Timer::after(on).await;
+        led.set_low().ok();
+        Timer::after(off).await;
+    }
 }
@@ -1,86 +1,453 @@
 // pub type I2C: I2C
-use core::{ops, result::Result};
+use core::{marker::PhantomData, ops, result::Result};
 
 // #[cfg(feature = "defmt")]
 // use defmt::Format;
 
 use defmt::Format;
 use embassy_time::{Duration, Timer};
-use esp_println::println;
+use embedded_hal::blocking::delay::DelayUs;
+#[cfg(feature = "riscv")]
 use hal::{
-    adc::{AdcPin, ADC, ADC1},
-    gpio::{Analog, Gpio3, Gpio4, Input},
-    prelude::*,
+    adc::{AdcPin, ADC1},
+    gpio::{Analog, Gpio3, Gpio4},
 };
 
-use heapless::{HistoryBuffer, Vec};
+use heapless::Vec;
 
-use crate::helper::find_mediana;
+use crate::{
+    adc::{AdcSource, ADC_MAX},
+    calibration::{Calibration, FixedCalibration},
+    helper::{
+        filter::{Filter, Passthrough},
+        find_mediana,
+        sliding_median::SlidingMedian,
+        Ratio,
+    },
+    ocv::OcvCurve,
+    power_sense::ChargingState,
+    println,
+    temperature::{Inhibits, TemperatureCompensation, TemperatureLimits},
+};
 
+#[cfg(feature = "riscv")]
 pub type BatteryMeasurementPin = AdcPin<Gpio3<Analog>, ADC1>;
+#[cfg(feature = "riscv")]
 pub type PowerSensePin = AdcPin<Gpio4<Analog>, ADC1>;
 
 /// A voltage divider on GPIO3 with 470 Omhs each and safe to read with a scale factor.
 ///
+/// The samples are read from an [`AdcSource`] passed to each measurement,
+/// on the board this is the ADC1 channel of the [`BatteryMeasurementPin`].
+///
+/// The mediana of each batch of measurements goes through the [`Filter`]
+/// (e.g. a [`Chain`](crate::helper::filter::Chain) of filters) before it's written to the history.
 // #[derive(Debug)]
 // #[cfg_attr(feature = "defmt", derive(Format))]
-pub struct BatteryMeasurement<const R1: usize, const R2: usize> {
-    pub analog_pin: BatteryMeasurementPin,
+pub struct BatteryMeasurement<const R1: usize, const R2: usize, F: Filter = Passthrough> {
     // The medianas of the last X battery measurements as an ADC value
     // it's easier to compare and sort
     /// Buffer should always be with an odd size
-    pub last_measurements: HistoryBuffer<u16, 101>,
+    pub last_measurements: SlidingMedian<101>,
     pub voltage_divider: VoltageDivider<R1, R2>,
+    /// The ADC transfer function for the battery measurement channel
+    pub calibration: Calibration,
+    /// The fixed-point version of `calibration` used by the integer pipeline,
+    /// see [`BatteryMeasurement::measure_permille`]
+    pub fixed_calibration: FixedCalibration,
+    pub filter: F,
+    /// How the ADC reads of the measurements are retried
+    pub retry_policy: RetryPolicy,
+    /// The battery temperature (in `0.1 °C`), `None` until it's known
+    temperature: Option<i16>,
+    inhibits: Inhibits,
+}
+
+/// The chemistry of the battery cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Chemistry {
+    LiPo,
+    LiFePO4,
+    NiMH,
+}
+
+impl Chemistry {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Chemistry::LiPo => "LIPO",
+            Chemistry::LiFePO4 => "LIFEPO4",
+            Chemistry::NiMH => "NIMH",
+        }
+    }
+
+    /// The typical number of full cycles until the capacity fades to 80%
+    pub fn rated_cycles(&self) -> u32 {
+        match self {
+            Chemistry::LiPo => 500,
+            Chemistry::LiFePO4 => 2000,
+            Chemistry::NiMH => 500,
+        }
+    }
+}
+
+/// Where a battery voltage is compared to the absolute limits of the [`Battery`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum VoltageLimit {
+    BelowMinimum,
+    Within,
+    AboveMaximum,
+}
+
+/// A battery reading outside of the [`Battery::absolute_millivolts`],
+/// e.g. a disconnected battery or a broken voltage divider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Implausible {
+    /// The pack voltage of the reading's mediana
+    pub millivolts: u32,
+    pub limit: VoltageLimit,
 }
 
+/// The profile of a battery pack of `series_cells` identical cells.
+///
+/// All the voltages are of a single cell, use [`Battery::pack_millivolts`]
+/// to get the voltage of the whole pack which is what we measure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Battery {
-    pub cut_out_voltage: f32,
-    pub charged_voltage: f32,
+    pub chemistry: Chemistry,
+    /// The number of cells in series
+    pub series_cells: u8,
+    /// The nominal capacity in mAh
+    pub capacity_mah: u32,
+    /// The discharge curve of a single cell used to convert the measured voltage to percentage
+    pub ocv_curve: OcvCurve<'static>,
+    /// The cell is empty at this voltage (in mV)
+    pub cut_out_millivolts: u32,
+    /// The charger terminates charging at this cell voltage (in mV)
+    pub charge_termination_millivolts: u32,
+    /// The absolute minimum and maximum cell voltage (in mV),
+    /// outside of it the cell is damaged or the measurement is wrong
+    pub absolute_millivolts: (u32, u32),
+    /// How much higher (in mV) the cell voltage is while charging
+    /// compared to its open-circuit voltage
+    pub charging_offset_millivolts: u32,
+}
+
+impl Battery {
+    /// The [LiPo 3.7V battery of 250 mAh](https://www.olimex.com/Products/Power/Lipo-battery/BATTERY-LIPO250mAh/)
+    pub const OLIMEX_LIPO_250MAH: Battery = Battery {
+        chemistry: Chemistry::LiPo,
+        series_cells: 1,
+        capacity_mah: 250,
+        ocv_curve: crate::ocv::OLIMEX_LIPO_250MAH,
+        cut_out_millivolts: 3000,
+        charge_termination_millivolts: 4200,
+        absolute_millivolts: (2500, 4300),
+        charging_offset_millivolts: 100,
+    };
+
+    /// A single 3.7V LiPo (or Li-ion) cell of 1000 mAh
+    pub const LIPO_1S: Battery = Battery {
+        capacity_mah: 1000,
+        ocv_curve: crate::ocv::LIPO,
+        ..Self::OLIMEX_LIPO_250MAH
+    };
+
+    /// Two 3.7V LiPo (or Li-ion) cells of 1000 mAh in series
+    pub const LIPO_2S: Battery = Self::LIPO_1S.with_series_cells(2);
+
+    /// A single 3.2V LiFePO4 cell of 1500 mAh, e.g. an IFR18650
+    pub const LIFEPO4_1S: Battery = Battery {
+        chemistry: Chemistry::LiFePO4,
+        series_cells: 1,
+        capacity_mah: 1500,
+        ocv_curve: crate::ocv::LIFEPO4,
+        cut_out_millivolts: 2500,
+        charge_termination_millivolts: 3600,
+        absolute_millivolts: (2000, 3650),
+        charging_offset_millivolts: 50,
+    };
+
+    /// Four 1.2V NiMH AA cells of 2000 mAh in series
+    pub const NIMH_4S: Battery = Battery {
+        chemistry: Chemistry::NiMH,
+        series_cells: 4,
+        capacity_mah: 2000,
+        ocv_curve: crate::ocv::NIMH,
+        cut_out_millivolts: 1000,
+        charge_termination_millivolts: 1450,
+        absolute_millivolts: (900, 1500),
+        charging_offset_millivolts: 60,
+    };
+
+    pub const fn with_capacity(self, capacity_mah: u32) -> Self {
+        Self {
+            capacity_mah,
+            ..self
+        }
+    }
+
+    /// # Panics
+    ///
+    /// When `series_cells` is `0`.
+    pub const fn with_series_cells(self, series_cells: u8) -> Self {
+        assert!(series_cells > 0, "Should have at least 1 cell");
+
+        Self {
+            series_cells,
+            ..self
+        }
+    }
+
+    /// The voltage of the whole pack for the given cell voltage (in mV).
+    pub const fn pack_millivolts(&self, cell_millivolts: u32) -> u32 {
+        cell_millivolts * self.series_cells as u32
+    }
+
+    /// The voltage of a single cell for the given pack voltage (in mV),
+    /// rounded to the nearest millivolt.
+    pub const fn cell_millivolts(&self, pack_millivolts: u32) -> u32 {
+        let cells = self.series_cells as u32;
+
+        (pack_millivolts + cells / 2) / cells
+    }
+
+    /// Looks up the percentage for the given pack voltage in the [`OcvCurve`].
+    ///
+    /// The result is clamped between `0%` and `100%`.
+    pub fn percentage(&self, voltage: f32) -> f32 {
+        self.ocv_curve
+            .percentage(voltage / self.series_cells as f32)
+    }
+
+    /// Looks up the State of Charge in per-mille for the given pack voltage (in mV)
+    /// in the [`OcvCurve`].
+    pub fn permille(&self, millivolts: u32) -> u16 {
+        self.ocv_curve.permille(self.cell_millivolts(millivolts))
+    }
+
+    /// The pack voltage (in mV) for the given State of Charge in per-mille.
+    pub fn millivolts(&self, permille: u16) -> u32 {
+        self.pack_millivolts(self.ocv_curve.millivolts(permille))
+    }
+
+    /// Compares the pack voltage (in mV) to the absolute limits of the cells.
+    pub fn limit(&self, millivolts: u32) -> VoltageLimit {
+        let (min, max) = self.absolute_millivolts;
+
+        if millivolts < self.pack_millivolts(min) {
+            VoltageLimit::BelowMinimum
+        } else if millivolts > self.pack_millivolts(max) {
+            VoltageLimit::AboveMaximum
+        } else {
+            VoltageLimit::Within
+        }
+    }
+}
+
+/// The step of a measurement which failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum MeasurementStep {
+    /// A single ADC read, e.g. [`BatteryMeasurement::measure_once`] or a [`Voltmeter`]
+    Read,
+    /// The sample (`0`-based) of a batch of ADC reads,
+    /// e.g. [`BatteryMeasurement::measure_permille`]
+    Batch(usize),
+    /// The mediana of a batch, e.g. when it's [`Implausible`]
+    Mediana,
+}
+
+/// Why a try of an ADC read failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ReadFailure<E> {
+    /// The conversion was still in progress ([`nb::Error::WouldBlock`])
+    Timeout,
+    /// The ADC returned an error ([`nb::Error::Other`])
+    Adc(E),
+    /// The ADC returned a value above [`ADC_MAX`]
+    OutOfRange(u16),
+    /// The battery voltage (in mV) is outside of its absolute limits, see [`Implausible`]
+    Implausible(u32),
+}
+
+/// A failed measurement, `E` is the error of the [`AdcSource`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Error<E> {
+    pub step: MeasurementStep,
+    /// The number of ADC reads before giving up
+    pub tries: usize,
+    /// The last failure other than a [`ReadFailure::Timeout`],
+    /// which is only reported when all the tries timed out
+    pub failure: ReadFailure<E>,
+}
+
+impl<E> Error<E> {
+    /// The same error but for the given step, e.g. a read of a batch.
+    pub fn at(self, step: MeasurementStep) -> Self {
+        Self { step, ..self }
+    }
+}
+
+/// The number of failed measurements by the [`ReadFailure`] they reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct MeasurementFailures {
+    pub timeouts: u32,
+    pub adc_errors: u32,
+    pub out_of_range: u32,
+}
+
+impl MeasurementFailures {
+    pub const fn new() -> Self {
+        Self {
+            timeouts: 0,
+            adc_errors: 0,
+            out_of_range: 0,
+        }
+    }
+
+    pub fn record<E>(&mut self, error: &Error<E>) {
+        let counter = match error.failure {
+            ReadFailure::Timeout => &mut self.timeouts,
+            ReadFailure::Adc(_) => &mut self.adc_errors,
+            ReadFailure::OutOfRange(_) | ReadFailure::Implausible(_) => &mut self.out_of_range,
+        };
+
+        *counter = counter.saturating_add(1);
+    }
+
+    pub fn total(&self) -> u32 {
+        self.timeouts
+            .saturating_add(self.adc_errors)
+            .saturating_add(self.out_of_range)
+    }
+}
+
+/// How many times an ADC read is tried and how long to wait between the tries.
+///
+/// The [`Async`] API waits with a timer, the [`Blocking`] one busy-waits with a `DelayUs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The maximum number of ADC reads before giving up
+    pub max_tries: usize,
+    /// The delay after the first try
+    pub delay: Duration,
+    /// Each following delay is multiplied by it, `1` keeps the delay constant
+    pub backoff: u32,
+    /// The longest delay between two tries
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// [`MAX_TRIES`] tries with a constant [`RETRY_DELAY`] between them
+    pub const DEFAULT: RetryPolicy = RetryPolicy::constant(MAX_TRIES, RETRY_DELAY);
+
+    pub const fn constant(max_tries: usize, delay: Duration) -> Self {
+        Self {
+            max_tries,
+            delay,
+            backoff: 1,
+            max_delay: delay,
+        }
+    }
+
+    /// Doubles the delay after each try up to `max_delay`.
+    pub const fn exponential(max_tries: usize, delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            max_tries,
+            delay,
+            backoff: 2,
+            max_delay,
+        }
+    }
+
+    /// The delay after the given try (`0`-based).
+    pub fn delay(&self, try_index: usize) -> Duration {
+        let mut delay = self.delay;
+        for _ in 0..try_index {
+            if self.backoff <= 1 || delay >= self.max_delay {
+                break;
+            }
+            delay = delay * self.backoff;
+        }
+
+        delay.min(self.max_delay)
+    }
 }
 
-#[derive(Debug)]
-pub struct Error;
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
 
 /// The number of measurements that we do before averaging the ADC value
 pub const MEASUREMENTS: usize = 15;
 
 impl<const R1: usize, const R2: usize> BatteryMeasurement<R1, R2> {
-    /// for internal 3.3v reference, add correct scale factor once ADC calibration is implemented:
-    /// <https://github.com/esp-rs/esp-hal/issues/326#issuecomment-1438911773>
-    // pub const PRECISION_FACTOR: f32 = 1.315772567 * 3.3 / 4096.0;
-    pub const PRECISION_FACTOR: f32 = 3.3 / 4096.0;
-
-    pub async fn measure_once<'a>(&mut self, adc: &mut ADC<'a, ADC1>) -> Result<u16, Error> {
-        self.analog_pin.pin.internal_pull_down(false);
-        // FIXME: Is there an async reading at all in esp32c3_hal?!
-        let max_tries = 30;
-        for try_index in 0..max_tries {
-            match adc.read(&mut self.analog_pin) {
-                Ok(value) => return Ok(value),
-                Err(nb::Error::WouldBlock) => {}
-                Err(err) => println!(
-                    "{}/{} tries ADC read error: {:?}",
-                    try_index, max_tries, err
-                ),
-            }
-            Timer::after(Duration::from_micros(100)).await;
+    /// Without filtering the medianas of the measurements.
+    pub fn new(voltage_divider: VoltageDivider<R1, R2>, calibration: Calibration) -> Self {
+        Self::with_filter(voltage_divider, calibration, Passthrough)
+    }
+}
+
+impl<const R1: usize, const R2: usize, F: Filter> BatteryMeasurement<R1, R2, F> {
+    pub fn with_filter(
+        voltage_divider: VoltageDivider<R1, R2>,
+        calibration: Calibration,
+        filter: F,
+    ) -> Self {
+        Self {
+            last_measurements: SlidingMedian::new(),
+            voltage_divider,
+            calibration,
+            fixed_calibration: FixedCalibration::from(&calibration),
+            filter,
+            retry_policy: RetryPolicy::DEFAULT,
+            temperature: None,
+            inhibits: Inhibits::NONE,
         }
+    }
+
+    /// Sets the battery temperature (in `0.1 °C`) for the State of Charge compensation
+    /// and returns the charge/discharge inhibits of the [`TemperatureLimits`] at it.
+    pub fn update_temperature(&mut self, decicelsius: i16, battery: &Battery) -> Inhibits {
+        let limits = TemperatureLimits::for_chemistry(battery.chemistry);
+
+        self.temperature = Some(decicelsius);
+        self.inhibits = limits.inhibits(decicelsius, self.inhibits);
+
+        self.inhibits
+    }
 
-        Err(Error)
+    /// The last battery temperature (in `0.1 °C`)
+    pub fn temperature(&self) -> Option<i16> {
+        self.temperature
     }
 
-    pub async fn measure_percentage<'a>(
+    pub fn inhibits(&self) -> Inhibits {
+        self.inhibits
+    }
+
+    pub async fn measure_once<S: AdcSource>(
         &mut self,
-        adc: &mut ADC<'a, ADC1>,
-        battery: &Battery,
-    ) -> Result<f32, Error> {
+        adc: &mut S,
+    ) -> Result<u16, Error<S::Error>> {
+        read_with_retries(adc, &self.retry_policy).await
+    }
+
+    /// Makes [`MEASUREMENTS`] ADC reads 1 ms apart.
+    async fn measure_batch<S: AdcSource>(
+        &mut self,
+        adc: &mut S,
+    ) -> Result<Vec<u16, MEASUREMENTS>, Error<S::Error>> {
         let mut current_measurements: Vec<u16, MEASUREMENTS> = Vec::new();
-        let mut sum = 0.0;
 
         for i in 1..=MEASUREMENTS {
             // read ADC level
             // let checkpoint = embassy_time::Instant::now();
-            let adc_level = self.measure_once(adc).await?;
+            let adc_level = self
+                .measure_once(adc)
+                .await
+                .map_err(|error| error.at(MeasurementStep::Batch(i - 1)))?;
 
             // defmt::debug!(
             //     "ADC read took: {} microseconds",
@@ -88,7 +455,6 @@ impl<const R1: usize, const R2: usize> BatteryMeasurement<R1, R2> {
             // );
 
             current_measurements.push(adc_level).expect("Should fit");
-            sum += adc_level as f32;
 
             // skip last timer
             if i != MEASUREMENTS {
@@ -96,94 +462,188 @@ impl<const R1: usize, const R2: usize> BatteryMeasurement<R1, R2> {
             }
         }
 
-        // calculate the average value
-        let average = sum / MEASUREMENTS as f32;
-        println!("Battery average ADC value: {}", average);
+        Ok(current_measurements)
+    }
+
+    /// The State of Charge of [`BatteryMeasurement::measure_permille`] in percentage.
+    pub async fn measure_percentage<S: AdcSource>(
+        &mut self,
+        adc: &mut S,
+        battery: &Battery,
+    ) -> Result<f32, Error<S::Error>> {
+        let permille = self.measure_permille(adc, battery).await?;
 
-        // ADC is 12 bit resolution
-        // Resolution = 1.1V/2^12 = 1.1/4095 = 0.268555 mV for every 1_u16 of ADC value
-        let voltage_avg = average * Self::PRECISION_FACTOR;
-        println!("Divider: {}", self.voltage_divider.divider());
-        let voltage_avg = voltage_avg / self.voltage_divider.divider();
+        Ok(permille as f32 / 10.0)
+    }
 
-        // current mediana
-        let current_adc_mediana = {
-            let mut measurements: Vec<u16, 101> =
-                Vec::from_slice(current_measurements.as_slice()).expect("Should never fail");
+    /// Measures the State of Charge in per-mille (`0..=1000`) with integer arithmetic
+    /// for the FPU-less ESP32-C3, compensated for the temperature by
+    /// [`BatteryMeasurement::permille`].
+    ///
+    /// It uses the mediana of the measurements, an [`Implausible`] one fails
+    /// with [`ReadFailure::Implausible`].
+    pub async fn measure_permille<S: AdcSource>(
+        &mut self,
+        adc: &mut S,
+        battery: &Battery,
+    ) -> Result<u16, Error<S::Error>> {
+        let mut current_measurements = self.measure_batch(adc).await?;
+
+        self.record_permille(&mut current_measurements, battery)
+            .map_err(|implausible| Error {
+                step: MeasurementStep::Mediana,
+                tries: MEASUREMENTS,
+                failure: ReadFailure::Implausible(implausible.millivolts),
+            })
+    }
+
+    /// Same as [`BatteryMeasurement::measure_permille`] but for already taken samples,
+    /// e.g. an [`AdcReading`](crate::adc_scheduler::AdcReading) from the ADC scheduler.
+    ///
+    /// A mediana outside of the [`Battery::absolute_millivolts`] is [`Implausible`],
+    /// it's neither filtered nor written to the history.
+    pub fn record_permille<const N: usize>(
+        &mut self,
+        samples: &mut Vec<u16, N>,
+        battery: &Battery,
+    ) -> Result<u16, Implausible> {
+        let mediana = find_mediana(samples);
+        let mediana_millivolts = self.adc_to_millivolts(mediana);
+        match battery.limit(mediana_millivolts) {
+            VoltageLimit::Within => {}
+            limit => {
+                return Err(Implausible {
+                    millivolts: mediana_millivolts,
+                    limit,
+                })
+            }
+        }
+        let current_adc_mediana = self.record(mediana);
+
+        let millivolts = self.adc_to_millivolts(current_adc_mediana);
+        let permille = self.permille(millivolts, battery);
 
-            find_mediana(&mut measurements)
-        };
-        println!("Current ADC mediana: {}", current_adc_mediana);
-        let voltage_mediana = current_adc_mediana as f32 * Self::PRECISION_FACTOR;
-        let voltage_mediana = voltage_mediana / self.voltage_divider.divider();
-
-        let _percentage_avg = self.percentage(voltage_avg, battery);
-        let percentage_mediana = self.percentage(voltage_mediana, battery);
-
-        // println!(
-        //     "Current average of {} measurements - Battery voltage: {}V ; percentage: {}%",
-        //     MEASUREMENTS,
-        //     voltage_avg,
-        //     percentage_avg
-        // );
         println!(
-            "Current mediana of {} measurements - Battery voltage: {}V; percentage: {}%",
-            MEASUREMENTS, voltage_mediana, percentage_mediana
+            "Current mediana of {} measurements - Battery voltage: {}mV; {}‰",
+            samples.len(),
+            millivolts,
+            permille
         );
 
-        // write current_mediana to history
-        self.last_measurements.write(current_adc_mediana);
+        Ok(permille)
+    }
+
+    /// Feeds the mediana to the [`Filter`] and writes the filtered value to the history.
+    ///
+    /// A rejected mediana is not written and the most recent value in the history
+    /// is used instead, or the mediana itself when the history is still empty.
+    fn record(&mut self, mediana: u16) -> u16 {
+        match self.filter.update(mediana) {
+            Some(filtered) => {
+                self.last_measurements.write(filtered);
 
-        // println!(
-        //     "History mediana of {} measurements - ADC value: {}; percentage: {}%",
-        //     MEASUREMENTS, self.historic_mediana(), self.historic_percentage(battery),
-        // );
+                filtered
+            }
+            None => {
+                println!("Battery ADC mediana {} rejected by the filter", mediana);
 
-        if percentage_mediana < 100.0 {
-            Ok(percentage_mediana)
-        } else {
-            Ok(100.0)
+                self.last_measurements.recent().copied().unwrap_or(mediana)
+            }
         }
     }
 
+    /// The mediana of the history, `0` when it's empty.
     pub fn historic_mediana(&self) -> u16 {
-        // calculate the mediana of the new history
-        let mut history = Vec::<_, 101>::new();
-        history
-            .extend_from_slice(self.last_measurements.as_slice())
-            .expect("Should not fail");
+        self.last_measurements.median().unwrap_or(0)
+    }
 
-        find_mediana(&mut history)
+    /// The nearest-rank percentile (`0..=100`) of the history as an ADC value,
+    /// `0` when it's empty.
+    pub fn historic_percentile(&self, percent: u8) -> u16 {
+        self.last_measurements.percentile(percent).unwrap_or(0)
     }
 
+    /// The State of Charge of [`BatteryMeasurement::historic_permille`] in percentage.
     pub fn historic_percentage(&self, battery: &Battery) -> f32 {
-        let adc_mediana = self.historic_mediana();
-        let voltage_mediana = self.adc_to_voltage(adc_mediana);
+        self.historic_permille(battery) as f32 / 10.0
+    }
+
+    pub fn historic_permille(&self, battery: &Battery) -> u16 {
+        let millivolts = self.adc_to_millivolts(self.historic_mediana());
 
-        self.percentage(voltage_mediana, battery)
+        self.permille(millivolts, battery)
     }
 
     /// adc value to voltage
     pub fn adc_to_voltage(&self, value: u16) -> f32 {
-        let measured_voltage = value as f32 * Self::PRECISION_FACTOR;
+        let measured_voltage = self.calibration.to_voltage(value as f32);
         let actual_voltage = measured_voltage / self.voltage_divider.divider();
 
         actual_voltage
     }
 
-    /// For a battery with 4.2V maximum charge and 3.0V cut out voltage:
+    /// The State of Charge of [`BatteryMeasurement::permille`] in percentage,
+    /// the voltage is rounded to the nearest millivolt.
     ///
-    // (voltage - 3.0) / (4.2 - 3.0) * 100
+    /// The result is clamped between `0%` and `100%`.
     pub fn percentage(&self, voltage: f32, battery: &Battery) -> f32 {
-        // (voltage - 3.0) / (4.2 - 3.0) * 100
-        ((voltage - battery.cut_out_voltage) / (battery.charged_voltage - battery.cut_out_voltage))
-            * 100.0
+        let millivolts = (voltage.max(0.0) * 1000.0 + 0.5) as u32;
+
+        self.permille(millivolts, battery) as f32 / 10.0
     }
-}
 
-// #[derive(Debug)]
-// #[cfg_attr(feature = "defmt", derive(Format))]
-pub struct PowerSense(PowerSensePin);
+    /// ADC value to the battery voltage in millivolts using only integer arithmetic.
+    ///
+    /// The result is within `1.05 mV * (R1 + R2) / R2 + 0.5 mV` of
+    /// [`BatteryMeasurement::adc_to_voltage`] (`* 1000`),
+    /// see [`FixedCalibration`] and [`VoltageDivider::input_millivolts`].
+    pub fn adc_to_millivolts(&self, value: u16) -> u32 {
+        let measured_millivolts = self.fixed_calibration.to_millivolts(value);
+
+        self.voltage_divider.input_millivolts(measured_millivolts)
+    }
+
+    /// Looks up the State of Charge in per-mille for the given voltage
+    /// in the battery's [`OcvCurve`], see [`Battery::permille`].
+    ///
+    /// Once the temperature is known the voltage is corrected to the curve's temperature
+    /// and the State of Charge is of the capacity usable at the temperature,
+    /// see [`TemperatureCompensation`].
+    pub fn permille(&self, millivolts: u32, battery: &Battery) -> u16 {
+        match self.temperature {
+            Some(decicelsius) => {
+                let compensation = TemperatureCompensation::for_chemistry(battery.chemistry);
+                let permille =
+                    battery.permille(compensation.millivolts(millivolts, battery, decicelsius));
+
+                compensation.usable_permille(permille, decicelsius)
+            }
+            None => battery.permille(millivolts),
+        }
+    }
+
+    /// The State of Charge in per-mille taking the [`ChargingState`] into account.
+    ///
+    /// While charging the battery voltage is not an open-circuit voltage,
+    /// it's compensated with [`Battery::charging_offset_millivolts`] of each cell before
+    /// the [`OcvCurve`] lookup. A charged battery is always `1000‰`.
+    pub fn state_of_charge(
+        &self,
+        millivolts: u32,
+        battery: &Battery,
+        charging_state: ChargingState,
+    ) -> u16 {
+        match charging_state {
+            ChargingState::Charged => 1000,
+            ChargingState::Charging => self.permille(
+                millivolts
+                    .saturating_sub(battery.pack_millivolts(battery.charging_offset_millivolts)),
+                battery,
+            ),
+            ChargingState::NotPowered | ChargingState::Fault => self.permille(millivolts, battery),
+        }
+    }
+}
 
 /// R2 is towards the positive (+) side
 /// R1 is towards the GND
@@ -194,6 +654,21 @@ pub struct PowerSense(PowerSensePin);
 pub struct VoltageDivider<const R1: usize, const R2: usize>;
 
 impl<const R1: usize, const R2: usize> VoltageDivider<R1, R2> {
+    /// The voltage divider value as a reduced fraction calculated at compile time:
+    /// `R2 / (R1 + R2)`
+    pub const RATIO: Ratio = if R2 == 0 {
+        Ratio::new(1, 1)
+    } else {
+        Ratio::new(R2 as u32, (R1 + R2) as u32)
+    };
+
+    /// The voltage before the divider for the measured (divided) voltage in millivolts.
+    ///
+    /// The result is rounded to the nearest millivolt.
+    pub fn input_millivolts(&self, millivolts: u32) -> u32 {
+        Self::RATIO.divide(millivolts)
+    }
+
     /// The voltage divider value
     pub fn divider(&self) -> f32 {
         if R2 == 0 {
@@ -225,27 +700,699 @@ impl ApiType for Blocking {}
 pub struct Async;
 impl ApiType for Async {}
 
-// pub struct Voltmeter<API, const R1: usize, const R2: usize> {
-//     api: PhantomData<API>,
-//     voltage_divider: PhantomData<VoltageDivider<R1, R2>>,
-//     // const VOLTAGE_DIVIDER_R1: usize;
-//     // const VOLTAGE_DIVIDER_R2: usize;
-// }
-
-// impl<const R1: usize, const R2: usize, API> Voltmeter<API, R1, R2>
-// where
-//     API: ApiType,
-// {
-//     pub fn new(voltage_divider: VoltageDivider<R1, R2>, api: API) -> Self {
-//         Self {
-//             voltage_divider,
-//             api: PhantomData,
-//         }
-//     }
-// }
-
-// impl<const R1: usize, const R2: usize> Voltmeter<Blocking, R1, R2> {
-//     fn measure(&self) {
-
-//     }
-// }
+/// Measures the voltage before a [`VoltageDivider`].
+///
+/// The `API` type parameter selects between the [`Blocking`] `measure`,
+/// which can be used before the embassy executor is running (e.g. for self-checks),
+/// and the [`Async`] `measure` for the embassy tasks.
+pub struct Voltmeter<API, const R1: usize, const R2: usize> {
+    pub voltage_divider: VoltageDivider<R1, R2>,
+    pub calibration: Calibration,
+    /// The integer version of the `calibration` for [`Voltmeter::to_millivolts`]
+    pub fixed_calibration: FixedCalibration,
+    /// How the ADC reads are retried
+    pub retry_policy: RetryPolicy,
+    failures: MeasurementFailures,
+    api: PhantomData<API>,
+}
+
+impl<const R1: usize, const R2: usize, API> Voltmeter<API, R1, R2>
+where
+    API: ApiType,
+{
+    pub fn new(
+        voltage_divider: VoltageDivider<R1, R2>,
+        calibration: Calibration,
+        _api: API,
+    ) -> Self {
+        Self {
+            voltage_divider,
+            fixed_calibration: FixedCalibration::from(&calibration),
+            calibration,
+            retry_policy: RetryPolicy::DEFAULT,
+            failures: MeasurementFailures::new(),
+            api: PhantomData,
+        }
+    }
+
+    /// The failed measurements since boot
+    pub fn failures(&self) -> MeasurementFailures {
+        self.failures
+    }
+
+    /// Converts a raw ADC sample to the voltage before the divider
+    pub fn to_voltage(&self, value: u16) -> f32 {
+        self.calibration.to_voltage(value as f32) / self.voltage_divider
+    }
+
+    /// Converts a raw ADC sample to the millivolts before the divider
+    /// using only integer arithmetic, see [`BatteryMeasurement::adc_to_millivolts`].
+    pub fn to_millivolts(&self, value: u16) -> u32 {
+        let measured_millivolts = self.fixed_calibration.to_millivolts(value);
+
+        self.voltage_divider.input_millivolts(measured_millivolts)
+    }
+}
+
+impl<const R1: usize, const R2: usize> Voltmeter<Blocking, R1, R2> {
+    /// Busy-waits for the ADC conversion, retrying up to [`RetryPolicy::max_tries`] times
+    /// with the [`RetryPolicy::delay`] between the tries.
+    pub fn measure<S: AdcSource, D: DelayUs<u32>>(
+        &mut self,
+        adc: &mut S,
+        delay: &mut D,
+    ) -> Result<f32, Error<S::Error>> {
+        let mut retries = Retries::new(&self.retry_policy);
+        let result = loop {
+            match retries.try_read(adc) {
+                NextTry::Wait(duration) => {
+                    delay.delay_us(duration.as_micros().try_into().unwrap_or(u32::MAX))
+                }
+                NextTry::Done(result) => break result,
+            }
+        };
+
+        match result {
+            Ok(value) => Ok(self.to_voltage(value)),
+            Err(error) => {
+                self.failures.record(&error);
+                Err(error)
+            }
+        }
+    }
+}
+
+impl<const R1: usize, const R2: usize> Voltmeter<Async, R1, R2> {
+    /// Waits for the ADC conversion, retrying up to [`RetryPolicy::max_tries`] times
+    /// with the [`RetryPolicy::delay`] between the tries.
+    pub async fn measure<S: AdcSource>(&mut self, adc: &mut S) -> Result<f32, Error<S::Error>> {
+        let value = self.read(adc).await?;
+
+        Ok(self.to_voltage(value))
+    }
+
+    /// Like [`Voltmeter::measure`], but in millivolts using [`Voltmeter::to_millivolts`].
+    pub async fn measure_millivolts<S: AdcSource>(
+        &mut self,
+        adc: &mut S,
+    ) -> Result<u32, Error<S::Error>> {
+        let value = self.read(adc).await?;
+
+        Ok(self.to_millivolts(value))
+    }
+
+    async fn read<S: AdcSource>(&mut self, adc: &mut S) -> Result<u16, Error<S::Error>> {
+        let result = read_with_retries(adc, &self.retry_policy).await;
+        if let Err(error) = &result {
+            self.failures.record(error);
+        }
+
+        result
+    }
+}
+
+/// The default maximum number of ADC reads before giving up on a measurement
+pub const MAX_TRIES: usize = 30;
+
+/// The default delay between ADC read tries
+pub const RETRY_DELAY: Duration = Duration::from_micros(100);
+
+/// Makes a single read try, logging any failures other than a timeout.
+fn try_read<S: AdcSource>(
+    adc: &mut S,
+    try_index: usize,
+    policy: &RetryPolicy,
+) -> Result<u16, ReadFailure<S::Error>> {
+    let failure = match adc.read_raw() {
+        Ok(value) if value <= ADC_MAX => return Ok(value),
+        Ok(value) => ReadFailure::OutOfRange(value),
+        Err(nb::Error::WouldBlock) => return Err(ReadFailure::Timeout),
+        Err(nb::Error::Other(err)) => ReadFailure::Adc(err),
+    };
+
+    println!(
+        "{}/{} tries ADC read failure: {:?}",
+        try_index + 1,
+        policy.max_tries,
+        failure
+    );
+
+    Err(failure)
+}
+
+/// What to do after a try of [`Retries::try_read`]
+enum NextTry<E> {
+    /// Wait this long before the next try
+    Wait(Duration),
+    /// The read succeeded or all the tries failed
+    Done(Result<u16, Error<E>>),
+}
+
+/// The retry loop of an ADC read following a [`RetryPolicy`],
+/// the caller only waits: busy-waiting in the [`Blocking`] [`Voltmeter::measure`]
+/// and with a [`Timer`] in [`read_with_retries`].
+struct Retries<'a, E> {
+    policy: &'a RetryPolicy,
+    try_index: usize,
+    /// The last failure other than a timeout
+    failure: ReadFailure<E>,
+}
+
+impl<'a, E> Retries<'a, E> {
+    fn new(policy: &'a RetryPolicy) -> Self {
+        Self {
+            policy,
+            try_index: 0,
+            failure: ReadFailure::Timeout,
+        }
+    }
+
+    fn try_read<S: AdcSource<Error = E>>(&mut self, adc: &mut S) -> NextTry<E> {
+        if self.try_index < self.policy.max_tries {
+            match try_read(adc, self.try_index, self.policy) {
+                Ok(value) => return NextTry::Done(Ok(value)),
+                Err(ReadFailure::Timeout) => {}
+                Err(other) => self.failure = other,
+            }
+
+            self.try_index += 1;
+            // no need to wait after the last try
+            if self.try_index < self.policy.max_tries {
+                return NextTry::Wait(self.policy.delay(self.try_index - 1));
+            }
+        }
+
+        NextTry::Done(Err(Error {
+            step: MeasurementStep::Read,
+            tries: self.policy.max_tries,
+            failure: core::mem::replace(&mut self.failure, ReadFailure::Timeout),
+        }))
+    }
+}
+
+pub(crate) async fn read_with_retries<S: AdcSource>(
+    adc: &mut S,
+    policy: &RetryPolicy,
+) -> Result<u16, Error<S::Error>> {
+    let mut retries = Retries::new(policy);
+
+    // FIXME: Is there an async reading at all in esp32c3_hal?!
+    loop {
+        match retries.try_read(adc) {
+            NextTry::Wait(duration) => Timer::after(duration).await,
+            NextTry::Done(result) => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use embassy_futures::block_on;
+
+    use crate::{
+        adc::{Sample, ScriptedAdc, ScriptedError},
+        calibration::{Attenuation, CalibrationPoint},
+        helper::filter::{MadOutlierRejection, MovingAverage},
+    };
+
+    use super::*;
+
+    /// The Olimex board's divider of 470 Ohms each
+    type OlimexMeasurement = BatteryMeasurement<470, 470>;
+
+    #[test]
+    fn test_measure_once_retries() {
+        let mut measurement = OlimexMeasurement::new(VoltageDivider, Calibration::UNCALIBRATED);
+
+        let script = [
+            Sample::WouldBlock,
+            Sample::Error,
+            Sample::WouldBlock,
+            Sample::Value(2377),
+        ];
+        let mut adc = ScriptedAdc::new(&script);
+        assert_eq!(2377, block_on(measurement.measure_once(&mut adc)).unwrap());
+        assert_eq!(4, adc.position());
+
+        let mut failing_adc = ScriptedAdc::new(&[Sample::Error]);
+        assert!(block_on(measurement.measure_once(&mut failing_adc)).is_err());
+        assert_eq!(MAX_TRIES, failing_adc.position());
+    }
+
+    /// Adds up the busy-waits in microseconds
+    #[derive(Default)]
+    struct BusyWait(u32);
+
+    impl DelayUs<u32> for BusyWait {
+        fn delay_us(&mut self, micros: u32) {
+            self.0 += micros;
+        }
+    }
+
+    /// An ADC which returns values outside of 12-bits
+    struct OutOfRangeAdc;
+
+    impl AdcSource for OutOfRangeAdc {
+        type Error = ScriptedError;
+
+        fn read_raw(&mut self) -> nb::Result<u16, Self::Error> {
+            Ok(5000)
+        }
+    }
+
+    #[test]
+    fn test_measurement_errors() {
+        let mut measurement = OlimexMeasurement::new(VoltageDivider, Calibration::UNCALIBRATED);
+        measurement.retry_policy = RetryPolicy::constant(5, Duration::from_micros(10));
+
+        let mut timing_out_adc = ScriptedAdc::new(&[Sample::WouldBlock]);
+        assert_eq!(
+            Err(Error {
+                step: MeasurementStep::Read,
+                tries: 5,
+                failure: ReadFailure::Timeout,
+            }),
+            block_on(measurement.measure_once(&mut timing_out_adc))
+        );
+        assert_eq!(5, timing_out_adc.position());
+
+        // the ADC error is reported even if the last try timed out
+        let mut failing_adc = ScriptedAdc::new(&[Sample::Error, Sample::WouldBlock]);
+        assert_eq!(
+            Err(Error {
+                step: MeasurementStep::Read,
+                tries: 5,
+                failure: ReadFailure::Adc(ScriptedError),
+            }),
+            block_on(measurement.measure_once(&mut failing_adc))
+        );
+
+        assert_eq!(
+            ReadFailure::OutOfRange(5000),
+            block_on(measurement.measure_once(&mut OutOfRangeAdc))
+                .unwrap_err()
+                .failure
+        );
+
+        // the third read of the batch fails
+        let script = [
+            Sample::Value(2377),
+            Sample::Value(2377),
+            Sample::Error,
+            Sample::Error,
+            Sample::Error,
+            Sample::Error,
+            Sample::Error,
+        ];
+        let mut adc = ScriptedAdc::new(&script);
+        let battery = Battery::OLIMEX_LIPO_250MAH;
+        let error = block_on(measurement.measure_permille(&mut adc, &battery)).unwrap_err();
+        assert_eq!(MeasurementStep::Batch(2), error.step);
+        assert!(measurement.last_measurements.is_empty());
+
+        let mut failures = MeasurementFailures::new();
+        failures.record(&error);
+        failures.record(&error.at(MeasurementStep::Read));
+        failures.record(&Error {
+            step: MeasurementStep::Read,
+            tries: 5,
+            failure: ReadFailure::<ScriptedError>::Timeout,
+        });
+        assert_eq!(
+            MeasurementFailures {
+                timeouts: 1,
+                adc_errors: 2,
+                out_of_range: 0,
+            },
+            failures
+        );
+        assert_eq!(3, failures.total());
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let constant = RetryPolicy::DEFAULT;
+        assert_eq!(RETRY_DELAY, constant.delay(0));
+        assert_eq!(RETRY_DELAY, constant.delay(MAX_TRIES));
+
+        let exponential =
+            RetryPolicy::exponential(10, Duration::from_micros(100), Duration::from_millis(1));
+        let delays: std::vec::Vec<u64> = (0..6)
+            .map(|try_index| exponential.delay(try_index).as_micros())
+            .collect();
+        assert_eq!(std::vec![100, 200, 400, 800, 1000, 1000], delays);
+        assert_eq!(Duration::from_millis(1), exponential.delay(usize::MAX));
+    }
+
+    #[test]
+    fn test_measure_percentage_and_history() {
+        let mut measurement = OlimexMeasurement::new(VoltageDivider, Calibration::UNCALIBRATED);
+        let battery = Battery::OLIMEX_LIPO_250MAH;
+
+        // 2377 * 3.3 / 4096 / 0.5 = ~3.83V
+        let voltage = measurement.adc_to_voltage(2377);
+        assert!((voltage - 3.83).abs() < 0.001, "{voltage}");
+
+        // noisy samples with a mediana of 2377
+        let script = [
+            Sample::Value(2377),
+            Sample::Value(2300),
+            Sample::Value(2450),
+            Sample::WouldBlock,
+        ];
+        let mut adc = ScriptedAdc::new(&script);
+        let percentage = block_on(measurement.measure_percentage(&mut adc, &battery)).unwrap();
+
+        // the per-mille of the same voltage
+        assert_eq!(52.0, percentage);
+        assert_eq!(52.0, measurement.percentage(voltage, &battery));
+        assert_eq!(1, measurement.last_measurements.len());
+
+        // a second, lower, batch of measurements
+        let mut adc = ScriptedAdc::new(&[Sample::Value(2300)]);
+        block_on(measurement.measure_percentage(&mut adc, &battery)).unwrap();
+        let mut adc = ScriptedAdc::new(&[Sample::Value(2250)]);
+        block_on(measurement.measure_percentage(&mut adc, &battery)).unwrap();
+
+        assert_eq!(3, measurement.last_measurements.len());
+        assert_eq!(2300, measurement.historic_mediana());
+        assert_eq!(2250, measurement.historic_percentile(0));
+        assert_eq!(2377, measurement.historic_percentile(100));
+        assert_eq!(
+            measurement.historic_permille(&battery) as f32 / 10.0,
+            measurement.historic_percentage(&battery)
+        );
+
+        // both are compensated for the temperature
+        measurement.update_temperature(-100, &battery);
+        let permille = measurement.permille(measurement.adc_to_millivolts(2377), &battery);
+        assert!(permille < 520, "{permille}");
+        assert_eq!(
+            permille as f32 / 10.0,
+            measurement.percentage(voltage, &battery)
+        );
+        assert_eq!(
+            measurement.historic_permille(&battery) as f32 / 10.0,
+            measurement.historic_percentage(&battery)
+        );
+    }
+
+    #[test]
+    fn test_voltmeter_blocking_and_async() {
+        let script = [Sample::WouldBlock, Sample::Error, Sample::Value(2048)];
+        // 2048 * 3.3 / 4096 / 0.5 = 3.3V
+        let expected = 3.3;
+
+        let mut blocking = Voltmeter::new(
+            VoltageDivider::<470, 470>,
+            Calibration::UNCALIBRATED,
+            Blocking,
+        );
+        let mut delay = BusyWait::default();
+        let voltage = blocking
+            .measure(&mut ScriptedAdc::new(&script), &mut delay)
+            .unwrap();
+        assert!((expected - voltage).abs() < 0.001, "{voltage}");
+        // waited after the first 2 tries
+        assert_eq!(2 * RETRY_DELAY.as_micros() as u32, delay.0);
+
+        let mut asynchronous =
+            Voltmeter::new(VoltageDivider::<470, 470>, Calibration::UNCALIBRATED, Async);
+        let voltage = block_on(asynchronous.measure(&mut ScriptedAdc::new(&script))).unwrap();
+        assert!((expected - voltage).abs() < 0.001, "{voltage}");
+        let millivolts =
+            block_on(asynchronous.measure_millivolts(&mut ScriptedAdc::new(&script))).unwrap();
+        assert_eq!(3300, millivolts);
+        for raw in (0..4096).step_by(7) {
+            let float_millivolts = asynchronous.to_voltage(raw) * 1000.0;
+            let millivolts = asynchronous.to_millivolts(raw) as f32;
+            assert!((float_millivolts - millivolts).abs() < 3.0, "{raw}");
+        }
+
+        // both give up after the same number of tries, without waiting after the last one
+        let mut failing_adc = ScriptedAdc::new(&[Sample::WouldBlock]);
+        let mut delay = BusyWait::default();
+        assert!(blocking.measure(&mut failing_adc, &mut delay).is_err());
+        assert_eq!(MAX_TRIES, failing_adc.position());
+        assert_eq!(
+            (MAX_TRIES - 1) as u32 * RETRY_DELAY.as_micros() as u32,
+            delay.0
+        );
+        assert_eq!(1, blocking.failures().timeouts);
+
+        let mut failing_adc = ScriptedAdc::new(&[Sample::Error]);
+        assert!(block_on(asynchronous.measure(&mut failing_adc)).is_err());
+        assert_eq!(MAX_TRIES, failing_adc.position());
+        assert_eq!(1, asynchronous.failures().adc_errors);
+    }
+
+    #[test]
+    fn test_calibrated_adc_to_voltage() {
+        let calibration = Calibration::two_point(
+            Attenuation::Attenuation11dB,
+            CalibrationPoint::new(500, 400.0),
+            CalibrationPoint::new(3000, 2400.0),
+        )
+        .unwrap();
+        let measurement = OlimexMeasurement::new(VoltageDivider, calibration);
+
+        // 2000 * 0.8 mV = 1.6V at the ADC pin, 3.2V before the divider
+        let voltage = measurement.adc_to_voltage(2000);
+        assert!((3.2 - voltage).abs() < 0.001, "{voltage}");
+    }
+
+    #[test]
+    fn test_voltage_divider_ratio() {
+        assert_eq!(Ratio::new(1, 2), VoltageDivider::<470, 470>::RATIO);
+        assert_eq!(Ratio::new(1, 3), VoltageDivider::<20_000, 10_000>::RATIO);
+        assert_eq!(Ratio::new(1, 1), VoltageDivider::<470, 0>::RATIO);
+
+        assert_eq!(3830, VoltageDivider::<470, 470>.input_millivolts(1915));
+        assert_eq!(
+            4500,
+            VoltageDivider::<20_000, 10_000>.input_millivolts(1500)
+        );
+    }
+
+    #[test]
+    fn test_integer_pipeline_matches_float() {
+        let battery = Battery::OLIMEX_LIPO_250MAH;
+        let calibrations = [
+            Calibration::UNCALIBRATED,
+            Calibration::from_coefficients(Attenuation::Attenuation11dB, 0.8123, -25.7),
+        ];
+
+        // the steepest part of the curve in per-mille per millivolt
+        let max_slope = battery
+            .ocv_curve
+            .points()
+            .windows(2)
+            .map(|points| {
+                (points[1].permille - points[0].permille) as f32
+                    / (points[1].millivolts - points[0].millivolts) as f32
+            })
+            .fold(0.0, f32::max);
+
+        for calibration in calibrations {
+            let measurement = OlimexMeasurement::new(VoltageDivider, calibration);
+            // 1.05 mV * (R1 + R2) / R2 + 0.5 mV
+            let millivolts_bound = 1.05 * 2.0 + 0.5;
+            let permille_bound = millivolts_bound * max_slope + 0.5;
+
+            for raw in 0..=4095 {
+                let voltage = measurement.adc_to_voltage(raw);
+                let millivolts = measurement.adc_to_millivolts(raw);
+                let difference = (millivolts as f32 - voltage.max(0.0) * 1000.0).abs();
+                assert!(
+                    difference <= millivolts_bound,
+                    "raw: {raw}, {millivolts} mV vs {voltage} V"
+                );
+
+                let percentage = battery.percentage(voltage);
+                let permille = measurement.permille(millivolts, &battery);
+                let difference = (permille as f32 - percentage * 10.0).abs();
+                assert!(
+                    difference <= permille_bound,
+                    "raw: {raw}, {permille}‰ vs {percentage}%"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_measure_permille() {
+        let mut measurement = OlimexMeasurement::new(VoltageDivider, Calibration::UNCALIBRATED);
+        let battery = Battery::OLIMEX_LIPO_250MAH;
+
+        let script = [
+            Sample::Value(2377),
+            Sample::Value(2300),
+            Sample::Value(2450),
+        ];
+        let permille =
+            block_on(measurement.measure_permille(&mut ScriptedAdc::new(&script), &battery))
+                .unwrap();
+
+        // 2377 * 3300 / 4096 = 1915 mV => 3830 mV
+        assert_eq!(3830, measurement.adc_to_millivolts(2377));
+        assert_eq!(520, permille);
+        assert_eq!(520, measurement.historic_permille(&battery));
+        assert_eq!(1, measurement.last_measurements.len());
+    }
+
+    #[test]
+    fn test_implausible_readings() {
+        let mut measurement = OlimexMeasurement::new(VoltageDivider, Calibration::UNCALIBRATED);
+        let battery = Battery::OLIMEX_LIPO_250MAH;
+        let mut record = |mediana| {
+            let mut samples = Vec::<u16, MEASUREMENTS>::from_slice(&[mediana]).unwrap();
+            measurement.record_permille(&mut samples, &battery)
+        };
+
+        // 1551 => 2500 mV and 2669 => 4300 mV are the absolute limits of the cell
+        assert_eq!(Ok(0), record(1551));
+        assert_eq!(
+            Err(Implausible {
+                millivolts: 2498,
+                limit: VoltageLimit::BelowMinimum
+            }),
+            record(1550)
+        );
+        assert!(record(2669).is_ok());
+        assert_eq!(
+            Err(Implausible {
+                millivolts: 4350,
+                limit: VoltageLimit::AboveMaximum
+            }),
+            record(2700)
+        );
+        // only the plausible readings are in the history
+        assert_eq!(2, measurement.last_measurements.len());
+        assert_eq!(2669, measurement.historic_percentile(100));
+
+        let mut adc = ScriptedAdc::new(&[Sample::Value(0)]);
+        let error = block_on(measurement.measure_permille(&mut adc, &battery)).unwrap_err();
+        assert_eq!(MeasurementStep::Mediana, error.step);
+        assert_eq!(ReadFailure::Implausible(0), error.failure);
+        assert_eq!(2, measurement.last_measurements.len());
+    }
+
+    #[test]
+    fn test_filtered_measurements() {
+        let filter =
+            MadOutlierRejection::<5>::new(Ratio::new(3, 1), 4).then(MovingAverage::<2>::new());
+        let mut measurement = BatteryMeasurement::<470, 470, _>::with_filter(
+            VoltageDivider,
+            Calibration::UNCALIBRATED,
+            filter,
+        );
+        let battery = Battery::OLIMEX_LIPO_250MAH;
+
+        for mediana in [2377, 2381, 2379] {
+            let mut samples = Vec::<u16, MEASUREMENTS>::from_slice(&[mediana]).unwrap();
+            measurement.record_permille(&mut samples, &battery).unwrap();
+        }
+        // the moving average of the last 2
+        assert_eq!(Some(&2380), measurement.last_measurements.recent());
+
+        // a glitch is rejected and the last value is used instead
+        let mut samples = Vec::<u16, MEASUREMENTS>::from_slice(&[2600, 2600, 2600]).unwrap();
+        let permille = measurement.record_permille(&mut samples, &battery).unwrap();
+        assert_eq!(
+            measurement.permille(measurement.adc_to_millivolts(2380), &battery),
+            permille
+        );
+        assert_eq!(3, measurement.last_measurements.len());
+    }
+
+    #[test]
+    fn test_battery_profiles() {
+        let battery = Battery::LIPO_2S.with_capacity(2200);
+        assert_eq!(2, battery.series_cells);
+        assert_eq!(2200, battery.capacity_mah);
+        assert_eq!(Chemistry::LiPo, battery.chemistry);
+
+        assert_eq!(7660, battery.pack_millivolts(3830));
+        assert_eq!(3830, battery.cell_millivolts(7660));
+        // rounds to the nearest
+        assert_eq!(3831, battery.cell_millivolts(7661));
+
+        // the same State of Charge as a single cell at half the voltage
+        assert_eq!(520, battery.permille(7660));
+        assert_eq!(7660, battery.millivolts(520));
+        let percentage = battery.percentage(7.66);
+        assert!((percentage - 52.0).abs() < 0.01, "{percentage}");
+
+        assert_eq!(VoltageLimit::BelowMinimum, battery.limit(4999));
+        assert_eq!(VoltageLimit::Within, battery.limit(5000));
+        assert_eq!(VoltageLimit::Within, battery.limit(8600));
+        assert_eq!(VoltageLimit::AboveMaximum, battery.limit(8601));
+
+        // LiFePO4 is flat in the middle
+        let lifepo4 = Battery::LIFEPO4_1S;
+        assert_eq!(500, lifepo4.permille(3280));
+        assert_eq!(VoltageLimit::AboveMaximum, lifepo4.limit(4200));
+
+        let nimh = Battery::NIMH_4S;
+        assert_eq!(500, nimh.permille(4960));
+        assert_eq!(VoltageLimit::Within, nimh.limit(5800));
+    }
+
+    #[test]
+    fn test_state_of_charge_while_charging() {
+        let measurement = OlimexMeasurement::new(VoltageDivider, Calibration::UNCALIBRATED);
+        let battery = Battery::OLIMEX_LIPO_250MAH;
+
+        // 3930 mV while charging is ~3830 mV open-circuit voltage
+        assert_eq!(
+            520,
+            measurement.state_of_charge(3930, &battery, ChargingState::Charging)
+        );
+        assert_eq!(
+            measurement.permille(3930, &battery),
+            measurement.state_of_charge(3930, &battery, ChargingState::NotPowered)
+        );
+        assert_eq!(
+            1000,
+            measurement.state_of_charge(4150, &battery, ChargingState::Charged)
+        );
+
+        // the offset is for each cell in series
+        assert_eq!(
+            520,
+            measurement.state_of_charge(7860, &Battery::LIPO_2S, ChargingState::Charging)
+        );
+    }
+
+    #[test]
+    fn test_temperature_compensation() {
+        let mut measurement = OlimexMeasurement::new(VoltageDivider, Calibration::UNCALIBRATED);
+        let battery = Battery::OLIMEX_LIPO_250MAH;
+
+        assert_eq!(None, measurement.temperature());
+        assert_eq!(520, measurement.permille(3830, &battery));
+
+        // the same as without a temperature at the reference temperature
+        assert_eq!(
+            Inhibits::NONE,
+            measurement.update_temperature(250, &battery)
+        );
+        assert_eq!(Some(250), measurement.temperature());
+        assert_eq!(520, measurement.permille(3830, &battery));
+
+        // colder: a higher open-circuit voltage but less of the capacity is usable
+        let inhibits = measurement.update_temperature(-50, &battery);
+        assert_eq!(
+            Inhibits {
+                charge: true,
+                discharge: false
+            },
+            inhibits
+        );
+        assert_eq!(inhibits, measurement.inhibits());
+        let cold = measurement.permille(3830, &battery);
+        assert!(cold < 520, "{cold}");
+        assert_eq!(0, measurement.permille(3300, &battery));
+        assert_eq!(1000, measurement.permille(4200, &battery));
+    }
+}
@@ -0,0 +1,598 @@
+//! ADC calibration - converting raw ADC samples to millivolts.
+//!
+//! The ESP32-C3 ADC is far from ideal, the raw values need to be corrected
+//! with a gain and an offset for each attenuation:
+//!
+//! `millivolts = raw * gain + offset`
+//!
+//! The coefficients can come from:
+//! - the factory calibration burned in the eFuse ([`Calibration::from_efuse`])
+//! - a two-point calibration against known reference voltages ([`Calibration::two_point`])
+//! - user-stored coefficients ([`Calibration::from_coefficients`])
+//!
+//! See <https://github.com/esp-rs/esp-hal/issues/326#issuecomment-1438911773>
+use defmt::Format;
+
+/// The ADC attenuation, i.e. the input voltage range.
+///
+/// The ranges are the recommended ones from the ESP32-C3 datasheet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Attenuation {
+    /// 0 - 750 mV
+    Attenuation0dB = 0,
+    /// 0 - 1050 mV
+    Attenuation2p5dB = 1,
+    /// 0 - 1300 mV
+    Attenuation6dB = 2,
+    /// 0 - 2500 mV
+    Attenuation11dB = 3,
+}
+
+#[cfg(feature = "riscv")]
+impl From<Attenuation> for hal::adc::Attenuation {
+    fn from(attenuation: Attenuation) -> Self {
+        match attenuation {
+            Attenuation::Attenuation0dB => hal::adc::Attenuation::Attenuation0dB,
+            Attenuation::Attenuation2p5dB => hal::adc::Attenuation::Attenuation2p5dB,
+            Attenuation::Attenuation6dB => hal::adc::Attenuation::Attenuation6dB,
+            Attenuation::Attenuation11dB => hal::adc::Attenuation::Attenuation11dB,
+        }
+    }
+}
+
+/// Where the [`Calibration`] coefficients came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum CalibrationSource {
+    /// No calibration, assumes the full 3.3V reference over the 12-bit range
+    Uncalibrated,
+    /// Factory calibration from the eFuse
+    Efuse,
+    /// Two-point calibration against known reference voltages
+    TwoPoint,
+    /// User-stored gain & offset coefficients
+    Coefficients,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum CalibrationError {
+    /// The eFuse calibration scheme version is not supported (or the chip is not calibrated)
+    UnsupportedEfuseVersion(u8),
+    /// The two calibration points have the same raw value
+    DegeneratePoints,
+    /// The resulting gain is not a positive number
+    InvalidGain,
+}
+
+/// A known reference voltage and the raw ADC value measured for it.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct CalibrationPoint {
+    pub raw: u16,
+    pub millivolts: f32,
+}
+
+impl CalibrationPoint {
+    pub const fn new(raw: u16, millivolts: f32) -> Self {
+        Self { raw, millivolts }
+    }
+}
+
+/// The linear transfer function of the ADC for a given attenuation.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Calibration {
+    pub attenuation: Attenuation,
+    pub source: CalibrationSource,
+    /// millivolts per raw ADC value
+    pub gain: f32,
+    /// millivolts at raw ADC value of `0`
+    pub offset: f32,
+}
+
+impl Calibration {
+    /// The uncalibrated 11dB attenuation transfer function, assuming the 3.3V reference
+    /// is spread over the whole 12-bit range (`3.3 / 4096.0` V per ADC value).
+    pub const UNCALIBRATED: Calibration = Calibration {
+        attenuation: Attenuation::Attenuation11dB,
+        source: CalibrationSource::Uncalibrated,
+        gain: 3300.0 / 4096.0,
+        offset: 0.0,
+    };
+
+    /// User-stored coefficients, e.g. from a previous [`Calibration::two_point`].
+    pub const fn from_coefficients(attenuation: Attenuation, gain: f32, offset: f32) -> Self {
+        Self {
+            attenuation,
+            source: CalibrationSource::Coefficients,
+            gain,
+            offset,
+        }
+    }
+
+    /// Calibrates the ADC using two known reference voltages.
+    ///
+    /// The points should be as far apart as possible for the given attenuation.
+    pub fn two_point(
+        attenuation: Attenuation,
+        low: CalibrationPoint,
+        high: CalibrationPoint,
+    ) -> Result<Self, CalibrationError> {
+        if low.raw == high.raw {
+            return Err(CalibrationError::DegeneratePoints);
+        }
+
+        let gain = (high.millivolts - low.millivolts) / (high.raw as f32 - low.raw as f32);
+        if gain.is_nan() || gain <= 0.0 {
+            return Err(CalibrationError::InvalidGain);
+        }
+        let offset = low.millivolts - gain * low.raw as f32;
+
+        Ok(Self {
+            attenuation,
+            source: CalibrationSource::TwoPoint,
+            gain,
+            offset,
+        })
+    }
+
+    /// Uses the factory calibration from the eFuse.
+    ///
+    /// The factory calibration assumes the ADC has been initialised
+    /// with [`EfuseCalibration::init_code`] which removes the offset,
+    /// leaving only the gain to correct, see [`EfuseCalibration::apply_init_code`].
+    pub fn from_efuse(
+        efuse: &EfuseCalibration,
+        attenuation: Attenuation,
+    ) -> Result<Self, CalibrationError> {
+        let point = efuse.reference_point(attenuation)?;
+        if point.raw == 0 {
+            return Err(CalibrationError::InvalidGain);
+        }
+
+        Ok(Self {
+            attenuation,
+            source: CalibrationSource::Efuse,
+            gain: point.millivolts / point.raw as f32,
+            offset: 0.0,
+        })
+    }
+
+    /// Converts a raw ADC value (or an average of such values) to millivolts.
+    pub fn to_millivolts(&self, raw: f32) -> f32 {
+        raw * self.gain + self.offset
+    }
+
+    /// Converts a raw ADC value (or an average of such values) to Volts.
+    pub fn to_voltage(&self, raw: f32) -> f32 {
+        self.to_millivolts(raw) / 1000.0
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::UNCALIBRATED
+    }
+}
+
+/// A fixed-point [`Calibration`] for converting raw ADC values without an FPU.
+///
+/// The gain is in millivolts per raw ADC value with [`FixedCalibration::FRACTION_BITS`]
+/// fractional bits (Q16.16), the same scaling ESP-IDF uses for its ADC calibration.
+///
+/// The result of [`FixedCalibration::to_millivolts`] is within `1.05 mV`
+/// of [`Calibration::to_millivolts`] for the whole 12-bit range:
+/// `0.5 mV` from rounding the result, `0.5 mV` from rounding the offset
+/// and less than `0.04 mV` from the gain quantisation (`4095 * 0.5 / 2^16`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct FixedCalibration {
+    /// millivolts per raw ADC value in Q16.16
+    pub gain: u32,
+    /// millivolts at raw ADC value of `0`
+    pub offset: i32,
+}
+
+impl FixedCalibration {
+    pub const FRACTION_BITS: u32 = 16;
+
+    /// The fixed-point version of [`Calibration::UNCALIBRATED`]
+    pub const UNCALIBRATED: FixedCalibration = FixedCalibration {
+        gain: (3300 << Self::FRACTION_BITS) / 4096,
+        offset: 0,
+    };
+
+    /// Converts a raw ADC value to millivolts, rounding to the nearest millivolt.
+    ///
+    /// Negative results (possible with a positive offset) are clamped to `0`.
+    pub fn to_millivolts(&self, raw: u16) -> u32 {
+        let scaled = (raw as u64 * self.gain as u64 + (1 << (Self::FRACTION_BITS - 1)))
+            >> Self::FRACTION_BITS;
+        let millivolts = scaled as i64 + self.offset as i64;
+
+        millivolts.max(0) as u32
+    }
+}
+
+impl From<&Calibration> for FixedCalibration {
+    /// Uses floating point, but it's done only once when setting up the measurement
+    fn from(calibration: &Calibration) -> Self {
+        let gain = calibration.gain * (1 << Self::FRACTION_BITS) as f32;
+
+        Self {
+            gain: (gain + 0.5) as u32,
+            offset: if calibration.offset < 0.0 {
+                (calibration.offset - 0.5) as i32
+            } else {
+                (calibration.offset + 0.5) as i32
+            },
+        }
+    }
+}
+
+/// The ADC1 factory calibration stored in eFuse block 2 (`SYS_DATA_PART1`).
+///
+/// Field positions and the decoding follow ESP-IDF's `esp_efuse_rtc_calib.c` for the ESP32-C3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct EfuseCalibration {
+    /// The calibration scheme version (`BLK_VERSION_MAJOR`)
+    pub version: u8,
+    /// `ADC1_INIT_CODE_ATTEN0..=3` - raw, as stored in the eFuse
+    pub init_codes: [u16; 4],
+    /// `ADC1_CAL_VOL_ATTEN0..=3` - raw, as stored in the eFuse
+    pub cal_voltages: [u16; 4],
+}
+
+impl EfuseCalibration {
+    /// The only supported calibration scheme version
+    pub const SUPPORTED_VERSION: u8 = 1;
+
+    /// The reference voltages (in mV) of calibration scheme version 1 for each attenuation
+    pub const REFERENCE_MILLIVOLTS: [u16; 4] = [400, 550, 750, 1370];
+
+    const VERSION_BIT: usize = 128;
+    const VERSION_BITS: usize = 2;
+    const INIT_CODE_BIT: usize = 148;
+    const CAL_VOLTAGE_BIT: usize = 188;
+    const FIELD_BITS: usize = 10;
+
+    /// Decodes the calibration from the 8 words of eFuse block 2.
+    pub fn from_block2(words: &[u32; 8]) -> Self {
+        let mut init_codes = [0; 4];
+        let mut cal_voltages = [0; 4];
+
+        for attenuation in 0..4 {
+            let offset = attenuation * Self::FIELD_BITS;
+
+            init_codes[attenuation] =
+                read_bits(words, Self::INIT_CODE_BIT + offset, Self::FIELD_BITS) as u16;
+            cal_voltages[attenuation] =
+                read_bits(words, Self::CAL_VOLTAGE_BIT + offset, Self::FIELD_BITS) as u16;
+        }
+
+        Self {
+            version: read_bits(words, Self::VERSION_BIT, Self::VERSION_BITS) as u8,
+            init_codes,
+            cal_voltages,
+        }
+    }
+
+    /// Reads the calibration from the eFuse of the chip.
+    #[cfg(feature = "riscv")]
+    pub fn read() -> Self {
+        // Safety: we only read the eFuse registers
+        let efuse = unsafe { &*hal::peripherals::EFUSE::PTR };
+        let words = [
+            efuse.rd_sys_part1_data0.read().bits(),
+            efuse.rd_sys_part1_data1.read().bits(),
+            efuse.rd_sys_part1_data2.read().bits(),
+            efuse.rd_sys_part1_data3.read().bits(),
+            efuse.rd_sys_part1_data4.read().bits(),
+            efuse.rd_sys_part1_data5.read().bits(),
+            efuse.rd_sys_part1_data6.read().bits(),
+            efuse.rd_sys_part1_data7.read().bits(),
+        ];
+
+        Self::from_block2(&words)
+    }
+
+    /// The ADC initialisation code (the raw value for 0V) for the given attenuation.
+    pub fn init_code(&self, attenuation: Attenuation) -> Result<u16, CalibrationError> {
+        self.check_version()?;
+
+        Ok(self.init_codes[attenuation as usize] + 1000)
+    }
+
+    /// Programs ADC1 with the [`EfuseCalibration::init_code`] of the attenuation,
+    /// like ESP-IDF's `adc_hal_set_calibration_param`, so that `0V` reads as `0`.
+    ///
+    /// The code is shared by all the ADC1 pins, they must use the same attenuation.
+    /// It has to be called after ADC1 is configured.
+    #[cfg(feature = "riscv")]
+    pub fn apply_init_code(&self, attenuation: Attenuation) -> Result<(), CalibrationError> {
+        let (high, low) = init_code_fields(self.init_code(attenuation)?);
+
+        // Safety: the ROM function only writes the analog registers of the SAR ADC
+        unsafe {
+            for (address, msb, lsb, value) in [
+                (ADC_SAR1_DREF_ADDR, 6, 4, ADC_SAR1_DREF),
+                (ADC_SAR1_INITIAL_CODE_HIGH_ADDR, 3, 0, high),
+                (ADC_SAR1_INITIAL_CODE_LOW_ADDR, 7, 0, low),
+            ] {
+                rom_i2c_writeReg_Mask(
+                    I2C_SAR_ADC,
+                    I2C_SAR_ADC_HOSTID,
+                    address,
+                    msb,
+                    lsb,
+                    value.into(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The raw ADC value measured for the reference voltage of the given attenuation.
+    pub fn reference_point(
+        &self,
+        attenuation: Attenuation,
+    ) -> Result<CalibrationPoint, CalibrationError> {
+        self.check_version()?;
+
+        let cal_voltage = self.cal_voltages[attenuation as usize];
+        // bit 9 is the sign bit
+        let digital = if cal_voltage & (1 << 9) != 0 {
+            2000 - (cal_voltage & !(1 << 9))
+        } else {
+            2000 + cal_voltage
+        };
+
+        Ok(CalibrationPoint::new(
+            digital,
+            Self::REFERENCE_MILLIVOLTS[attenuation as usize] as f32,
+        ))
+    }
+
+    fn check_version(&self) -> Result<(), CalibrationError> {
+        if self.version == Self::SUPPORTED_VERSION {
+            Ok(())
+        } else {
+            Err(CalibrationError::UnsupportedEfuseVersion(self.version))
+        }
+    }
+}
+
+/// The SAR ADC block of the analog (`regi2c`) registers, from ESP-IDF's `regi2c_saradc.h`
+#[cfg(feature = "riscv")]
+const I2C_SAR_ADC: u32 = 0x69;
+#[cfg(feature = "riscv")]
+const I2C_SAR_ADC_HOSTID: u32 = 0;
+#[cfg(feature = "riscv")]
+const ADC_SAR1_INITIAL_CODE_HIGH_ADDR: u32 = 0x1;
+#[cfg(feature = "riscv")]
+const ADC_SAR1_INITIAL_CODE_LOW_ADDR: u32 = 0x0;
+#[cfg(feature = "riscv")]
+const ADC_SAR1_DREF_ADDR: u32 = 0x2;
+/// The reference voltage setting the init code was calibrated with
+#[cfg(feature = "riscv")]
+const ADC_SAR1_DREF: u8 = 4;
+
+#[cfg(feature = "riscv")]
+extern "C" {
+    /// The ESP32-C3 ROM function (linked by the HAL) writing the bits `msb..=lsb`
+    /// of an analog register
+    #[allow(non_snake_case)]
+    fn rom_i2c_writeReg_Mask(
+        block: u32,
+        block_hostid: u32,
+        reg_add: u32,
+        reg_add_msb: u32,
+        reg_add_lsb: u32,
+        indata: u32,
+    );
+}
+
+/// Splits the 12-bit init code into the high 4 bits and the low 8 bits of its registers.
+#[cfg(any(feature = "riscv", test))]
+fn init_code_fields(init_code: u16) -> (u8, u8) {
+    (((init_code >> 8) & 0x0F) as u8, (init_code & 0xFF) as u8)
+}
+
+/// Reads `count` (up to 32) little-endian bits starting at bit `start`.
+fn read_bits(words: &[u32], start: usize, count: usize) -> u32 {
+    (0..count).fold(0, |value, index| {
+        let bit = start + index;
+        let bit_value = (words[bit / 32] >> (bit % 32)) & 1;
+
+        value | (bit_value << index)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_approx(expected: f32, actual: f32) {
+        assert!(
+            (expected - actual).abs() < 0.01,
+            "expected: {expected}, actual: {actual}"
+        );
+    }
+
+    /// Writes `count` bits of `value` starting at bit `start`
+    fn write_bits(words: &mut [u32; 8], start: usize, count: usize, value: u32) {
+        for index in 0..count {
+            let bit = start + index;
+            if value & (1 << index) != 0 {
+                words[bit / 32] |= 1 << (bit % 32);
+            }
+        }
+    }
+
+    #[test]
+    fn test_uncalibrated_matches_previous_precision_factor() {
+        let calibration = Calibration::default();
+
+        assert_approx(3.3 / 4096.0 * 2048.0, calibration.to_voltage(2048.0));
+        assert_approx(0.0, calibration.to_millivolts(0.0));
+    }
+
+    #[test]
+    fn test_two_point_calibration() {
+        let calibration = Calibration::two_point(
+            Attenuation::Attenuation11dB,
+            CalibrationPoint::new(500, 400.0),
+            CalibrationPoint::new(3000, 2400.0),
+        )
+        .unwrap();
+
+        assert_eq!(CalibrationSource::TwoPoint, calibration.source);
+        assert_approx(0.8, calibration.gain);
+        assert_approx(0.0, calibration.offset);
+        assert_approx(400.0, calibration.to_millivolts(500.0));
+        assert_approx(2400.0, calibration.to_millivolts(3000.0));
+        assert_approx(1.4, calibration.to_voltage(1750.0));
+
+        // with an offset
+        let calibration = Calibration::two_point(
+            Attenuation::Attenuation6dB,
+            CalibrationPoint::new(100, 150.0),
+            CalibrationPoint::new(1100, 1150.0),
+        )
+        .unwrap();
+        assert_approx(1.0, calibration.gain);
+        assert_approx(50.0, calibration.offset);
+
+        // the same user-stored coefficients give the same results
+        let stored = Calibration::from_coefficients(Attenuation::Attenuation6dB, 1.0, 50.0);
+        assert_eq!(
+            calibration.to_millivolts(700.0),
+            stored.to_millivolts(700.0)
+        );
+    }
+
+    #[test]
+    fn test_two_point_calibration_errors() {
+        assert_eq!(
+            Err(CalibrationError::DegeneratePoints),
+            Calibration::two_point(
+                Attenuation::Attenuation0dB,
+                CalibrationPoint::new(1000, 100.0),
+                CalibrationPoint::new(1000, 700.0),
+            )
+        );
+
+        assert_eq!(
+            Err(CalibrationError::InvalidGain),
+            Calibration::two_point(
+                Attenuation::Attenuation0dB,
+                CalibrationPoint::new(1000, 700.0),
+                CalibrationPoint::new(2000, 100.0),
+            )
+        );
+    }
+
+    #[test]
+    fn test_efuse_decoding() {
+        let mut words = [0_u32; 8];
+        write_bits(&mut words, 128, 2, 1);
+        // init codes
+        for (attenuation, init_code) in [10, 20, 30, 40].into_iter().enumerate() {
+            write_bits(&mut words, 148 + attenuation * 10, 10, init_code);
+        }
+        // positive (+100), negative (-50 with the sign bit), zero and +1000
+        for (attenuation, cal_voltage) in [100, (1 << 9) | 50, 0, 0x1FF].into_iter().enumerate() {
+            write_bits(&mut words, 188 + attenuation * 10, 10, cal_voltage);
+        }
+
+        let efuse = EfuseCalibration::from_block2(&words);
+        assert_eq!(1, efuse.version);
+        assert_eq!([10, 20, 30, 40], efuse.init_codes);
+
+        assert_eq!(Ok(1010), efuse.init_code(Attenuation::Attenuation0dB));
+        assert_eq!(Ok(1040), efuse.init_code(Attenuation::Attenuation11dB));
+
+        assert_eq!(
+            Ok(CalibrationPoint::new(2100, 400.0)),
+            efuse.reference_point(Attenuation::Attenuation0dB)
+        );
+        assert_eq!(
+            Ok(CalibrationPoint::new(1950, 550.0)),
+            efuse.reference_point(Attenuation::Attenuation2p5dB)
+        );
+        assert_eq!(
+            Ok(CalibrationPoint::new(2000, 750.0)),
+            efuse.reference_point(Attenuation::Attenuation6dB)
+        );
+        assert_eq!(
+            Ok(CalibrationPoint::new(2511, 1370.0)),
+            efuse.reference_point(Attenuation::Attenuation11dB)
+        );
+
+        let calibration = Calibration::from_efuse(&efuse, Attenuation::Attenuation6dB).unwrap();
+        assert_eq!(CalibrationSource::Efuse, calibration.source);
+        assert_approx(750.0, calibration.to_millivolts(2000.0));
+        assert_approx(375.0, calibration.to_millivolts(1000.0));
+    }
+
+    #[test]
+    fn test_efuse_init_code() {
+        let mut words = [0_u32; 8];
+        write_bits(&mut words, 128, 2, 1);
+        write_bits(&mut words, 148 + 3 * 10, 10, 0x2A7);
+        write_bits(&mut words, 188 + 3 * 10, 10, 0);
+
+        let efuse = EfuseCalibration::from_block2(&words);
+        let init_code = efuse.init_code(Attenuation::Attenuation11dB).unwrap();
+        assert_eq!(0x2A7 + 1000, init_code);
+        // 1679 = 0x68F
+        assert_eq!((0x6, 0x8F), init_code_fields(init_code));
+
+        // the offset is removed by the init code, only the gain is corrected
+        let calibration = Calibration::from_efuse(&efuse, Attenuation::Attenuation11dB).unwrap();
+        assert_approx(0.0, calibration.offset);
+        assert_approx(1370.0, calibration.to_millivolts(2000.0));
+
+        // the init code of an uncalibrated chip isn't applied
+        assert_eq!(
+            Err(CalibrationError::UnsupportedEfuseVersion(0)),
+            EfuseCalibration::from_block2(&[0; 8]).init_code(Attenuation::Attenuation11dB)
+        );
+    }
+
+    #[test]
+    fn test_fixed_point_matches_float() {
+        let calibrations = [
+            Calibration::UNCALIBRATED,
+            Calibration::from_coefficients(Attenuation::Attenuation6dB, 0.3172, 12.4),
+            Calibration::from_coefficients(Attenuation::Attenuation11dB, 0.8123, -25.7),
+        ];
+
+        assert_eq!(
+            FixedCalibration::UNCALIBRATED,
+            FixedCalibration::from(&Calibration::UNCALIBRATED)
+        );
+
+        for calibration in calibrations {
+            let fixed = FixedCalibration::from(&calibration);
+
+            for raw in 0..=4095_u16 {
+                let float = calibration.to_millivolts(raw as f32).max(0.0);
+                let integer = fixed.to_millivolts(raw);
+
+                assert!(
+                    (integer as f32 - float).abs() <= 1.05,
+                    "raw: {raw}, fixed: {integer} mV, float: {float} mV"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_efuse_unsupported_version() {
+        // an uncalibrated chip has all bits set to 0
+        let efuse = EfuseCalibration::from_block2(&[0; 8]);
+
+        assert_eq!(
+            Err(CalibrationError::UnsupportedEfuseVersion(0)),
+            Calibration::from_efuse(&efuse, Attenuation::Attenuation11dB)
+        );
+    }
+}
@@ -0,0 +1,400 @@
+//! CN3791 solar charger status.
+//!
+//! The CN3791 has two open-drain (active low) status outputs:
+//!
+//! | CHRG     | DONE | State                                    |
+//! |----------|------|------------------------------------------|
+//! | low      | high | charging                                 |
+//! | high     | low  | charge complete                          |
+//! | high     | high | idle, e.g. no input power                |
+//! | low      | low  | fault, not a valid combination           |
+//! | blinking | high | fault, e.g. the battery is not connected |
+//!
+//! The decoded [`ChargerState`] is cross-checked against the battery voltage trend,
+//! since a faulty charger (or a broken status line) would mislead the State of Charge
+//! and the power modes.
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
+use embedded_hal::digital::v2::InputPin;
+
+use crate::power_sense::PowerSenseConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ChargerState {
+    /// Not charging, e.g. there's no input power
+    Idle,
+    Charging,
+    /// The charge is terminated
+    Complete,
+    /// Invalid status outputs or the battery is not connected
+    Fault,
+}
+
+impl ChargerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChargerState::Idle => "IDLE",
+            ChargerState::Charging => "CHARGING",
+            ChargerState::Complete => "COMPLETE",
+            ChargerState::Fault => "FAULT",
+        }
+    }
+
+    /// Decodes the levels of the open-drain status outputs, without the blinking.
+    pub fn decode(chrg_low: bool, done_low: bool) -> Self {
+        match (chrg_low, done_low) {
+            (true, false) => ChargerState::Charging,
+            (false, true) => ChargerState::Complete,
+            (false, false) => ChargerState::Idle,
+            (true, true) => ChargerState::Fault,
+        }
+    }
+}
+
+/// The latest change of the [`ChargerState`] for the battery task,
+/// a newer one replaces a state the task hasn't taken yet.
+pub static CHARGER_STATE: Signal<CriticalSectionRawMutex, ChargerState> = Signal::new();
+
+/// Reads the CHRG and DONE status outputs of the CN3791.
+///
+/// The pins should have pull-ups since the outputs are open-drain.
+pub struct ChargerStatus<CHRG, DONE> {
+    chrg: CHRG,
+    done: DONE,
+    /// More CHRG changes than this within the [`ChargerStatus::blink_window`] are blinking
+    pub max_changes: u8,
+    pub blink_window: Duration,
+    /// The CHRG level and the changes of it within the blink window
+    chrg_low: Option<bool>,
+    changes: u8,
+    window_started_at: Option<Instant>,
+    last_change_at: Option<Instant>,
+    /// Latched until a whole blink window without a CHRG change
+    blinking: bool,
+    state: Option<ChargerState>,
+}
+
+impl<CHRG, DONE, E> ChargerStatus<CHRG, DONE>
+where
+    CHRG: InputPin<Error = E>,
+    DONE: InputPin<Error = E>,
+{
+    pub const DEFAULT_MAX_CHANGES: u8 = 2;
+    pub const DEFAULT_BLINK_WINDOW: Duration = Duration::from_secs(4);
+
+    pub fn new(chrg: CHRG, done: DONE) -> Self {
+        Self {
+            chrg,
+            done,
+            max_changes: Self::DEFAULT_MAX_CHANGES,
+            blink_window: Self::DEFAULT_BLINK_WINDOW,
+            chrg_low: None,
+            changes: 0,
+            window_started_at: None,
+            last_change_at: None,
+            blinking: false,
+            state: None,
+        }
+    }
+
+    /// The last decoded state, `None` before the first [`ChargerStatus::read`]
+    pub fn state(&self) -> Option<ChargerState> {
+        self.state
+    }
+
+    /// Reads the status outputs and decodes them.
+    ///
+    /// Should be called more often than the CHRG output blinks to detect it,
+    /// a blinking CHRG is a [`ChargerState::Fault`] until a whole blink window passes
+    /// without a change of it.
+    pub fn read(&mut self, now: Instant) -> Result<ChargerState, E> {
+        let chrg_low = self.chrg.is_low()?;
+        let done_low = self.done.is_low()?;
+
+        let window_started_at = *self.window_started_at.get_or_insert(now);
+        if now
+            .checked_duration_since(window_started_at)
+            .unwrap_or(Duration::from_ticks(0))
+            >= self.blink_window
+        {
+            self.changes = 0;
+            self.window_started_at = Some(now);
+        }
+        if self.chrg_low == Some(!chrg_low) {
+            self.changes = self.changes.saturating_add(1);
+            self.last_change_at = Some(now);
+        }
+        self.chrg_low = Some(chrg_low);
+
+        if self.changes > self.max_changes {
+            self.blinking = true;
+        } else if self.blinking {
+            let steady_for = self
+                .last_change_at
+                .and_then(|last_change_at| now.checked_duration_since(last_change_at))
+                .unwrap_or(Duration::from_ticks(0));
+            self.blinking = steady_for < self.blink_window;
+        }
+
+        let state = if self.blinking {
+            ChargerState::Fault
+        } else {
+            ChargerState::decode(chrg_low, done_low)
+        };
+        self.state = Some(state);
+
+        Ok(state)
+    }
+}
+
+/// The charger state doesn't agree with the battery voltage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Disagreement {
+    /// Charging, but the battery voltage is falling
+    ChargingButFalling,
+    /// Charge complete, but the battery is not charged
+    CompleteButLow,
+    /// Not charging, but the battery voltage is rising
+    IdleButRising,
+}
+
+impl Disagreement {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Disagreement::ChargingButFalling => "CHARGING_BUT_FALLING",
+            Disagreement::CompleteButLow => "COMPLETE_BUT_LOW",
+            Disagreement::IdleButRising => "IDLE_BUT_RISING",
+        }
+    }
+}
+
+/// Cross-checks the [`ChargerState`] against the battery voltage (in mV) and its trend
+/// (in mV per minute), with the flat trend and the charged voltage of the [`PowerSenseConfig`].
+///
+/// A fault is not a disagreement, it's already reported by the charger.
+pub fn cross_check(
+    config: &PowerSenseConfig,
+    state: ChargerState,
+    battery_millivolts: u32,
+    battery_trend: Option<i32>,
+) -> Option<Disagreement> {
+    let flat_trend = config.flat_trend as i32;
+
+    match (state, battery_trend) {
+        (ChargerState::Charging, Some(trend)) if trend < -flat_trend => {
+            Some(Disagreement::ChargingButFalling)
+        }
+        (ChargerState::Complete, _) if battery_millivolts < config.charged_millivolts => {
+            Some(Disagreement::CompleteButLow)
+        }
+        (ChargerState::Idle, Some(trend)) if trend > flat_trend => {
+            Some(Disagreement::IdleButRising)
+        }
+        _ => None,
+    }
+}
+
+/// Flags the [`Disagreement`]s which persist for consecutive checks,
+/// e.g. the voltage trend lags behind the charger for a while after it changes the state.
+#[derive(Debug, Clone)]
+pub struct ChargerMonitor {
+    pub config: PowerSenseConfig,
+    /// The consecutive checks of the same disagreement to flag it
+    pub confirm_checks: u8,
+    pending: Option<(Disagreement, u8)>,
+    flagged: Option<Disagreement>,
+}
+
+impl ChargerMonitor {
+    pub const DEFAULT_CONFIRM_CHECKS: u8 = 5;
+
+    pub fn new(config: PowerSenseConfig) -> Self {
+        Self {
+            config,
+            confirm_checks: Self::DEFAULT_CONFIRM_CHECKS,
+            pending: None,
+            flagged: None,
+        }
+    }
+
+    /// The currently flagged disagreement
+    pub fn flagged(&self) -> Option<Disagreement> {
+        self.flagged
+    }
+
+    /// Cross-checks the charger state and returns the flagged disagreement.
+    pub fn update(
+        &mut self,
+        state: ChargerState,
+        battery_millivolts: u32,
+        battery_trend: Option<i32>,
+    ) -> Option<Disagreement> {
+        let disagreement = cross_check(&self.config, state, battery_millivolts, battery_trend);
+
+        match disagreement {
+            None => {
+                self.pending = None;
+                self.flagged = None;
+            }
+            Some(disagreement) if self.flagged == Some(disagreement) => {}
+            Some(disagreement) => {
+                let checks = match self.pending {
+                    Some((pending, checks)) if pending == disagreement => checks + 1,
+                    _ => 1,
+                };
+
+                if checks >= self.confirm_checks {
+                    self.pending = None;
+                    self.flagged = Some(disagreement);
+                } else {
+                    self.pending = Some((disagreement, checks));
+                }
+            }
+        }
+
+        self.flagged
+    }
+}
+
+/// A simulated status output, low while the shared flag is set.
+#[derive(Debug, Clone, Copy)]
+pub struct MockPin<'a>(pub &'a core::cell::Cell<bool>);
+
+impl<'a> InputPin for MockPin<'a> {
+    type Error = core::convert::Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(!self.0.get())
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(self.0.get())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::cell::Cell;
+
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(250);
+
+    #[test]
+    fn test_decode_status_outputs() {
+        let chrg = Cell::new(false);
+        let done = Cell::new(false);
+        let mut status = ChargerStatus::new(MockPin(&chrg), MockPin(&done));
+        let start = Instant::from_secs(0);
+        assert_eq!(None, status.state());
+
+        assert_eq!(Ok(ChargerState::Idle), status.read(start));
+
+        chrg.set(true);
+        assert_eq!(Ok(ChargerState::Charging), status.read(start + TICK));
+
+        chrg.set(false);
+        done.set(true);
+        assert_eq!(Ok(ChargerState::Complete), status.read(start + TICK * 2));
+
+        chrg.set(true);
+        assert_eq!(Ok(ChargerState::Fault), status.read(start + TICK * 3));
+        assert_eq!(Some(ChargerState::Fault), status.state());
+    }
+
+    #[test]
+    fn test_blinking_chrg_is_a_fault() {
+        let chrg = Cell::new(false);
+        let done = Cell::new(false);
+        let mut status = ChargerStatus::new(MockPin(&chrg), MockPin(&done));
+        let start = Instant::from_secs(0);
+
+        // blinking at 1 Hz for 3 blink windows
+        let states: std::vec::Vec<ChargerState> = (0..48_u32)
+            .map(|tick| {
+                chrg.set((tick / 2) % 2 == 1);
+                status.read(start + TICK * tick).unwrap()
+            })
+            .collect();
+
+        // the third change is at the 6th tick, it stays a fault across the windows
+        assert!(states[..6]
+            .iter()
+            .all(|state| *state != ChargerState::Fault));
+        assert!(states[6..]
+            .iter()
+            .all(|state| *state == ChargerState::Fault));
+
+        // steadily charging after a whole blink window without a change,
+        // the last change was at the 46th tick
+        chrg.set(true);
+        let states: std::vec::Vec<ChargerState> = (48..72_u32)
+            .map(|tick| status.read(start + TICK * tick).unwrap())
+            .collect();
+        assert!(states[..14]
+            .iter()
+            .all(|state| *state == ChargerState::Fault));
+        assert!(states[14..]
+            .iter()
+            .all(|state| *state == ChargerState::Charging));
+    }
+
+    #[test]
+    fn test_cross_check() {
+        let config = PowerSenseConfig::LIPO_1S;
+
+        assert_eq!(
+            Some(Disagreement::ChargingButFalling),
+            cross_check(&config, ChargerState::Charging, 3900, Some(-10))
+        );
+        assert_eq!(
+            None,
+            cross_check(&config, ChargerState::Charging, 3900, Some(-2))
+        );
+        assert_eq!(
+            Some(Disagreement::CompleteButLow),
+            cross_check(&config, ChargerState::Complete, 3900, None)
+        );
+        assert_eq!(
+            None,
+            cross_check(&config, ChargerState::Complete, 4180, Some(0))
+        );
+        assert_eq!(
+            Some(Disagreement::IdleButRising),
+            cross_check(&config, ChargerState::Idle, 3900, Some(5))
+        );
+        assert_eq!(
+            None,
+            cross_check(&config, ChargerState::Fault, 3900, Some(-50))
+        );
+        // no trend yet
+        assert_eq!(None, cross_check(&config, ChargerState::Idle, 3900, None));
+    }
+
+    #[test]
+    fn test_monitor_confirms_disagreements() {
+        let mut monitor = ChargerMonitor::new(PowerSenseConfig::LIPO_1S);
+
+        for _ in 0..4 {
+            assert_eq!(
+                None,
+                monitor.update(ChargerState::Charging, 3900, Some(-10))
+            );
+        }
+        assert_eq!(
+            Some(Disagreement::ChargingButFalling),
+            monitor.update(ChargerState::Charging, 3900, Some(-10))
+        );
+        assert_eq!(Some(Disagreement::ChargingButFalling), monitor.flagged());
+
+        // agreeing clears it immediately
+        assert_eq!(None, monitor.update(ChargerState::Charging, 3900, Some(5)));
+        // a glitch restarts the confirmation
+        for _ in 0..3 {
+            monitor.update(ChargerState::Idle, 3900, Some(5));
+        }
+        monitor.update(ChargerState::Idle, 3900, Some(0));
+        assert_eq!(None, monitor.update(ChargerState::Idle, 3900, Some(5)));
+    }
+}
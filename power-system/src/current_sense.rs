@@ -0,0 +1,361 @@
+//! Hardware-independent current sensing.
+//!
+//! The energy accounting only needs "something that measures a current",
+//! on the board that's an [`Ina219`] on the I2C bus and on the host
+//! the [`ScriptedCurrentSense`].
+use core::{cell::RefCell, fmt};
+
+use defmt::Format;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+use crate::adc::ScriptedError;
+
+/// A source of current measurements.
+pub trait CurrentSense {
+    type Error: fmt::Debug;
+
+    /// The current in milliamps, positive in the direction of the sensor,
+    /// e.g. from the solar panels or into the battery.
+    fn read_milliamps(&mut self) -> Result<i32, Self::Error>;
+}
+
+impl<T: CurrentSense> CurrentSense for &mut T {
+    type Error = T::Error;
+
+    fn read_milliamps(&mut self) -> Result<i32, Self::Error> {
+        T::read_milliamps(self)
+    }
+}
+
+/// The INA219 registers
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+enum Register {
+    Configuration = 0x00,
+    ShuntVoltage = 0x01,
+    BusVoltage = 0x02,
+}
+
+/// The error returned by the [`Ina219`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Ina219Error<E> {
+    I2c(E),
+    /// The bus voltage is outside of the range and the measurement is not valid
+    Overflow,
+    /// A triggered conversion didn't complete in time
+    NotReady,
+}
+
+/// The bus voltage and the current of a single INA219 conversion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Ina219Measurement {
+    pub millivolts: u32,
+    pub milliamps: i32,
+}
+
+/// The TI INA219 current and bus voltage monitor over I2C.
+///
+/// The current is calculated from the shunt voltage (`10 µV` resolution),
+/// so the calibration register of the chip is not used.
+pub struct Ina219<I2C> {
+    i2c: I2C,
+    address: u8,
+    /// The shunt resistor in milliohms
+    shunt_milliohms: u32,
+}
+
+impl<I2C, E> Ina219<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    /// The address with both `A0` and `A1` to GND
+    pub const DEFAULT_ADDRESS: u8 = 0x40;
+
+    /// The reset value: `32 V` bus range, `±320 mV` shunt range, 12-bit conversions
+    /// and continuous shunt and bus measurements
+    pub const CONFIGURATION: u16 = 0x399F;
+
+    /// The [`Ina219::CONFIGURATION`] with a single triggered shunt and bus measurement
+    pub const TRIGGERED_CONFIGURATION: u16 = 0x399B;
+
+    /// How many times the bus voltage is read for the conversion ready flag,
+    /// a conversion of both voltages takes about `1.1 ms` and a read `0.4 ms` at `100 kHz`
+    const READY_POLLS: usize = 8;
+
+    /// The shunt voltage LSB in microvolts
+    const SHUNT_MICROVOLTS_LSB: i32 = 10;
+
+    /// The bus voltage LSB in millivolts
+    const BUS_MILLIVOLTS_LSB: u32 = 4;
+
+    pub fn new(i2c: I2C, address: u8, shunt_milliohms: u32) -> Self {
+        Self {
+            i2c,
+            address,
+            shunt_milliohms,
+        }
+    }
+
+    /// Writes the [`Ina219::CONFIGURATION`], i.e. starts continuous conversions.
+    pub fn init(&mut self) -> Result<(), Ina219Error<E>> {
+        let [high, low] = Self::CONFIGURATION.to_be_bytes();
+
+        self.i2c
+            .write(self.address, &[Register::Configuration as u8, high, low])
+            .map_err(Ina219Error::I2c)
+    }
+
+    /// Triggers a single conversion and returns its bus voltage and current,
+    /// so that they are taken at the same moment (e.g. for the power).
+    ///
+    /// The chip stays in the triggered mode, i.e. the registers keep this conversion.
+    pub fn measure(&mut self) -> Result<Ina219Measurement, Ina219Error<E>> {
+        let [high, low] = Self::TRIGGERED_CONFIGURATION.to_be_bytes();
+        // writing the mode clears the conversion ready flag
+        self.i2c
+            .write(self.address, &[Register::Configuration as u8, high, low])
+            .map_err(Ina219Error::I2c)?;
+
+        for _ in 0..Self::READY_POLLS {
+            let raw = self.read_register(Register::BusVoltage)?;
+            // the conversion ready flag
+            if raw & 0b10 == 0 {
+                continue;
+            }
+            if raw & 0b1 != 0 {
+                return Err(Ina219Error::Overflow);
+            }
+
+            return Ok(Ina219Measurement {
+                millivolts: (raw >> 3) as u32 * Self::BUS_MILLIVOLTS_LSB,
+                milliamps: self.shunt_microvolts()? / self.shunt_milliohms.max(1) as i32,
+            });
+        }
+
+        Err(Ina219Error::NotReady)
+    }
+
+    /// The voltage across the shunt resistor in microvolts
+    pub fn shunt_microvolts(&mut self) -> Result<i32, Ina219Error<E>> {
+        let raw = self.read_register(Register::ShuntVoltage)? as i16;
+
+        Ok(raw as i32 * Self::SHUNT_MICROVOLTS_LSB)
+    }
+
+    /// The voltage between the bus and GND in millivolts
+    pub fn bus_millivolts(&mut self) -> Result<u32, Ina219Error<E>> {
+        let raw = self.read_register(Register::BusVoltage)?;
+        // the math overflow flag
+        if raw & 0b1 != 0 {
+            return Err(Ina219Error::Overflow);
+        }
+
+        Ok((raw >> 3) as u32 * Self::BUS_MILLIVOLTS_LSB)
+    }
+
+    /// Releases the I2C bus
+    pub fn release(self) -> I2C {
+        self.i2c
+    }
+
+    fn read_register(&mut self, register: Register) -> Result<u16, Ina219Error<E>> {
+        let mut buffer = [0_u8; 2];
+        self.i2c
+            .write_read(self.address, &[register as u8], &mut buffer)
+            .map_err(Ina219Error::I2c)?;
+
+        Ok(u16::from_be_bytes(buffer))
+    }
+}
+
+impl<I2C, E> CurrentSense for Ina219<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: fmt::Debug,
+{
+    type Error = Ina219Error<E>;
+
+    fn read_milliamps(&mut self) -> Result<i32, Self::Error> {
+        let microvolts = self.shunt_microvolts()?;
+
+        Ok(microvolts / self.shunt_milliohms.max(1) as i32)
+    }
+}
+
+/// Shares an I2C bus between the drivers used by a single task,
+/// e.g. an [`Ina219`] for the solar panels and another one for the battery.
+pub struct SharedI2c<'a, I2C>(pub &'a RefCell<I2C>);
+
+impl<'a, I2C: Write> Write for SharedI2c<'a, I2C> {
+    type Error = I2C::Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0.borrow_mut().write(address, bytes)
+    }
+}
+
+impl<'a, I2C: WriteRead> WriteRead for SharedI2c<'a, I2C> {
+    type Error = I2C::Error;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.0.borrow_mut().write_read(address, bytes, buffer)
+    }
+}
+
+/// A simulated current sensor which replays a script of currents in milliamps,
+/// `None` is a failed measurement.
+///
+/// Once the end of the script is reached it starts from the beginning.
+#[derive(Debug, Clone)]
+pub struct ScriptedCurrentSense<'a> {
+    script: &'a [Option<i32>],
+    position: usize,
+}
+
+impl<'a> ScriptedCurrentSense<'a> {
+    /// # Panics
+    ///
+    /// When the script is empty.
+    pub fn new(script: &'a [Option<i32>]) -> Self {
+        assert!(!script.is_empty(), "Script should have at least 1 current");
+
+        Self {
+            script,
+            position: 0,
+        }
+    }
+}
+
+impl<'a> CurrentSense for ScriptedCurrentSense<'a> {
+    type Error = ScriptedError;
+
+    fn read_milliamps(&mut self) -> Result<i32, Self::Error> {
+        let milliamps = self.script[self.position % self.script.len()];
+        self.position += 1;
+
+        milliamps.ok_or(ScriptedError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// An I2C bus with an INA219 which returns the given register values
+    /// and records the writes.
+    #[derive(Default)]
+    struct FakeIna219 {
+        shunt_voltage: u16,
+        bus_voltage: u16,
+        writes: Vec<(u8, Vec<u8>)>,
+    }
+
+    impl Write for FakeIna219 {
+        type Error = ();
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+            self.writes.push((address, bytes.to_vec()));
+            Ok(())
+        }
+    }
+
+    impl WriteRead for FakeIna219 {
+        type Error = ();
+
+        fn write_read(
+            &mut self,
+            address: u8,
+            bytes: &[u8],
+            buffer: &mut [u8],
+        ) -> Result<(), Self::Error> {
+            if address != Ina219::<Self>::DEFAULT_ADDRESS {
+                return Err(());
+            }
+            let value = match bytes {
+                [0x01] => self.shunt_voltage,
+                [0x02] => self.bus_voltage,
+                _ => return Err(()),
+            };
+            buffer.copy_from_slice(&value.to_be_bytes());
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_ina219() {
+        let bus = RefCell::new(FakeIna219 {
+            // 12.5 mV
+            shunt_voltage: 1250,
+            // 5000 mV and the conversion ready flag
+            bus_voltage: (1250 << 3) | 0b10,
+            ..Default::default()
+        });
+        let mut ina219 = Ina219::new(SharedI2c(&bus), 0x40, 100);
+
+        ina219.init().unwrap();
+        assert_eq!(vec![(0x40, vec![0x00, 0x39, 0x9F])], bus.borrow().writes);
+
+        // 12.5 mV across 100 mOhm
+        assert_eq!(Ok(125), ina219.read_milliamps());
+        assert_eq!(Ok(5000), ina219.bus_millivolts());
+
+        // negative shunt voltage, i.e. the current flows the other way
+        bus.borrow_mut().shunt_voltage = (-1250_i16) as u16;
+        assert_eq!(Ok(-125), ina219.read_milliamps());
+
+        bus.borrow_mut().bus_voltage = (1250 << 3) | 0b11;
+        assert_eq!(Err(Ina219Error::Overflow), ina219.bus_millivolts());
+
+        let mut other = Ina219::new(SharedI2c(&bus), 0x41, 100);
+        assert_eq!(Err(Ina219Error::I2c(())), other.read_milliamps());
+    }
+
+    #[test]
+    fn test_ina219_measure() {
+        let bus = RefCell::new(FakeIna219 {
+            // 12.5 mV
+            shunt_voltage: 1250,
+            // 5000 mV and the conversion ready flag
+            bus_voltage: (1250 << 3) | 0b10,
+            ..Default::default()
+        });
+        let mut ina219 = Ina219::new(SharedI2c(&bus), 0x40, 100);
+
+        assert_eq!(
+            Ok(Ina219Measurement {
+                millivolts: 5000,
+                milliamps: 125,
+            }),
+            ina219.measure()
+        );
+        // triggered a single conversion
+        assert_eq!(vec![(0x40, vec![0x00, 0x39, 0x9B])], bus.borrow().writes);
+
+        bus.borrow_mut().bus_voltage = (1250 << 3) | 0b11;
+        assert_eq!(Err(Ina219Error::Overflow), ina219.measure());
+
+        // the conversion never completes
+        bus.borrow_mut().bus_voltage = 1250 << 3;
+        assert_eq!(Err(Ina219Error::NotReady), ina219.measure());
+    }
+
+    #[test]
+    fn test_scripted_current_sense() {
+        let script = [Some(100), None, Some(-50)];
+        let mut sense = ScriptedCurrentSense::new(&script);
+
+        assert_eq!(Ok(100), sense.read_milliamps());
+        assert_eq!(Err(ScriptedError), sense.read_milliamps());
+        assert_eq!(Ok(-50), sense.read_milliamps());
+        // starts from the beginning
+        assert_eq!(Ok(100), sense.read_milliamps());
+    }
+}
@@ -0,0 +1,349 @@
+//! Energy accounting - how much energy the solar panels delivered and the bus consumed.
+//!
+//! The power of each flow (the bus voltage × current of a single conversion,
+//! see [`Ina219::measure`](crate::current_sense::Ina219::measure)) is integrated over time
+//! with the trapezoidal rule and the energy is kept for the last minute, the last orbit
+//! and since boot.
+//!
+//! The energy is in microjoules (i.e. milliwatt-milliseconds) to keep the integration
+//! in integers, `3_600_000 µJ` is `1 mWh`.
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+
+/// The power (in milliwatts) of each flow at a moment, `None` when it's not measured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct PowerFlow {
+    /// From the solar panels (or USB) to the charger
+    pub solar: Option<u32>,
+    /// Into the battery when positive and out of it when negative
+    pub battery: Option<i32>,
+}
+
+impl PowerFlow {
+    /// The power (in milliwatts) for the voltage (in millivolts) and current (in milliamps)
+    pub fn milliwatts(millivolts: u32, milliamps: i32) -> i32 {
+        (millivolts as i64 * milliamps as i64 / 1000) as i32
+    }
+
+    /// The power consumed by the bus, i.e. what comes from the solar panels
+    /// and doesn't go into the battery, `None` unless both flows are measured.
+    pub fn consumed(&self) -> Option<u32> {
+        let solar = self.solar? as i32;
+        let battery = self.battery?;
+
+        Some(solar.saturating_sub(battery).max(0) as u32)
+    }
+}
+
+/// The periods the energy is accounted for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum EnergyPeriod {
+    /// The last whole minute
+    Minute,
+    /// The last whole orbit
+    Orbit,
+    /// Since boot
+    Boot,
+}
+
+impl EnergyPeriod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EnergyPeriod::Minute => "MIN",
+            EnergyPeriod::Orbit => "ORBIT",
+            EnergyPeriod::Boot => "BOOT",
+        }
+    }
+}
+
+/// The energy (in µJ) of each flow over a period of time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct Energy {
+    /// From the solar panels
+    pub harvested: u64,
+    /// Into the battery
+    pub charged: u64,
+    /// Out of the battery
+    pub discharged: u64,
+    /// By the bus
+    pub consumed: u64,
+}
+
+impl Energy {
+    pub const ZERO: Energy = Energy {
+        harvested: 0,
+        charged: 0,
+        discharged: 0,
+        consumed: 0,
+    };
+
+    /// The microjoules in a milliwatt-hour
+    pub const MICROJOULES_PER_MILLIWATT_HOUR: u64 = 3_600_000;
+
+    /// The microjoules (in `0.1 mWh`), rounded to the nearest
+    pub fn decimilliwatt_hours(microjoules: u64) -> u64 {
+        let per_decimilliwatt_hour = Self::MICROJOULES_PER_MILLIWATT_HOUR / 10;
+
+        (microjoules + per_decimilliwatt_hour / 2) / per_decimilliwatt_hour
+    }
+
+    /// Adds the energy of the flows between the two powers
+    /// and the milliseconds between them (trapezoidal rule).
+    fn add(&mut self, from: &PowerFlow, to: &PowerFlow, milliseconds: u64) {
+        fn trapezoid(from: i64, to: i64, milliseconds: u64) -> i64 {
+            (from + to) * milliseconds as i64 / 2
+        }
+
+        if let (Some(from), Some(to)) = (from.solar, to.solar) {
+            self.harvested += trapezoid(from.into(), to.into(), milliseconds).max(0) as u64;
+        }
+
+        if let (Some(from), Some(to)) = (from.battery, to.battery) {
+            let battery = trapezoid(from.into(), to.into(), milliseconds);
+            if battery >= 0 {
+                self.charged += battery as u64;
+            } else {
+                self.discharged += battery.unsigned_abs();
+            }
+        }
+
+        if let (Some(from), Some(to)) = (from.consumed(), to.consumed()) {
+            self.consumed += trapezoid(from.into(), to.into(), milliseconds).max(0) as u64;
+        }
+    }
+}
+
+/// The energy of consecutive periods of the same length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Window {
+    length: Duration,
+    started_at: Instant,
+    current: Energy,
+    /// `None` until the first period ends
+    last: Option<Energy>,
+}
+
+impl Window {
+    fn new(length: Duration, now: Instant) -> Self {
+        Self {
+            length,
+            started_at: now,
+            current: Energy::ZERO,
+            last: None,
+        }
+    }
+
+    /// Ends the current period (and the empty ones after it) if it's over.
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now
+            .checked_duration_since(self.started_at)
+            .unwrap_or(Duration::from_ticks(0));
+        let periods = elapsed.as_ticks() / self.length.as_ticks().max(1);
+
+        match periods {
+            0 => return,
+            1 => self.last = Some(self.current),
+            _ => self.last = Some(Energy::ZERO),
+        }
+        self.current = Energy::ZERO;
+        self.started_at += Duration::from_ticks(self.length.as_ticks() * periods);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnergyConfig {
+    /// The orbital period, e.g. `~92 minutes` for the ISS
+    pub orbit_period: Duration,
+    /// The energy between samples further apart than this is not integrated,
+    /// the power could've changed in any way in the meantime
+    pub max_interval: Duration,
+}
+
+impl EnergyConfig {
+    /// A Low Earth Orbit at `~400 km`
+    pub const LEO: EnergyConfig = EnergyConfig {
+        orbit_period: Duration::from_secs(92 * 60),
+        max_interval: Duration::from_secs(10),
+    };
+}
+
+impl Default for EnergyConfig {
+    fn default() -> Self {
+        Self::LEO
+    }
+}
+
+/// Integrates the [`PowerFlow`]s into the [`Energy`] of the last minute,
+/// the last orbit and since boot.
+#[derive(Debug, Clone)]
+pub struct EnergyAccountant {
+    config: EnergyConfig,
+    previous: Option<(Instant, PowerFlow)>,
+    minute: Window,
+    orbit: Window,
+    since_boot: Energy,
+}
+
+impl EnergyAccountant {
+    pub const MINUTE: Duration = Duration::from_secs(60);
+
+    pub fn new(config: EnergyConfig, now: Instant) -> Self {
+        Self {
+            config,
+            previous: None,
+            minute: Window::new(Self::MINUTE, now),
+            orbit: Window::new(config.orbit_period, now),
+            since_boot: Energy::ZERO,
+        }
+    }
+
+    pub fn config(&self) -> &EnergyConfig {
+        &self.config
+    }
+
+    /// Adds the energy since the previous power measurement.
+    ///
+    /// The energy between two measurements goes to the period of the later one.
+    pub fn update(&mut self, flow: PowerFlow, at: Instant) {
+        self.minute.roll(at);
+        self.orbit.roll(at);
+
+        if let Some((previous_at, previous)) = self.previous {
+            let interval = at.checked_duration_since(previous_at);
+
+            match interval {
+                Some(interval) if interval <= self.config.max_interval => {
+                    let milliseconds = interval.as_millis();
+                    self.minute.current.add(&previous, &flow, milliseconds);
+                    self.orbit.current.add(&previous, &flow, milliseconds);
+                    self.since_boot.add(&previous, &flow, milliseconds);
+                }
+                _ => {}
+            }
+        }
+
+        self.previous = Some((at, flow));
+    }
+
+    /// The energy of the last whole minute, `None` for the first minute after boot
+    pub fn last_minute(&self) -> Option<Energy> {
+        self.minute.last
+    }
+
+    /// The energy of the last whole orbit, `None` for the first orbit after boot
+    pub fn last_orbit(&self) -> Option<Energy> {
+        self.orbit.last
+    }
+
+    pub fn since_boot(&self) -> Energy {
+        self.since_boot
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::current_sense::{CurrentSense, ScriptedCurrentSense};
+
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_power_flow() {
+        assert_eq!(500, PowerFlow::milliwatts(5000, 100));
+        assert_eq!(-383, PowerFlow::milliwatts(3830, -100));
+
+        let flow = PowerFlow {
+            solar: Some(1000),
+            battery: Some(300),
+        };
+        assert_eq!(Some(700), flow.consumed());
+        // discharging while in the sun
+        let flow = PowerFlow {
+            solar: Some(200),
+            battery: Some(-300),
+        };
+        assert_eq!(Some(500), flow.consumed());
+        let flow = PowerFlow {
+            solar: None,
+            battery: Some(-300),
+        };
+        assert_eq!(None, flow.consumed());
+    }
+
+    #[test]
+    fn test_energy_periods() {
+        let start = Instant::from_secs(0);
+        let config = EnergyConfig {
+            orbit_period: Duration::from_secs(180),
+            ..EnergyConfig::LEO
+        };
+        let mut accountant = EnergyAccountant::new(config, start);
+
+        // 3 minutes of a constant 3.6 W from the solar panels,
+        // 1.8 W into the battery in the first minute and 0.9 W out of it afterwards
+        let solar = [Some(3000)];
+        let mut solar_sense = ScriptedCurrentSense::new(&solar);
+        for second in 0..=180_u64 {
+            let battery = if second <= 60 { 1800 } else { -900 };
+            let flow = PowerFlow {
+                solar: Some(
+                    PowerFlow::milliwatts(1200, solar_sense.read_milliamps().unwrap()) as u32,
+                ),
+                battery: Some(battery),
+            };
+            accountant.update(flow, start + SECOND * second as u32);
+
+            if second == 59 {
+                assert_eq!(None, accountant.last_minute());
+            }
+        }
+
+        // 3.6 W for a minute is 60 mWh
+        let last_minute = accountant.last_minute().unwrap();
+        assert_eq!(
+            600,
+            Energy::decimilliwatt_hours(last_minute.harvested),
+            "{last_minute:?}"
+        );
+        assert_eq!(0, last_minute.charged);
+        assert_eq!(150, Energy::decimilliwatt_hours(last_minute.discharged));
+        assert_eq!(750, Energy::decimilliwatt_hours(last_minute.consumed));
+
+        // the energy up to the 180th second belongs to the next orbit
+        let orbit = accountant.last_orbit().unwrap();
+        assert_eq!(1790, Energy::decimilliwatt_hours(orbit.harvested));
+        // the second between 60 and 61 is the average of 1.8 W and -0.9 W
+        assert_eq!(60 * 1_800_000 + 450_000, orbit.charged);
+        assert_eq!(118 * 900_000, orbit.discharged);
+        assert_eq!(
+            orbit.discharged + 900_000,
+            accountant.since_boot().discharged
+        );
+    }
+
+    #[test]
+    fn test_gaps_are_not_integrated() {
+        let start = Instant::from_secs(0);
+        let mut accountant = EnergyAccountant::new(EnergyConfig::LEO, start);
+        let flow = PowerFlow {
+            solar: Some(1000),
+            battery: None,
+        };
+
+        accountant.update(flow, start);
+        accountant.update(flow, start + SECOND * 2);
+        // a gap longer than the max interval
+        accountant.update(flow, start + SECOND * 30);
+        accountant.update(flow, start + SECOND * 31);
+
+        // 1 W for 3 seconds
+        assert_eq!(3_000_000, accountant.since_boot().harvested);
+        assert_eq!(0, accountant.since_boot().charged);
+        assert_eq!(0, accountant.since_boot().consumed);
+
+        // 5 minutes without measurements
+        accountant.update(flow, start + SECOND * 331);
+        assert_eq!(Some(Energy::ZERO), accountant.last_minute());
+    }
+}
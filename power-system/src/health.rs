@@ -0,0 +1,764 @@
+//! Battery ageing - internal resistance, cycle counting and the State of Health.
+//!
+//! - The internal resistance is estimated from the battery voltage step when
+//!   a load with a known current switches on or off (`R = ΔV / ΔI`).
+//! - The charge and discharge cycles are counted with [`Rainflow`] over the State of Charge.
+//!
+//! Both of them wear the battery, the State of Health is `100%` for a new battery
+//! and [`HealthConfig::END_OF_LIFE_PERCENT`] at its end of life, i.e. either after its
+//! rated cycles or when the internal resistance reaches the end of life resistance.
+//! The [`HealthRecord`] keeps them across reboots and the [`HealthStorage`] stores it
+//! in flash, so that it survives a brownout or a power loss too.
+use core::cell::Cell;
+
+use defmt::Format;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::{
+    battery::Battery,
+    helper::{
+        filter::{ExponentialMovingAverage, Filter},
+        rainflow::Rainflow,
+    },
+};
+
+/// A load with a known current switched on (positive) or off (negative), e.g. the radio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadChange {
+    /// The change of the battery current in milliamps
+    pub milliamps: i32,
+    pub at: Instant,
+}
+
+/// The tasks switching known loads add the [`LoadChange`]s here for the battery task.
+pub static LOAD_CHANGES: LoadChanges = LoadChanges::new();
+
+/// The [`LoadChange`]s since the battery task last took them, added up.
+///
+/// Unlike a queue it can't overflow and lose a change, and the battery task only needs
+/// the total step between two readings anyway (see [`BatteryHealth::load_changed`]).
+pub struct LoadChanges {
+    pending: Mutex<CriticalSectionRawMutex, Cell<Option<LoadChange>>>,
+}
+
+impl LoadChanges {
+    pub const fn new() -> Self {
+        Self {
+            pending: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    pub fn add(&self, change: LoadChange) {
+        self.pending.lock(|pending| {
+            let total = match pending.get() {
+                Some(previous) => LoadChange {
+                    milliamps: previous.milliamps.saturating_add(change.milliamps),
+                    at: previous.at.max(change.at),
+                },
+                None => change,
+            };
+            pending.set(Some(total));
+        });
+    }
+
+    /// The sum of the changes since the last call, `None` without any
+    pub fn take(&self) -> Option<LoadChange> {
+        self.pending.lock(|pending| pending.take())
+    }
+}
+
+impl Default for LoadChanges {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthConfig {
+    /// The internal resistance of the new battery pack (in mΩ)
+    pub new_milliohms: u16,
+    /// The internal resistance of the battery pack at its end of life (in mΩ)
+    pub end_of_life_milliohms: u16,
+    /// The number of equivalent full cycles until the end of life
+    pub rated_cycles: u32,
+    /// Smaller current steps (in mA) give voltage steps within the ADC noise
+    pub min_step_milliamps: u32,
+    /// The longest time between the readings before and after a load change,
+    /// otherwise the State of Charge changes the voltage too
+    pub max_step_interval: Duration,
+    /// Smaller changes of the State of Charge (in ‰) are not counted as cycles
+    pub cycle_hysteresis: u16,
+}
+
+impl HealthConfig {
+    /// The State of Health at the end of life, i.e. `80%` of the capacity is left
+    pub const END_OF_LIFE_PERCENT: u8 = 80;
+
+    /// The internal resistance doubles by the end of life of the cells
+    pub fn for_battery(battery: &Battery, cell_milliohms: u16) -> Self {
+        let new_milliohms = cell_milliohms.saturating_mul(battery.series_cells as u16);
+
+        Self {
+            new_milliohms,
+            end_of_life_milliohms: new_milliohms.saturating_mul(2),
+            rated_cycles: battery.chemistry.rated_cycles(),
+            min_step_milliamps: 50,
+            max_step_interval: Duration::from_secs(5),
+            cycle_hysteresis: 20,
+        }
+    }
+}
+
+/// What's kept across reboots.
+///
+/// The cycles which were still in progress are lost, which is at most a cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct HealthRecord {
+    /// `None` until the first estimate
+    pub resistance_milliohms: Option<u16>,
+    /// Equivalent full cycles in per-mille
+    pub cycles_permille: u32,
+}
+
+impl HealthRecord {
+    /// A new battery
+    pub const NEW: HealthRecord = HealthRecord {
+        resistance_milliohms: None,
+        cycles_permille: 0,
+    };
+
+    /// The length of [`HealthRecord::to_bytes`]
+    pub const LEN: usize = 10;
+    const MAGIC: [u8; 2] = *b"SH";
+    const VERSION: u8 = 1;
+
+    /// Magic, version, resistance (`0` when unknown), cycles (both little-endian) and checksum.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0..2].copy_from_slice(&Self::MAGIC);
+        bytes[2] = Self::VERSION;
+        bytes[3..5].copy_from_slice(&self.resistance_milliohms.unwrap_or(0).to_le_bytes());
+        bytes[5..9].copy_from_slice(&self.cycles_permille.to_le_bytes());
+        bytes[9] = checksum(&bytes[..9]);
+
+        bytes
+    }
+
+    /// Returns `None` for anything else than a record written by [`HealthRecord::to_bytes`],
+    /// e.g. uninitialized memory after a power loss.
+    pub fn from_bytes(bytes: &[u8; Self::LEN]) -> Option<Self> {
+        if bytes[0..2] != Self::MAGIC
+            || bytes[2] != Self::VERSION
+            || bytes[9] != checksum(&bytes[..9])
+        {
+            return None;
+        }
+
+        let resistance_milliohms = u16::from_le_bytes([bytes[3], bytes[4]]);
+        let cycles_permille = u32::from_le_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]);
+
+        Some(Self {
+            resistance_milliohms: (resistance_milliohms != 0).then_some(resistance_milliohms),
+            cycles_permille,
+        })
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0_u8, |checksum, byte| checksum.rotate_left(1) ^ byte)
+}
+
+/// A [`HealthStorage`] slot - the record, the sequence (little-endian),
+/// the slot's checksum and a padding byte
+const SLOT_LEN: usize = 16;
+
+/// The [`HealthRecord`]s in a flash partition of at least 2 sectors.
+///
+/// Each record is appended to the next slot with a sequence number and the valid one
+/// with the highest sequence is the latest. A sector is erased only when the slots reach
+/// it, so the other one keeps the last records and a power loss while saving
+/// loses at most the record being saved.
+pub struct HealthStorage<F> {
+    flash: F,
+    /// The start of the partition, aligned to the erase size
+    offset: u32,
+    slots: u32,
+    /// The slot and the sequence of the latest record
+    latest: Option<(u32, u32)>,
+}
+
+impl<F: NorFlash> HealthStorage<F> {
+    pub fn new(flash: F, offset: u32, len: u32) -> Self {
+        let sector = F::ERASE_SIZE as u32;
+        assert!(
+            offset % sector == 0 && len >= 2 * sector,
+            "The health partition should be at least 2 aligned sectors"
+        );
+
+        Self {
+            flash,
+            offset,
+            slots: len / sector * sector / SLOT_LEN as u32,
+            latest: None,
+        }
+    }
+
+    /// Reads the latest record, it has to be called before [`HealthStorage::save`].
+    ///
+    /// Returns `None` for a new (erased) or corrupted partition.
+    pub fn load(&mut self) -> Result<Option<HealthRecord>, F::Error> {
+        self.latest = None;
+        let mut latest = None;
+
+        for slot in 0..self.slots {
+            let mut bytes = [0; SLOT_LEN];
+            self.flash.read(self.address(slot), &mut bytes)?;
+            let (record, sequence) = match Self::decode(&bytes) {
+                Some(decoded) => decoded,
+                None => continue,
+            };
+            let newer = match self.latest {
+                Some((_, latest_sequence)) => sequence > latest_sequence,
+                None => true,
+            };
+            if newer {
+                self.latest = Some((slot, sequence));
+                latest = Some(record);
+            }
+        }
+
+        Ok(latest)
+    }
+
+    /// Appends the record after the latest one.
+    pub fn save(&mut self, record: &HealthRecord) -> Result<(), F::Error> {
+        let (mut slot, sequence) = match self.latest {
+            Some((slot, sequence)) => ((slot + 1) % self.slots, sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+
+        // a slot which isn't erased, e.g. written partially before a power loss, is skipped
+        loop {
+            let address = self.address(slot);
+            if address % F::ERASE_SIZE as u32 == 0 {
+                self.flash.erase(address, address + F::ERASE_SIZE as u32)?;
+                break;
+            }
+            let mut bytes = [0; SLOT_LEN];
+            self.flash.read(address, &mut bytes)?;
+            if bytes.iter().all(|byte| *byte == 0xFF) {
+                break;
+            }
+            slot = (slot + 1) % self.slots;
+        }
+
+        let mut bytes = [0xFF; SLOT_LEN];
+        bytes[..HealthRecord::LEN].copy_from_slice(&record.to_bytes());
+        bytes[10..14].copy_from_slice(&sequence.to_le_bytes());
+        bytes[14] = checksum(&bytes[..14]);
+        self.flash.write(self.address(slot), &bytes)?;
+        self.latest = Some((slot, sequence));
+
+        Ok(())
+    }
+
+    fn address(&self, slot: u32) -> u32 {
+        self.offset + slot * SLOT_LEN as u32
+    }
+
+    fn decode(bytes: &[u8; SLOT_LEN]) -> Option<(HealthRecord, u32)> {
+        if bytes[14] != checksum(&bytes[..14]) {
+            return None;
+        }
+        let mut record = [0; HealthRecord::LEN];
+        record.copy_from_slice(&bytes[..HealthRecord::LEN]);
+        let sequence = u32::from_le_bytes([bytes[10], bytes[11], bytes[12], bytes[13]]);
+
+        Some((HealthRecord::from_bytes(&record)?, sequence))
+    }
+}
+
+/// The number of State of Charge reversals which don't form a cycle yet
+pub const CYCLE_REVERSALS: usize = 32;
+
+/// A load change waiting for the first reading after it
+#[derive(Debug, Clone, Copy)]
+struct PendingStep {
+    change: LoadChange,
+    before_at: Instant,
+    before_millivolts: u32,
+}
+
+/// Estimates the ageing of the battery from its readings.
+#[derive(Debug, Clone)]
+pub struct BatteryHealth {
+    config: HealthConfig,
+    /// Smooths the estimates of the internal resistance
+    resistance: ExponentialMovingAverage,
+    resistance_milliohms: Option<u16>,
+    last_reading: Option<(Instant, u32)>,
+    pending_step: Option<PendingStep>,
+    cycles: Rainflow<CYCLE_REVERSALS>,
+}
+
+impl BatteryHealth {
+    pub fn new(config: HealthConfig) -> Self {
+        Self::restore(config, HealthRecord::NEW)
+    }
+
+    /// Continues from the record kept before the reboot.
+    pub fn restore(config: HealthConfig, record: HealthRecord) -> Self {
+        let mut resistance = ExponentialMovingAverage::new(2);
+        let resistance_milliohms = record
+            .resistance_milliohms
+            .and_then(|milliohms| resistance.update(milliohms));
+
+        Self {
+            config,
+            resistance,
+            resistance_milliohms,
+            last_reading: None,
+            pending_step: None,
+            cycles: Rainflow::with_cycles(config.cycle_hysteresis, record.cycles_permille),
+        }
+    }
+
+    pub fn config(&self) -> &HealthConfig {
+        &self.config
+    }
+
+    /// A known load switched, the voltage step is measured with the next reading.
+    ///
+    /// It's ignored without a reading before the change.
+    /// Multiple changes between two readings add up to a single step.
+    pub fn load_changed(&mut self, change: LoadChange) {
+        self.pending_step = match (self.pending_step, self.last_reading) {
+            (Some(pending), _) => Some(PendingStep {
+                change: LoadChange {
+                    milliamps: pending.change.milliamps.saturating_add(change.milliamps),
+                    at: pending.change.at.max(change.at),
+                },
+                ..pending
+            }),
+            (None, Some((before_at, before_millivolts))) if before_at <= change.at => {
+                Some(PendingStep {
+                    change,
+                    before_at,
+                    before_millivolts,
+                })
+            }
+            _ => None,
+        };
+    }
+
+    /// Feeds a battery reading - the unfiltered voltage (in mV), since filtering smooths
+    /// the voltage step, and the State of Charge (in per-mille).
+    ///
+    /// Returns the new internal resistance (in mΩ) when the reading completes a voltage step.
+    pub fn update(&mut self, millivolts: u32, permille: u16, taken_at: Instant) -> Option<u16> {
+        self.cycles.update(permille);
+        self.last_reading = Some((taken_at, millivolts));
+
+        let step = self.pending_step?;
+        if taken_at <= step.change.at {
+            return None;
+        }
+        self.pending_step = None;
+
+        let interval = taken_at
+            .checked_duration_since(step.before_at)
+            .unwrap_or(Duration::from_ticks(0));
+        if interval > self.config.max_step_interval
+            || step.change.milliamps.unsigned_abs() < self.config.min_step_milliamps
+        {
+            return None;
+        }
+
+        // switching a load on drops the voltage and switching it off raises it
+        let drop = step.before_millivolts as i64 - millivolts as i64;
+        let milliohms = drop * 1000 / step.change.milliamps as i64;
+        if milliohms <= 0 {
+            return None;
+        }
+
+        self.resistance_milliohms = self
+            .resistance
+            .update(milliohms.min(u16::MAX as i64) as u16);
+
+        self.resistance_milliohms
+    }
+
+    /// The smoothed internal resistance (in mΩ), `None` until the first estimate
+    pub fn resistance_milliohms(&self) -> Option<u16> {
+        self.resistance_milliohms
+    }
+
+    /// The equivalent full cycles in per-mille
+    pub fn cycles_permille(&self) -> u32 {
+        self.cycles.cycles_permille()
+    }
+
+    /// The State of Health in percentage, from `100%` for a new battery
+    /// down to [`HealthConfig::END_OF_LIFE_PERCENT`] at its end of life and below after it.
+    ///
+    /// The worse of the cycle and resistance wear is used.
+    pub fn state_of_health(&self) -> u8 {
+        let cycle_wear = self.cycles_permille() / self.config.rated_cycles.max(1);
+
+        let resistance_wear = match self.resistance_milliohms {
+            Some(milliohms) => {
+                let increase = milliohms.saturating_sub(self.config.new_milliohms) as u32;
+                let end_of_life_increase = self
+                    .config
+                    .end_of_life_milliohms
+                    .saturating_sub(self.config.new_milliohms)
+                    .max(1) as u32;
+
+                increase * 1000 / end_of_life_increase
+            }
+            None => 0,
+        };
+
+        // in per-mille of the life
+        let wear = cycle_wear.max(resistance_wear);
+        let fade = (100 - HealthConfig::END_OF_LIFE_PERCENT) as u32 * wear;
+
+        // rounds to the nearest
+        100_u32.saturating_sub((fade + 500) / 1000) as u8
+    }
+
+    /// What should be kept across reboots.
+    pub fn record(&self) -> HealthRecord {
+        HealthRecord {
+            resistance_milliohms: self.resistance_milliohms,
+            cycles_permille: self.cycles_permille(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn config() -> HealthConfig {
+        HealthConfig::for_battery(&Battery::OLIMEX_LIPO_250MAH, 200)
+    }
+
+    #[test]
+    fn test_config_for_battery() {
+        let config = HealthConfig::for_battery(&Battery::LIPO_2S, 150);
+
+        assert_eq!(300, config.new_milliohms);
+        assert_eq!(600, config.end_of_life_milliohms);
+        assert_eq!(500, config.rated_cycles);
+        assert_eq!(
+            2000,
+            HealthConfig::for_battery(&Battery::LIFEPO4_1S, 50).rated_cycles
+        );
+    }
+
+    #[test]
+    fn test_resistance_from_load_steps() {
+        let mut health = BatteryHealth::new(config());
+        let start = Instant::from_secs(0);
+
+        // a load change before the first reading is ignored
+        health.load_changed(LoadChange {
+            milliamps: 100,
+            at: start,
+        });
+        assert_eq!(None, health.update(3900, 800, start + SECOND));
+
+        // a 120 mA load (e.g. the radio) with a 300 mOhm battery: 36 mV step
+        let trace = [
+            (3900, None),
+            (3864, Some(120)),
+            (3864, None),
+            (3900, Some(-120)),
+            (3900, None),
+        ];
+        let mut estimates = std::vec::Vec::new();
+        for (second, (millivolts, load)) in trace.into_iter().enumerate() {
+            let taken_at = start + SECOND * (second as u32 + 2);
+            if let Some(milliamps) = load {
+                // switched between the previous and this reading
+                health.load_changed(LoadChange {
+                    milliamps,
+                    at: taken_at - SECOND / 2,
+                });
+            }
+
+            estimates.extend(health.update(millivolts, 800, taken_at));
+        }
+        assert_eq!(std::vec![300, 300], estimates);
+        assert_eq!(Some(300), health.resistance_milliohms());
+
+        // a voltage step in the wrong direction (e.g. the charger turned on) is ignored,
+        // and so are small current steps
+        let at = start + SECOND * 10;
+        health.load_changed(LoadChange {
+            milliamps: 120,
+            at: at - SECOND / 2,
+        });
+        assert_eq!(None, health.update(4000, 800, at));
+        health.load_changed(LoadChange {
+            milliamps: 10,
+            at: at + SECOND / 2,
+        });
+        assert_eq!(None, health.update(3990, 800, at + SECOND));
+
+        // the reading after the change is too late, the State of Charge changed meanwhile
+        health.load_changed(LoadChange {
+            milliamps: 120,
+            at: at + SECOND * 2,
+        });
+        assert_eq!(None, health.update(3900, 800, at + SECOND * 30));
+        assert_eq!(Some(300), health.resistance_milliohms());
+    }
+
+    #[test]
+    fn test_load_changes_add_up() {
+        let changes = LoadChanges::new();
+        assert_eq!(None, changes.take());
+
+        let start = Instant::from_secs(0);
+        for (milliamps, second) in [(120, 1), (80, 3), (-120, 2)] {
+            changes.add(LoadChange {
+                milliamps,
+                at: start + SECOND * second,
+            });
+        }
+        assert_eq!(
+            Some(LoadChange {
+                milliamps: 80,
+                at: start + SECOND * 3,
+            }),
+            changes.take()
+        );
+        assert_eq!(None, changes.take());
+    }
+
+    #[test]
+    fn test_load_changes_between_readings_add_up() {
+        let mut health = BatteryHealth::new(config());
+        let start = Instant::from_secs(0);
+        assert_eq!(None, health.update(3900, 800, start));
+
+        // the radio (120 mA) and the payload (80 mA) switched on before the next reading:
+        // a 60 mV step of a 300 mOhm battery
+        health.load_changed(LoadChange {
+            milliamps: 120,
+            at: start + SECOND / 4,
+        });
+        health.load_changed(LoadChange {
+            milliamps: 80,
+            at: start + SECOND / 2,
+        });
+        assert_eq!(Some(300), health.update(3840, 800, start + SECOND));
+
+        // switching the radio on and off again before the next reading is no step at all
+        health.load_changed(LoadChange {
+            milliamps: 120,
+            at: start + SECOND * 5 / 4,
+        });
+        health.load_changed(LoadChange {
+            milliamps: -120,
+            at: start + SECOND * 3 / 2,
+        });
+        assert_eq!(None, health.update(3840, 800, start + SECOND * 2));
+        assert_eq!(Some(300), health.resistance_milliohms());
+    }
+
+    #[test]
+    fn test_state_of_health() {
+        let mut health = BatteryHealth::new(config());
+        assert_eq!(100, health.state_of_health());
+
+        // 125 orbits of a full cycle, a quarter of the rated 500 cycles
+        let mut now = Instant::from_secs(0);
+        for _ in 0..125 {
+            for permille in (0..1000).step_by(50).chain((50..=1000).rev().step_by(50)) {
+                health.update(3800, permille, now);
+                now += SECOND;
+            }
+        }
+        let cycles = health.cycles_permille();
+        assert!((124_000..=125_000).contains(&cycles), "{cycles}");
+        assert_eq!(95, health.state_of_health());
+
+        // half way from 200 to the end of life 400 mOhm is worse than the cycles
+        let worn = BatteryHealth::restore(
+            config(),
+            HealthRecord {
+                resistance_milliohms: Some(300),
+                cycles_permille: cycles,
+            },
+        );
+        assert_eq!(90, worn.state_of_health());
+
+        // past the end of life
+        let dead = BatteryHealth::restore(
+            config(),
+            HealthRecord {
+                resistance_milliohms: Some(2000),
+                cycles_permille: 0,
+            },
+        );
+        assert_eq!(0, dead.state_of_health());
+    }
+
+    #[test]
+    fn test_record_survives_reboot() {
+        let mut health = BatteryHealth::new(config());
+        let start = Instant::from_secs(0);
+        health.update(3900, 900, start);
+        health.load_changed(LoadChange {
+            milliamps: 100,
+            at: start,
+        });
+        health.update(3880, 100, start + SECOND);
+        health.update(3900, 900, start + SECOND * 2);
+        health.update(3900, 500, start + SECOND * 3);
+
+        let record = health.record();
+        assert_eq!(
+            HealthRecord {
+                resistance_milliohms: Some(200),
+                cycles_permille: 400,
+            },
+            record
+        );
+
+        let bytes = record.to_bytes();
+        assert_eq!(Some(record), HealthRecord::from_bytes(&bytes));
+        let restored = BatteryHealth::restore(config(), record);
+        assert_eq!(record, restored.record());
+        assert_eq!(health.state_of_health(), restored.state_of_health());
+
+        assert_eq!(
+            Some(HealthRecord::NEW),
+            HealthRecord::from_bytes(&HealthRecord::NEW.to_bytes())
+        );
+
+        // uninitialized or corrupted memory
+        assert_eq!(None, HealthRecord::from_bytes(&[0; HealthRecord::LEN]));
+        let mut corrupted = bytes;
+        corrupted[6] ^= 0x01;
+        assert_eq!(None, HealthRecord::from_bytes(&corrupted));
+    }
+
+    /// A NOR flash of 2 small sectors, a write can only clear bits
+    struct FakeFlash {
+        bytes: std::vec::Vec<u8>,
+        erases: usize,
+    }
+
+    impl FakeFlash {
+        fn erased() -> Self {
+            Self {
+                bytes: std::vec![0xFF; 2 * Self::ERASE_SIZE],
+                erases: 0,
+            }
+        }
+    }
+
+    impl ErrorType for FakeFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for FakeFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            let stored = self
+                .bytes
+                .get(offset..offset + bytes.len())
+                .ok_or(NorFlashErrorKind::OutOfBounds)?;
+            bytes.copy_from_slice(stored);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.bytes.len()
+        }
+    }
+
+    impl NorFlash for FakeFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 128;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            if from as usize % Self::ERASE_SIZE != 0 || to as usize % Self::ERASE_SIZE != 0 {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            self.bytes[from as usize..to as usize].fill(0xFF);
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            if offset as usize % Self::WRITE_SIZE != 0 || bytes.len() % Self::WRITE_SIZE != 0 {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            for (stored, byte) in self.bytes[offset as usize..].iter_mut().zip(bytes) {
+                *stored &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    fn record(cycles_permille: u32) -> HealthRecord {
+        HealthRecord {
+            resistance_milliohms: Some(210),
+            cycles_permille,
+        }
+    }
+
+    #[test]
+    fn test_storage_survives_power_loss() {
+        let mut storage = HealthStorage::new(FakeFlash::erased(), 0, 256);
+        assert_eq!(Ok(None), storage.load());
+        storage.save(&record(1)).unwrap();
+        storage.save(&record(2)).unwrap();
+
+        // after a power loss
+        let mut storage = HealthStorage::new(storage.flash, 0, 256);
+        assert_eq!(Ok(Some(record(2))), storage.load());
+
+        // 8 slots per sector, the first sector is erased again by the 17th record
+        // while the second one keeps the last records
+        for cycles_permille in 3..=17 {
+            storage.save(&record(cycles_permille)).unwrap();
+        }
+        assert_eq!(3, storage.flash.erases);
+        assert_eq!(Ok(Some(record(17))), storage.load());
+        assert_eq!(
+            Some((record(16), 15)),
+            HealthStorage::<FakeFlash>::decode(storage.flash.bytes[240..256].try_into().unwrap())
+        );
+
+        // a record written partially before a power loss is skipped
+        storage.flash.bytes[16..20].fill(0x00);
+        let mut storage = HealthStorage::new(storage.flash, 0, 256);
+        assert_eq!(Ok(Some(record(17))), storage.load());
+        storage.save(&record(18)).unwrap();
+        assert_eq!(Ok(Some(record(18))), storage.load());
+        assert_eq!(
+            Some((record(18), 17)),
+            HealthStorage::<FakeFlash>::decode(storage.flash.bytes[32..48].try_into().unwrap())
+        );
+
+        // a corrupted latest record falls back to the previous one
+        storage.flash.bytes[33] ^= 0x01;
+        assert_eq!(Ok(Some(record(17))), storage.load());
+    }
+}
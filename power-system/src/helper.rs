@@ -1,5 +1,9 @@
 use heapless::Vec;
 
+pub mod filter;
+pub mod rainflow;
+pub mod sliding_median;
+
 /// Calculates the mediana by first sorting the [`Vec`],
 /// and calculates it based on an even or odd number of records.
 pub fn find_mediana<const N: usize>(measurements: &mut Vec<u16, N>) -> u16 {
@@ -30,10 +34,94 @@ pub fn find_mediana<const N: usize>(measurements: &mut Vec<u16, N>) -> u16 {
     mediana
 }
 
+/// A reduced fraction which can be used instead of a `f32` on the FPU-less ESP32-C3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ratio {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+impl Ratio {
+    /// Creates a new reduced fraction, can be used in a `const` context.
+    ///
+    /// # Panics
+    ///
+    /// When the denominator is `0`.
+    pub const fn new(numerator: u32, denominator: u32) -> Self {
+        assert!(denominator != 0, "Denominator should not be 0");
+
+        let divisor = gcd(numerator, denominator);
+
+        Self {
+            numerator: numerator / divisor,
+            denominator: denominator / divisor,
+        }
+    }
+
+    /// Multiplies the value by the ratio, rounding to the nearest integer.
+    pub const fn multiply(&self, value: u32) -> u32 {
+        let numerator = value as u64 * self.numerator as u64;
+
+        ((numerator + self.denominator as u64 / 2) / self.denominator as u64) as u32
+    }
+
+    /// Divides the value by the ratio, rounding to the nearest integer.
+    ///
+    /// # Panics
+    ///
+    /// When the numerator is `0`.
+    pub const fn divide(&self, value: u32) -> u32 {
+        Self::new(self.denominator, self.numerator).multiply(value)
+    }
+}
+
+/// The natural logarithm for `no_std`, within `1e-6` (relative) of `f32::ln`.
+///
+/// Returns `NaN` for non-positive values.
+pub fn ln(value: f32) -> f32 {
+    if value.is_nan() || value <= 0.0 {
+        return f32::NAN;
+    }
+
+    // value = mantissa * 2^exponent with the mantissa in [1, 2)
+    let bits = value.to_bits();
+    let (bits, exponent) = if bits >> 23 == 0 {
+        // subnormal, normalize it first
+        let normalized = (value * (1_u32 << 23) as f32).to_bits();
+        (normalized, ((normalized >> 23) as i32) - 127 - 23)
+    } else {
+        (bits, ((bits >> 23) as i32) - 127)
+    };
+    let mantissa = f32::from_bits((bits & 0x007F_FFFF) | 0x3F80_0000);
+
+    // ln(m) = 2 * atanh((m - 1) / (m + 1)) with |z| <= 1/3
+    let z = (mantissa - 1.0) / (mantissa + 1.0);
+    let z2 = z * z;
+    let series = z * (1.0 + z2 * (1.0 / 3.0 + z2 * (1.0 / 5.0 + z2 * (1.0 / 7.0 + z2 / 9.0))));
+
+    exponent as f32 * core::f32::consts::LN_2 + 2.0 * series
+}
+
+/// The greatest common divisor
+pub const fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        let remainder = a % b;
+        a = b;
+        b = remainder;
+    }
+
+    if a == 0 {
+        1
+    } else {
+        a
+    }
+}
 
 #[cfg(test)]
 mod test {
-    use supper::find_mediana;
+    use heapless::Vec;
+
+    use super::{find_mediana, ln, Ratio};
 
     #[test]
     fn test_find_mediana() {
@@ -43,13 +131,12 @@ mod test {
         {
             let measurements = [78_u16, 99, 25, 1, 46];
 
-            let mut mediana_records =
-                Vec::<u16, MEASUREMENTS>::from_slice(&measurements).unwrap();
+            let mut mediana_records = Vec::<u16, MEASUREMENTS>::from_slice(&measurements).unwrap();
             let mediana = find_mediana(&mut mediana_records);
 
             // assert expected sorting
             {
-                let expected_sorted = [1_u16, 25, 46, 78, 9];
+                let expected_sorted = [1_u16, 25, 46, 78, 99];
                 assert_eq!(mediana_records, expected_sorted);
             }
 
@@ -72,11 +159,43 @@ mod test {
             // 1, 15, 18, 39
             let measurements = [18_u16, 39, 15, 1];
 
-            let mut mediana_records =
-                Vec::<u16, MEASUREMENTS>::from_slice(&measurements).unwrap();
+            let mut mediana_records = Vec::<u16, MEASUREMENTS>::from_slice(&measurements).unwrap();
             let mediana = find_mediana(&mut mediana_records);
 
-            assert_eq!(mediana, 11);
+            assert_eq!(mediana, 16);
+        }
+    }
+
+    #[test]
+    fn test_ratio() {
+        const HALF: Ratio = Ratio::new(470, 940);
+        assert_eq!(Ratio::new(1, 2), HALF);
+
+        assert_eq!(1650, HALF.multiply(3300));
+        // rounds to the nearest
+        assert_eq!(2, HALF.multiply(3));
+        assert_eq!(6600, HALF.divide(3300));
+
+        let thirds = Ratio::new(10_000, 30_000);
+        assert_eq!(Ratio::new(1, 3), thirds);
+        assert_eq!(1100, thirds.multiply(3300));
+        assert_eq!(3300, thirds.divide(1100));
+
+        assert_eq!(Ratio::new(0, 1), Ratio::new(0, 5));
+    }
+
+    #[test]
+    fn test_ln() {
+        for value in [
+            1e-30_f32, 1e-3, 0.5, 1.0, 2.0, 3.3, 10_000.0, 123_456.7, 3e30,
+        ] {
+            let expected = value.ln();
+            let error = (ln(value) - expected).abs();
+            assert!(error <= 1e-6 * expected.abs().max(1.0), "ln({value})");
         }
+
+        assert_eq!(0.0, ln(1.0));
+        assert!(ln(0.0).is_nan());
+        assert!(ln(-1.0).is_nan());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,475 @@
+//! Composable filters for streams of ADC values.
+//!
+//! All the filters are `no_std` and use fixed-size `heapless` buffers.
+//! They can be chained with [`Filter::then`], e.g. rejecting outliers
+//! before smoothing the values:
+//!
+//! ```ignore
+//! let filter = MadOutlierRejection::<9>::new(Ratio::new(3, 1), 4)
+//!     .then(ExponentialMovingAverage::new(2));
+//! ```
+use heapless::{Deque, Vec};
+
+use super::{find_mediana, Ratio};
+
+/// A filter over a stream of values, e.g. the ADC values of the battery measurements.
+pub trait Filter {
+    /// Feeds the next value to the filter and returns the filtered one.
+    ///
+    /// Returns `None` if the value was rejected, e.g. as an outlier.
+    fn update(&mut self, value: u16) -> Option<u16>;
+
+    /// Forgets all the previous values.
+    fn reset(&mut self);
+
+    /// Chains another filter which is fed with the values of this one.
+    fn then<F: Filter>(self, next: F) -> Chain<Self, F>
+    where
+        Self: Sized,
+    {
+        Chain {
+            first: self,
+            second: next,
+        }
+    }
+}
+
+/// Passes all the values through unchanged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Passthrough;
+
+impl Filter for Passthrough {
+    fn update(&mut self, value: u16) -> Option<u16> {
+        Some(value)
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// Two filters one after the other, see [`Filter::then`].
+#[derive(Debug, Clone)]
+pub struct Chain<A, B> {
+    pub first: A,
+    pub second: B,
+}
+
+impl<A: Filter, B: Filter> Filter for Chain<A, B> {
+    fn update(&mut self, value: u16) -> Option<u16> {
+        self.first
+            .update(value)
+            .and_then(|value| self.second.update(value))
+    }
+
+    fn reset(&mut self) {
+        self.first.reset();
+        self.second.reset();
+    }
+}
+
+/// The average of the last `N` values.
+#[derive(Debug, Clone)]
+pub struct MovingAverage<const N: usize> {
+    window: Deque<u16, N>,
+    sum: u32,
+}
+
+impl<const N: usize> MovingAverage<N> {
+    pub fn new() -> Self {
+        Self {
+            window: Deque::new(),
+            sum: 0,
+        }
+    }
+}
+
+impl<const N: usize> Default for MovingAverage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Filter for MovingAverage<N> {
+    /// Until there are `N` values, it's the average of all of them.
+    fn update(&mut self, value: u16) -> Option<u16> {
+        if self.window.is_full() {
+            let oldest = self.window.pop_front().expect("Window should be full");
+            self.sum -= oldest as u32;
+        }
+        self.window.push_back(value).expect("Should have space");
+        self.sum += value as u32;
+
+        let len = self.window.len() as u32;
+
+        // rounds to the nearest
+        Some(((self.sum + len / 2) / len) as u16)
+    }
+
+    fn reset(&mut self) {
+        self.window.clear();
+        self.sum = 0;
+    }
+}
+
+/// An exponential moving average with a smoothing factor of `1 / 2^shift`
+/// using only integer arithmetic.
+///
+/// The larger the `shift`, the smoother (and slower) the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExponentialMovingAverage {
+    shift: u8,
+    /// The average in Q16.16 fixed-point, `None` until the first value
+    average: Option<u32>,
+}
+
+impl ExponentialMovingAverage {
+    const FRACTION_BITS: u32 = 16;
+
+    /// # Panics
+    ///
+    /// When `shift` is larger than `15`.
+    pub const fn new(shift: u8) -> Self {
+        assert!(shift < 16, "Shift should be less than 16");
+
+        Self {
+            shift,
+            average: None,
+        }
+    }
+}
+
+impl Filter for ExponentialMovingAverage {
+    /// The first value is used as the initial average.
+    fn update(&mut self, value: u16) -> Option<u16> {
+        let value = (value as u32) << Self::FRACTION_BITS;
+
+        let average = match self.average {
+            Some(average) => {
+                let difference = (value as i64 - average as i64) >> self.shift;
+
+                (average as i64 + difference) as u32
+            }
+            None => value,
+        };
+        self.average = Some(average);
+
+        // rounds to the nearest
+        Some(((average + (1 << (Self::FRACTION_BITS - 1))) >> Self::FRACTION_BITS) as u16)
+    }
+
+    fn reset(&mut self) {
+        self.average = None;
+    }
+}
+
+/// The mediana of the last `N` values.
+#[derive(Debug, Clone)]
+pub struct RunningMedian<const N: usize> {
+    window: Deque<u16, N>,
+}
+
+impl<const N: usize> RunningMedian<N> {
+    pub fn new() -> Self {
+        Self {
+            window: Deque::new(),
+        }
+    }
+}
+
+impl<const N: usize> Default for RunningMedian<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Filter for RunningMedian<N> {
+    /// Until there are `N` values, it's the mediana of all of them.
+    fn update(&mut self, value: u16) -> Option<u16> {
+        if self.window.is_full() {
+            self.window.pop_front();
+        }
+        self.window.push_back(value).expect("Should have space");
+
+        Some(mediana(&self.window))
+    }
+
+    fn reset(&mut self) {
+        self.window.clear();
+    }
+}
+
+/// Rejects outliers based on the Median Absolute Deviation (MAD) of the last `N` accepted values.
+///
+/// A value is an outlier when it's further than `threshold * MAD` from the mediana.
+/// The MAD is at least `min_deviation` so a window of identical values
+/// does not reject every small change.
+///
+/// When `N` consecutive values are rejected, the signal has changed its level
+/// (e.g. the charger was plugged in) and the window restarts from the new values.
+#[derive(Debug, Clone)]
+pub struct MadOutlierRejection<const N: usize> {
+    threshold: Ratio,
+    min_deviation: u16,
+    window: Deque<u16, N>,
+    rejected_in_row: usize,
+}
+
+impl<const N: usize> MadOutlierRejection<N> {
+    /// The minimum number of accepted values before rejecting outliers
+    pub const MIN_VALUES: usize = if N < 3 { N } else { 3 };
+
+    pub fn new(threshold: Ratio, min_deviation: u16) -> Self {
+        Self {
+            threshold,
+            min_deviation,
+            window: Deque::new(),
+            rejected_in_row: 0,
+        }
+    }
+
+    /// Whether the value is an outlier compared to the current window.
+    pub fn is_outlier(&self, value: u16) -> bool {
+        if self.window.len() < Self::MIN_VALUES {
+            return false;
+        }
+
+        let mediana = mediana(&self.window);
+        let mut deviations: Vec<u16, N> = self
+            .window
+            .iter()
+            .map(|value| value.abs_diff(mediana))
+            .collect();
+        let deviation = find_mediana(&mut deviations).max(self.min_deviation);
+
+        value.abs_diff(mediana) as u32 > self.threshold.multiply(deviation as u32)
+    }
+
+    fn accept(&mut self, value: u16) {
+        if self.window.is_full() {
+            self.window.pop_front();
+        }
+        self.window.push_back(value).expect("Should have space");
+    }
+}
+
+impl<const N: usize> Filter for MadOutlierRejection<N> {
+    fn update(&mut self, value: u16) -> Option<u16> {
+        if !self.is_outlier(value) {
+            self.rejected_in_row = 0;
+            self.accept(value);
+
+            return Some(value);
+        }
+
+        self.rejected_in_row += 1;
+        if self.rejected_in_row >= N {
+            self.reset();
+            self.accept(value);
+
+            return Some(value);
+        }
+
+        None
+    }
+
+    fn reset(&mut self) {
+        self.window.clear();
+        self.rejected_in_row = 0;
+    }
+}
+
+/// A 1-D Kalman filter for a (nearly) constant value, e.g. the battery voltage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Kalman {
+    /// How much the actual value changes between two updates (variance)
+    pub process_noise: f32,
+    /// How noisy the measured values are (variance)
+    pub measurement_noise: f32,
+    /// The current estimate and its variance, `None` until the first value
+    estimate: Option<(f32, f32)>,
+}
+
+impl Kalman {
+    pub const fn new(process_noise: f32, measurement_noise: f32) -> Self {
+        Self {
+            process_noise,
+            measurement_noise,
+            estimate: None,
+        }
+    }
+
+    /// The current estimate, `None` until the first value.
+    pub fn estimate(&self) -> Option<f32> {
+        self.estimate.map(|(estimate, _variance)| estimate)
+    }
+}
+
+impl Filter for Kalman {
+    /// The first value is used as the initial estimate with the measurement noise variance.
+    fn update(&mut self, value: u16) -> Option<u16> {
+        let measured = value as f32;
+
+        let (estimate, variance) = match self.estimate {
+            Some((estimate, variance)) => {
+                // predict
+                let variance = variance + self.process_noise;
+                // update
+                let gain = variance / (variance + self.measurement_noise);
+
+                (
+                    estimate + gain * (measured - estimate),
+                    (1.0 - gain) * variance,
+                )
+            }
+            None => (measured, self.measurement_noise),
+        };
+        self.estimate = Some((estimate, variance));
+
+        Some((estimate + 0.5) as u16)
+    }
+
+    fn reset(&mut self) {
+        self.estimate = None;
+    }
+}
+
+fn mediana<const N: usize>(window: &Deque<u16, N>) -> u16 {
+    let mut values: Vec<u16, N> = window.iter().copied().collect();
+
+    find_mediana(&mut values)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run<F: Filter>(filter: &mut F, values: &[u16]) -> std::vec::Vec<Option<u16>> {
+        values.iter().map(|value| filter.update(*value)).collect()
+    }
+
+    #[test]
+    fn test_passthrough() {
+        assert_eq!(
+            std::vec![Some(1), Some(4095), Some(0)],
+            run(&mut Passthrough, &[1, 4095, 0])
+        );
+    }
+
+    #[test]
+    fn test_moving_average() {
+        let mut filter = MovingAverage::<3>::new();
+
+        assert_eq!(
+            std::vec![Some(10), Some(15), Some(20), Some(30), Some(33)],
+            run(&mut filter, &[10, 20, 30, 40, 30])
+        );
+
+        filter.reset();
+        assert_eq!(Some(4095), filter.update(4095));
+        // no overflow of the sum
+        assert_eq!(Some(4095), MovingAverage::<32>::new().update(4095));
+    }
+
+    #[test]
+    fn test_exponential_moving_average() {
+        // alpha = 1/2
+        let mut filter = ExponentialMovingAverage::new(1);
+        assert_eq!(
+            std::vec![Some(100), Some(150), Some(175), Some(88)],
+            run(&mut filter, &[100, 200, 200, 0])
+        );
+
+        // converges to a constant value
+        let mut filter = ExponentialMovingAverage::new(3);
+        filter.update(0);
+        let last = run(&mut filter, &[2000; 200]).pop().unwrap();
+        assert_eq!(Some(2000), last);
+
+        filter.reset();
+        assert_eq!(Some(4095), filter.update(4095));
+    }
+
+    #[test]
+    fn test_running_median() {
+        let mut filter = RunningMedian::<3>::new();
+
+        assert_eq!(
+            std::vec![Some(10), Some(505), Some(30), Some(30), Some(30)],
+            run(&mut filter, &[10, 1000, 30, 20, 40])
+        );
+
+        filter.reset();
+        assert_eq!(Some(7), filter.update(7));
+    }
+
+    #[test]
+    fn test_mad_outlier_rejection() {
+        let mut filter = MadOutlierRejection::<5>::new(Ratio::new(3, 1), 2);
+
+        // accepted before there are enough values
+        assert_eq!(
+            std::vec![Some(2400), Some(2404), Some(2398)],
+            run(&mut filter, &[2400, 2404, 2398])
+        );
+
+        // mediana = 2400, MAD = 2 => 6 away is still fine
+        assert!(!filter.is_outlier(2406));
+        assert_eq!(None, filter.update(2407));
+        assert_eq!(None, filter.update(1000));
+        assert_eq!(Some(2401), filter.update(2401));
+
+        // the minimum deviation for identical values
+        let mut filter = MadOutlierRejection::<5>::new(Ratio::new(3, 1), 2);
+        run(&mut filter, &[2000; 5]);
+        assert_eq!(Some(2006), filter.update(2006));
+        assert_eq!(None, filter.update(2010));
+    }
+
+    #[test]
+    fn test_mad_outlier_rejection_level_change() {
+        let mut filter = MadOutlierRejection::<3>::new(Ratio::new(3, 1), 2);
+        run(&mut filter, &[2400, 2401, 2400]);
+
+        // the charger is plugged in and the level jumps
+        assert_eq!(
+            std::vec![None, None, Some(2600), Some(2601)],
+            run(&mut filter, &[2600, 2602, 2600, 2601])
+        );
+    }
+
+    #[test]
+    fn test_kalman() {
+        let mut filter = Kalman::new(0.01, 25.0);
+        assert_eq!(None, filter.estimate());
+        assert_eq!(Some(2400), filter.update(2400));
+
+        // noisy measurements around 2410
+        let noisy = [2415, 2405, 2420, 2400, 2412, 2408, 2418, 2402, 2410, 2410];
+        let filtered = run(&mut filter, &noisy);
+        for value in filtered.iter().skip(3) {
+            let value = value.unwrap();
+            assert!((2403..=2412).contains(&value), "{value}");
+        }
+
+        // less noisy than the measurements
+        let estimate = filter.estimate().unwrap();
+        assert!((estimate - 2410.0).abs() < 5.0, "{estimate}");
+
+        filter.reset();
+        assert_eq!(None, filter.estimate());
+    }
+
+    #[test]
+    fn test_chain() {
+        let mut filter = MadOutlierRejection::<5>::new(Ratio::new(3, 1), 2)
+            .then(MovingAverage::<2>::new())
+            .then(Passthrough);
+
+        assert_eq!(
+            std::vec![Some(2400), Some(2402), Some(2402), None, Some(2401)],
+            run(&mut filter, &[2400, 2404, 2400, 3000, 2402])
+        );
+
+        filter.reset();
+        assert_eq!(Some(100), filter.update(100));
+    }
+}
@@ -0,0 +1,214 @@
+//! Streaming rainflow cycle counting.
+//!
+//! The history of a value (e.g. the State of Charge) is reduced to its reversals
+//! (peaks and valleys) and the ranges between them are counted as full and half cycles
+//! with the three-point algorithm of ASTM E1049, without keeping the whole history.
+use heapless::Vec;
+
+/// Counts the cycles of a value in per-mille (`0..=1000`), e.g. the State of Charge.
+///
+/// The cycles are weighted by their range, i.e. a full cycle of `1000‰`
+/// is a single equivalent full cycle and a full cycle of `200‰` is `0.2` of it.
+///
+/// Keeps up to `N` reversals which don't form a cycle yet,
+/// once full the oldest range is counted as a half cycle.
+#[derive(Debug, Clone)]
+pub struct Rainflow<const N: usize> {
+    /// Changes in the direction smaller than this are noise and not reversals
+    hysteresis: u16,
+    /// The reversals which don't form a cycle yet, the first one is the starting point
+    reversals: Vec<u16, N>,
+    /// The extreme value since the last reversal and whether it's rising,
+    /// `None` until the value moves away from the last reversal
+    extreme: Option<(u16, bool)>,
+    /// The counted cycles in half per-mille of an equivalent full cycle
+    half_cycles_permille: u32,
+}
+
+impl<const N: usize> Rainflow<N> {
+    /// # Panics
+    ///
+    /// When `N` is less than `3`, the least number of reversals to find a cycle.
+    pub fn new(hysteresis: u16) -> Self {
+        Self::with_cycles(hysteresis, 0)
+    }
+
+    /// Continues counting from already counted equivalent full cycles (in per-mille),
+    /// e.g. restored after a reboot.
+    pub fn with_cycles(hysteresis: u16, cycles_permille: u32) -> Self {
+        assert!(N >= 3, "Should keep at least 3 reversals");
+
+        Self {
+            hysteresis,
+            reversals: Vec::new(),
+            extreme: None,
+            half_cycles_permille: cycles_permille.saturating_mul(2),
+        }
+    }
+
+    /// Adds the next value of the history.
+    pub fn update(&mut self, value: u16) {
+        let value = value.min(1000);
+
+        let last_reversal = match self.reversals.last() {
+            Some(last_reversal) => *last_reversal,
+            None => {
+                self.push_reversal(value);
+                return;
+            }
+        };
+
+        match self.extreme {
+            None => {
+                if value.abs_diff(last_reversal) >= self.hysteresis && value != last_reversal {
+                    self.extreme = Some((value, value > last_reversal));
+                }
+            }
+            Some((extreme, rising)) => {
+                if (rising && value >= extreme) || (!rising && value <= extreme) {
+                    self.extreme = Some((value, rising));
+                } else if value.abs_diff(extreme) >= self.hysteresis {
+                    self.push_reversal(extreme);
+                    self.extreme = Some((value, !rising));
+                }
+            }
+        }
+    }
+
+    /// The counted cycles as equivalent full cycles in per-mille,
+    /// e.g. `1500` is one and a half full cycles.
+    ///
+    /// Only the closed cycles are counted, the ones still in progress are not.
+    pub fn cycles_permille(&self) -> u32 {
+        self.half_cycles_permille / 2
+    }
+
+    /// The reversals which don't form a cycle yet.
+    pub fn reversals(&self) -> &[u16] {
+        &self.reversals
+    }
+
+    fn push_reversal(&mut self, reversal: u16) {
+        if self.reversals.is_full() {
+            // the oldest range will never be closed, count it as a half cycle
+            let range = self.reversals[0].abs_diff(self.reversals[1]);
+            self.half_cycles_permille = self.half_cycles_permille.saturating_add(range as u32);
+            self.reversals.remove(0);
+        }
+        self.reversals
+            .push(reversal)
+            .expect("Should have space after removing the oldest reversal");
+
+        self.count_cycles();
+    }
+
+    /// The three-point algorithm, `X` is the most recent range and `Y` the previous one.
+    fn count_cycles(&mut self) {
+        while self.reversals.len() >= 3 {
+            let len = self.reversals.len();
+            let x = self.reversals[len - 1].abs_diff(self.reversals[len - 2]);
+            let y = self.reversals[len - 2].abs_diff(self.reversals[len - 3]);
+
+            if x < y {
+                break;
+            }
+
+            if len == 3 {
+                // `Y` contains the starting point, it's a half cycle
+                self.half_cycles_permille = self.half_cycles_permille.saturating_add(y as u32);
+                self.reversals.remove(0);
+            } else {
+                self.half_cycles_permille = self.half_cycles_permille.saturating_add(2 * y as u32);
+                self.reversals.remove(len - 2);
+                self.reversals.remove(len - 3);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Feeds a linear ramp between each of the points, 10‰ per step.
+    fn ramps<const N: usize>(rainflow: &mut Rainflow<N>, points: &[u16]) {
+        rainflow.update(points[0]);
+        for pair in points.windows(2) {
+            let (from, to) = (pair[0] as i32, pair[1] as i32);
+            let step = if to > from { 10 } else { -10 };
+
+            let mut value = from;
+            while value != to {
+                value += step;
+                rainflow.update(value as u16);
+            }
+        }
+    }
+
+    #[test]
+    fn test_astm_example() {
+        // the example of ASTM E1049 scaled by 100 and shifted by 500:
+        // -2, 1, -3, 5, -1, 3, -4, 4, -2
+        let mut rainflow = Rainflow::<16>::new(50);
+        for value in [300, 600, 200, 1000, 400, 800, 100, 900, 300] {
+            rainflow.update(value);
+        }
+        // the last value is not yet a reversal
+        rainflow.update(1000);
+
+        // half cycles of 3, 4 and 8 and a full cycle of 4 (in units of 100‰)
+        // = (3 + 4 + 8) / 2 + 4 = 11.5
+        assert_eq!(1150, rainflow.cycles_permille());
+        // the residue: 5, -4, 4, -2
+        assert_eq!(&[1000, 100, 900, 300], rainflow.reversals());
+    }
+
+    #[test]
+    fn test_orbits_with_noise() {
+        let mut rainflow = Rainflow::<16>::new(20);
+
+        // 10 orbits charging to 900‰ in the sun and discharging to 700‰ in eclipse
+        // with noise of less than the hysteresis on each sample
+        for orbit in 0..10 {
+            for (index, value) in (700..900)
+                .step_by(10)
+                .chain((710..=900).rev().step_by(10))
+                .enumerate()
+            {
+                let noise = if index % 2 == 0 { 5 } else { 0 };
+                rainflow.update(value + noise);
+            }
+            assert!(rainflow.reversals().len() <= 3, "orbit {orbit}");
+        }
+
+        // ~10 cycles of 200‰, i.e. ~2 equivalent full cycles
+        let cycles = rainflow.cycles_permille();
+        assert!((1700..=2000).contains(&cycles), "{cycles}");
+    }
+
+    #[test]
+    fn test_half_cycles_and_restore() {
+        let mut rainflow = Rainflow::<8>::with_cycles(20, 5_000);
+
+        // half cycles of 500‰, 1000‰ and 1000‰
+        ramps(&mut rainflow, &[500, 1000, 0, 1000, 0, 500]);
+
+        assert_eq!(5_000 + 250 + 500 + 500, rainflow.cycles_permille());
+        assert_eq!(&[1000, 0], rainflow.reversals());
+    }
+
+    #[test]
+    fn test_oldest_reversal_is_counted_when_full() {
+        let mut rainflow = Rainflow::<3>::new(10);
+
+        // shrinking ranges never close a cycle
+        for value in [0, 1000, 100, 900, 200, 800] {
+            rainflow.update(value);
+        }
+        rainflow.update(500);
+
+        // the ranges of 1000‰, 900‰ and 800‰ were counted as half cycles to make space
+        assert_eq!(1350, rainflow.cycles_permille());
+        assert_eq!(&[900, 200, 800], rainflow.reversals());
+    }
+}
@@ -0,0 +1,243 @@
+//! A sliding window which answers the mediana and percentiles without sorting.
+use heapless::Deque;
+
+use crate::adc::ADC_MAX;
+
+/// The number of possible 12-bit ADC values
+const VALUES: usize = ADC_MAX as usize + 1;
+
+/// The last `N` ADC values with their mediana and percentiles in `O(log 4096)`.
+///
+/// Next to the window itself it keeps a Fenwick (binary indexed) tree with
+/// the count of each 12-bit value in the window, i.e. `8 KiB` regardless of `N`.
+/// Writing a value and finding the k-th smallest one takes 12 steps each,
+/// instead of copying and sorting the whole window like [`find_mediana`](super::find_mediana).
+#[derive(Clone)]
+pub struct SlidingMedian<const N: usize> {
+    window: Deque<u16, N>,
+    /// 1-based Fenwick tree, the value `v` is at index `v + 1`
+    counts: [u16; VALUES + 1],
+}
+
+impl<const N: usize> SlidingMedian<N> {
+    pub const fn new() -> Self {
+        Self {
+            window: Deque::new(),
+            counts: [0; VALUES + 1],
+        }
+    }
+
+    /// Writes a new value, removing the oldest one once there are `N` values.
+    ///
+    /// Values above [`ADC_MAX`] are clamped.
+    pub fn write(&mut self, value: u16) {
+        let value = value.min(ADC_MAX);
+
+        if self.window.is_full() {
+            let oldest = self.window.pop_front().expect("Window should be full");
+            self.remove_count(oldest);
+        }
+
+        self.window.push_back(value).expect("Should have space");
+        self.add_count(value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.window.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.window.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.window.is_full()
+    }
+
+    /// The most recently written value.
+    pub fn recent(&self) -> Option<&u16> {
+        self.window.back()
+    }
+
+    /// The values from the oldest to the most recent one.
+    pub fn iter(&self) -> impl Iterator<Item = &u16> {
+        self.window.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.window.clear();
+        self.counts = [0; VALUES + 1];
+    }
+
+    /// The `k`-th smallest value (`0`-based), i.e. the value at index `k` of the sorted window.
+    pub fn kth_smallest(&self, k: usize) -> Option<u16> {
+        if k >= self.len() {
+            return None;
+        }
+
+        // the largest position with less than `k + 1` values at or before it
+        let mut position = 0;
+        let mut remaining = k + 1;
+        let mut step = VALUES;
+        while step > 0 {
+            let next = position + step;
+            if next <= VALUES && (self.counts[next] as usize) < remaining {
+                position = next;
+                remaining -= self.counts[next] as usize;
+            }
+            step /= 2;
+        }
+
+        // the next position (`position + 1`) is the value `position`
+        Some(position as u16)
+    }
+
+    /// The mediana with the same rounding as [`find_mediana`](super::find_mediana),
+    /// `None` when the window is empty.
+    pub fn median(&self) -> Option<u16> {
+        let len = self.len();
+
+        if len % 2 == 0 {
+            let first = self.kth_smallest((len / 2).checked_sub(1)?)?;
+            let second = self.kth_smallest(len / 2)?;
+
+            // will floor the result!
+            Some(((first as u32 + second as u32) / 2) as u16)
+        } else {
+            self.kth_smallest(len / 2)
+        }
+    }
+
+    /// The nearest-rank percentile (`0..=100`), `None` when the window is empty.
+    ///
+    /// `0` is the minimum and `100` the maximum value in the window.
+    pub fn percentile(&self, percent: u8) -> Option<u16> {
+        let last_index = self.len().checked_sub(1)?;
+        let percent = percent.min(100) as usize;
+
+        // rounds to the nearest index
+        self.kth_smallest((percent * last_index + 50) / 100)
+    }
+
+    fn add_count(&mut self, value: u16) {
+        let mut index = value as usize + 1;
+        while index <= VALUES {
+            self.counts[index] += 1;
+            index += index & index.wrapping_neg();
+        }
+    }
+
+    fn remove_count(&mut self, value: u16) {
+        let mut index = value as usize + 1;
+        while index <= VALUES {
+            self.counts[index] -= 1;
+            index += index & index.wrapping_neg();
+        }
+    }
+}
+
+impl<const N: usize> Default for SlidingMedian<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> core::fmt::Debug for SlidingMedian<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.window.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use heapless::Vec;
+
+    use super::*;
+    use crate::helper::find_mediana;
+
+    /// A xorshift generator so the property tests are reproducible without extra dependencies
+    struct Random(u32);
+
+    impl Random {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        /// A random ADC value, mostly around a level with some noise and glitches
+        fn adc_value(&mut self) -> u16 {
+            match self.next() % 10 {
+                0 => (self.next() % VALUES as u32) as u16,
+                _ => 2300 + (self.next() % 64) as u16,
+            }
+        }
+    }
+
+    /// Checks the window against sorting a copy of it after every write.
+    fn check_against_sorting<const N: usize>(seed: u32, writes: usize) {
+        let mut random = Random(seed);
+        let mut sliding = SlidingMedian::<N>::new();
+
+        for _ in 0..writes {
+            sliding.write(random.adc_value());
+
+            let mut values: Vec<u16, N> = sliding.iter().copied().collect();
+            assert_eq!(Some(find_mediana(&mut values)), sliding.median());
+
+            // `find_mediana` sorts the values
+            for (k, value) in values.iter().enumerate() {
+                assert_eq!(Some(*value), sliding.kth_smallest(k));
+            }
+            assert_eq!(None, sliding.kth_smallest(values.len()));
+
+            for percent in [0, 10, 25, 50, 75, 90, 99, 100] {
+                let index = (percent * (values.len() - 1) + 50) / 100;
+                assert_eq!(Some(values[index]), sliding.percentile(percent as u8));
+            }
+        }
+    }
+
+    #[test]
+    fn test_matches_find_mediana() {
+        for seed in 1..=20 {
+            check_against_sorting::<1>(seed, 10);
+            check_against_sorting::<2>(seed, 10);
+            check_against_sorting::<5>(seed, 50);
+            check_against_sorting::<16>(seed, 100);
+            check_against_sorting::<101>(seed, 300);
+        }
+    }
+
+    #[test]
+    fn test_window() {
+        let mut sliding = SlidingMedian::<3>::new();
+        assert!(sliding.is_empty());
+        assert_eq!(None, sliding.median());
+        assert_eq!(None, sliding.percentile(50));
+
+        for value in [10, 40, 30, 20] {
+            sliding.write(value);
+        }
+        assert!(sliding.is_full());
+        assert_eq!(Some(&20), sliding.recent());
+        assert_eq!(
+            std::vec![40, 30, 20],
+            sliding.iter().copied().collect::<std::vec::Vec<_>>()
+        );
+        assert_eq!(Some(30), sliding.median());
+        assert_eq!(Some(20), sliding.percentile(0));
+        assert_eq!(Some(40), sliding.percentile(100));
+
+        // clamped to 12-bits
+        sliding.write(u16::MAX);
+        assert_eq!(Some(&ADC_MAX), sliding.recent());
+        assert_eq!(Some(ADC_MAX), sliding.percentile(100));
+
+        sliding.clear();
+        assert_eq!(None, sliding.median());
+        sliding.write(0);
+        assert_eq!(Some(0), sliding.median());
+    }
+}
@@ -1,10 +1,44 @@
 #![cfg_attr(not(any(feature = "std", test)), no_std)]
-
-#![no_main]
+#![cfg_attr(not(any(feature = "std", test)), no_main)]
 #![feature(type_alias_impl_trait)]
 
+#[cfg(feature = "riscv")]
 pub use application::Application;
 
+pub mod adc;
+pub mod adc_scheduler;
+pub mod alarm;
+#[cfg(feature = "riscv")]
 pub mod application;
 pub mod battery;
+pub mod calibration;
+pub mod charger;
+pub mod current_sense;
+pub mod energy;
+pub mod health;
 pub mod helper;
+pub mod ocv;
+pub mod power_mode;
+pub mod power_sense;
+pub mod prediction;
+pub mod rails;
+pub mod solar;
+pub mod telemetry;
+pub mod temperature;
+
+/// Prints using `esp-println` on the board and the standard output on the host.
+#[cfg(feature = "riscv")]
+pub(crate) use esp_println::println;
+#[cfg(all(not(feature = "riscv"), any(feature = "std", test)))]
+pub(crate) use std::println;
+
+/// Without a board or the standard library there's nowhere to print,
+/// the arguments are only type-checked.
+#[cfg(not(any(feature = "riscv", feature = "std", test)))]
+macro_rules! println {
+    ($($arg:tt)*) => {{
+        let _ = core::format_args!($($arg)*);
+    }};
+}
+#[cfg(not(any(feature = "riscv", feature = "std", test)))]
+pub(crate) use println;
@@ -0,0 +1,405 @@
+//! Open-circuit voltage (OCV) to State of Charge (SoC) estimation.
+//!
+//! A LiPo cell does not discharge linearly - the curve is very flat
+//! between 3.7V and 3.9V and drops steeply close to the cut-off voltage.
+//! Instead of mapping the voltage linearly between the cut-out and the charged voltage
+//! we use a piecewise-linear lookup table of the cell's discharge curve.
+use defmt::Format;
+
+/// A single point of an [`OcvCurve`].
+///
+/// The points are stored as integers so the curve can be used
+/// without floating point arithmetic (see [`OcvCurve::permille`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct OcvPoint {
+    /// The open-circuit voltage of the cell in millivolts
+    pub millivolts: u16,
+    /// The State of Charge at this voltage in per-mille (`0..=1000`)
+    pub permille: u16,
+}
+
+impl OcvPoint {
+    pub const fn new(millivolts: u16, permille: u16) -> Self {
+        Self {
+            millivolts,
+            permille,
+        }
+    }
+
+    /// The open-circuit voltage of the cell in Volts
+    pub fn voltage(&self) -> f32 {
+        self.millivolts as f32 / 1000.0
+    }
+
+    /// The State of Charge at this voltage in percentage (`0.0..=100.0`)
+    pub fn percentage(&self) -> f32 {
+        self.permille as f32 / 10.0
+    }
+}
+
+/// A piecewise-linear OCV -> SoC curve.
+///
+/// The points must be sorted by ascending voltage (and State of Charge)
+/// and there should be at least 2 of them.
+/// Voltages outside of the curve are clamped to `0%` and `100%`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OcvCurve<'a> {
+    points: &'a [OcvPoint],
+}
+
+impl<'a> OcvCurve<'a> {
+    /// Creates a new curve from the given points.
+    ///
+    /// The points must be sorted by ascending voltage and State of Charge.
+    ///
+    /// # Panics
+    ///
+    /// When there are less than 2 points or when they are not sorted.
+    /// When used in a `const` context the check is done at compile time.
+    pub const fn new(points: &'a [OcvPoint]) -> Self {
+        assert!(points.len() >= 2, "OCV curve needs at least 2 points");
+
+        let mut index = 1;
+        while index < points.len() {
+            assert!(
+                points[index - 1].millivolts < points[index].millivolts,
+                "OCV curve voltages should be strictly ascending"
+            );
+            assert!(
+                points[index - 1].permille <= points[index].permille,
+                "OCV curve State of Charge should be ascending"
+            );
+            index += 1;
+        }
+
+        Self { points }
+    }
+
+    pub fn points(&self) -> &'a [OcvPoint] {
+        self.points
+    }
+
+    /// The voltage of the empty (`0%`) end of the curve.
+    pub fn min_voltage(&self) -> f32 {
+        self.points[0].voltage()
+    }
+
+    /// The voltage of the full (`100%`) end of the curve.
+    pub fn max_voltage(&self) -> f32 {
+        self.points[self.points.len() - 1].voltage()
+    }
+
+    /// Interpolates the State of Charge (in percentage) for the given voltage.
+    ///
+    /// A `NaN` voltage, e.g. from a failed conversion, is treated as an empty battery.
+    pub fn percentage(&self, voltage: f32) -> f32 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+
+        if voltage.is_nan() || voltage <= first.voltage() {
+            return first.percentage();
+        }
+        if voltage >= last.voltage() {
+            return last.percentage();
+        }
+
+        // find the first point with higher voltage than the measured one
+        let upper_index = self.upper_index(|point| point.voltage() > voltage);
+        let lower = self.points[upper_index - 1];
+        let upper = self.points[upper_index];
+
+        lower.percentage()
+            + (voltage - lower.voltage()) / (upper.voltage() - lower.voltage())
+                * (upper.percentage() - lower.percentage())
+    }
+
+    /// Interpolates the State of Charge (in per-mille) for the given voltage in millivolts
+    /// using only integer arithmetic.
+    ///
+    /// The result is rounded to the nearest per-mille, i.e. it's within `0.5‰`
+    /// of the exact interpolation and of [`OcvCurve::percentage`] for the same voltage.
+    pub fn permille(&self, millivolts: u32) -> u16 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+
+        if millivolts <= first.millivolts.into() {
+            return first.permille;
+        }
+        if millivolts >= last.millivolts.into() {
+            return last.permille;
+        }
+
+        let upper_index = self.upper_index(|point| u32::from(point.millivolts) > millivolts);
+        let lower = self.points[upper_index - 1];
+        let upper = self.points[upper_index];
+
+        let millivolts_range = u32::from(upper.millivolts - lower.millivolts);
+        let permille_range = u32::from(upper.permille - lower.permille);
+        let offset = millivolts - u32::from(lower.millivolts);
+
+        // round to the nearest per-mille
+        let interpolated = (offset * permille_range + millivolts_range / 2) / millivolts_range;
+
+        lower.permille + interpolated as u16
+    }
+
+    /// Interpolates the voltage in millivolts for the given State of Charge (in per-mille)
+    /// using only integer arithmetic.
+    ///
+    /// This is the inverse of [`OcvCurve::permille`], for a flat part of the curve
+    /// it's the lowest voltage with that State of Charge.
+    pub fn millivolts(&self, permille: u16) -> u32 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+
+        if permille <= first.permille {
+            return first.millivolts.into();
+        }
+        if permille >= last.permille {
+            return last.millivolts.into();
+        }
+
+        let upper_index = self.upper_index(|point| point.permille >= permille);
+        let lower = self.points[upper_index - 1];
+        let upper = self.points[upper_index];
+
+        let millivolts_range = u32::from(upper.millivolts - lower.millivolts);
+        let permille_range = u32::from(upper.permille - lower.permille);
+        let offset = u32::from(permille - lower.permille);
+
+        // round to the nearest millivolt
+        let interpolated = (offset * millivolts_range + permille_range / 2) / permille_range;
+
+        u32::from(lower.millivolts) + interpolated
+    }
+
+    /// Interpolates the voltage for a given State of Charge (in percentage).
+    ///
+    /// This is the inverse of [`OcvCurve::percentage`], a `NaN` percentage is treated as empty.
+    pub fn voltage(&self, percentage: f32) -> f32 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+
+        if percentage.is_nan() || percentage <= first.percentage() {
+            return first.voltage();
+        }
+        if percentage >= last.percentage() {
+            return last.voltage();
+        }
+
+        let upper_index = self.upper_index(|point| point.percentage() > percentage);
+        let lower = self.points[upper_index - 1];
+        let upper = self.points[upper_index];
+
+        lower.voltage()
+            + (percentage - lower.percentage()) / (upper.percentage() - lower.percentage())
+                * (upper.voltage() - lower.voltage())
+    }
+
+    /// The index of the first point above the value, i.e. the upper end of its segment.
+    ///
+    /// The value is already clamped to the curve, the last segment is used
+    /// if no point is above it instead of panicking.
+    fn upper_index(&self, is_above: impl Fn(&OcvPoint) -> bool) -> usize {
+        self.points
+            .iter()
+            .skip(1)
+            .position(is_above)
+            .map_or(self.points.len() - 1, |index| index + 1)
+    }
+}
+
+/// The [LiPo 3.7V battery of 250 mAh][olimex-battery-250mha] used on the Olimex ESP32-C3 board.
+///
+/// The end points are the [datasheet][battery-datasheet]'s cut-off voltage (3.0V)
+/// and charge voltage (4.2V).
+///
+/// **Not an open-circuit voltage curve yet:** the rest of the points follow a typical
+/// LiPo discharge curve at 0.2C (50 mA), i.e. measured under load, so the State of Charge
+/// of a resting battery reads low. They have to be replaced with the rest voltages of the
+/// datasheet or of a rest-OCV test of the cell (e.g. discharging it in 5% steps and
+/// measuring the voltage after an hour of rest), citing the source here.
+///
+/// [olimex-battery-250mha]: https://www.olimex.com/Products/Power/Lipo-battery/BATTERY-LIPO250mAh/
+/// [battery-datasheet]: https://www.olimex.com/Products/Power/Lipo-battery/BATTERY-LIPO250mAh/resources/JA602025P-Spec-Data-Sheet-3.7V-250mAh--170116.pdf
+pub const OLIMEX_LIPO_250MAH: OcvCurve<'static> = OcvCurve::new(&[
+    OcvPoint::new(3000, 0),
+    OcvPoint::new(3300, 20),
+    OcvPoint::new(3500, 50),
+    OcvPoint::new(3600, 80),
+    OcvPoint::new(3680, 120),
+    OcvPoint::new(3720, 200),
+    OcvPoint::new(3750, 280),
+    OcvPoint::new(3780, 380),
+    OcvPoint::new(3810, 470),
+    OcvPoint::new(3850, 570),
+    OcvPoint::new(3900, 660),
+    OcvPoint::new(3950, 740),
+    OcvPoint::new(4000, 810),
+    OcvPoint::new(4050, 880),
+    OcvPoint::new(4100, 940),
+    OcvPoint::new(4150, 980),
+    OcvPoint::new(4200, 1000),
+]);
+
+/// A typical single LiPo (or Li-ion) cell, same as [`OLIMEX_LIPO_250MAH`].
+pub const LIPO: OcvCurve<'static> = OLIMEX_LIPO_250MAH;
+
+/// Resting voltage of a single LiFePO4 cell.
+///
+/// The curve is very flat between 3.2V and 3.3V (from 15% to 70%),
+/// so small voltage errors mean large State of Charge errors.
+pub const LIFEPO4: OcvCurve<'static> = OcvCurve::new(&[
+    OcvPoint::new(2500, 0),
+    OcvPoint::new(2900, 50),
+    OcvPoint::new(3000, 90),
+    OcvPoint::new(3200, 140),
+    OcvPoint::new(3220, 170),
+    OcvPoint::new(3250, 200),
+    OcvPoint::new(3260, 300),
+    OcvPoint::new(3270, 400),
+    OcvPoint::new(3300, 700),
+    OcvPoint::new(3320, 900),
+    OcvPoint::new(3350, 990),
+    OcvPoint::new(3400, 1000),
+]);
+
+/// Resting voltage of a single NiMH cell.
+pub const NIMH: OcvCurve<'static> = OcvCurve::new(&[
+    OcvPoint::new(1000, 0),
+    OcvPoint::new(1100, 50),
+    OcvPoint::new(1180, 100),
+    OcvPoint::new(1200, 200),
+    OcvPoint::new(1220, 350),
+    OcvPoint::new(1240, 500),
+    OcvPoint::new(1260, 650),
+    OcvPoint::new(1280, 800),
+    OcvPoint::new(1320, 900),
+    OcvPoint::new(1380, 1000),
+]);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_approx(expected: f32, actual: f32) {
+        assert!(
+            (expected - actual).abs() < 0.01,
+            "expected: {expected}, actual: {actual}"
+        );
+    }
+
+    #[test]
+    fn test_olimex_curve_points() {
+        let curve = OLIMEX_LIPO_250MAH;
+
+        // every point of the curve should map to its exact percentage
+        for point in curve.points() {
+            assert_approx(point.percentage(), curve.percentage(point.voltage()));
+            assert_approx(point.voltage(), curve.voltage(point.percentage()));
+            assert_eq!(point.permille, curve.permille(point.millivolts.into()));
+            assert_eq!(
+                u32::from(point.millivolts),
+                curve.millivolts(point.permille)
+            );
+        }
+
+        assert_approx(3.0, curve.min_voltage());
+        assert_approx(4.2, curve.max_voltage());
+    }
+
+    #[test]
+    fn test_interpolation_and_clamping() {
+        let curve = OLIMEX_LIPO_250MAH;
+
+        // half way between 3.81V (47%) and 3.85V (57%)
+        assert_approx(52.0, curve.percentage(3.83));
+        assert_approx(3.83, curve.voltage(52.0));
+
+        // the flat part of the curve should not be treated as half charged
+        // as the linear mapping does: (3.75 - 3.0) / (4.2 - 3.0) = 62.5%
+        assert_approx(28.0, curve.percentage(3.75));
+
+        assert_approx(0.0, curve.percentage(2.5));
+        assert_approx(100.0, curve.percentage(4.35));
+        assert_approx(3.0, curve.voltage(-10.0));
+        assert_approx(4.2, curve.voltage(110.0));
+
+        // e.g. from a failed voltage conversion
+        assert_approx(0.0, curve.percentage(f32::NAN));
+        assert_approx(3.0, curve.voltage(f32::NAN));
+    }
+
+    #[test]
+    fn test_user_supplied_curve() {
+        const POINTS: [OcvPoint; 3] = [
+            OcvPoint::new(2500, 0),
+            OcvPoint::new(3200, 500),
+            OcvPoint::new(3600, 1000),
+        ];
+        let curve = OcvCurve::new(&POINTS);
+
+        assert_approx(25.0, curve.percentage(2.85));
+        assert_approx(75.0, curve.percentage(3.4));
+        assert_eq!(250, curve.permille(2850));
+        assert_eq!(750, curve.permille(3400));
+        assert_eq!(2850, curve.millivolts(250));
+        assert_eq!(3400, curve.millivolts(750));
+        assert_eq!(2500, curve.millivolts(0));
+        assert_eq!(3600, curve.millivolts(1000));
+    }
+
+    #[test]
+    fn test_builtin_curves_millivolts_roundtrip() {
+        for curve in [LIPO, LIFEPO4, NIMH] {
+            for permille in (0..=1000).step_by(10) {
+                let millivolts = curve.millivolts(permille);
+
+                // the steepest parts are 10‰ per mV, i.e. rounding to the nearest mV is within 5‰
+                let roundtrip = curve.permille(millivolts);
+                assert!(
+                    roundtrip.abs_diff(permille) <= 5,
+                    "{permille}‰ => {millivolts} mV => {roundtrip}‰"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_permille_matches_percentage() {
+        let curve = OLIMEX_LIPO_250MAH;
+
+        assert_eq!(0, curve.permille(0));
+        assert_eq!(0, curve.permille(2900));
+        assert_eq!(1000, curve.permille(4350));
+        // half way between 3.81V (47%) and 3.85V (57%)
+        assert_eq!(520, curve.permille(3830));
+
+        for millivolts in 2900..=4300 {
+            let percentage = curve.percentage(millivolts as f32 / 1000.0);
+            let permille = curve.permille(millivolts);
+
+            let difference = (permille as f32 - percentage * 10.0).abs();
+            assert!(
+                difference <= 0.5 + 0.01,
+                "{millivolts} mV: {permille}‰ vs {percentage}%"
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_curve_with_single_point() {
+        let points = [OcvPoint::new(3600, 0)];
+
+        OcvCurve::new(&points);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unsorted_curve() {
+        let points = [OcvPoint::new(3600, 0), OcvPoint::new(3200, 1000)];
+
+        OcvCurve::new(&points);
+    }
+}
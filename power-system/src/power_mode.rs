@@ -0,0 +1,463 @@
+//! Power modes of the satellite based on the battery voltage.
+//!
+//! The [`PowerModeManager`] is fed with the battery voltage and decides
+//! in which [`PowerMode`] the satellite should operate.
+//! Each degraded mode has its own entry and exit threshold (hysteresis)
+//! and recovering to a better mode requires the voltage to stay above the
+//! exit threshold for a minimum dwell time.
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
+use embassy_time::{Duration, Instant};
+
+use crate::battery::Battery;
+
+/// The power modes ordered by severity, i.e. `Nominal < Low < Critical < Survival`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub enum PowerMode {
+    /// Everything is running
+    Nominal,
+    /// Reduced telemetry and sampling rates
+    Low,
+    /// Only the essential tasks are running
+    Critical,
+    /// Keep the battery alive until there's enough power again
+    Survival,
+}
+
+impl PowerMode {
+    /// All modes ordered from the best to the worst one
+    pub const ALL: [PowerMode; 4] = [
+        PowerMode::Nominal,
+        PowerMode::Low,
+        PowerMode::Critical,
+        PowerMode::Survival,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerMode::Nominal => "NOMINAL",
+            PowerMode::Low => "LOW",
+            PowerMode::Critical => "CRITICAL",
+            PowerMode::Survival => "SURVIVAL",
+        }
+    }
+
+    /// The next better mode, `None` for [`PowerMode::Nominal`]
+    pub fn better(&self) -> Option<PowerMode> {
+        match self {
+            PowerMode::Nominal => None,
+            PowerMode::Low => Some(PowerMode::Nominal),
+            PowerMode::Critical => Some(PowerMode::Low),
+            PowerMode::Survival => Some(PowerMode::Critical),
+        }
+    }
+
+    /// How often the battery should be measured
+    pub fn sampling_period(&self) -> Duration {
+        match self {
+            PowerMode::Nominal => Duration::from_secs(1),
+            PowerMode::Low => Duration::from_secs(5),
+            PowerMode::Critical => Duration::from_secs(15),
+            PowerMode::Survival => Duration::from_secs(60),
+        }
+    }
+
+    /// How often telemetry should be sent to the onboard computer
+    pub fn telemetry_period(&self) -> Duration {
+        match self {
+            PowerMode::Nominal => Duration::from_secs(1),
+            PowerMode::Low => Duration::from_secs(5),
+            PowerMode::Critical => Duration::from_secs(30),
+            PowerMode::Survival => Duration::from_secs(120),
+        }
+    }
+
+    /// The LED blink pattern - the time the LED is on and then off.
+    ///
+    /// In [`PowerMode::Survival`] the LED only flashes briefly to save power.
+    pub fn blink_pattern(&self) -> (Duration, Duration) {
+        match self {
+            PowerMode::Nominal => (Duration::from_millis(500), Duration::from_millis(500)),
+            PowerMode::Low => (Duration::from_millis(200), Duration::from_millis(1800)),
+            PowerMode::Critical => (Duration::from_millis(100), Duration::from_millis(4900)),
+            PowerMode::Survival => (Duration::from_millis(20), Duration::from_millis(29_980)),
+        }
+    }
+}
+
+/// The hysteresis of a degraded [`PowerMode`].
+///
+/// The mode is entered when the voltage drops below `enter` and it can be left
+/// once the voltage is at or above `exit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Threshold {
+    /// Battery voltage in millivolts
+    pub enter: u32,
+    /// Battery voltage in millivolts, should be higher than `enter`
+    pub exit: u32,
+}
+
+impl Threshold {
+    pub const fn new(enter: u32, exit: u32) -> Self {
+        assert!(
+            enter < exit,
+            "Exit threshold should be higher than the entry"
+        );
+
+        Self { enter, exit }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerModeConfig {
+    pub low: Threshold,
+    pub critical: Threshold,
+    pub survival: Threshold,
+    /// The minimum time spent in a mode before recovering to a better one.
+    ///
+    /// Degrading to a worse mode is always immediate.
+    pub min_dwell: Duration,
+}
+
+impl PowerModeConfig {
+    /// Thresholds for a single LiPo cell with 3.0V cut-off voltage
+    pub const LIPO_1S: PowerModeConfig = PowerModeConfig {
+        low: Threshold::new(3600, 3700),
+        critical: Threshold::new(3450, 3550),
+        survival: Threshold::new(3300, 3400),
+        min_dwell: Duration::from_secs(30),
+    };
+
+    /// The State of Charge (in per-mille) at which each degraded mode is entered and left,
+    /// see [`PowerModeConfig::for_battery`]
+    pub const LOW_PERMILLE: (u16, u16) = (80, 160);
+    pub const CRITICAL_PERMILLE: (u16, u16) = (40, 80);
+    pub const SURVIVAL_PERMILLE: (u16, u16) = (20, 50);
+
+    /// Thresholds at the same State of Charge for any [`Battery`] profile,
+    /// i.e. the pack voltage of the `*_PERMILLE` levels on its OCV curve.
+    pub fn for_battery(battery: &Battery) -> Self {
+        let threshold = |(enter, exit): (u16, u16)| {
+            Threshold::new(battery.millivolts(enter), battery.millivolts(exit))
+        };
+
+        Self {
+            low: threshold(Self::LOW_PERMILLE),
+            critical: threshold(Self::CRITICAL_PERMILLE),
+            survival: threshold(Self::SURVIVAL_PERMILLE),
+            ..Self::LIPO_1S
+        }
+    }
+
+    /// The threshold of a degraded mode, `None` for [`PowerMode::Nominal`]
+    pub fn threshold(&self, mode: PowerMode) -> Option<Threshold> {
+        match mode {
+            PowerMode::Nominal => None,
+            PowerMode::Low => Some(self.low),
+            PowerMode::Critical => Some(self.critical),
+            PowerMode::Survival => Some(self.survival),
+        }
+    }
+}
+
+impl Default for PowerModeConfig {
+    fn default() -> Self {
+        Self::LIPO_1S
+    }
+}
+
+/// A transition between two power modes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct PowerModeChange {
+    pub from: PowerMode,
+    pub to: PowerMode,
+    /// The battery voltage (in millivolts) which triggered the change
+    pub millivolts: u32,
+}
+
+/// The capacity, subscribers and publishers of the [`POWER_MODE`] channel.
+pub type PowerModeChannel = PubSubChannel<CriticalSectionRawMutex, PowerModeChange, 4, 4, 1>;
+
+/// Every power mode change is published here for the rest of the tasks
+/// (blinking, telemetry, etc.).
+pub static POWER_MODE: PowerModeChannel = PubSubChannel::new();
+
+/// The power mode state machine
+#[derive(Debug, Clone)]
+pub struct PowerModeManager {
+    config: PowerModeConfig,
+    mode: PowerMode,
+    entered_at: Instant,
+}
+
+impl PowerModeManager {
+    /// Starts in [`PowerMode::Nominal`], the first [`PowerModeManager::update`]
+    /// will immediately degrade the mode if the battery is low.
+    pub fn new(config: PowerModeConfig, now: Instant) -> Self {
+        Self {
+            config,
+            mode: PowerMode::Nominal,
+            entered_at: now,
+        }
+    }
+
+    pub fn mode(&self) -> PowerMode {
+        self.mode
+    }
+
+    pub fn config(&self) -> &PowerModeConfig {
+        &self.config
+    }
+
+    /// The time spent in the current mode
+    pub fn dwell(&self, now: Instant) -> Duration {
+        now.checked_duration_since(self.entered_at)
+            .unwrap_or(Duration::from_ticks(0))
+    }
+
+    /// Feeds a new battery voltage (in millivolts) to the state machine.
+    ///
+    /// - Degrades immediately to the worst mode whose entry threshold is crossed.
+    /// - Recovers one mode at a time, once the voltage is at or above the exit
+    ///   threshold of the current mode and the minimum dwell time has passed.
+    pub fn update(&mut self, millivolts: u32, now: Instant) -> Option<PowerModeChange> {
+        let worst_entered = PowerMode::ALL
+            .into_iter()
+            .rev()
+            .filter(|mode| *mode > self.mode)
+            .find(|mode| {
+                self.config
+                    .threshold(*mode)
+                    .map_or(false, |threshold| millivolts < threshold.enter)
+            });
+
+        let new_mode = match worst_entered {
+            Some(worse) => worse,
+            None => {
+                let can_exit = self
+                    .config
+                    .threshold(self.mode)
+                    .map_or(false, |threshold| millivolts >= threshold.exit);
+
+                match self.mode.better() {
+                    Some(better) if can_exit && self.dwell(now) >= self.config.min_dwell => better,
+                    _ => return None,
+                }
+            }
+        };
+
+        let change = PowerModeChange {
+            from: self.mode,
+            to: new_mode,
+            millivolts,
+        };
+        self.mode = new_mode;
+        self.entered_at = now;
+
+        Some(change)
+    }
+
+    /// Degrades to the mode for a reason other than the voltage,
+    /// e.g. discharging is inhibited by the battery temperature.
+    ///
+    /// Does nothing if the current mode is already the same or worse.
+    pub fn degrade_to(
+        &mut self,
+        mode: PowerMode,
+        millivolts: u32,
+        now: Instant,
+    ) -> Option<PowerModeChange> {
+        if mode <= self.mode {
+            return None;
+        }
+
+        let change = PowerModeChange {
+            from: self.mode,
+            to: mode,
+            millivolts,
+        };
+        self.mode = mode;
+        self.entered_at = now;
+
+        Some(change)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Feeds a voltage trace, one sample per second, and returns the changes
+    /// with the second at which they happened.
+    fn run_trace(
+        manager: &mut PowerModeManager,
+        start: Instant,
+        trace: &[u32],
+    ) -> Vec<(u64, PowerModeChange)> {
+        trace
+            .iter()
+            .enumerate()
+            .filter_map(|(second, millivolts)| {
+                let now = start + Duration::from_secs(second as u64);
+
+                manager
+                    .update(*millivolts, now)
+                    .map(|change| (second as u64, change))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_discharge_degrades_immediately() {
+        let start = Instant::from_secs(0);
+        let mut manager = PowerModeManager::new(PowerModeConfig::LIPO_1S, start);
+
+        let trace = [3900, 3650, 3599, 3500, 3449, 3400, 3299, 3200];
+        let changes = run_trace(&mut manager, start, &trace);
+
+        assert_eq!(3, changes.len());
+        assert_eq!((2, PowerMode::Nominal, PowerMode::Low), at(changes[0]));
+        assert_eq!((4, PowerMode::Low, PowerMode::Critical), at(changes[1]));
+        assert_eq!(
+            (6, PowerMode::Critical, PowerMode::Survival),
+            at(changes[2])
+        );
+        assert_eq!(PowerMode::Survival, manager.mode());
+    }
+
+    #[test]
+    fn test_sudden_drop_skips_modes() {
+        let start = Instant::from_secs(0);
+        let mut manager = PowerModeManager::new(PowerModeConfig::LIPO_1S, start);
+
+        let changes = run_trace(&mut manager, start, &[3900, 3100]);
+
+        assert_eq!(
+            [(1, PowerMode::Nominal, PowerMode::Survival)].as_slice(),
+            changes.into_iter().map(at).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_hysteresis_prevents_flapping() {
+        let start = Instant::from_secs(0);
+        let config = PowerModeConfig {
+            min_dwell: Duration::from_secs(0),
+            ..PowerModeConfig::LIPO_1S
+        };
+        let mut manager = PowerModeManager::new(config, start);
+
+        // noisy voltage around the Low entry threshold (3600 mV)
+        let trace = [3610, 3590, 3620, 3595, 3650, 3598, 3690, 3599];
+        let changes = run_trace(&mut manager, start, &trace);
+
+        // enters Low once and never leaves it since the exit is at 3700 mV
+        assert_eq!(1, changes.len());
+        assert_eq!((1, PowerMode::Nominal, PowerMode::Low), at(changes[0]));
+
+        // reaching the exit threshold recovers
+        let change = manager.update(3700, start + Duration::from_secs(10));
+        assert_eq!(
+            Some((PowerMode::Low, PowerMode::Nominal)),
+            change.map(|change| (change.from, change.to))
+        );
+    }
+
+    #[test]
+    fn test_recovery_waits_for_min_dwell_one_mode_at_a_time() {
+        let start = Instant::from_secs(0);
+        let mut manager = PowerModeManager::new(PowerModeConfig::LIPO_1S, start);
+
+        // drops to Survival and then the solar panels start charging the battery
+        let mut trace = [3250_u32; 100];
+        trace[0] = 3900;
+        for millivolts in trace.iter_mut().skip(5) {
+            *millivolts = 4000;
+        }
+        let changes = run_trace(&mut manager, start, &trace);
+
+        assert_eq!(4, changes.len());
+        assert_eq!((1, PowerMode::Nominal, PowerMode::Survival), at(changes[0]));
+        // 30 seconds of dwell time for each recovery
+        assert_eq!(
+            (31, PowerMode::Survival, PowerMode::Critical),
+            at(changes[1])
+        );
+        assert_eq!((61, PowerMode::Critical, PowerMode::Low), at(changes[2]));
+        assert_eq!((91, PowerMode::Low, PowerMode::Nominal), at(changes[3]));
+        assert_eq!(4000, changes[3].1.millivolts);
+    }
+
+    #[test]
+    fn test_degrading_resets_dwell() {
+        let start = Instant::from_secs(0);
+        let mut manager = PowerModeManager::new(PowerModeConfig::LIPO_1S, start);
+
+        assert!(manager.update(3440, start).is_some());
+        assert_eq!(PowerMode::Critical, manager.mode());
+        // still in Critical's hysteresis
+        assert!(manager
+            .update(3540, start + Duration::from_secs(60))
+            .is_none());
+        assert!(manager
+            .update(3290, start + Duration::from_secs(61))
+            .is_some());
+        assert_eq!(PowerMode::Survival, manager.mode());
+        // recovered voltage, but the dwell time in Survival is too short
+        assert!(manager
+            .update(3500, start + Duration::from_secs(62))
+            .is_none());
+        assert_eq!(
+            Duration::from_secs(1),
+            manager.dwell(start + Duration::from_secs(62))
+        );
+    }
+
+    #[test]
+    fn test_degrade_to() {
+        let start = Instant::from_secs(0);
+        let mut manager = PowerModeManager::new(PowerModeConfig::LIPO_1S, start);
+        assert!(manager.update(3550, start).is_some());
+
+        let change = manager.degrade_to(PowerMode::Survival, 3550, start + Duration::from_secs(5));
+        assert_eq!(
+            Some((PowerMode::Low, PowerMode::Survival)),
+            change.map(|change| (change.from, change.to))
+        );
+        assert_eq!(
+            Duration::from_secs(0),
+            manager.dwell(start + Duration::from_secs(5))
+        );
+
+        // never recovers
+        assert!(manager
+            .degrade_to(PowerMode::Critical, 3550, start + Duration::from_secs(6))
+            .is_none());
+        assert_eq!(PowerMode::Survival, manager.mode());
+    }
+
+    fn at((second, change): (u64, PowerModeChange)) -> (u64, PowerMode, PowerMode) {
+        (second, change.from, change.to)
+    }
+
+    #[test]
+    fn test_thresholds_for_battery() {
+        let config = PowerModeConfig::for_battery(&Battery::OLIMEX_LIPO_250MAH);
+        assert_eq!(Threshold::new(3600, 3700), config.low);
+        assert_eq!(Threshold::new(3433, 3600), config.critical);
+        assert_eq!(Threshold::new(3300, 3500), config.survival);
+        assert_eq!(PowerModeConfig::LIPO_1S.min_dwell, config.min_dwell);
+
+        // the same State of Charge for a pack of 2 cells
+        let config_2s = PowerModeConfig::for_battery(&Battery::LIPO_2S);
+        assert_eq!(Threshold::new(7200, 7400), config_2s.low);
+
+        for battery in [Battery::LIFEPO4_1S, Battery::NIMH_4S] {
+            let config = PowerModeConfig::for_battery(&battery);
+
+            assert!(config.survival.enter < config.critical.enter);
+            assert!(config.critical.enter < config.low.enter);
+            assert!(battery.pack_millivolts(battery.cut_out_millivolts) <= config.survival.enter);
+        }
+    }
+}
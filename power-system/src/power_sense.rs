@@ -0,0 +1,334 @@
+//! External power (solar panels or USB) and charging detection.
+//!
+//! The supply-side voltage is measured through a voltage divider on GPIO4
+//! ([`PowerSensePin`](crate::battery::PowerSensePin)) and combined with the trend
+//! of the battery voltage to decide the [`ChargingState`].
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use heapless::Deque;
+
+use crate::{
+    adc::AdcSource,
+    battery::{Async, Battery, Error, Voltmeter},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ChargingState {
+    /// There's no external power (solar or USB)
+    NotPowered,
+    /// External power is present and the battery is being charged
+    Charging,
+    /// External power is present and the battery is fully charged
+    Charged,
+    /// External power is present, but the battery is discharging,
+    /// is missing or has an implausible voltage
+    Fault,
+}
+
+impl ChargingState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChargingState::NotPowered => "NOT_POWERED",
+            ChargingState::Charging => "CHARGING",
+            ChargingState::Charged => "CHARGED",
+            ChargingState::Fault => "FAULT",
+        }
+    }
+
+    /// Whether the battery voltage is affected by charging
+    /// and cannot be treated as an open-circuit voltage.
+    pub fn is_charging(&self) -> bool {
+        matches!(self, ChargingState::Charging)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerSenseConfig {
+    /// External power is present at or above this supply voltage (in mV)
+    pub present_millivolts: u32,
+    /// The battery is charged at or above this voltage (in mV)
+    pub charged_millivolts: u32,
+    /// The range of plausible battery voltages (in mV) while powered,
+    /// outside of it the battery is either missing or faulty
+    pub plausible_battery_millivolts: (u32, u32),
+    /// The battery voltage change (in mV per minute) which is still considered flat
+    pub flat_trend: u32,
+    /// The minimum time between two samples of the battery voltage trend
+    pub trend_interval: Duration,
+}
+
+impl PowerSenseConfig {
+    /// For the 5V USB or the solar charger and a single LiPo cell
+    pub const LIPO_1S: PowerSenseConfig = PowerSenseConfig {
+        present_millivolts: 4500,
+        charged_millivolts: 4150,
+        plausible_battery_millivolts: (2500, 4300),
+        flat_trend: 2,
+        trend_interval: Duration::from_secs(10),
+    };
+}
+
+impl PowerSenseConfig {
+    /// How much lower (in mV per cell) than the charge termination voltage
+    /// the battery is considered charged
+    pub const CHARGED_MARGIN_MILLIVOLTS: u32 = 50;
+    /// How much higher (in mV) than the pack's charge termination voltage
+    /// the supply should be for the charger to work
+    pub const CHARGER_HEADROOM_MILLIVOLTS: u32 = 300;
+
+    /// The thresholds for the given [`Battery`] profile.
+    pub fn for_battery(battery: &Battery) -> Self {
+        let (min, max) = battery.absolute_millivolts;
+        let termination = battery.pack_millivolts(battery.charge_termination_millivolts);
+
+        Self {
+            present_millivolts: Self::LIPO_1S
+                .present_millivolts
+                .max(termination + Self::CHARGER_HEADROOM_MILLIVOLTS),
+            charged_millivolts: termination
+                - battery.pack_millivolts(Self::CHARGED_MARGIN_MILLIVOLTS),
+            plausible_battery_millivolts: (
+                battery.pack_millivolts(min),
+                battery.pack_millivolts(max),
+            ),
+            ..Self::LIPO_1S
+        }
+    }
+}
+
+impl Default for PowerSenseConfig {
+    fn default() -> Self {
+        Self::LIPO_1S
+    }
+}
+
+/// The number of battery voltage samples used for the trend
+pub const TREND_SAMPLES: usize = 16;
+
+/// Measures the supply-side voltage and tracks the [`ChargingState`].
+pub struct PowerSense<const R1: usize, const R2: usize> {
+    pub voltmeter: Voltmeter<Async, R1, R2>,
+    pub config: PowerSenseConfig,
+    battery_trend: Deque<(Instant, u32), TREND_SAMPLES>,
+    state: ChargingState,
+}
+
+impl<const R1: usize, const R2: usize> PowerSense<R1, R2> {
+    pub fn new(voltmeter: Voltmeter<Async, R1, R2>, config: PowerSenseConfig) -> Self {
+        Self {
+            voltmeter,
+            config,
+            battery_trend: Deque::new(),
+            state: ChargingState::NotPowered,
+        }
+    }
+
+    /// Measures the supply-side voltage in millivolts.
+    pub async fn measure<S: AdcSource>(&mut self, adc: &mut S) -> Result<u32, Error<S::Error>> {
+        self.voltmeter.measure_millivolts(adc).await
+    }
+
+    /// The supply-side voltage in millivolts for a raw ADC value,
+    /// e.g. the mediana of an [`AdcReading`](crate::adc_scheduler::AdcReading).
+    pub fn millivolts(&self, value: u16) -> u32 {
+        self.voltmeter.to_millivolts(value)
+    }
+
+    /// The latest [`ChargingState`] decided by [`PowerSense::update`].
+    pub fn state(&self) -> ChargingState {
+        self.state
+    }
+
+    /// Updates the [`ChargingState`] with a new supply and battery voltage (in mV).
+    pub fn update(
+        &mut self,
+        supply_millivolts: u32,
+        battery_millivolts: u32,
+        now: Instant,
+    ) -> ChargingState {
+        let should_sample = match self.battery_trend.back() {
+            Some((sampled_at, _)) => {
+                now.checked_duration_since(*sampled_at)
+                    .unwrap_or(Duration::from_ticks(0))
+                    >= self.config.trend_interval
+            }
+            None => true,
+        };
+        if should_sample {
+            if self.battery_trend.is_full() {
+                self.battery_trend.pop_front();
+            }
+            self.battery_trend
+                .push_back((now, battery_millivolts))
+                .expect("Should have space after removing the oldest sample");
+        }
+
+        self.state = charging_state(
+            &self.config,
+            supply_millivolts,
+            battery_millivolts,
+            self.battery_trend(),
+        );
+
+        self.state
+    }
+
+    /// The battery voltage trend in mV per minute between the oldest and newest sample.
+    ///
+    /// `None` until there are at least 2 samples.
+    pub fn battery_trend(&self) -> Option<i32> {
+        let (oldest_at, oldest) = self.battery_trend.front()?;
+        let (newest_at, newest) = self.battery_trend.back()?;
+
+        let elapsed_ms = newest_at.checked_duration_since(*oldest_at)?.as_millis();
+        if elapsed_ms == 0 {
+            return None;
+        }
+
+        let change = *newest as i64 - *oldest as i64;
+
+        Some((change * 60_000 / elapsed_ms as i64) as i32)
+    }
+}
+
+/// Decides the [`ChargingState`] from the supply and battery voltages (in mV)
+/// and the battery voltage trend (in mV per minute).
+pub fn charging_state(
+    config: &PowerSenseConfig,
+    supply_millivolts: u32,
+    battery_millivolts: u32,
+    battery_trend: Option<i32>,
+) -> ChargingState {
+    if supply_millivolts < config.present_millivolts {
+        return ChargingState::NotPowered;
+    }
+
+    let (min_battery, max_battery) = config.plausible_battery_millivolts;
+    if battery_millivolts < min_battery || battery_millivolts > max_battery {
+        return ChargingState::Fault;
+    }
+
+    let flat_trend = config.flat_trend as i32;
+    match battery_trend {
+        // powered, but the battery is discharging
+        Some(trend) if trend < -flat_trend => ChargingState::Fault,
+        Some(trend) if battery_millivolts >= config.charged_millivolts && trend <= flat_trend => {
+            ChargingState::Charged
+        }
+        None if battery_millivolts >= config.charged_millivolts => ChargingState::Charged,
+        _ => ChargingState::Charging,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use embassy_futures::block_on;
+
+    use crate::{
+        adc::{Sample, ScriptedAdc},
+        battery::VoltageDivider,
+        calibration::Calibration,
+    };
+
+    const CONFIG: PowerSenseConfig = PowerSenseConfig::LIPO_1S;
+
+    #[test]
+    fn test_charging_state_decision() {
+        assert_eq!(
+            ChargingState::NotPowered,
+            charging_state(&CONFIG, 300, 3800, Some(-5))
+        );
+        // no battery
+        assert_eq!(
+            ChargingState::Fault,
+            charging_state(&CONFIG, 5000, 1200, None)
+        );
+        assert_eq!(
+            ChargingState::Charging,
+            charging_state(&CONFIG, 5000, 3800, None)
+        );
+        assert_eq!(
+            ChargingState::Charging,
+            charging_state(&CONFIG, 5000, 3800, Some(10))
+        );
+        // constant voltage phase is still charging
+        assert_eq!(
+            ChargingState::Charging,
+            charging_state(&CONFIG, 5000, 4180, Some(5))
+        );
+        assert_eq!(
+            ChargingState::Charged,
+            charging_state(&CONFIG, 5000, 4190, Some(1))
+        );
+        assert_eq!(
+            ChargingState::Charged,
+            charging_state(&CONFIG, 5000, 4190, None)
+        );
+        // powered, but discharging
+        assert_eq!(
+            ChargingState::Fault,
+            charging_state(&CONFIG, 5000, 3700, Some(-10))
+        );
+    }
+
+    #[test]
+    fn test_config_for_battery() {
+        assert_eq!(
+            CONFIG,
+            PowerSenseConfig::for_battery(&Battery::OLIMEX_LIPO_250MAH)
+        );
+
+        let config = PowerSenseConfig::for_battery(&Battery::LIPO_2S);
+        assert_eq!(8700, config.present_millivolts);
+        assert_eq!(8300, config.charged_millivolts);
+        assert_eq!((5000, 8600), config.plausible_battery_millivolts);
+    }
+
+    #[test]
+    fn test_battery_trend_and_state() {
+        let voltmeter =
+            Voltmeter::new(VoltageDivider::<470, 470>, Calibration::UNCALIBRATED, Async);
+        let mut power_sense = PowerSense::new(voltmeter, CONFIG);
+        let start = Instant::from_secs(0);
+
+        assert_eq!(
+            ChargingState::NotPowered,
+            power_sense.update(0, 3700, start)
+        );
+        assert_eq!(None, power_sense.battery_trend());
+
+        // plugged in, the battery voltage rises 1 mV every second => 60 mV / minute
+        for second in 1..=60 {
+            let state = power_sense.update(
+                5000,
+                3700 + second,
+                start + Duration::from_secs(second.into()),
+            );
+            assert_eq!(ChargingState::Charging, state);
+        }
+        assert_eq!(Some(60), power_sense.battery_trend());
+
+        // only the last 16 samples 10 seconds apart are kept
+        for second in 61..=300 {
+            power_sense.update(5000, 3760, start + Duration::from_secs(second));
+        }
+        assert_eq!(Some(0), power_sense.battery_trend());
+        assert_eq!(ChargingState::Charging, power_sense.state());
+    }
+
+    #[test]
+    fn test_measure_supply_voltage() {
+        let voltmeter =
+            Voltmeter::new(VoltageDivider::<470, 470>, Calibration::UNCALIBRATED, Async);
+        let mut power_sense = PowerSense::new(voltmeter, CONFIG);
+
+        // 3103 * 3300 / 4096 * 2 = ~5000 mV
+        let mut adc = ScriptedAdc::new(&[Sample::Value(3103)]);
+        let millivolts = block_on(power_sense.measure(&mut adc)).unwrap();
+
+        assert!((4998..=5001).contains(&millivolts), "{millivolts}");
+        assert_eq!(millivolts, power_sense.millivolts(3103));
+    }
+}
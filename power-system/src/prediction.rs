@@ -0,0 +1,260 @@
+//! Time-to-empty and time-to-full prediction.
+//!
+//! Under a constant load (or charging current) the State of Charge changes linearly
+//! with time, so we fit a line through the timestamped State of Charge history
+//! (least squares) and extrapolate it to `0‰` or `1000‰`.
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use heapless::Deque;
+
+/// What the [`Prediction`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum PredictionKind {
+    /// The battery is discharging, minutes until it's empty
+    TimeToEmpty,
+    /// The battery is charging, minutes until it's full
+    TimeToFull,
+}
+
+impl PredictionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PredictionKind::TimeToEmpty => "EMPTY",
+            PredictionKind::TimeToFull => "FULL",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Prediction {
+    pub kind: PredictionKind,
+    pub minutes: u32,
+    /// How well the history fits a line (the coefficient of determination) in percentage
+    pub confidence: u8,
+}
+
+/// The number of State of Charge samples used for the prediction
+pub const PREDICTION_SAMPLES: usize = 101;
+
+/// Predicts the time to empty or full from the State of Charge history.
+#[derive(Debug, Clone)]
+pub struct Predictor<const N: usize = PREDICTION_SAMPLES> {
+    /// The minimum time between two samples of the history,
+    /// e.g. 1 minute gives us `N` minutes of history
+    pub sample_interval: Duration,
+    /// The State of Charge (in ‰ per hour) below which the battery is considered steady
+    pub flat_slope: u32,
+    samples: Deque<(Instant, u16), N>,
+}
+
+impl<const N: usize> Predictor<N> {
+    /// The minimum number of samples for a prediction
+    pub const MIN_SAMPLES: usize = 5;
+
+    pub fn new(sample_interval: Duration, flat_slope: u32) -> Self {
+        Self {
+            sample_interval,
+            flat_slope,
+            samples: Deque::new(),
+        }
+    }
+
+    /// Adds the State of Charge (in per-mille) to the history
+    /// if at least [`Predictor::sample_interval`] passed since the last sample.
+    pub fn update(&mut self, permille: u16, now: Instant) {
+        let should_sample = match self.samples.back() {
+            Some((sampled_at, _)) => {
+                now.checked_duration_since(*sampled_at)
+                    .unwrap_or(Duration::from_ticks(0))
+                    >= self.sample_interval
+            }
+            None => true,
+        };
+
+        if should_sample {
+            if self.samples.is_full() {
+                self.samples.pop_front();
+            }
+            self.samples
+                .push_back((now, permille))
+                .expect("Should have space after removing the oldest sample");
+        }
+    }
+
+    /// Forgets the history, e.g. when the battery is replaced.
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Fits a line through the history and extrapolates it to `0‰` or `1000‰`.
+    ///
+    /// Returns `None` when there are less than [`Predictor::MIN_SAMPLES`]
+    /// or the State of Charge is steady.
+    pub fn predict(&self) -> Option<Prediction> {
+        if self.samples.len() < Self::MIN_SAMPLES {
+            return None;
+        }
+
+        let (first_at, _) = *self.samples.front()?;
+        let (last_at, _) = *self.samples.back()?;
+
+        // integer sums of the seconds since the first sample and the State of Charge
+        let (mut n, mut sum_x, mut sum_y, mut sum_xx, mut sum_xy, mut sum_yy) =
+            (0_i64, 0_i64, 0_i64, 0_i64, 0_i64, 0_i64);
+        for (at, permille) in self.samples.iter() {
+            let x = at.checked_duration_since(first_at)?.as_secs() as i64;
+            let y = *permille as i64;
+
+            n += 1;
+            sum_x += x;
+            sum_y += y;
+            sum_xx += x * x;
+            sum_xy += x * y;
+            sum_yy += y * y;
+        }
+
+        let variance_x = (n * sum_xx - sum_x * sum_x) as f32;
+        let variance_y = (n * sum_yy - sum_y * sum_y) as f32;
+        let covariance = (n * sum_xy - sum_x * sum_y) as f32;
+        if variance_x <= 0.0 || variance_y <= 0.0 {
+            return None;
+        }
+
+        // in ‰ per second
+        let slope = covariance / variance_x;
+        if slope.abs() * 3600.0 < self.flat_slope as f32 {
+            return None;
+        }
+
+        // the fitted State of Charge at the last sample
+        let last_x = last_at.checked_duration_since(first_at)?.as_secs() as f32;
+        let mean_x = sum_x as f32 / n as f32;
+        let mean_y = sum_y as f32 / n as f32;
+        let current = (mean_y + slope * (last_x - mean_x)).clamp(0.0, 1000.0);
+
+        let (kind, seconds) = if slope < 0.0 {
+            (PredictionKind::TimeToEmpty, current / -slope)
+        } else {
+            (PredictionKind::TimeToFull, (1000.0 - current) / slope)
+        };
+
+        let r_squared = covariance * covariance / (variance_x * variance_y);
+
+        Some(Prediction {
+            kind,
+            minutes: (seconds / 60.0 + 0.5) as u32,
+            confidence: (r_squared * 100.0 + 0.5).min(100.0) as u8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn predictor() -> Predictor<PREDICTION_SAMPLES> {
+        Predictor::new(MINUTE, 5)
+    }
+
+    #[test]
+    fn test_time_to_empty() {
+        let mut predictor = predictor();
+        let start = Instant::from_secs(1000);
+
+        // 2‰ per minute, i.e. 100% in ~8 hours
+        for minute in 0..30_u16 {
+            predictor.update(800 - minute * 2, start + MINUTE * minute.into());
+            // more samples within the same minute are skipped
+            predictor.update(0, start + MINUTE * minute.into() + Duration::from_secs(1));
+        }
+        assert_eq!(30, predictor.len());
+
+        // 742‰ left at 2‰ per minute
+        assert_eq!(
+            Some(Prediction {
+                kind: PredictionKind::TimeToEmpty,
+                minutes: 371,
+                confidence: 100,
+            }),
+            predictor.predict()
+        );
+    }
+
+    #[test]
+    fn test_time_to_full_with_noise() {
+        let mut predictor = predictor();
+        let start = Instant::from_secs(0);
+
+        // 5‰ per minute with ±10‰ of noise
+        let noise = [0, 10, -10, 5, -5, 10, 0, -10, 5, -5];
+        for minute in 0..40_i32 {
+            let permille = 300 + minute * 5 + noise[minute as usize % noise.len()];
+            predictor.update(permille as u16, start + MINUTE * minute as u32);
+        }
+
+        let prediction = predictor.predict().unwrap();
+        assert_eq!(PredictionKind::TimeToFull, prediction.kind);
+        // 495‰ left at 5‰ per minute
+        assert!((95..=103).contains(&prediction.minutes), "{prediction:?}");
+        assert!((90..100).contains(&prediction.confidence), "{prediction:?}");
+    }
+
+    #[test]
+    fn test_no_prediction() {
+        let mut predictor = predictor();
+        let start = Instant::from_secs(0);
+
+        for minute in 0..4 {
+            predictor.update(800 - minute * 10, start + MINUTE * minute.into());
+        }
+        // not enough samples
+        assert_eq!(None, predictor.predict());
+
+        // steady
+        predictor.reset();
+        assert!(predictor.is_empty());
+        for minute in 0..20 {
+            predictor.update(800, start + MINUTE * minute);
+        }
+        assert_eq!(None, predictor.predict());
+
+        // 3‰ per hour is below the flat slope
+        predictor.reset();
+        for minute in 0..=60 {
+            predictor.update(800 - (minute / 20) as u16, start + MINUTE * minute);
+        }
+        assert_eq!(None, predictor.predict());
+    }
+
+    #[test]
+    fn test_keeps_the_last_samples() {
+        let mut predictor = Predictor::<10>::new(MINUTE, 5);
+        let start = Instant::from_secs(0);
+
+        // charging and then discharging
+        for minute in 0..20_u16 {
+            let permille = if minute < 10 {
+                500 + minute
+            } else {
+                510 - (minute - 10) * 10
+            };
+            predictor.update(permille, start + MINUTE * minute.into());
+        }
+        assert_eq!(10, predictor.len());
+
+        let prediction = predictor.predict().unwrap();
+        assert_eq!(PredictionKind::TimeToEmpty, prediction.kind);
+        // 420‰ left at 10‰ per minute
+        assert_eq!(42, prediction.minutes);
+    }
+}
@@ -0,0 +1,851 @@
+//! Switchable power rails with load shedding.
+//!
+//! Each [`RailId`] has an enable output, a [`RailPriority`], a default state and optionally
+//! an over-current latch input. The [`RailManager`] decides which rails are on
+//! and the [`RailSwitches`] switch them:
+//!
+//! - the rails are shed by their priority as the [`PowerMode`] worsens
+//!   and restored once it recovers,
+//! - a rail whose over-current latch is triggered is switched off until it's reset,
+//! - the onboard computer switches the rails with [`RailCommand`]s
+//!   (`$PPWC,RAIL,<rail>,<ON|OFF|RESET>*hh`), which are refused by the [`Interlock`]s.
+use core::str::FromStr;
+
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use heapless::Vec;
+
+use crate::{power_mode::PowerMode, telemetry::checksum};
+
+/// The power rails of the satellite bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RailId {
+    /// The onboard computer
+    Obc,
+    Gnss,
+    Radio,
+    Payload,
+}
+
+impl RailId {
+    pub const ALL: [RailId; 4] = [RailId::Obc, RailId::Gnss, RailId::Radio, RailId::Payload];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RailId::Obc => "OBC",
+            RailId::Gnss => "GNSS",
+            RailId::Radio => "RADIO",
+            RailId::Payload => "PAYLOAD",
+        }
+    }
+}
+
+impl FromStr for RailId {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RailId::ALL
+            .into_iter()
+            .find(|rail| rail.as_str() == s)
+            .ok_or(CommandError::UnknownRail)
+    }
+}
+
+/// The rail priorities ordered from the most to the least important one,
+/// i.e. `Essential < High < Medium < Low`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub enum RailPriority {
+    /// Never shed and cannot be switched off
+    Essential,
+    /// Shed in [`PowerMode::Survival`]
+    High,
+    /// Shed in [`PowerMode::Critical`] and worse
+    Medium,
+    /// Shed in [`PowerMode::Low`] and worse
+    Low,
+}
+
+impl RailPriority {
+    /// Whether a rail of this priority is shed in the power mode
+    pub fn is_shed(&self, mode: PowerMode) -> bool {
+        match self {
+            RailPriority::Essential => false,
+            RailPriority::High => mode >= PowerMode::Survival,
+            RailPriority::Medium => mode >= PowerMode::Critical,
+            RailPriority::Low => mode >= PowerMode::Low,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct RailConfig {
+    pub id: RailId,
+    pub priority: RailPriority,
+    /// Whether the rail is on after boot (unless it's shed)
+    pub default_on: bool,
+    /// Whether the rail has an over-current latch input
+    pub has_latch: bool,
+    /// The typical current (in mA) of the rail's load from the battery
+    pub load_milliamps: u32,
+}
+
+impl RailConfig {
+    /// The rails of the satellite bus, keeping the onboard computer and the radio
+    /// for as long as possible
+    pub const BUS: [RailConfig; 4] = [
+        RailConfig {
+            id: RailId::Obc,
+            priority: RailPriority::Essential,
+            default_on: true,
+            has_latch: false,
+            load_milliamps: 40,
+        },
+        RailConfig {
+            id: RailId::Gnss,
+            priority: RailPriority::Medium,
+            default_on: true,
+            has_latch: false,
+            load_milliamps: 25,
+        },
+        RailConfig {
+            id: RailId::Radio,
+            priority: RailPriority::High,
+            default_on: true,
+            has_latch: true,
+            load_milliamps: 120,
+        },
+        RailConfig {
+            id: RailId::Payload,
+            priority: RailPriority::Low,
+            default_on: false,
+            has_latch: true,
+            load_milliamps: 200,
+        },
+    ];
+}
+
+/// The state of a rail, see [`RailManager::state`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RailState {
+    On,
+    /// Switched off by default or by a command
+    Off,
+    /// Switched off by the power mode
+    Shed,
+    /// Switched off by the over-current latch
+    Tripped,
+}
+
+impl RailState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RailState::On => "ON",
+            RailState::Off => "OFF",
+            RailState::Shed => "SHED",
+            RailState::Tripped => "TRIPPED",
+        }
+    }
+}
+
+/// A rail switched on or off, the enable output should follow it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct RailChange {
+    pub rail: RailId,
+    pub on: bool,
+    /// The change of the battery current (in mA), positive when switched on
+    pub milliamps: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RailAction {
+    On,
+    Off,
+    /// Clears the over-current latch, the rail is switched back on if it was requested
+    Reset,
+}
+
+impl RailAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RailAction::On => "ON",
+            RailAction::Off => "OFF",
+            RailAction::Reset => "RESET",
+        }
+    }
+}
+
+impl FromStr for RailAction {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ON" => Ok(RailAction::On),
+            "OFF" => Ok(RailAction::Off),
+            "RESET" => Ok(RailAction::Reset),
+            _ => Err(CommandError::UnknownAction),
+        }
+    }
+}
+
+/// Why a command sentence was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum CommandError {
+    /// Not a `$PPWC,RAIL,...*hh` sentence
+    Malformed,
+    Checksum,
+    UnknownRail,
+    UnknownAction,
+}
+
+impl CommandError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandError::Malformed => "MALFORMED",
+            CommandError::Checksum => "CHECKSUM",
+            CommandError::UnknownRail => "UNKNOWN_RAIL",
+            CommandError::UnknownAction => "UNKNOWN_ACTION",
+        }
+    }
+}
+
+/// A command of the onboard computer: `$PPWC,RAIL,<rail>,<action>*hh`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct RailCommand {
+    pub rail: RailId,
+    pub action: RailAction,
+}
+
+impl RailCommand {
+    /// Parses a command sentence, with or without the trailing `\r\n`.
+    pub fn parse(sentence: &str) -> Result<Self, CommandError> {
+        let sentence = sentence.trim_end_matches(['\r', '\n']);
+        let (body, hex) = sentence
+            .strip_prefix('$')
+            .and_then(|sentence| sentence.split_once('*'))
+            .ok_or(CommandError::Malformed)?;
+
+        let expected = u8::from_str_radix(hex, 16).map_err(|_| CommandError::Malformed)?;
+        if checksum(body.as_bytes()) != expected {
+            return Err(CommandError::Checksum);
+        }
+
+        let mut fields = body.split(',');
+        if (fields.next(), fields.next()) != (Some("PPWC"), Some("RAIL")) {
+            return Err(CommandError::Malformed);
+        }
+        let rail = fields.next().ok_or(CommandError::Malformed)?.parse()?;
+        let action = fields.next().ok_or(CommandError::Malformed)?.parse()?;
+        if fields.next().is_some() {
+            return Err(CommandError::Malformed);
+        }
+
+        Ok(Self { rail, action })
+    }
+}
+
+/// Why a [`RailCommand`] was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Interlock {
+    /// An [`RailPriority::Essential`] rail cannot be switched off
+    Essential,
+    /// The rail cannot be switched on in the current power mode
+    Shed,
+    /// The rail cannot be switched on until its over-current latch is reset
+    Tripped,
+    /// The rail doesn't have an over-current latch to reset
+    NoLatch,
+    /// The rail is not managed by this board
+    NotManaged,
+}
+
+impl Interlock {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Interlock::Essential => "ESSENTIAL",
+            Interlock::Shed => "SHED",
+            Interlock::Tripped => "TRIPPED",
+            Interlock::NoLatch => "NO_LATCH",
+            Interlock::NotManaged => "NOT_MANAGED",
+        }
+    }
+}
+
+/// The result of a [`RailCommand`] for the onboard computer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct CommandResponse {
+    pub command: RailCommand,
+    pub result: Result<RailState, Interlock>,
+}
+
+/// The commands received from the onboard computer for the rails task.
+pub static RAIL_COMMANDS: Channel<CriticalSectionRawMutex, RailCommand, 4> = Channel::new();
+
+/// The responses to the [`RAIL_COMMANDS`] for the onboard computer.
+pub static RAIL_RESPONSES: Channel<CriticalSectionRawMutex, CommandResponse, 4> = Channel::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rail {
+    config: RailConfig,
+    /// Switched on by default or by a command
+    requested_on: bool,
+    tripped: bool,
+    /// The state of the enable output
+    on: bool,
+}
+
+/// Decides which rails are on.
+///
+/// All the rails start off, the first [`RailManager::set_power_mode`]
+/// switches on the default ones.
+#[derive(Debug, Clone)]
+pub struct RailManager<const N: usize> {
+    rails: [Rail; N],
+    mode: PowerMode,
+}
+
+impl<const N: usize> RailManager<N> {
+    pub fn new(configs: [RailConfig; N]) -> Self {
+        Self {
+            rails: configs.map(|config| Rail {
+                config,
+                requested_on: config.default_on,
+                tripped: false,
+                on: false,
+            }),
+            mode: PowerMode::Nominal,
+        }
+    }
+
+    pub fn power_mode(&self) -> PowerMode {
+        self.mode
+    }
+
+    /// The state of the rail, `None` if it's not managed
+    pub fn state(&self, rail: RailId) -> Option<RailState> {
+        self.rail(rail).map(|rail| {
+            if rail.on {
+                RailState::On
+            } else if rail.tripped {
+                RailState::Tripped
+            } else if rail.requested_on && rail.config.priority.is_shed(self.mode) {
+                RailState::Shed
+            } else {
+                RailState::Off
+            }
+        })
+    }
+
+    /// The state of each rail
+    pub fn states(&self) -> [(RailId, RailState); N] {
+        self.rails.map(|rail| {
+            let id = rail.config.id;
+            (id, self.state(id).unwrap_or(RailState::Off))
+        })
+    }
+
+    /// Sheds or restores the rails for the power mode.
+    ///
+    /// The rails are switched off from the least important one
+    /// and switched on from the most important one.
+    pub fn set_power_mode(&mut self, mode: PowerMode) -> Vec<RailChange, N> {
+        self.mode = mode;
+
+        self.apply()
+    }
+
+    /// The over-current latch of the rail is triggered, it stays off until it's reset.
+    ///
+    /// Ignored for a rail without a latch or which is not on,
+    /// the latch is only meaningful while the rail is powered.
+    pub fn over_current(&mut self, rail: RailId) -> Option<RailChange> {
+        let index = self.index(rail)?;
+        if !self.rails[index].config.has_latch || !self.rails[index].on {
+            return None;
+        }
+        self.rails[index].tripped = true;
+
+        self.apply().into_iter().next()
+    }
+
+    /// Executes a command of the onboard computer unless an [`Interlock`] refuses it.
+    pub fn command(&mut self, command: RailCommand) -> (CommandResponse, Option<RailChange>) {
+        let result = self.try_command(command);
+        let change = match result {
+            Ok(()) => self.apply().into_iter().next(),
+            Err(_) => None,
+        };
+        let response = CommandResponse {
+            command,
+            result: result.map(|()| self.state(command.rail).unwrap_or(RailState::Off)),
+        };
+
+        (response, change)
+    }
+
+    fn try_command(&mut self, command: RailCommand) -> Result<(), Interlock> {
+        let mode = self.mode;
+        let index = self.index(command.rail).ok_or(Interlock::NotManaged)?;
+        let rail = &mut self.rails[index];
+
+        match command.action {
+            RailAction::On if rail.tripped => Err(Interlock::Tripped),
+            RailAction::On if rail.config.priority.is_shed(mode) => Err(Interlock::Shed),
+            RailAction::On => {
+                rail.requested_on = true;
+                Ok(())
+            }
+            RailAction::Off if rail.config.priority == RailPriority::Essential => {
+                Err(Interlock::Essential)
+            }
+            RailAction::Off => {
+                rail.requested_on = false;
+                Ok(())
+            }
+            RailAction::Reset if !rail.config.has_latch => Err(Interlock::NoLatch),
+            RailAction::Reset => {
+                rail.tripped = false;
+                Ok(())
+            }
+        }
+    }
+
+    /// Switches the rails to their decided state and returns the changes
+    /// ordered by the priority.
+    fn apply(&mut self) -> Vec<RailChange, N> {
+        let mode = self.mode;
+        let mut changes = Vec::<RailChange, N>::new();
+
+        for rail in self.rails.iter_mut() {
+            let on = rail.requested_on && !rail.tripped && !rail.config.priority.is_shed(mode);
+            if on != rail.on {
+                rail.on = on;
+                let milliamps = rail.config.load_milliamps as i32;
+                changes
+                    .push(RailChange {
+                        rail: rail.config.id,
+                        on,
+                        milliamps: if on { milliamps } else { -milliamps },
+                    })
+                    .expect("Should have at most 1 change per rail");
+            }
+        }
+
+        // off from the least important rail and then on from the most important one
+        changes.sort_unstable_by_key(|change| {
+            let priority =
+                self.rail(change.rail)
+                    .map_or(RailPriority::Low, |rail| rail.config.priority) as i8;
+
+            (change.on, if change.on { priority } else { -priority })
+        });
+
+        changes
+    }
+
+    fn index(&self, rail: RailId) -> Option<usize> {
+        self.rails
+            .iter()
+            .position(|managed| managed.config.id == rail)
+    }
+
+    fn rail(&self, rail: RailId) -> Option<&Rail> {
+        self.index(rail).map(|index| &self.rails[index])
+    }
+}
+
+/// The TCA9534 registers
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+enum Register {
+    Input = 0x00,
+    Output = 0x01,
+    Configuration = 0x03,
+}
+
+/// The rail enables and over-current latch flags on a TCA9534 I2C I/O expander.
+///
+/// The ESP32-C3 board has no free GPIOs for them: the rest are strapping pins (2, 8 and 9)
+/// and the USB (18 and 19), so the expander shares the I2C bus with the current sensors.
+///
+/// - `P0..=P3` are the enables of the rails in [`RailId::ALL`] order, high switches
+///   the rail on. The expander's pins are inputs after its power-on reset, the pull-downs
+///   of the load switches keep the rails off until [`RailSwitches::init`].
+///   The expander keeps its registers through a reset of the ESP32-C3,
+///   so a reboot of the power system doesn't switch the rails.
+/// - `P4..=P7` are the (active low) fault flags of the rails' load switches
+///   in the same order, only the rails with [`RailConfig::has_latch`] have one.
+pub struct RailSwitches<I2C> {
+    i2c: I2C,
+    address: u8,
+    /// The enables as they should be
+    outputs: u8,
+    /// Whether the last write of the enables succeeded
+    synced: bool,
+}
+
+impl<I2C, E> RailSwitches<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    /// The address with `A0`, `A1` and `A2` to GND
+    pub const DEFAULT_ADDRESS: u8 = 0x20;
+
+    const ENABLES: u8 = 0x0F;
+    const FAULT_SHIFT: u8 = 4;
+
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self {
+            i2c,
+            address,
+            outputs: 0,
+            synced: false,
+        }
+    }
+
+    /// Configures the enables as outputs, the essential rails are switched on
+    /// and the others are kept as they are.
+    ///
+    /// After a reboot of the power system the expander still drives the enables,
+    /// they are read back so that no rail is power-cycled. After the expander's
+    /// power-on reset only the essential rails are switched on before the enables
+    /// become outputs (the output register defaults to all high).
+    pub fn init(&mut self, rails: &[RailConfig]) -> Result<(), E> {
+        let essential = rails
+            .iter()
+            .filter(|rail| rail.priority == RailPriority::Essential)
+            .fold(0, |mask, rail| mask | 1 << rail.id as u8);
+
+        let configuration = self.read(Register::Configuration)?;
+        let outputs = if configuration & Self::ENABLES == 0 {
+            self.read(Register::Output)? & Self::ENABLES
+        } else {
+            0
+        };
+        self.outputs = outputs | essential;
+        self.sync()?;
+
+        self.i2c.write(
+            self.address,
+            &[Register::Configuration as u8, !Self::ENABLES],
+        )
+    }
+
+    /// Whether the rail's enable is (to be) switched on
+    pub fn is_on(&self, rail: RailId) -> bool {
+        self.outputs & 1 << rail as u8 != 0
+    }
+
+    /// Switches the rail's enable.
+    ///
+    /// When the write fails the enable is still switched by the next [`RailSwitches::sync`].
+    pub fn switch(&mut self, rail: RailId, on: bool) -> Result<(), E> {
+        let bit = 1 << rail as u8;
+        if on {
+            self.outputs |= bit;
+        } else {
+            self.outputs &= !bit;
+        }
+
+        self.sync()
+    }
+
+    /// Whether the enables were written since the last change or failure
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Writes the enables.
+    pub fn sync(&mut self) -> Result<(), E> {
+        let result = self
+            .i2c
+            .write(self.address, &[Register::Output as u8, self.outputs]);
+        self.synced = result.is_ok();
+
+        result
+    }
+
+    /// The rails whose over-current fault flag is raised.
+    pub fn over_current(&mut self) -> Result<Vec<RailId, 4>, E> {
+        let faults = !self.read(Register::Input)? >> Self::FAULT_SHIFT;
+
+        Ok(RailId::ALL
+            .into_iter()
+            .filter(|rail| faults & (1 << *rail as u8) != 0)
+            .collect())
+    }
+
+    fn read(&mut self, register: Register) -> Result<u8, E> {
+        let mut buffer = [0_u8; 1];
+        self.i2c
+            .write_read(self.address, &[register as u8], &mut buffer)?;
+
+        Ok(buffer[0])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn change(rail: RailId, on: bool, milliamps: i32) -> RailChange {
+        RailChange {
+            rail,
+            on,
+            milliamps,
+        }
+    }
+
+    fn command(rail: RailId, action: RailAction) -> RailCommand {
+        RailCommand { rail, action }
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            Ok(command(RailId::Payload, RailAction::On)),
+            RailCommand::parse("$PPWC,RAIL,PAYLOAD,ON*61\r\n")
+        );
+        assert_eq!(
+            Ok(command(RailId::Gnss, RailAction::Reset)),
+            RailCommand::parse("$PPWC,RAIL,GNSS,RESET*72")
+        );
+        assert_eq!(
+            Err(CommandError::Checksum),
+            RailCommand::parse("$PPWC,RAIL,PAYLOAD,ON*62")
+        );
+        assert_eq!(
+            Err(CommandError::Malformed),
+            RailCommand::parse("PPWC,RAIL,PAYLOAD,ON")
+        );
+        assert_eq!(
+            Err(CommandError::UnknownRail),
+            RailCommand::parse("$PPWC,RAIL,HEATER,ON*20")
+        );
+        assert_eq!(
+            Err(CommandError::UnknownAction),
+            RailCommand::parse("$PPWC,RAIL,GNSS,TOGGLE*35")
+        );
+    }
+
+    #[test]
+    fn test_load_shedding() {
+        let mut manager = RailManager::new(RailConfig::BUS);
+        assert_eq!(Some(RailState::Off), manager.state(RailId::Obc));
+
+        // the default rails from the most important one
+        assert_eq!(
+            [
+                change(RailId::Obc, true, 40),
+                change(RailId::Radio, true, 120),
+                change(RailId::Gnss, true, 25),
+            ]
+            .as_slice(),
+            manager.set_power_mode(PowerMode::Nominal).as_slice()
+        );
+        assert_eq!(
+            (
+                CommandResponse {
+                    command: command(RailId::Payload, RailAction::On),
+                    result: Ok(RailState::On),
+                },
+                Some(change(RailId::Payload, true, 200))
+            ),
+            manager.command(command(RailId::Payload, RailAction::On))
+        );
+
+        // from the least important one
+        assert_eq!(
+            [
+                change(RailId::Payload, false, -200),
+                change(RailId::Gnss, false, -25),
+            ]
+            .as_slice(),
+            manager.set_power_mode(PowerMode::Critical).as_slice()
+        );
+        assert_eq!(Some(RailState::Shed), manager.state(RailId::Payload));
+        assert_eq!(
+            [change(RailId::Radio, false, -120)].as_slice(),
+            manager.set_power_mode(PowerMode::Survival).as_slice()
+        );
+        assert_eq!(Some(RailState::On), manager.state(RailId::Obc));
+
+        // restored from the most important one
+        assert_eq!(
+            [
+                change(RailId::Radio, true, 120),
+                change(RailId::Gnss, true, 25),
+                change(RailId::Payload, true, 200),
+            ]
+            .as_slice(),
+            manager.set_power_mode(PowerMode::Nominal).as_slice()
+        );
+    }
+
+    #[test]
+    fn test_interlocks() {
+        let mut manager = RailManager::new(RailConfig::BUS);
+        manager.set_power_mode(PowerMode::Low);
+
+        let refused = |manager: &mut RailManager<4>, rail, action| {
+            let (response, switched) = manager.command(command(rail, action));
+            assert_eq!(None, switched);
+            response.result.unwrap_err()
+        };
+
+        assert_eq!(
+            Interlock::Essential,
+            refused(&mut manager, RailId::Obc, RailAction::Off)
+        );
+        assert_eq!(
+            Interlock::Shed,
+            refused(&mut manager, RailId::Payload, RailAction::On)
+        );
+        assert_eq!(
+            Interlock::NoLatch,
+            refused(&mut manager, RailId::Gnss, RailAction::Reset)
+        );
+
+        // switching off a rail is always allowed, except for the essential ones
+        let (response, switched) = manager.command(command(RailId::Gnss, RailAction::Off));
+        assert_eq!(Ok(RailState::Off), response.result);
+        assert_eq!(Some(change(RailId::Gnss, false, -25)), switched);
+
+        // the latch keeps the rail off until it's reset
+        manager.set_power_mode(PowerMode::Nominal);
+        manager.command(command(RailId::Payload, RailAction::On));
+        assert_eq!(
+            Some(change(RailId::Payload, false, -200)),
+            manager.over_current(RailId::Payload)
+        );
+        assert_eq!(Some(RailState::Tripped), manager.state(RailId::Payload));
+        assert_eq!(
+            Interlock::Tripped,
+            refused(&mut manager, RailId::Payload, RailAction::On)
+        );
+        // a fault flag of a rail without a latch, or which is off, is not an over-current
+        assert_eq!(None, manager.over_current(RailId::Obc));
+        assert_eq!(None, manager.over_current(RailId::Payload));
+        assert_eq!(
+            (
+                CommandResponse {
+                    command: command(RailId::Payload, RailAction::Reset),
+                    result: Ok(RailState::On),
+                },
+                Some(change(RailId::Payload, true, 200))
+            ),
+            manager.command(command(RailId::Payload, RailAction::Reset))
+        );
+    }
+
+    /// An I2C bus with a TCA9534 which returns the given input port
+    /// and records the writes.
+    struct FakeTca9534 {
+        input: u8,
+        output: u8,
+        configuration: u8,
+        writes: std::vec::Vec<std::vec::Vec<u8>>,
+    }
+
+    impl FakeTca9534 {
+        /// The registers after the expander's power-on reset
+        fn powered_on() -> Self {
+            Self {
+                input: 0xFF,
+                output: 0xFF,
+                configuration: 0xFF,
+                writes: std::vec::Vec::new(),
+            }
+        }
+    }
+
+    impl Write for FakeTca9534 {
+        type Error = ();
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+            if address != RailSwitches::<Self>::DEFAULT_ADDRESS {
+                return Err(());
+            }
+            match bytes {
+                [0x01, output] => self.output = *output,
+                [0x03, configuration] => self.configuration = *configuration,
+                _ => return Err(()),
+            }
+            self.writes.push(bytes.to_vec());
+            Ok(())
+        }
+    }
+
+    impl WriteRead for FakeTca9534 {
+        type Error = ();
+
+        fn write_read(
+            &mut self,
+            address: u8,
+            bytes: &[u8],
+            buffer: &mut [u8],
+        ) -> Result<(), Self::Error> {
+            if address != RailSwitches::<Self>::DEFAULT_ADDRESS {
+                return Err(());
+            }
+            buffer[0] = match bytes {
+                [0x00] => self.input,
+                [0x01] => self.output,
+                [0x03] => self.configuration,
+                _ => return Err(()),
+            };
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_rail_switches() {
+        let mut switches = RailSwitches::new(FakeTca9534::powered_on(), 0x20);
+        assert!(!switches.is_synced());
+
+        // only the onboard computer is switched on before the enables become outputs
+        switches.init(&RailConfig::BUS).unwrap();
+        assert!(switches.is_synced());
+        assert!(switches.is_on(RailId::Obc));
+        switches.switch(RailId::Radio, true).unwrap();
+        switches.switch(RailId::Gnss, true).unwrap();
+        switches.switch(RailId::Gnss, false).unwrap();
+        assert_eq!(
+            std::vec![
+                std::vec![0x01, 0b0001],
+                std::vec![0x03, 0xF0],
+                std::vec![0x01, 0b0101],
+                std::vec![0x01, 0b0111],
+                std::vec![0x01, 0b0101],
+            ],
+            switches.i2c.writes
+        );
+
+        // a reboot of the power system keeps the rails on
+        let mut rebooted = RailSwitches::new(switches.i2c, 0x20);
+        rebooted.i2c.writes.clear();
+        rebooted.init(&RailConfig::BUS).unwrap();
+        assert!(rebooted.is_on(RailId::Radio));
+        assert!(!rebooted.is_on(RailId::Gnss));
+        assert_eq!(
+            std::vec![std::vec![0x01, 0b0101], std::vec![0x03, 0xF0]],
+            rebooted.i2c.writes
+        );
+        let mut switches = rebooted;
+
+        // the radio's fault flag is low, the enables read back as they are
+        switches.i2c.input = 0b1011_0100;
+        assert_eq!(
+            [RailId::Radio].as_slice(),
+            switches.over_current().unwrap().as_slice()
+        );
+        switches.i2c.input = 0xF0;
+        assert!(switches.over_current().unwrap().is_empty());
+
+        // a missing expander
+        let mut missing = RailSwitches::new(FakeTca9534::powered_on(), 0x21);
+        assert_eq!(Err(()), missing.init(&RailConfig::BUS));
+        assert_eq!(Err(()), missing.switch(RailId::Gnss, true));
+        assert!(!missing.is_synced());
+    }
+}
@@ -0,0 +1,286 @@
+//! Solar panels input and eclipse detection.
+//!
+//! The voltage of the solar panels (before the CN3791 charger) is measured through
+//! its own [`VoltageDivider`](crate::battery::VoltageDivider) and the [`EclipseDetector`]
+//! decides whether the satellite is sunlit or in the Earth's shadow.
+//!
+//! The resulting [`EclipseEvent`]s are published on [`ECLIPSE_EVENTS`].
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
+use embassy_time::{Duration, Instant};
+
+use crate::battery::{Async, Voltmeter};
+
+/// Whether the solar panels are illuminated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Illumination {
+    /// Not decided yet, e.g. right after boot while the voltage is within the hysteresis
+    Unknown,
+    Sunlit,
+    Eclipse,
+}
+
+impl Illumination {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Illumination::Unknown => "UNKNOWN",
+            Illumination::Sunlit => "SUNLIT",
+            Illumination::Eclipse => "ECLIPSE",
+        }
+    }
+}
+
+/// An eclipse entry or exit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EclipseEvent {
+    Entered {
+        at: Instant,
+    },
+    Exited {
+        at: Instant,
+        /// `None` when the entry was not observed, e.g. after booting in eclipse
+        duration: Option<Duration>,
+    },
+}
+
+impl EclipseEvent {
+    pub fn at(&self) -> Instant {
+        match self {
+            EclipseEvent::Entered { at } | EclipseEvent::Exited { at, .. } => *at,
+        }
+    }
+}
+
+/// The capacity, subscribers and publishers of the [`ECLIPSE_EVENTS`] channel.
+pub type EclipseChannel = PubSubChannel<CriticalSectionRawMutex, EclipseEvent, 4, 2, 1>;
+
+/// Every eclipse entry and exit is published here for the rest of the tasks
+/// and reported to the onboard computer.
+pub static ECLIPSE_EVENTS: EclipseChannel = PubSubChannel::new();
+
+/// The solar panels' voltage divider
+pub struct SolarInput<const R1: usize, const R2: usize> {
+    pub voltmeter: Voltmeter<Async, R1, R2>,
+}
+
+impl<const R1: usize, const R2: usize> SolarInput<R1, R2> {
+    pub fn new(voltmeter: Voltmeter<Async, R1, R2>) -> Self {
+        Self { voltmeter }
+    }
+
+    /// The solar panels' voltage in millivolts for a raw ADC value,
+    /// e.g. the mediana of an [`AdcReading`](crate::adc_scheduler::AdcReading).
+    pub fn millivolts(&self, value: u16) -> u32 {
+        self.voltmeter.to_millivolts(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct EclipseConfig {
+    /// Sunlit at or above this solar voltage (in mV)
+    pub sunlit_millivolts: u32,
+    /// In eclipse below this solar voltage (in mV)
+    pub eclipse_millivolts: u32,
+    /// The number of consecutive samples beyond a threshold to change the illumination,
+    /// e.g. to ignore a panel briefly facing away from the Sun while tumbling
+    pub confirm_samples: u8,
+}
+
+impl EclipseConfig {
+    /// Two 6 V panels in parallel
+    pub const SOLAR_6V: EclipseConfig = EclipseConfig {
+        sunlit_millivolts: 3000,
+        eclipse_millivolts: 1500,
+        confirm_samples: 3,
+    };
+}
+
+impl Default for EclipseConfig {
+    fn default() -> Self {
+        Self::SOLAR_6V
+    }
+}
+
+/// Decides the [`Illumination`] from the solar voltage with hysteresis
+/// and times the eclipses.
+#[derive(Debug, Clone)]
+pub struct EclipseDetector {
+    config: EclipseConfig,
+    illumination: Illumination,
+    /// The illumination beyond the thresholds, when it was first seen and for how many samples
+    pending: Option<(Illumination, Instant, u8)>,
+    /// When the current eclipse started, `None` when sunlit or the entry was not observed
+    entered_at: Option<Instant>,
+    last_duration: Option<Duration>,
+    eclipses: u32,
+}
+
+impl EclipseDetector {
+    pub fn new(config: EclipseConfig) -> Self {
+        Self {
+            config,
+            illumination: Illumination::Unknown,
+            pending: None,
+            entered_at: None,
+            last_duration: None,
+            eclipses: 0,
+        }
+    }
+
+    pub fn config(&self) -> &EclipseConfig {
+        &self.config
+    }
+
+    pub fn illumination(&self) -> Illumination {
+        self.illumination
+    }
+
+    /// The duration of the last whole eclipse
+    pub fn last_duration(&self) -> Option<Duration> {
+        self.last_duration
+    }
+
+    /// The number of eclipses entered since boot
+    pub fn eclipses(&self) -> u32 {
+        self.eclipses
+    }
+
+    /// Feeds a new solar voltage (in millivolts) to the detector.
+    ///
+    /// The entry and exit are timestamped with the first of the confirming samples.
+    /// The first decided illumination after boot is not an event.
+    pub fn update(&mut self, millivolts: u32, now: Instant) -> Option<EclipseEvent> {
+        let beyond = if millivolts >= self.config.sunlit_millivolts {
+            Illumination::Sunlit
+        } else if millivolts < self.config.eclipse_millivolts {
+            Illumination::Eclipse
+        } else {
+            // within the hysteresis
+            self.illumination
+        };
+
+        if beyond == self.illumination {
+            self.pending = None;
+            return None;
+        }
+
+        let (since, samples) = match self.pending {
+            Some((pending, since, samples)) if pending == beyond => (since, samples + 1),
+            _ => (now, 1),
+        };
+        if samples < self.config.confirm_samples {
+            self.pending = Some((beyond, since, samples));
+            return None;
+        }
+
+        let previous = self.illumination;
+        self.illumination = beyond;
+        self.pending = None;
+
+        match (previous, beyond) {
+            (Illumination::Sunlit, Illumination::Eclipse) => {
+                self.entered_at = Some(since);
+                self.eclipses += 1;
+
+                Some(EclipseEvent::Entered { at: since })
+            }
+            (Illumination::Eclipse, Illumination::Sunlit) => {
+                let duration = self
+                    .entered_at
+                    .take()
+                    .and_then(|entered_at| since.checked_duration_since(entered_at));
+                if duration.is_some() {
+                    self.last_duration = duration;
+                }
+
+                Some(EclipseEvent::Exited {
+                    at: since,
+                    duration,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Feeds a voltage trace, one sample per second, and returns the events.
+    fn run_trace(detector: &mut EclipseDetector, trace: &[u32]) -> std::vec::Vec<EclipseEvent> {
+        trace
+            .iter()
+            .enumerate()
+            .filter_map(|(second, millivolts)| {
+                detector.update(*millivolts, Instant::from_secs(second as u64))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_eclipse_entry_and_exit() {
+        let mut detector = EclipseDetector::new(EclipseConfig::SOLAR_6V);
+
+        // sunlit, a single glitch, eclipse for ~30 seconds and sunlit again
+        let mut trace = [5500_u32; 60];
+        trace[5] = 100;
+        for millivolts in &mut trace[10..40] {
+            *millivolts = 200;
+        }
+        // within the hysteresis doesn't change anything
+        trace[25] = 2000;
+        trace[40] = 2500;
+
+        let events = run_trace(&mut detector, &trace);
+
+        assert_eq!(
+            std::vec![
+                EclipseEvent::Entered {
+                    at: Instant::from_secs(10)
+                },
+                EclipseEvent::Exited {
+                    at: Instant::from_secs(41),
+                    duration: Some(Duration::from_secs(31))
+                },
+            ],
+            events
+        );
+        assert_eq!(Illumination::Sunlit, detector.illumination());
+        assert_eq!(Some(Duration::from_secs(31)), detector.last_duration());
+        assert_eq!(1, detector.eclipses());
+    }
+
+    #[test]
+    fn test_booting_in_eclipse() {
+        let mut detector = EclipseDetector::new(EclipseConfig::SOLAR_6V);
+
+        let mut trace = [0_u32; 20];
+        for millivolts in &mut trace[..3] {
+            *millivolts = 2000;
+        }
+        for millivolts in &mut trace[10..] {
+            *millivolts = 4000;
+        }
+        let events = run_trace(&mut detector, &trace);
+
+        assert_eq!(
+            std::vec![EclipseEvent::Exited {
+                at: Instant::from_secs(10),
+                duration: None
+            }],
+            events
+        );
+        assert_eq!(None, detector.last_duration());
+        assert_eq!(0, detector.eclipses());
+        assert_eq!(Instant::from_secs(10), events[0].at());
+    }
+
+    #[test]
+    fn test_unknown_within_hysteresis() {
+        let mut detector = EclipseDetector::new(EclipseConfig::SOLAR_6V);
+
+        assert!(run_trace(&mut detector, &[2000, 2900, 1500]).is_empty());
+        assert_eq!(Illumination::Unknown, detector.illumination());
+    }
+}
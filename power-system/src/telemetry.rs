@@ -0,0 +1,675 @@
+//! Telemetry sent to the onboard computer over UART.
+//!
+//! The telemetry is sent as proprietary NMEA 0183 sentences (`$PPWR,...*hh\r\n`)
+//! since the onboard computer already parses NMEA sentences from the GNSS.
+//! The first field is the type of the sentence, e.g.:
+//!
+//! - `$PPWR,BAT,3830,520,NOMINAL,EMPTY,371,98*hh` - battery voltage (mV), State of Charge (‰),
+//!   power mode and the prediction - time to `EMPTY` or `FULL` (minutes) with its confidence (%),
+//!   the prediction fields are empty when there's no prediction
+//! - `$PPWR,MODE,NOMINAL,LOW,3599*hh` - power mode change from, to and the battery voltage (mV)
+//! - `$PPWR,SUP,5012,CHARGING*hh` - supply-side voltage (mV) and charging state
+//! - `$PPWR,CHG,CHARGING,CHARGING_BUT_FALLING*hh` - CN3791 charger state and its disagreement
+//!   with the battery voltage trend (empty when they agree)
+//! - `$PPWR,SOL,5230,SUNLIT*hh` - solar panels' voltage (mV) and the illumination
+//!   (`SUNLIT`, `ECLIPSE` or `UNKNOWN`)
+//! - `$PPWR,HLTH,95,210,124.500*hh` - State of Health (%), internal resistance (mΩ, empty
+//!   until it's estimated) and the equivalent full cycles
+//! - `$PPWR,TEMP,-5.2,NTC,CHARGE*hh` - battery temperature (°C), its source (`NTC` or `INT`)
+//!   and the inhibits (`NONE`, `CHARGE`, `DISCHARGE` or `BOTH`)
+//! - `$PPWR,NRG,MIN,60.0,0.0,15.0,75.0*hh` - energy (mWh) harvested from the solar panels,
+//!   charged into and discharged from the battery and consumed by the bus in the last minute
+//!   (`MIN`), the last orbit (`ORBIT`) and since boot (`BOOT`), only sent with current sensors
+//! - `$PPWR,ERR,2,1,0*hh` - failed ADC readings since boot: timeouts, ADC errors and
+//!   out of range values, only sent after the first failure
+//! - `$PPWR,ALARM,UNDER_VOLTAGE,RAISED,3290*hh` - battery alarm raised or cleared
+//!   and the battery voltage (mV)
+//! - `$PPWR,ECL,ENTER,5520*hh` and `$PPWR,ECL,EXIT,7680,2160*hh` - eclipse entry and exit
+//!   (seconds since boot) and the eclipse duration (seconds, empty when the entry was not seen)
+//! - `$PPWR,RAILS,ON,ON,SHED,OFF*hh` - state of the OBC, GNSS, radio and payload power rails
+//!   (`ON`, `OFF`, `SHED` or `TRIPPED`, empty when the rail is not managed)
+//! - `$PPWR,RAIL,PAYLOAD,ON,OK,ON*hh` and `$PPWR,RAIL,PAYLOAD,ON,REFUSED,SHED*hh` - response
+//!   to a rail command (`$PPWC,RAIL,PAYLOAD,ON*hh`): the rail, the action and either the new
+//!   state of the rail or the interlock which refused it
+//! - `$PPWR,CMD,CHECKSUM*hh` - a command which couldn't be parsed and why
+use core::fmt::{self, Write};
+
+use defmt::Format;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use heapless::String;
+
+use crate::{
+    alarm::AlarmEvent,
+    battery::MeasurementFailures,
+    charger::{ChargerState, Disagreement},
+    energy::{Energy, EnergyPeriod},
+    power_mode::{PowerMode, PowerModeChange},
+    power_sense::ChargingState,
+    prediction::Prediction,
+    rails::{CommandError, CommandResponse, RailId, RailState},
+    solar::{EclipseEvent, Illumination},
+    temperature::{Inhibits, TemperatureSource},
+};
+
+/// The maximum length of an NMEA sentence, including the `$` and `\r\n`
+pub const MAX_SENTENCE_LENGTH: usize = 82;
+
+pub type Sentence = String<MAX_SENTENCE_LENGTH>;
+
+/// The latest telemetry, updated by the measurement tasks and sent by the UART task.
+pub static TELEMETRY: Mutex<CriticalSectionRawMutex, Telemetry> = Mutex::new(Telemetry::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct BatteryTelemetry {
+    pub millivolts: u32,
+    /// State of Charge in per-mille
+    pub permille: u16,
+    /// Time to empty or full, `None` when it's not known
+    pub prediction: Option<Prediction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct PowerSenseTelemetry {
+    /// Supply-side (solar or USB) voltage
+    pub millivolts: u32,
+    pub charging_state: ChargingState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct ChargerTelemetry {
+    pub state: ChargerState,
+    /// `None` while the charger state agrees with the battery voltage
+    pub disagreement: Option<Disagreement>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct SolarTelemetry {
+    /// Solar panels' voltage
+    pub millivolts: u32,
+    pub illumination: Illumination,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct HealthTelemetry {
+    /// State of Health in percentage
+    pub state_of_health: u8,
+    pub resistance_milliohms: Option<u16>,
+    /// Equivalent full cycles in per-mille
+    pub cycles_permille: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct TemperatureTelemetry {
+    /// Battery temperature in `0.1 °C`
+    pub decicelsius: i16,
+    pub source: TemperatureSource,
+    pub inhibits: Inhibits,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct EnergyTelemetry {
+    /// `None` for the first minute after boot
+    pub last_minute: Option<Energy>,
+    /// `None` for the first orbit after boot
+    pub last_orbit: Option<Energy>,
+    pub since_boot: Energy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct RailsTelemetry {
+    /// In the order of [`RailId::ALL`], `None` when the rail is not managed
+    pub states: [Option<RailState>; RailId::ALL.len()],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Telemetry {
+    /// `None` until the first successful battery measurement
+    pub battery: Option<BatteryTelemetry>,
+    pub power_mode: PowerMode,
+    /// `None` until the first successful supply-side measurement
+    pub power_sense: Option<PowerSenseTelemetry>,
+    /// `None` until the first charger state
+    pub charger: Option<ChargerTelemetry>,
+    /// `None` until the first solar panels' measurement
+    pub solar: Option<SolarTelemetry>,
+    /// `None` until the battery health is known
+    pub health: Option<HealthTelemetry>,
+    /// `None` until the first temperature reading
+    pub temperature: Option<TemperatureTelemetry>,
+    /// `None` without current sensors
+    pub energy: Option<EnergyTelemetry>,
+    /// `None` until the rails are switched after boot
+    pub rails: Option<RailsTelemetry>,
+    /// The failed ADC readings since boot
+    pub failures: MeasurementFailures,
+}
+
+impl Telemetry {
+    pub const fn new() -> Self {
+        Self {
+            battery: None,
+            power_mode: PowerMode::Nominal,
+            power_sense: None,
+            charger: None,
+            solar: None,
+            health: None,
+            temperature: None,
+            energy: None,
+            rails: None,
+            failures: MeasurementFailures::new(),
+        }
+    }
+
+    /// Writes all the telemetry sentences which have data.
+    pub fn write_sentences<W: Write>(&self, writer: &mut W) -> fmt::Result {
+        if let Some(battery) = self.battery {
+            let battery_sentence = match battery.prediction {
+                Some(prediction) => sentence(format_args!(
+                    "BAT,{},{},{},{},{},{}",
+                    battery.millivolts,
+                    battery.permille,
+                    self.power_mode.as_str(),
+                    prediction.kind.as_str(),
+                    prediction.minutes,
+                    prediction.confidence
+                )),
+                None => sentence(format_args!(
+                    "BAT,{},{},{},,,",
+                    battery.millivolts,
+                    battery.permille,
+                    self.power_mode.as_str()
+                )),
+            }?;
+            writer.write_str(&battery_sentence)?;
+        }
+
+        if let Some(power_sense) = self.power_sense {
+            writer.write_str(&sentence(format_args!(
+                "SUP,{},{}",
+                power_sense.millivolts,
+                power_sense.charging_state.as_str()
+            ))?)?;
+        }
+
+        if let Some(charger) = self.charger {
+            writer.write_str(&sentence(format_args!(
+                "CHG,{},{}",
+                charger.state.as_str(),
+                charger
+                    .disagreement
+                    .map_or("", |disagreement| disagreement.as_str())
+            ))?)?;
+        }
+
+        if let Some(solar) = self.solar {
+            writer.write_str(&sentence(format_args!(
+                "SOL,{},{}",
+                solar.millivolts,
+                solar.illumination.as_str()
+            ))?)?;
+        }
+
+        if let Some(health) = self.health {
+            let cycles = health.cycles_permille;
+            let health_sentence = match health.resistance_milliohms {
+                Some(milliohms) => sentence(format_args!(
+                    "HLTH,{},{},{}.{:03}",
+                    health.state_of_health,
+                    milliohms,
+                    cycles / 1000,
+                    cycles % 1000
+                )),
+                None => sentence(format_args!(
+                    "HLTH,{},,{}.{:03}",
+                    health.state_of_health,
+                    cycles / 1000,
+                    cycles % 1000
+                )),
+            }?;
+            writer.write_str(&health_sentence)?;
+        }
+
+        if let Some(temperature) = self.temperature {
+            let decicelsius = temperature.decicelsius;
+            writer.write_str(&sentence(format_args!(
+                "TEMP,{}{}.{},{},{}",
+                if decicelsius < 0 { "-" } else { "" },
+                decicelsius.unsigned_abs() / 10,
+                decicelsius.unsigned_abs() % 10,
+                temperature.source.as_str(),
+                temperature.inhibits.as_str()
+            ))?)?;
+        }
+
+        if let Some(energy) = self.energy {
+            let periods = [
+                (EnergyPeriod::Minute, energy.last_minute),
+                (EnergyPeriod::Orbit, energy.last_orbit),
+                (EnergyPeriod::Boot, Some(energy.since_boot)),
+            ];
+            for (period, energy) in periods {
+                if let Some(energy) = energy {
+                    writer.write_str(&energy.to_sentence(period)?)?;
+                }
+            }
+        }
+
+        if let Some(rails) = self.rails {
+            let [obc, gnss, radio, payload] = rails
+                .states
+                .map(|state| state.map_or("", |state| state.as_str()));
+            writer.write_str(&sentence(format_args!(
+                "RAILS,{},{},{},{}",
+                obc, gnss, radio, payload
+            ))?)?;
+        }
+
+        if self.failures.total() > 0 {
+            writer.write_str(&sentence(format_args!(
+                "ERR,{},{},{}",
+                self.failures.timeouts, self.failures.adc_errors, self.failures.out_of_range
+            ))?)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PowerModeChange {
+    pub fn to_sentence(&self) -> Result<Sentence, fmt::Error> {
+        sentence(format_args!(
+            "MODE,{},{},{}",
+            self.from.as_str(),
+            self.to.as_str(),
+            self.millivolts
+        ))
+    }
+}
+
+impl AlarmEvent {
+    pub fn to_sentence(&self) -> Result<Sentence, fmt::Error> {
+        sentence(format_args!(
+            "ALARM,{},{},{}",
+            self.alarm.as_str(),
+            self.state.as_str(),
+            self.millivolts
+        ))
+    }
+}
+
+impl EclipseEvent {
+    pub fn to_sentence(&self) -> Result<Sentence, fmt::Error> {
+        match self {
+            EclipseEvent::Entered { at } => sentence(format_args!("ECL,ENTER,{}", at.as_secs())),
+            EclipseEvent::Exited {
+                at,
+                duration: Some(duration),
+            } => sentence(format_args!(
+                "ECL,EXIT,{},{}",
+                at.as_secs(),
+                duration.as_secs()
+            )),
+            EclipseEvent::Exited { at, duration: None } => {
+                sentence(format_args!("ECL,EXIT,{},", at.as_secs()))
+            }
+        }
+    }
+}
+
+impl Energy {
+    pub fn to_sentence(&self, period: EnergyPeriod) -> Result<Sentence, fmt::Error> {
+        let [harvested, charged, discharged, consumed] =
+            [self.harvested, self.charged, self.discharged, self.consumed]
+                .map(Energy::decimilliwatt_hours);
+
+        sentence(format_args!(
+            "NRG,{},{}.{},{}.{},{}.{},{}.{}",
+            period.as_str(),
+            harvested / 10,
+            harvested % 10,
+            charged / 10,
+            charged % 10,
+            discharged / 10,
+            discharged % 10,
+            consumed / 10,
+            consumed % 10
+        ))
+    }
+}
+
+impl CommandResponse {
+    pub fn to_sentence(&self) -> Result<Sentence, fmt::Error> {
+        let rail = self.command.rail.as_str();
+        let action = self.command.action.as_str();
+
+        match self.result {
+            Ok(state) => sentence(format_args!(
+                "RAIL,{},{},OK,{}",
+                rail,
+                action,
+                state.as_str()
+            )),
+            Err(interlock) => sentence(format_args!(
+                "RAIL,{},{},REFUSED,{}",
+                rail,
+                action,
+                interlock.as_str()
+            )),
+        }
+    }
+}
+
+impl CommandError {
+    pub fn to_sentence(&self) -> Result<Sentence, fmt::Error> {
+        sentence(format_args!("CMD,{}", self.as_str()))
+    }
+}
+
+/// Creates a `$PPWR` sentence with the given fields and calculates its checksum.
+pub fn sentence(fields: fmt::Arguments) -> Result<Sentence, fmt::Error> {
+    // without the `$`, `*hh` and `\r\n`
+    let mut body = String::<{ MAX_SENTENCE_LENGTH - 6 }>::new();
+    write!(body, "PPWR,{}", fields)?;
+
+    let mut sentence = Sentence::new();
+    write!(sentence, "${}*{:02X}\r\n", body, checksum(body.as_bytes()))?;
+
+    Ok(sentence)
+}
+
+/// The NMEA checksum - XOR of all the bytes between the `$` and the `*`
+pub fn checksum(body: &[u8]) -> u8 {
+    body.iter().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use embassy_time::{Duration, Instant};
+
+    use crate::{
+        alarm::{AlarmState, BatteryAlarm},
+        prediction::PredictionKind,
+        rails::{Interlock, RailAction, RailCommand},
+    };
+
+    #[test]
+    fn test_checksum() {
+        // a sentence from tests/nmea.log
+        assert_eq!(0x1E, checksum(b"GPGSA,A,1,,,,,,,,,,,,,,,"));
+    }
+
+    #[test]
+    fn test_telemetry_sentences() {
+        let mut telemetry = Telemetry::new();
+        let mut output = String::<256>::new();
+
+        // nothing to send before the first measurement
+        telemetry.write_sentences(&mut output).unwrap();
+        assert!(output.is_empty());
+
+        telemetry.battery = Some(BatteryTelemetry {
+            millivolts: 3830,
+            permille: 520,
+            prediction: None,
+        });
+        telemetry.write_sentences(&mut output).unwrap();
+
+        let expected_checksum = checksum(b"PPWR,BAT,3830,520,NOMINAL,,,");
+        let expected = std::format!("$PPWR,BAT,3830,520,NOMINAL,,,*{expected_checksum:02X}\r\n");
+        assert_eq!(expected.as_str(), output.as_str());
+
+        output.clear();
+        telemetry.battery = Some(BatteryTelemetry {
+            millivolts: 3830,
+            permille: 520,
+            prediction: Some(Prediction {
+                kind: PredictionKind::TimeToEmpty,
+                minutes: 371,
+                confidence: 98,
+            }),
+        });
+        telemetry.write_sentences(&mut output).unwrap();
+        assert!(output.starts_with("$PPWR,BAT,3830,520,NOMINAL,EMPTY,371,98*"));
+
+        output.clear();
+        telemetry.power_sense = Some(PowerSenseTelemetry {
+            millivolts: 5012,
+            charging_state: ChargingState::Charging,
+        });
+        telemetry.write_sentences(&mut output).unwrap();
+        let mut lines = output.split_terminator("\r\n");
+        assert!(lines.next().unwrap().starts_with("$PPWR,BAT,"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("$PPWR,SUP,5012,CHARGING*"));
+        assert_eq!(None, lines.next());
+
+        output.clear();
+        telemetry.power_sense = None;
+        telemetry.solar = Some(SolarTelemetry {
+            millivolts: 5230,
+            illumination: Illumination::Sunlit,
+        });
+        telemetry.write_sentences(&mut output).unwrap();
+        assert!(output
+            .split_terminator("\r\n")
+            .nth(1)
+            .unwrap()
+            .starts_with("$PPWR,SOL,5230,SUNLIT*"));
+
+        output.clear();
+        telemetry.solar = None;
+        telemetry.charger = Some(ChargerTelemetry {
+            state: ChargerState::Charging,
+            disagreement: Some(Disagreement::ChargingButFalling),
+        });
+        telemetry.write_sentences(&mut output).unwrap();
+        assert!(output
+            .split_terminator("\r\n")
+            .nth(1)
+            .unwrap()
+            .starts_with("$PPWR,CHG,CHARGING,CHARGING_BUT_FALLING*"));
+
+        output.clear();
+        telemetry.charger = Some(ChargerTelemetry {
+            state: ChargerState::Idle,
+            disagreement: None,
+        });
+        telemetry.write_sentences(&mut output).unwrap();
+        assert!(output
+            .split_terminator("\r\n")
+            .nth(1)
+            .unwrap()
+            .starts_with("$PPWR,CHG,IDLE,*"));
+
+        output.clear();
+        telemetry.charger = None;
+        telemetry.health = Some(HealthTelemetry {
+            state_of_health: 95,
+            resistance_milliohms: None,
+            cycles_permille: 124_050,
+        });
+        telemetry.write_sentences(&mut output).unwrap();
+        assert!(output
+            .split_terminator("\r\n")
+            .nth(1)
+            .unwrap()
+            .starts_with("$PPWR,HLTH,95,,124.050*"));
+
+        output.clear();
+        telemetry.health = Some(HealthTelemetry {
+            resistance_milliohms: Some(210),
+            ..telemetry.health.unwrap()
+        });
+        telemetry.failures = MeasurementFailures {
+            timeouts: 2,
+            adc_errors: 1,
+            out_of_range: 0,
+        };
+        telemetry.write_sentences(&mut output).unwrap();
+        let mut lines = output.split_terminator("\r\n").skip(1);
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("$PPWR,HLTH,95,210,124.050*"));
+        assert!(lines.next().unwrap().starts_with("$PPWR,ERR,2,1,0*"));
+
+        output.clear();
+        telemetry.health = None;
+        telemetry.temperature = Some(TemperatureTelemetry {
+            decicelsius: -52,
+            source: TemperatureSource::Ntc,
+            inhibits: Inhibits {
+                charge: true,
+                discharge: false,
+            },
+        });
+        telemetry.write_sentences(&mut output).unwrap();
+        assert!(output
+            .split_terminator("\r\n")
+            .nth(1)
+            .unwrap()
+            .starts_with("$PPWR,TEMP,-5.2,NTC,CHARGE*"));
+
+        output.clear();
+        telemetry.temperature = Some(TemperatureTelemetry {
+            decicelsius: 5,
+            source: TemperatureSource::Internal,
+            inhibits: Inhibits::NONE,
+        });
+        telemetry.write_sentences(&mut output).unwrap();
+        assert!(output
+            .split_terminator("\r\n")
+            .nth(1)
+            .unwrap()
+            .starts_with("$PPWR,TEMP,0.5,INT,NONE*"));
+        telemetry.temperature = None;
+
+        output.clear();
+        let minute = Energy {
+            harvested: 216_000_000,
+            charged: 0,
+            discharged: 54_000_000,
+            consumed: 270_000_000,
+        };
+        telemetry.energy = Some(EnergyTelemetry {
+            last_minute: Some(minute),
+            last_orbit: None,
+            since_boot: Energy {
+                harvested: 36_000_000_000_000,
+                ..minute
+            },
+        });
+        telemetry.write_sentences(&mut output).unwrap();
+        let mut lines = output.split_terminator("\r\n").skip(1);
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("$PPWR,NRG,MIN,60.0,0.0,15.0,75.0*"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("$PPWR,NRG,BOOT,10000000.0,0.0,15.0,75.0*"));
+        telemetry.energy = None;
+
+        output.clear();
+        telemetry.rails = Some(RailsTelemetry {
+            states: [
+                Some(RailState::On),
+                None,
+                Some(RailState::Shed),
+                Some(RailState::Tripped),
+            ],
+        });
+        telemetry.write_sentences(&mut output).unwrap();
+        assert!(output
+            .split_terminator("\r\n")
+            .nth(1)
+            .unwrap()
+            .starts_with("$PPWR,RAILS,ON,,SHED,TRIPPED*"));
+        telemetry.rails = None;
+
+        let change = PowerModeChange {
+            from: PowerMode::Nominal,
+            to: PowerMode::Low,
+            millivolts: 3599,
+        };
+        let sentence = change.to_sentence().unwrap();
+        assert!(sentence.starts_with("$PPWR,MODE,NOMINAL,LOW,3599*"));
+        assert!(sentence.ends_with("\r\n"));
+
+        let alarm = AlarmEvent {
+            alarm: BatteryAlarm::UnderVoltage,
+            state: AlarmState::Raised,
+            millivolts: 3290,
+        };
+        let sentence = alarm.to_sentence().unwrap();
+        assert!(sentence.starts_with("$PPWR,ALARM,UNDER_VOLTAGE,RAISED,3290*"));
+
+        let entered = EclipseEvent::Entered {
+            at: Instant::from_secs(5520),
+        };
+        assert!(entered
+            .to_sentence()
+            .unwrap()
+            .starts_with("$PPWR,ECL,ENTER,5520*"));
+        let exited = EclipseEvent::Exited {
+            at: Instant::from_millis(7_680_999),
+            duration: Some(Duration::from_secs(2160)),
+        };
+        assert!(exited
+            .to_sentence()
+            .unwrap()
+            .starts_with("$PPWR,ECL,EXIT,7680,2160*"));
+        let exited = EclipseEvent::Exited {
+            at: Instant::from_secs(7680),
+            duration: None,
+        };
+        assert!(exited
+            .to_sentence()
+            .unwrap()
+            .starts_with("$PPWR,ECL,EXIT,7680,*"));
+
+        let command = RailCommand {
+            rail: RailId::Payload,
+            action: RailAction::On,
+        };
+        let accepted = CommandResponse {
+            command,
+            result: Ok(RailState::On),
+        };
+        assert!(accepted
+            .to_sentence()
+            .unwrap()
+            .starts_with("$PPWR,RAIL,PAYLOAD,ON,OK,ON*"));
+        let refused = CommandResponse {
+            command,
+            result: Err(Interlock::Shed),
+        };
+        assert!(refused
+            .to_sentence()
+            .unwrap()
+            .starts_with("$PPWR,RAIL,PAYLOAD,ON,REFUSED,SHED*"));
+        assert!(CommandError::Checksum
+            .to_sentence()
+            .unwrap()
+            .starts_with("$PPWR,CMD,CHECKSUM*"));
+    }
+
+    #[test]
+    fn test_sentence_too_long() {
+        let long_field = [b'A'; MAX_SENTENCE_LENGTH];
+        let long_field = core::str::from_utf8(&long_field).unwrap();
+
+        assert!(sentence(format_args!("{}", long_field)).is_err());
+    }
+}
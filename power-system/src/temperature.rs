@@ -0,0 +1,503 @@
+//! Battery temperature and its effects on the State of Charge and charging.
+//!
+//! The temperature comes from either:
+//!
+//! - an NTC thermistor on the battery in a voltage divider on an ADC1 channel,
+//!   converted to temperature with the [`SteinhartHart`] equation, or
+//! - the ESP32-C3 internal sensor, which measures the chip and not the battery,
+//!   so it's only a fallback when the NTC is not connected.
+//!
+//! All the temperatures are in decidegrees Celsius (`0.1 °C`), e.g. `-52` is `-5.2 °C`.
+use defmt::Format;
+
+use crate::{
+    adc::ADC_MAX,
+    battery::{Battery, Chemistry},
+    calibration::FixedCalibration,
+    helper::ln,
+};
+
+/// Where the temperature comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum TemperatureSource {
+    /// The ESP32-C3 internal sensor
+    Internal,
+    /// The NTC thermistor on the battery
+    Ntc,
+}
+
+impl TemperatureSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TemperatureSource::Internal => "INT",
+            TemperatureSource::Ntc => "NTC",
+        }
+    }
+}
+
+/// The Steinhart–Hart equation of a thermistor: `1/T = A + B ln(R) + C ln(R)^3`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SteinhartHart {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+}
+
+impl SteinhartHart {
+    /// A `10 kΩ` NTC with `B = 3950 K`, i.e. [`SteinhartHart::from_beta`]`(3950.0, 10_000.0, 250)`
+    pub const NTC_10K_3950: SteinhartHart = SteinhartHart {
+        a: 1.022_284_6e-3,
+        b: 2.531_645_6e-4,
+        c: 0.0,
+    };
+
+    const ZERO_CELSIUS_KELVIN: f32 = 273.15;
+
+    /// The coefficients of a thermistor from its datasheet `B` constant (in K)
+    /// and its resistance (in Ohms) at the given temperature (in `0.1 °C`, usually `25 °C`).
+    pub fn from_beta(beta: f32, nominal_ohms: f32, nominal_decicelsius: i16) -> Self {
+        let nominal_kelvin = nominal_decicelsius as f32 / 10.0 + Self::ZERO_CELSIUS_KELVIN;
+
+        Self {
+            a: 1.0 / nominal_kelvin - ln(nominal_ohms) / beta,
+            b: 1.0 / beta,
+            c: 0.0,
+        }
+    }
+
+    /// The temperature (in `0.1 °C`) of the thermistor at the given resistance (in Ohms)
+    pub fn decicelsius(&self, ohms: f32) -> i16 {
+        let ln_r = ln(ohms);
+        let kelvin = 1.0 / (self.a + self.b * ln_r + self.c * ln_r * ln_r * ln_r);
+        let decicelsius = (kelvin - Self::ZERO_CELSIUS_KELVIN) * 10.0;
+
+        // rounds to the nearest
+        let rounded = if decicelsius < 0.0 {
+            decicelsius - 0.5
+        } else {
+            decicelsius + 0.5
+        };
+
+        rounded.clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+}
+
+/// An NTC thermistor between the ADC pin and ground,
+/// with a fixed resistor between the supply and the ADC pin.
+///
+/// With the 11dB attenuation the ADC saturates at about `2.5 V`, well below the supply,
+/// so the series resistor must keep the pin below that at the coldest temperature of interest.
+/// A saturated pin can't be told apart from a disconnected thermistor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thermistor {
+    pub coefficients: SteinhartHart,
+    /// The fixed resistor of the divider (in Ohms)
+    pub series_ohms: u32,
+    /// The voltage (in mV) the divider is supplied with
+    pub supply_millivolts: u32,
+    /// The minimum and maximum resistance (in Ohms) of the thermistor's R/T table,
+    /// anything outside of it is a wiring fault
+    pub plausible_ohms: (u32, u32),
+}
+
+impl Thermistor {
+    /// A `10 kΩ` NTC with a `47 kΩ` resistor from the `3.3 V` supply.
+    ///
+    /// The pin is at `2.2 V` at `-20 °C` and saturates the ADC below about `-25 °C`,
+    /// the plausible range is the `-40..=125 °C` of the R/T table.
+    pub const NTC_10K_3950: Thermistor = Thermistor {
+        coefficients: SteinhartHart::NTC_10K_3950,
+        series_ohms: 47_000,
+        supply_millivolts: 3300,
+        plausible_ohms: (350, 350_000),
+    };
+
+    /// Raw ADC values this close to [`ADC_MAX`] are a saturated (or floating) pin
+    pub const SATURATION_MARGIN: u16 = 16;
+
+    /// The resistance (in Ohms) of the thermistor for the voltage (in mV) at the ADC pin.
+    ///
+    /// Returns `None` when the thermistor is shorted (`0 mV`), disconnected (at the supply)
+    /// or the resistance is outside of the plausible range.
+    pub fn ohms(&self, pin_millivolts: u32) -> Option<f32> {
+        if pin_millivolts == 0 || pin_millivolts >= self.supply_millivolts {
+            return None;
+        }
+
+        let ohms = self.series_ohms as f32 * pin_millivolts as f32
+            / (self.supply_millivolts - pin_millivolts) as f32;
+        let (min_ohms, max_ohms) = self.plausible_ohms;
+
+        (min_ohms as f32..=max_ohms as f32)
+            .contains(&ohms)
+            .then_some(ohms)
+    }
+
+    /// The temperature (in `0.1 °C`) for the voltage (in mV) at the ADC pin,
+    /// `None` when the thermistor is shorted or disconnected.
+    pub fn decicelsius(&self, pin_millivolts: u32) -> Option<i16> {
+        self.ohms(pin_millivolts)
+            .map(|ohms| self.coefficients.decicelsius(ohms))
+    }
+
+    /// The temperature (in `0.1 °C`) for the raw ADC value of the pin.
+    ///
+    /// A saturated ADC is treated as a disconnected thermistor, since the calibrated
+    /// voltage of the full scale is below the supply and would read as a very cold one.
+    pub fn raw_decicelsius(&self, raw: u16, calibration: &FixedCalibration) -> Option<i16> {
+        if raw >= ADC_MAX - Self::SATURATION_MARGIN {
+            return None;
+        }
+
+        self.decicelsius(calibration.to_millivolts(raw))
+    }
+}
+
+/// The ESP32-C3 internal sensor output in `0.1 °C` for the default range (`-10..=80 °C`).
+///
+/// `T = 0.4386 * raw - 20.52 °C`, the conversion of ESP-IDF without a DAC offset.
+pub fn internal_decicelsius(raw: u8) -> i16 {
+    let millicelsius_x10 = 4386 * raw as i32 - 205_200;
+
+    // rounds to the nearest
+    ((millicelsius_x10 + 500 * millicelsius_x10.signum()) / 1000) as i16
+}
+
+/// The ESP32-C3 internal temperature sensor.
+#[cfg(feature = "riscv")]
+pub struct InternalTemperatureSensor {
+    _private: (),
+}
+
+#[cfg(feature = "riscv")]
+impl InternalTemperatureSensor {
+    /// Enables the clock of the sensor and powers it up.
+    pub fn new() -> Self {
+        // Safety: only the temperature sensor bits are modified
+        let system = unsafe { &*hal::peripherals::SYSTEM::PTR };
+        system
+            .perip_clk_en1
+            .modify(|_, w| w.tsens_clk_en().set_bit());
+
+        // Safety: the ADC1 driver doesn't use the temperature sensor registers
+        let saradc = unsafe { &*hal::peripherals::APB_SARADC::PTR };
+        // XTAL clock
+        saradc
+            .tsens_ctrl2
+            .modify(|_, w| w.tsens_clk_sel().set_bit());
+        saradc.apb_tsens_ctrl.modify(|_, w| w.tsens_pu().set_bit());
+
+        Self { _private: () }
+    }
+
+    /// The temperature of the chip in `0.1 °C`
+    pub fn read_decicelsius(&mut self) -> i16 {
+        // Safety: reading the sensor output doesn't change anything
+        let saradc = unsafe { &*hal::peripherals::APB_SARADC::PTR };
+
+        internal_decicelsius(saradc.apb_tsens_ctrl.read().tsens_out().bits())
+    }
+}
+
+#[cfg(feature = "riscv")]
+impl Default for InternalTemperatureSensor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Corrects the State of Charge for the temperature.
+///
+/// The OCV curves are measured at room temperature, when it's colder:
+///
+/// - the cell voltage is lower for the same State of Charge and
+/// - the bottom of the capacity is not usable, the voltage reaches the cut-out earlier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TemperatureCompensation {
+    /// The temperature (in `0.1 °C`) of the OCV curve
+    pub reference_decicelsius: i16,
+    /// How much the cell voltage drops (in µV) per `1 °C` below the reference,
+    /// and rises above it
+    pub cell_microvolts_per_celsius: i32,
+    /// The usable capacity (in ‰) at ascending temperatures (in `0.1 °C`),
+    /// interpolated between them and clamped outside of them
+    pub usable_capacity: &'static [(i16, u16)],
+}
+
+impl TemperatureCompensation {
+    pub const LIPO: TemperatureCompensation = TemperatureCompensation {
+        reference_decicelsius: 250,
+        cell_microvolts_per_celsius: 1000,
+        usable_capacity: &[(-200, 600), (-100, 750), (0, 850), (100, 950), (250, 1000)],
+    };
+
+    pub const LIFEPO4: TemperatureCompensation = TemperatureCompensation {
+        reference_decicelsius: 250,
+        cell_microvolts_per_celsius: 500,
+        usable_capacity: &[(-200, 550), (-100, 700), (0, 850), (250, 1000)],
+    };
+
+    pub const NIMH: TemperatureCompensation = TemperatureCompensation {
+        reference_decicelsius: 250,
+        cell_microvolts_per_celsius: 500,
+        usable_capacity: &[(-200, 500), (0, 800), (250, 1000)],
+    };
+
+    pub fn for_chemistry(chemistry: Chemistry) -> Self {
+        match chemistry {
+            Chemistry::LiPo => Self::LIPO,
+            Chemistry::LiFePO4 => Self::LIFEPO4,
+            Chemistry::NiMH => Self::NIMH,
+        }
+    }
+
+    /// The pack voltage (in mV) at the reference temperature.
+    pub fn millivolts(&self, millivolts: u32, battery: &Battery, decicelsius: i16) -> u32 {
+        let below_reference = (self.reference_decicelsius - decicelsius) as i64;
+        let cell_microvolts = below_reference * self.cell_microvolts_per_celsius as i64 / 10;
+        let pack_millivolts = cell_microvolts * battery.series_cells as i64 / 1000;
+
+        (millivolts as i64 + pack_millivolts).max(0) as u32
+    }
+
+    /// The usable capacity (in ‰) at the temperature (in `0.1 °C`).
+    pub fn usable_capacity(&self, decicelsius: i16) -> u16 {
+        let points = self.usable_capacity;
+        let (first, last) = match (points.first(), points.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return 1000,
+        };
+
+        if decicelsius <= first.0 {
+            return first.1;
+        }
+        if decicelsius >= last.0 {
+            return last.1;
+        }
+
+        points
+            .windows(2)
+            .find(|pair| decicelsius <= pair[1].0)
+            .map(|pair| {
+                let ((t0, c0), (t1, c1)) = (pair[0], pair[1]);
+                let offset = (decicelsius - t0) as i32 * (c1 as i32 - c0 as i32);
+
+                (c0 as i32 + offset / (t1 - t0) as i32) as u16
+            })
+            .unwrap_or(last.1)
+    }
+
+    /// The State of Charge (in ‰) of the usable capacity at the temperature,
+    /// for the State of Charge (in ‰) at the reference temperature.
+    pub fn usable_permille(&self, permille: u16, decicelsius: i16) -> u16 {
+        let usable = self.usable_capacity(decicelsius).clamp(1, 1000) as u32;
+        let unusable = 1000 - usable;
+
+        ((permille as u32).saturating_sub(unusable) * 1000 / usable).min(1000) as u16
+    }
+}
+
+/// Charging or discharging is not allowed at the current temperature
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct Inhibits {
+    pub charge: bool,
+    pub discharge: bool,
+}
+
+impl Inhibits {
+    pub const NONE: Inhibits = Inhibits {
+        charge: false,
+        discharge: false,
+    };
+
+    pub fn as_str(&self) -> &'static str {
+        match (self.charge, self.discharge) {
+            (false, false) => "NONE",
+            (true, false) => "CHARGE",
+            (false, true) => "DISCHARGE",
+            (true, true) => "BOTH",
+        }
+    }
+}
+
+/// The temperatures (in `0.1 °C`) at which the cells can be charged and discharged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct TemperatureLimits {
+    /// The minimum and maximum temperature for charging
+    pub charge: (i16, i16),
+    /// The minimum and maximum temperature for discharging
+    pub discharge: (i16, i16),
+    /// An inhibit is lifted once the temperature is this much within the limits
+    pub hysteresis: i16,
+}
+
+impl TemperatureLimits {
+    pub const LIPO: TemperatureLimits = TemperatureLimits {
+        charge: (0, 450),
+        discharge: (-200, 600),
+        hysteresis: 20,
+    };
+
+    pub const LIFEPO4: TemperatureLimits = TemperatureLimits {
+        charge: (0, 450),
+        discharge: (-200, 600),
+        hysteresis: 20,
+    };
+
+    pub const NIMH: TemperatureLimits = TemperatureLimits {
+        charge: (0, 450),
+        discharge: (-200, 500),
+        hysteresis: 20,
+    };
+
+    pub fn for_chemistry(chemistry: Chemistry) -> Self {
+        match chemistry {
+            Chemistry::LiPo => Self::LIPO,
+            Chemistry::LiFePO4 => Self::LIFEPO4,
+            Chemistry::NiMH => Self::NIMH,
+        }
+    }
+
+    /// The inhibits at the temperature, given the current ones for the hysteresis.
+    pub fn inhibits(&self, decicelsius: i16, current: Inhibits) -> Inhibits {
+        let inhibit = |(min, max): (i16, i16), inhibited: bool| {
+            if inhibited {
+                // lifted only once well within the limits
+                decicelsius < min.saturating_add(self.hysteresis)
+                    || decicelsius > max.saturating_sub(self.hysteresis)
+            } else {
+                decicelsius < min || decicelsius > max
+            }
+        };
+
+        Inhibits {
+            charge: inhibit(self.charge, current.charge),
+            discharge: inhibit(self.discharge, current.discharge),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_steinhart_hart() {
+        let from_beta = SteinhartHart::from_beta(3950.0, 10_000.0, 250);
+        let constant = SteinhartHart::NTC_10K_3950;
+        assert!((from_beta.a - constant.a).abs() < 1e-8, "{from_beta:?}");
+        assert!((from_beta.b - constant.b).abs() < 1e-10, "{from_beta:?}");
+
+        // from the R/T table of a 10k B3950 NTC,
+        // the B model is less accurate the further away from 25 °C
+        for (ohms, decicelsius) in [
+            (10_000.0, 250),
+            (32_650.0, 0),
+            (3_603.0, 500),
+            (96_300.0, -200),
+        ] {
+            let converted = constant.decicelsius(ohms);
+            assert!(
+                (converted - decicelsius).abs() <= 20,
+                "{ohms} Ohms: {converted}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_thermistor_divider() {
+        let thermistor = Thermistor {
+            series_ohms: 10_000,
+            ..Thermistor::NTC_10K_3950
+        };
+
+        // half of the supply at 25 °C
+        assert_eq!(Some(10_000.0), thermistor.ohms(1650));
+        assert_eq!(Some(250), thermistor.decicelsius(1650));
+
+        // colder is a higher resistance, 31.25 kOhm is ~1 °C
+        assert_eq!(Some(31_250.0), thermistor.ohms(2500));
+        assert_eq!(Some(14), thermistor.decicelsius(2500));
+
+        assert_eq!(None, thermistor.decicelsius(0));
+        assert_eq!(None, thermistor.decicelsius(3300));
+        // ~33 MOhm just below the supply is not a thermistor
+        assert_eq!(None, thermistor.decicelsius(3299));
+    }
+
+    #[test]
+    fn test_thermistor_raw() {
+        let thermistor = Thermistor::NTC_10K_3950;
+        let calibration = FixedCalibration::UNCALIBRATED;
+
+        // a disconnected thermistor saturates the ADC
+        assert_eq!(None, thermistor.raw_decicelsius(ADC_MAX, &calibration));
+        assert_eq!(None, thermistor.raw_decicelsius(ADC_MAX - 10, &calibration));
+        assert_eq!(None, thermistor.raw_decicelsius(0, &calibration));
+
+        // the discharge limit (-20 °C) is still measurable: ~2218 mV
+        let cold = thermistor
+            .raw_decicelsius(2754, &calibration)
+            .expect("Should measure -20 °C");
+        assert!((cold + 200).abs() <= 20, "{cold}");
+        // ~580 mV at 25 °C
+        let warm = thermistor
+            .raw_decicelsius(720, &calibration)
+            .expect("Should measure 25 °C");
+        assert!((warm - 250).abs() <= 20, "{warm}");
+    }
+
+    #[test]
+    fn test_internal_sensor() {
+        assert_eq!(233, internal_decicelsius(100));
+        assert_eq!(-205, internal_decicelsius(0));
+        assert_eq!(913, internal_decicelsius(255));
+    }
+
+    #[test]
+    fn test_compensation() {
+        let compensation = TemperatureCompensation::LIPO;
+        let battery = Battery::OLIMEX_LIPO_250MAH;
+
+        // no correction at the reference temperature
+        assert_eq!(3800, compensation.millivolts(3800, &battery, 250));
+        assert_eq!(500, compensation.usable_permille(500, 250));
+        // and above it the capacity stays the same
+        assert_eq!(1000, compensation.usable_capacity(400));
+
+        // 20 °C colder is 20 mV lower
+        assert_eq!(3820, compensation.millivolts(3800, &battery, 50));
+        assert_eq!(7640, compensation.millivolts(7600, &Battery::LIPO_2S, 50));
+
+        // interpolated between 0 °C and 10 °C
+        assert_eq!(900, compensation.usable_capacity(50));
+        // 10% of the capacity is not usable
+        assert_eq!(0, compensation.usable_permille(100, 50));
+        assert_eq!(500, compensation.usable_permille(550, 50));
+        assert_eq!(1000, compensation.usable_permille(1000, 50));
+        assert_eq!(600, compensation.usable_capacity(-400));
+    }
+
+    #[test]
+    fn test_inhibits() {
+        let limits = TemperatureLimits::LIPO;
+        let trace = [250, 10, -5, 10, 25, 470, 440, 420, 620, 590, 570];
+        let mut inhibits = Inhibits::NONE;
+
+        let states: std::vec::Vec<&str> = trace
+            .into_iter()
+            .map(|decicelsius| {
+                inhibits = limits.inhibits(decicelsius, inhibits);
+                inhibits.as_str()
+            })
+            .collect();
+
+        assert_eq!(
+            std::vec![
+                "NONE", "NONE", "CHARGE", // within the hysteresis
+                "CHARGE", "NONE", "CHARGE", "CHARGE", "NONE", "BOTH", "BOTH", "CHARGE",
+            ],
+            states
+        );
+    }
+}